@@ -0,0 +1,77 @@
+// benches/fanout.rs
+
+//! Fanout cost with 1k and 10k open connections. Presence broadcasts queue a frame on every
+//! connection; 1:1 messages scan the connection map for the recipient's and sender's sessions.
+//! Connections are simulated (no sockets), so the numbers isolate locking and routing.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use rust_chat::testing::{SimulatedConnection, TestServer};
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+
+const CONNECTION_COUNTS: [usize; 2] = [1_000, 10_000];
+
+fn setup(runtime: &Runtime, count: usize) -> (TestServer, Vec<SimulatedConnection>) {
+    runtime.block_on(async {
+        let server = TestServer::new().await;
+        let connections = server.simulate_connections(count).await;
+        (server, connections)
+    })
+}
+
+// Time to queue one status frame on every other connection. Draining the queues is not timed.
+fn status_broadcast(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("tokio runtime");
+    let mut group = c.benchmark_group("status_broadcast");
+    for count in CONNECTION_COUNTS {
+        let (server, mut connections) = setup(&runtime, count);
+        group.throughput(Throughput::Elements(count as u64 - 1));
+        group.bench_with_input(BenchmarkId::from_parameter(count), &count, |b, _| {
+            b.iter_custom(|iters| {
+                runtime.block_on(async {
+                    let mut elapsed = Duration::ZERO;
+                    for _ in 0..iters {
+                        let started = Instant::now();
+                        server.broadcast_status(&connections[0], "online").await;
+                        elapsed += started.elapsed();
+                        connections.iter_mut().for_each(|connection| {
+                            connection.drain();
+                        });
+                    }
+                    elapsed
+                })
+            });
+        });
+    }
+    group.finish();
+}
+
+// Latency of routing one chat message between two users while `count` connections are open.
+fn direct_message(c: &mut Criterion) {
+    let runtime = Runtime::new().expect("tokio runtime");
+    let mut group = c.benchmark_group("direct_message");
+    for count in CONNECTION_COUNTS {
+        let (server, mut connections) = setup(&runtime, count);
+        let to_user_id = connections[1].user_id();
+        group.throughput(Throughput::Elements(1));
+        group.bench_with_input(BenchmarkId::from_parameter(count), &count, |b, _| {
+            b.iter_custom(|iters| {
+                runtime.block_on(async {
+                    let mut elapsed = Duration::ZERO;
+                    for _ in 0..iters {
+                        let started = Instant::now();
+                        server.send_message(&connections[0], to_user_id, "benchmark message").await;
+                        elapsed += started.elapsed();
+                        connections[0].drain();
+                        connections[1].drain();
+                    }
+                    elapsed
+                })
+            });
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, status_broadcast, direct_message);
+criterion_main!(benches);
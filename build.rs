@@ -0,0 +1,15 @@
+// build.rs
+
+fn main() {
+    // The gRPC service is optional; only generate its code when the feature is enabled.
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/chat.proto");
+        // protox compiles the schema in pure Rust, so no system `protoc` is needed.
+        let descriptors = protox::compile(["proto/chat.proto"], ["proto"]).expect("invalid proto/chat.proto");
+        tonic_build::configure()
+            .build_client(false)
+            .compile_fds(descriptors)
+            .expect("failed to generate gRPC code");
+    }
+}
@@ -40,7 +40,7 @@ case $option in
         echo "1. Go to https://render.com"
         echo "2. Create account and connect GitHub"
         echo "3. New Web Service:"
-        echo "   - Build Command: cargo build --release"
+        echo "   - Build Command: cargo build --release --features full"
         echo "   - Start Command: ./target/release/rust_chat"
         echo "4. Create and wait for deployment"
         echo "5. Your URL: https://your-app.onrender.com"
@@ -0,0 +1,16 @@
+// fuzz/fuzz_targets/client_frame.rs
+
+// WebSocket frames parsed as ClientMessage and run through the message handlers.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rust_chat::fuzzing::FrameFuzzer;
+use std::sync::{Mutex, OnceLock};
+
+static FUZZER: OnceLock<Mutex<FrameFuzzer>> = OnceLock::new();
+
+fuzz_target!(|data: &[u8]| {
+    let fuzzer = FUZZER.get_or_init(|| Mutex::new(FrameFuzzer::new()));
+    fuzzer.lock().expect("fuzzer lock").frame(data);
+});
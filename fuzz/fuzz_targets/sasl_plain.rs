@@ -0,0 +1,11 @@
+// fuzz/fuzz_targets/sasl_plain.rs
+
+// Base64 SASL PLAIN responses of XMPP clients.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    rust_chat::fuzzing::sasl_plain(data);
+});
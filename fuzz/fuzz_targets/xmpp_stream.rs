@@ -0,0 +1,11 @@
+// fuzz/fuzz_targets/xmpp_stream.rs
+
+// Client bytes of an XMPP stream, parsed into stream headers and stanzas.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    rust_chat::fuzzing::xmpp_stream(data);
+});
@@ -0,0 +1,157 @@
+// src/access_log.rs
+
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use tracing::Span;
+use utoipa::OpenApi;
+use uuid::Uuid;
+use warp::http::header::CONTENT_LENGTH;
+use warp::http::HeaderMap;
+use warp::log::{Info, Log};
+use warp::trace::Trace;
+
+use crate::api_docs::ApiDoc;
+use crate::client_ip::client_ip;
+use crate::config::AccessLogConfig;
+use crate::redact::pseudonym;
+use crate::ws_handlers::AppState;
+
+/// Prefix of the versioned API, ignored when matching `log.access.routes`.
+const API_PREFIX: &str = "/api/v1";
+
+/// Routes served besides the JSON API described in the OpenAPI specification.
+const OTHER_ROUTES: &[&str] = &[
+    "/",
+    "/ws",
+    "/docs",
+    "/docs/openapi.json",
+    "/cluster/heartbeat",
+    "/cluster/presence",
+    "/_matrix/app/v1/transactions/{txn_id}",
+    "/_matrix/app/v1/users/{user_id}",
+    "/_matrix/app/v1/rooms/{alias}",
+    "/_matrix/app/v1/ping",
+];
+
+/// What paths matching no route are logged as.
+const UNMATCHED: &str = "(unmatched)";
+
+/// Templates of every route, without the API prefix.
+static ROUTES: OnceLock<Vec<String>> = OnceLock::new();
+
+/// The access log wrapped around the route tree: one event per HTTP request under the
+/// `access` target, with its method, route, status, latency, user and request body size.
+/// Requests are sampled by `log.access.sample_rate`, or the rate of the longest matching
+/// prefix in `log.access.routes`; slow requests and server errors are always logged, and
+/// slow ones as warnings.
+pub fn layer(app_state: Arc<AppState>) -> Log<impl Fn(Info<'_>) + Clone> {
+    warp::log::custom(move |info: Info<'_>| record(&app_state, &info))
+}
+
+/// The span around each HTTP request, at debug level. Every event logged while serving the
+/// request repeats its fields, so it names the route rather than the path, and the client
+/// address only as a pseudonym.
+pub fn request_span(app_state: Arc<AppState>) -> Trace<impl Fn(warp::trace::Info<'_>) -> Span + Clone> {
+    warp::trace(move |info: warp::trace::Info<'_>| {
+        let client_ip = request_client_ip(&app_state, info.remote_addr(), info.request_headers());
+        tracing::debug_span!("request", method = %info.method(), route = %route(info.path()), client_ip = ?client_ip.map(pseudonym))
+    })
+}
+
+/// The template of the route serving `path`, e.g. `/api/v1/hooks/{token}`, so that tokens,
+/// invite codes and usernames in paths stay out of the logs. Static files keep their name;
+/// other paths matching no route are logged as "(unmatched)".
+pub fn route(path: &str) -> String {
+    let (prefix, rest) = match path.strip_prefix(API_PREFIX) {
+        Some(rest) if rest.starts_with('/') => (API_PREFIX, rest),
+        _ => ("", path),
+    };
+    let routes = ROUTES.get_or_init(|| {
+        let api = ApiDoc::openapi().paths.paths.into_keys().map(|path| path.strip_prefix(API_PREFIX).map(str::to_string).unwrap_or(path));
+        api.chain(OTHER_ROUTES.iter().map(|route| route.to_string())).collect()
+    });
+    if let Some(template) = routes.iter().find(|template| route_matches(template, rest)) {
+        return format!("{}{}", prefix, template);
+    }
+    match path.strip_prefix('/') {
+        Some(file) if prefix.is_empty() && file.contains('.') && !file.contains('/') => path.to_string(),
+        _ => UNMATCHED.to_string(),
+    }
+}
+
+// Whether `path` has the segments of `template`, whose `{...}` segments match any.
+fn route_matches(template: &str, path: &str) -> bool {
+    template.split('/').count() == path.split('/').count()
+        && template.split('/').zip(path.split('/')).all(|(part, segment)| part.starts_with('{') || part == segment)
+}
+
+fn record(app_state: &AppState, info: &Info<'_>) {
+    let config = &app_state.config.log.access;
+    if !config.enabled {
+        return;
+    }
+    let latency = info.elapsed();
+    let slow = latency >= Duration::from_millis(config.slow_request_ms);
+    if !slow && !info.status().is_server_error() && !sampled(sample_rate(config, info.path())) {
+        return;
+    }
+    let headers = info.request_headers();
+    // Only sessions are looked up; bots' API tokens are left out of the log.
+    let user_id = header(headers, "x-session-key")
+        .and_then(|session_key| app_state.user_sessions.get(session_key))
+        .map(|session| session.user_id);
+    let request_bytes = header(headers, CONTENT_LENGTH.as_str()).and_then(|length| length.parse::<u64>().ok()).unwrap_or(0);
+    let client_ip = request_client_ip(app_state, info.remote_addr(), headers);
+    if slow {
+        tracing::warn!(
+            target: "access",
+            method = %info.method(),
+            route = %route(info.path()),
+            status = info.status().as_u16(),
+            latency_ms = latency.as_millis() as u64,
+            user_id = ?user_id,
+            request_bytes,
+            client_ip = ?client_ip.map(pseudonym),
+            slow = true,
+            "Slow request"
+        );
+    } else {
+        tracing::info!(
+            target: "access",
+            method = %info.method(),
+            route = %route(info.path()),
+            status = info.status().as_u16(),
+            latency_ms = latency.as_millis() as u64,
+            user_id = ?user_id,
+            request_bytes,
+            client_ip = ?client_ip.map(pseudonym),
+            "Request"
+        );
+    }
+}
+
+fn request_client_ip(app_state: &AppState, peer: Option<SocketAddr>, headers: &HeaderMap) -> Option<IpAddr> {
+    client_ip(peer, headers, &app_state.config.proxy.trusted_proxies)
+}
+
+fn header<'a>(headers: &'a HeaderMap, name: &str) -> Option<&'a str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+// The sample rate of the longest `routes` prefix matching `path`, versioned or not, or the
+// default rate.
+fn sample_rate(config: &AccessLogConfig, path: &str) -> f64 {
+    let path = path.strip_prefix(API_PREFIX).filter(|rest| rest.is_empty() || rest.starts_with('/')).unwrap_or(path);
+    config
+        .routes
+        .iter()
+        .filter(|(prefix, _)| path.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map_or(config.sample_rate, |(_, rate)| *rate)
+}
+
+// Whether to log a request sampled at `rate`, drawn from a v4 UUID's random bits.
+fn sampled(rate: f64) -> bool {
+    rate >= 1.0 || (rate > 0.0 && (Uuid::new_v4().as_u128() % 1_000_000) as f64 / 1_000_000.0 < rate)
+}
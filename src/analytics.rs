@@ -0,0 +1,150 @@
+// src/analytics.rs
+
+use chrono::{Datelike, Days, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::Arc;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::events::DomainEvent;
+use crate::ws_handlers::AppState;
+
+/// Days of activity kept; older days are dropped as new ones start.
+pub const RETENTION_DAYS: u64 = 90;
+
+/// What happened on one day (UTC).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DailyActivity {
+    // Users who connected or sent a message that day.
+    active_users: HashSet<Uuid>,
+    // Messages of any kind sent that day, by sender.
+    messages_by_user: HashMap<Uuid, u64>,
+    // Most connections open at once.
+    peak_connections: usize,
+}
+
+/// Daily activity of the last `RETENTION_DAYS` days, saved with the state snapshot.
+#[derive(Debug, Default)]
+pub struct Analytics {
+    // Day ("YYYY-MM-DD") -> activity.
+    days: BTreeMap<String, DailyActivity>,
+}
+
+/// Granularity of an analytics report.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize, ToSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum AnalyticsPeriod {
+    #[default]
+    Daily,
+    // Monday to Sunday.
+    Weekly,
+}
+
+/// Activity aggregated over one day or week.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct AnalyticsBucket {
+    // First and last day covered (inclusive), "YYYY-MM-DD".
+    start: String,
+    end: String,
+    // Distinct users who connected or sent a message.
+    active_users: usize,
+    messages: u64,
+    // Average messages per user who sent any.
+    messages_per_user: f64,
+    peak_concurrent_connections: usize,
+}
+
+impl Analytics {
+    /// The days recorded so far, for the snapshot.
+    pub fn days(&self) -> BTreeMap<String, DailyActivity> {
+        self.days.clone()
+    }
+
+    /// Restores the days saved in a snapshot.
+    pub fn from_days(days: BTreeMap<String, DailyActivity>) -> Self {
+        Analytics { days }
+    }
+
+    // Today's activity; starting a day drops those past the retention period.
+    fn today(&mut self) -> &mut DailyActivity {
+        let today = Utc::now().date_naive();
+        let key = day_key(today);
+        if !self.days.contains_key(&key) {
+            if let Some(oldest) = today.checked_sub_days(Days::new(RETENTION_DAYS - 1)) {
+                self.days = self.days.split_off(&day_key(oldest));
+            }
+        }
+        self.days.entry(key).or_default()
+    }
+
+    pub(crate) fn record_message(&mut self, sender: Uuid) {
+        let today = self.today();
+        today.active_users.insert(sender);
+        *today.messages_by_user.entry(sender).or_default() += 1;
+    }
+
+    pub(crate) fn record_online(&mut self, user_id: Uuid, open_connections: usize) {
+        let today = self.today();
+        today.active_users.insert(user_id);
+        today.peak_connections = today.peak_connections.max(open_connections);
+    }
+
+    /// The last `count` days or weeks up to today, oldest first.
+    pub fn report(&self, period: AnalyticsPeriod, count: usize) -> Vec<AnalyticsBucket> {
+        let today = Utc::now().date_naive();
+        let (last_start, length) = match period {
+            AnalyticsPeriod::Daily => (today, 1),
+            AnalyticsPeriod::Weekly => (today - Days::new(u64::from(today.weekday().num_days_from_monday())), 7),
+        };
+        (0..count as u64)
+            .rev()
+            .filter_map(|ago| last_start.checked_sub_days(Days::new(ago * length)))
+            .map(|start| self.bucket(start, start + Days::new(length - 1)))
+            .collect()
+    }
+
+    fn bucket(&self, start: NaiveDate, end: NaiveDate) -> AnalyticsBucket {
+        let mut active_users: HashSet<Uuid> = HashSet::new();
+        let mut messages_by_user: HashMap<Uuid, u64> = HashMap::new();
+        let mut peak_concurrent_connections = 0;
+        for (_, day) in self.days.range(day_key(start)..=day_key(end)) {
+            active_users.extend(&day.active_users);
+            for (user_id, messages) in &day.messages_by_user {
+                *messages_by_user.entry(*user_id).or_default() += messages;
+            }
+            peak_concurrent_connections = peak_concurrent_connections.max(day.peak_connections);
+        }
+        let messages: u64 = messages_by_user.values().sum();
+        AnalyticsBucket {
+            start: day_key(start),
+            end: day_key(end),
+            active_users: active_users.len(),
+            messages,
+            messages_per_user: if messages_by_user.is_empty() { 0.0 } else { messages as f64 / messages_by_user.len() as f64 },
+            peak_concurrent_connections,
+        }
+    }
+}
+
+fn day_key(day: NaiveDate) -> String {
+    day.format("%Y-%m-%d").to_string()
+}
+
+/// Event-bus subscriber: counts messages per sender, and users coming online along with
+/// the connections open at that moment.
+pub async fn on_event(app_state: Arc<AppState>, event: Arc<DomainEvent>) {
+    match &*event {
+        DomainEvent::MessageSent { from_user_id, .. } => app_state.analytics.lock().await.record_message(*from_user_id),
+        DomainEvent::UserOnline { user_id, .. } => {
+            let open_connections = app_state.connections.count().await;
+            app_state.analytics.lock().await.record_online(*user_id, open_connections);
+        }
+        DomainEvent::UserRegistered { .. }
+        | DomainEvent::RegistrationRequested { .. }
+        | DomainEvent::ContactAdded { .. }
+        | DomainEvent::AccountLocked { .. }
+        | DomainEvent::AccountUnlocked { .. }
+        | DomainEvent::UnfamiliarLogin { .. } => {}
+    }
+}
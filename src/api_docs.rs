@@ -0,0 +1,145 @@
+// src/api_docs.rs
+
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, Http, HttpAuthScheme, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+use warp::{Filter, Rejection, Reply};
+
+use crate::handlers;
+
+/// OpenAPI description of the JSON API, generated from the handler annotations.
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Rust Chat API", description = "HTTP API of the chat server. Real-time messaging uses the WebSocket at /ws?token=SESSION_KEY."),
+    paths(
+        handlers::auth::register_handler,
+        handlers::auth::create_guest_handler,
+        handlers::auth::login_handler,
+        handlers::auth::revoke_session_handler,
+        handlers::auth::unlock_self_handler,
+        handlers::auth::change_password_handler,
+        handlers::auth::create_invite_handler,
+        handlers::auth::list_invites_handler,
+        handlers::auth::delete_invite_handler,
+        handlers::auth::list_trusted_devices_handler,
+        handlers::auth::trust_device_handler,
+        handlers::auth::distrust_device_handler,
+        handlers::contacts::add_contact_handler,
+        handlers::contacts::get_contacts_handler,
+        handlers::messages::send_message_handler,
+        handlers::messages::send_encrypted_message_handler,
+        handlers::keys::upload_keys_handler,
+        handlers::keys::get_keys_handler,
+        handlers::keys::add_prekeys_handler,
+        handlers::keys::get_prekey_bundle_handler,
+        handlers::broadcasts::create_broadcast_list_handler,
+        handlers::broadcasts::list_broadcast_lists_handler,
+        handlers::broadcasts::delete_broadcast_list_handler,
+        handlers::broadcasts::send_broadcast_message_handler,
+        handlers::conversations::list_conversations_handler,
+        handlers::conversations::mark_conversation_read_handler,
+        handlers::conversations::clear_conversation_handler,
+        handlers::conversations::get_conversation_notifications_handler,
+        handlers::conversations::set_conversation_notifications_handler,
+        handlers::calls::list_calls_handler,
+        handlers::webhooks::create_webhook_handler,
+        handlers::webhooks::list_webhooks_handler,
+        handlers::webhooks::delete_webhook_handler,
+        handlers::webhooks::create_incoming_webhook_handler,
+        handlers::webhooks::list_incoming_webhooks_handler,
+        handlers::webhooks::delete_incoming_webhook_handler,
+        handlers::webhooks::post_incoming_webhook_handler,
+        handlers::bots::create_bot_handler,
+        handlers::bots::list_bots_handler,
+        handlers::bots::create_api_token_handler,
+        handlers::bots::list_api_tokens_handler,
+        handlers::bots::revoke_api_token_handler,
+        handlers::push::vapid_public_key_handler,
+        handlers::push::create_push_subscription_handler,
+        handlers::push::list_push_subscriptions_handler,
+        handlers::push::delete_push_subscription_handler,
+        handlers::push::register_device_handler,
+        handlers::push::list_devices_handler,
+        handlers::push::delete_device_handler,
+        handlers::push::get_notification_settings_handler,
+        handlers::push::set_notification_settings_handler,
+        handlers::admin::announcement_handler,
+        handlers::admin::set_role_handler,
+        handlers::admin::unlock_user_handler,
+        handlers::admin::list_registrations_handler,
+        handlers::admin::approve_registration_handler,
+        handlers::admin::reject_registration_handler,
+        handlers::admin::stats_handler,
+        handlers::admin::analytics_handler,
+        handlers::admin::list_connections_handler,
+        handlers::admin::close_connection_handler,
+        handlers::admin::reload_config_handler,
+        handlers::admin::get_ip_rules_handler,
+        handlers::admin::set_ip_rules_handler,
+        handlers::webhooks::create_global_webhook_handler,
+        handlers::webhooks::list_global_webhooks_handler,
+        handlers::webhooks::delete_global_webhook_handler,
+    ),
+    modifiers(&SessionKeyAuth),
+    tags(
+        (name = "auth", description = "Registration and login"),
+        (name = "contacts", description = "Contact list management"),
+        (name = "messages", description = "Sending messages without a WebSocket"),
+        (name = "keys", description = "Identity keys and prekey bundles for end-to-end encryption"),
+        (name = "broadcasts", description = "Broadcast lists: one message delivered separately to several contacts"),
+        (name = "conversations", description = "Conversation list, unread counts and per-conversation notification levels"),
+        (name = "calls", description = "Log of WebRTC calls"),
+        (name = "webhooks", description = "Outgoing webhooks for chat events and incoming webhooks posting into chats"),
+        (name = "bots", description = "Bot accounts and their API tokens"),
+        (name = "push", description = "Web Push subscriptions, mobile devices and notification settings for messages received while offline"),
+        (name = "admin", description = "Administration (requires the admin role)"),
+    )
+)]
+pub struct ApiDoc;
+
+/// Registers the `x-session-key` header returned by login, and the bearer API tokens of
+/// bot accounts, as the API's security schemes.
+struct SessionKeyAuth;
+
+impl Modify for SessionKeyAuth {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "session_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("x-session-key"))),
+        );
+        components.add_security_scheme("api_token", SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)));
+    }
+}
+
+// Swagger UI is loaded from a CDN, like the Tailwind stylesheet of the web client.
+const SWAGGER_UI_HTML: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="UTF-8">
+    <title>Rust Chat API</title>
+    <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css">
+</head>
+<body>
+    <div id="swagger-ui"></div>
+    <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+    <script>
+        window.onload = () => {
+            window.ui = SwaggerUIBundle({ url: '/docs/openapi.json', dom_id: '#swagger-ui' });
+        };
+    </script>
+</body>
+</html>
+"#;
+
+/// `GET /docs` (Swagger UI) and `GET /docs/openapi.json` (the generated specification).
+pub fn docs_routes() -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let spec = warp::path!("docs" / "openapi.json")
+        .and(warp::get())
+        .map(|| warp::reply::json(&ApiDoc::openapi()));
+
+    let ui = warp::path!("docs")
+        .and(warp::get())
+        .map(|| warp::reply::html(SWAGGER_UI_HTML));
+
+    spec.or(ui)
+}
@@ -0,0 +1,251 @@
+// src/bin/chat-loadtest.rs
+
+//! Load generator for a running chat server. Spawns simulated clients that register, log in,
+//! open a WebSocket and send chat messages in a ring (client i writes to client i + 1) at a
+//! fixed rate, then reports delivery latency percentiles and error rates.
+//!
+//! ```text
+//! chat-loadtest --url http://127.0.0.1:3030 --clients 200 --rate 2 --duration 30
+//! ```
+
+use futures::{SinkExt, StreamExt};
+use serde::Deserialize;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio_tungstenite::tungstenite::Message;
+use uuid::Uuid;
+
+const USAGE: &str = "Usage: chat-loadtest [--url URL] [--clients N] [--rate MSGS_PER_SEC] [--duration SECS]
+
+  --url       Base URL of the server (default http://127.0.0.1:3030)
+  --clients   Number of simulated clients (default 100)
+  --rate      Messages per second sent by each client (default 1)
+  --duration  Seconds to send messages for (default 30)";
+
+// How long to wait for messages still in flight after the clients stop sending.
+const DRAIN_GRACE: Duration = Duration::from_secs(2);
+
+/// Command-line options.
+#[derive(Debug)]
+struct Options {
+    url: String,
+    clients: usize,
+    rate: f64,
+    duration: Duration,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options {
+            url: "http://127.0.0.1:3030".to_string(),
+            clients: 100,
+            rate: 1.0,
+            duration: Duration::from_secs(30),
+        };
+        while let Some(flag) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("{} needs a value", flag));
+            match flag.as_str() {
+                "--url" => options.url = value()?.trim_end_matches('/').to_string(),
+                "--clients" => options.clients = parse_number(&flag, &value()?)?,
+                "--rate" => options.rate = parse_number(&flag, &value()?)?,
+                "--duration" => options.duration = Duration::from_secs(parse_number(&flag, &value()?)?),
+                "--help" | "-h" => return Err(USAGE.to_string()),
+                _ => return Err(format!("unknown argument {}\n\n{}", flag, USAGE)),
+            }
+        }
+        if options.clients < 2 {
+            return Err("--clients must be at least 2".to_string());
+        }
+        if options.rate <= 0.0 {
+            return Err("--rate must be greater than zero".to_string());
+        }
+        Ok(options)
+    }
+
+    fn ws_url(&self, session_key: &str) -> String {
+        let base = self.url.replacen("https://", "wss://", 1).replacen("http://", "ws://", 1);
+        format!("{}/ws?token={}", base, session_key)
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(flag: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid value {:?} for {}", value, flag))
+}
+
+#[derive(Deserialize)]
+struct AuthResponse {
+    session_key: String,
+    user_id: Uuid,
+}
+
+/// A client that registered and logged in.
+struct Client {
+    user_id: Uuid,
+    session_key: String,
+}
+
+/// Counters shared by every client task.
+#[derive(Default)]
+struct Report {
+    auth_errors: AtomicU64,
+    connect_errors: AtomicU64,
+    send_errors: AtomicU64,
+    sent: AtomicU64,
+    received: AtomicU64,
+    latencies: Mutex<Vec<Duration>>,
+}
+
+impl Report {
+    fn count(counter: &AtomicU64) -> u64 {
+        counter.load(Ordering::Relaxed)
+    }
+}
+
+/// Summary printed at the end of a run.
+struct Summary<'a> {
+    options: &'a Options,
+    report: &'a Report,
+}
+
+impl fmt::Display for Summary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let report = self.report;
+        let sent = Report::count(&report.sent);
+        let received = Report::count(&report.received);
+        let lost = sent.saturating_sub(received);
+        let rate = |count: u64, total: u64| if total == 0 { 0.0 } else { count as f64 * 100.0 / total as f64 };
+        let clients = self.options.clients as u64;
+        let seconds = self.options.duration.as_secs_f64();
+
+        writeln!(f, "clients:        {}", clients)?;
+        writeln!(f, "duration:       {:.1}s", seconds)?;
+        writeln!(f, "sent:           {} ({:.1} msg/s)", sent, sent as f64 / seconds)?;
+        writeln!(f, "received:       {}", received)?;
+        writeln!(f, "lost:           {} ({:.2}%)", lost, rate(lost, sent))?;
+        writeln!(f, "auth errors:    {} ({:.2}%)", Report::count(&report.auth_errors), rate(Report::count(&report.auth_errors), clients))?;
+        writeln!(f, "connect errors: {} ({:.2}%)", Report::count(&report.connect_errors), rate(Report::count(&report.connect_errors), clients))?;
+        writeln!(f, "send errors:    {} ({:.2}%)", Report::count(&report.send_errors), rate(Report::count(&report.send_errors), sent + Report::count(&report.send_errors)))?;
+
+        let mut latencies = report.latencies.lock().expect("latency lock").clone();
+        latencies.sort();
+        if latencies.is_empty() {
+            return writeln!(f, "latency:        no messages delivered");
+        }
+        let percentile = |p: f64| latencies[((latencies.len() - 1) as f64 * p).round() as usize];
+        write!(
+            f,
+            "latency:        p50 {:?}  p90 {:?}  p99 {:?}  max {:?}",
+            percentile(0.50),
+            percentile(0.90),
+            percentile(0.99),
+            latencies[latencies.len() - 1],
+        )
+    }
+}
+
+/// Registers a fresh user and logs it in.
+async fn authenticate(http: &reqwest::Client, options: &Options, username: &str) -> Result<Client, reqwest::Error> {
+    let payload = serde_json::json!({ "username": username, "password": "loadtest-password" });
+    http.post(format!("{}/api/v1/register", options.url)).json(&payload).send().await?.error_for_status()?;
+    let auth: AuthResponse =
+        http.post(format!("{}/api/v1/login", options.url)).json(&payload).send().await?.error_for_status()?.json().await?;
+    Ok(Client { user_id: auth.user_id, session_key: auth.session_key })
+}
+
+/// Sends messages to `to_user_id` until `deadline` while recording the latency of messages
+/// addressed to this client. Each message carries its send time relative to `epoch`.
+async fn run_client(client: Client, to_user_id: Uuid, options: Arc<Options>, report: Arc<Report>, epoch: Instant, deadline: Instant) {
+    let (socket, _) = match tokio_tungstenite::connect_async(options.ws_url(&client.session_key)).await {
+        Ok(connected) => connected,
+        Err(e) => {
+            eprintln!("WebSocket connection failed: {}", e);
+            report.connect_errors.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+    };
+    let (mut sender, mut receiver) = socket.split();
+
+    let reader_report = report.clone();
+    let reader = tokio::spawn(async move {
+        while let Some(Ok(message)) = receiver.next().await {
+            let Message::Text(text) = message else { continue };
+            let Ok(frame) = serde_json::from_str::<serde_json::Value>(&text) else { continue };
+            if frame["type"] != "chatMessage" || frame["to_user_id"] != client.user_id.to_string() {
+                continue;
+            }
+            let sent_at = frame["message"].as_str().and_then(|message| message.strip_prefix("loadtest:")).and_then(|nanos| nanos.parse().ok());
+            if let Some(sent_at) = sent_at {
+                let latency = epoch.elapsed().saturating_sub(Duration::from_nanos(sent_at));
+                reader_report.received.fetch_add(1, Ordering::Relaxed);
+                reader_report.latencies.lock().expect("latency lock").push(latency);
+            }
+        }
+    });
+
+    let mut interval = tokio::time::interval(Duration::from_secs_f64(1.0 / options.rate));
+    while Instant::now() < deadline {
+        interval.tick().await;
+        let message = format!("loadtest:{}", epoch.elapsed().as_nanos());
+        let frame = serde_json::json!({ "type": "chatMessage", "to_user_id": to_user_id, "message": message });
+        match sender.send(Message::Text(frame.to_string())).await {
+            Ok(()) => report.sent.fetch_add(1, Ordering::Relaxed),
+            Err(_) => report.send_errors.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    tokio::time::sleep(DRAIN_GRACE).await;
+    let _ = sender.close().await;
+    reader.abort();
+}
+
+#[tokio::main]
+async fn main() {
+    let options = match Options::parse(std::env::args().skip(1)) {
+        Ok(options) => Arc::new(options),
+        Err(message) => {
+            eprintln!("{}", message);
+            std::process::exit(2);
+        }
+    };
+    let report = Arc::new(Report::default());
+    let http = reqwest::Client::new();
+    let run_id = &Uuid::new_v4().simple().to_string()[..8];
+
+    println!("Registering {} clients against {}", options.clients, options.url);
+    let logins = (0..options.clients).map(|i| {
+        let (http, options, report) = (http.clone(), options.clone(), report.clone());
+        let username = format!("loadtest-{}-{}", run_id, i);
+        async move {
+            authenticate(&http, &options, &username)
+                .await
+                .inspect_err(|e| {
+                    eprintln!("Authenticating {} failed: {}", username, e);
+                    report.auth_errors.fetch_add(1, Ordering::Relaxed);
+                })
+                .ok()
+        }
+    });
+    let clients: Vec<Client> = futures::future::join_all(logins).await.into_iter().flatten().collect();
+    if clients.len() < 2 {
+        eprintln!("Fewer than two clients could log in; aborting.");
+        std::process::exit(1);
+    }
+
+    println!("Sending {} msg/s per client for {}s", options.rate, options.duration.as_secs());
+    let recipients: Vec<Uuid> = clients.iter().map(|client| client.user_id).collect();
+    let epoch = Instant::now();
+    let deadline = epoch + options.duration;
+    let tasks: Vec<_> = clients
+        .into_iter()
+        .enumerate()
+        .map(|(i, client)| {
+            let to_user_id = recipients[(i + 1) % recipients.len()];
+            tokio::spawn(run_client(client, to_user_id, options.clone(), report.clone(), epoch, deadline))
+        })
+        .collect();
+    futures::future::join_all(tasks).await;
+
+    println!("{}", Summary { options: &options, report: &report });
+}
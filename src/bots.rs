@@ -0,0 +1,57 @@
+// src/bots.rs
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Instant;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::device::Device;
+use crate::ws_handlers::{LastActive, UserSession};
+
+/// What an API token allows its bot to do. Tokens carry only the scopes they were issued with.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenScope {
+    // Send chat messages (POST /messages).
+    SendMessages,
+    // Read the bot's contact list (GET /contacts).
+    ReadContacts,
+}
+
+/// A long-lived API token authenticating a bot account. Tokens stay valid until revoked.
+#[derive(Debug, Clone)]
+pub struct ApiToken {
+    pub id: Uuid,
+    pub bot_user_id: Uuid,
+    pub bot_username: String,
+    pub scopes: Vec<TokenScope>,
+    pub created_at: chrono::DateTime<chrono::Utc>,
+}
+
+impl ApiToken {
+    /// The session a request authenticated with this token acts as.
+    pub fn session(&self) -> UserSession {
+        UserSession {
+            user_id: self.bot_user_id,
+            username: self.bot_username.clone(),
+            session_key: format!("token:{}", self.id),
+            created_at: Instant::now(),
+            client_ip: None,
+            country: None,
+            device: Device::default(),
+            trusted_device: Arc::default(),
+            signing_key: None,
+            last_active: LastActive::now(),
+        }
+    }
+}
+
+/// Generates the secret of a new API token.
+pub fn generate_token() -> String {
+    format!("bot_{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// Issued API tokens: token secret -> token.
+pub type ApiTokenRegistry = HashMap<String, ApiToken>;
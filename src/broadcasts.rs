@@ -0,0 +1,22 @@
+// src/broadcasts.rs
+
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Recipients a single broadcast list may hold.
+pub const MAX_BROADCAST_RECIPIENTS: usize = 256;
+
+/// A named set of contacts that a user messages together. Each recipient gets the message
+/// in their own 1:1 conversation with the owner; recipients don't see each other.
+#[derive(Debug, Clone)]
+pub struct BroadcastList {
+    pub id: Uuid,
+    pub owner_user_id: Uuid,
+    pub name: String,
+    pub recipient_ids: Vec<Uuid>,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Broadcast lists: list id -> list.
+pub type BroadcastListRegistry = HashMap<Uuid, BroadcastList>;
@@ -0,0 +1,138 @@
+// src/calls.rs
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// How long a call may ring unanswered before it is ended as missed.
+pub const RING_TIMEOUT: Duration = Duration::from_secs(45);
+/// Ended calls kept in each user's call log; older ones are dropped.
+pub const MAX_CALL_HISTORY: usize = 200;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CallState {
+    // Offered to the callee, not yet answered.
+    Ringing,
+    // Answered; media flows between the two clients.
+    Active,
+}
+
+/// Why a call ended (or never started), as reported in `callEnded` events.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CallEndReason {
+    // The caller or callee was already in another call.
+    Busy,
+    // The callee rejected the call.
+    Declined,
+    // Nobody answered within the ring timeout.
+    Timeout,
+    // Either party hung up (the caller before an answer cancels the call).
+    HangUp,
+    // The call was answered on another of the callee's sessions.
+    AnsweredElsewhere,
+    // Either party lost its last connection.
+    Disconnected,
+}
+
+/// A 1:1 call between two users, from the offer until it ends.
+#[derive(Debug, Clone)]
+pub struct Call {
+    pub call_id: Uuid,
+    pub caller_id: Uuid,
+    pub callee_id: Uuid,
+    pub state: CallState,
+    pub started_at: DateTime<Utc>,
+    pub answered_at: Option<DateTime<Utc>>,
+}
+
+impl Call {
+    /// A call that starts ringing now.
+    pub fn ringing(call_id: Uuid, caller_id: Uuid, callee_id: Uuid) -> Self {
+        Call { call_id, caller_id, callee_id, state: CallState::Ringing, started_at: Utc::now(), answered_at: None }
+    }
+
+    pub fn involves(&self, user_id: Uuid) -> bool {
+        self.caller_id == user_id || self.callee_id == user_id
+    }
+
+    /// The other party of the call, if `user_id` takes part in it.
+    pub fn peer_of(&self, user_id: Uuid) -> Option<Uuid> {
+        if user_id == self.caller_id {
+            Some(self.callee_id)
+        } else if user_id == self.callee_id {
+            Some(self.caller_id)
+        } else {
+            None
+        }
+    }
+}
+
+/// Calls that are ringing or in progress: call id -> call. A user takes part in at most one.
+pub type CallRegistry = HashMap<Uuid, Call>;
+
+/// Whether `user_id` already takes part in a call.
+pub fn is_busy(calls: &CallRegistry, user_id: Uuid) -> bool {
+    calls.values().any(|call| call.involves(user_id))
+}
+
+/// How an ended call went, as shown in the call log.
+#[derive(Serialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CallOutcome {
+    // The callee answered.
+    Answered,
+    // The callee rejected the call.
+    Declined,
+    // The call ended before anyone answered.
+    Missed,
+}
+
+/// An entry of the call log.
+#[derive(Debug, Clone)]
+pub struct CallRecord {
+    pub call_id: Uuid,
+    pub caller_id: Uuid,
+    pub callee_id: Uuid,
+    pub started_at: DateTime<Utc>,
+    pub answered_at: Option<DateTime<Utc>>,
+    pub ended_at: DateTime<Utc>,
+    pub outcome: CallOutcome,
+}
+
+impl CallRecord {
+    /// The log entry of a call that just ended for `reason`.
+    pub fn ended(call: &Call, reason: CallEndReason) -> Self {
+        let outcome = match (call.answered_at, reason) {
+            (Some(_), _) => CallOutcome::Answered,
+            (None, CallEndReason::Declined) => CallOutcome::Declined,
+            (None, _) => CallOutcome::Missed,
+        };
+        CallRecord {
+            call_id: call.call_id,
+            caller_id: call.caller_id,
+            callee_id: call.callee_id,
+            started_at: call.started_at,
+            answered_at: call.answered_at,
+            ended_at: Utc::now(),
+            outcome,
+        }
+    }
+}
+
+/// Call logs: user id -> ended calls the user took part in, oldest first.
+pub type CallHistory = HashMap<Uuid, VecDeque<CallRecord>>;
+
+/// Adds an ended call to the logs of both parties.
+pub fn record(history: &mut CallHistory, record: CallRecord) {
+    for user_id in [record.caller_id, record.callee_id] {
+        let log = history.entry(user_id).or_default();
+        if log.len() == MAX_CALL_HISTORY {
+            log.pop_front();
+        }
+        log.push_back(record.clone());
+    }
+}
@@ -0,0 +1,159 @@
+// src/client_ip.rs
+
+use ipnet::IpNet;
+use std::convert::Infallible;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use warp::http::HeaderMap;
+use warp::Filter;
+
+use crate::ws_handlers::AppState;
+
+/// A filter resolving the real client address of a request.
+///
+/// Forwarding headers are only honored when the direct peer is a trusted proxy
+/// (`proxy.trusted_proxies`), so clients cannot spoof their address by sending the headers
+/// themselves. `Forwarded` (RFC 7239) takes precedence over `X-Forwarded-For`.
+pub fn with_client_ip(
+    app_state: Arc<AppState>,
+) -> impl Filter<Extract = (Option<IpAddr>,), Error = Infallible> + Clone {
+    warp::addr::remote()
+        .and(warp::header::headers_cloned())
+        .map(move |peer: Option<SocketAddr>, headers: HeaderMap| client_ip(peer, &headers, &app_state.config.proxy.trusted_proxies))
+}
+
+/// The client address of a request from `peer` with these headers, as resolved by
+/// `with_client_ip`.
+pub(crate) fn client_ip(peer: Option<SocketAddr>, headers: &HeaderMap, trusted: &[IpNet]) -> Option<IpAddr> {
+    let chain = match (joined(headers, "forwarded"), joined(headers, "x-forwarded-for")) {
+        (Some(forwarded), _) => parse_forwarded(&forwarded),
+        (None, Some(x_forwarded_for)) => parse_x_forwarded_for(&x_forwarded_for),
+        (None, None) => Vec::new(),
+    };
+    resolve(peer.map(|addr| addr.ip()), &chain, trusted)
+}
+
+/// Every line of the header `name`, in order, as one comma-separated list. A proxy may add a
+/// line of its own rather than append to the one the client sent.
+fn joined(headers: &HeaderMap, name: &str) -> Option<String> {
+    let lines: Vec<&str> = headers.get_all(name).iter().filter_map(|value| value.to_str().ok()).collect();
+    (!lines.is_empty()).then(|| lines.join(","))
+}
+
+/// Walks the forwarding chain from the nearest hop outwards and returns the first address
+/// that is not a trusted proxy. If every hop is trusted, the farthest one is the client.
+fn resolve(peer: Option<IpAddr>, chain: &[IpAddr], trusted: &[IpNet]) -> Option<IpAddr> {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+
+    let peer = peer?;
+    if !is_trusted(&peer) {
+        return Some(peer);
+    }
+    chain
+        .iter()
+        .rev()
+        .find(|ip| !is_trusted(ip))
+        .or_else(|| chain.first())
+        .copied()
+        .or(Some(peer))
+}
+
+/// Extracts the `for=` addresses of a `Forwarded` header, in order.
+/// Obfuscated identifiers such as `for=unknown` or `for=_hidden` are skipped.
+fn parse_forwarded(header: &str) -> Vec<IpAddr> {
+    header
+        .split(',')
+        .flat_map(|element| element.split(';'))
+        .filter_map(|pair| {
+            let (key, value) = pair.split_once('=')?;
+            if !key.trim().eq_ignore_ascii_case("for") {
+                return None;
+            }
+            parse_node(value.trim().trim_matches('"'))
+        })
+        .collect()
+}
+
+fn parse_x_forwarded_for(header: &str) -> Vec<IpAddr> {
+    header.split(',').filter_map(|entry| parse_node(entry.trim())).collect()
+}
+
+/// Parses a node such as `192.0.2.60`, `192.0.2.60:4711`, `2001:db8::1` or `[2001:db8::1]:4711`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(addr) = node.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    node.strip_prefix('[')
+        .and_then(|rest| rest.split(']').next())
+        .and_then(|ip| ip.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn trusted() -> Vec<IpNet> {
+        vec!["10.0.0.0/8".parse().unwrap(), "2001:db8:ffff::/48".parse().unwrap()]
+    }
+
+    fn resolve_from(peer: &str, headers: &[(&'static str, &str)]) -> Option<IpAddr> {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(*name, value.parse().unwrap());
+        }
+        client_ip(Some(SocketAddr::new(peer.parse().unwrap(), 443)), &map, &trusted())
+    }
+
+    fn ip(ip: &str) -> Option<IpAddr> {
+        Some(ip.parse().unwrap())
+    }
+
+    #[test]
+    fn headers_from_an_untrusted_peer_are_ignored() {
+        assert_eq!(resolve_from("203.0.113.7", &[("x-forwarded-for", "198.51.100.1")]), ip("203.0.113.7"));
+        assert_eq!(resolve_from("203.0.113.7", &[("forwarded", "for=198.51.100.1")]), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn the_nearest_untrusted_hop_is_the_client() {
+        let headers = [("x-forwarded-for", "198.51.100.1, 203.0.113.7, 10.0.0.2")];
+        assert_eq!(resolve_from("10.0.0.1", &headers), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn the_farthest_hop_of_a_fully_trusted_chain_is_the_client() {
+        assert_eq!(resolve_from("10.0.0.1", &[("x-forwarded-for", "10.1.0.1, 10.0.0.2")]), ip("10.1.0.1"));
+        assert_eq!(resolve_from("10.0.0.1", &[]), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn forwarded_takes_precedence_over_x_forwarded_for() {
+        let headers = [("x-forwarded-for", "198.51.100.1"), ("forwarded", "for=203.0.113.7;proto=https")];
+        assert_eq!(resolve_from("10.0.0.1", &headers), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn bracketed_ipv6_nodes_with_a_port_are_parsed() {
+        let headers = [("forwarded", r#"for="[2001:db8::1]:4711", for="[2001:db8:ffff::2]""#)];
+        assert_eq!(resolve_from("10.0.0.1", &headers), ip("2001:db8::1"));
+        assert_eq!(resolve_from("10.0.0.1", &[("x-forwarded-for", "[2001:db8::1]:4711")]), ip("2001:db8::1"));
+    }
+
+    #[test]
+    fn obfuscated_nodes_are_skipped() {
+        let headers = [("forwarded", "for=203.0.113.7, for=unknown, for=_hidden")];
+        assert_eq!(resolve_from("10.0.0.1", &headers), ip("203.0.113.7"));
+    }
+
+    #[test]
+    fn every_header_line_is_read_in_order() {
+        // The client sent the first line; the trusted proxy added the second instead of appending.
+        let headers = [("x-forwarded-for", "198.51.100.1"), ("x-forwarded-for", "203.0.113.7")];
+        assert_eq!(resolve_from("10.0.0.1", &headers), ip("203.0.113.7"));
+        let headers = [("forwarded", "for=198.51.100.1"), ("forwarded", "for=203.0.113.7")];
+        assert_eq!(resolve_from("10.0.0.1", &headers), ip("203.0.113.7"));
+    }
+}
@@ -0,0 +1,52 @@
+// src/clock.rs
+
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Bits of a packed reading holding the counter; the milliseconds sit above them.
+const COUNTER_BITS: u32 = 16;
+
+/// A hybrid logical clock stamping the messages the server delivers. A reading is the wall
+/// clock in milliseconds plus a counter that orders readings within the same millisecond.
+/// Readings never repeat or go back, even when the system clock is adjusted backwards: the
+/// clock then carries on from its last reading until the wall clock catches up.
+#[derive(Debug, Default)]
+pub struct HybridClock {
+    // The last reading, packed as `millis << COUNTER_BITS | counter`.
+    last: AtomicU64,
+}
+
+/// One reading of a `HybridClock`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct HlcTimestamp {
+    pub millis: u64,
+    pub counter: u16,
+}
+
+impl HybridClock {
+    /// A reading later than every previous one.
+    pub fn now(&self) -> HlcTimestamp {
+        let wall = u64::try_from(Utc::now().timestamp_millis()).unwrap_or_default() << COUNTER_BITS;
+        let next = |last: u64| wall.max(last + 1);
+        // The closure always returns `Some`, so the update cannot fail.
+        let last = self.last.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |last| Some(next(last))).unwrap_or_default();
+        let packed = next(last);
+        HlcTimestamp { millis: packed >> COUNTER_BITS, counter: packed as u16 }
+    }
+}
+
+impl HlcTimestamp {
+    /// The reading's wall-clock part, in RFC 3339.
+    pub fn rfc3339(&self) -> String {
+        let millis = i64::try_from(self.millis).unwrap_or(i64::MAX);
+        DateTime::from_timestamp_millis(millis).unwrap_or_default().to_rfc3339()
+    }
+}
+
+/// `<millis>-<counter>`, zero-padded so the strings sort like the readings.
+impl fmt::Display for HlcTimestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:013}-{:05}", self.millis, self.counter)
+    }
+}
@@ -0,0 +1,251 @@
+// src/cluster.rs
+
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use utoipa::ToSchema;
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+use warp::{Filter, Rejection, Reply};
+
+use crate::config::ClusterConfig;
+use crate::presence::{self, ClusterPresence, NodePresence, PresenceAnnouncement};
+use crate::webhooks;
+use crate::ws_handlers::AppState;
+
+/// Requests signed further than this from the receiver's clock are rejected as replays.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(300);
+
+/// What a node sends its peers every heartbeat: that it is alive, its load, and every user
+/// connected to it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Heartbeat {
+    pub node_id: String,
+    // When the node started (RFC 3339).
+    pub started_at: String,
+    pub connections: usize,
+    pub users: Vec<NodePresence>,
+}
+
+/// Another node, as known from its last heartbeat.
+#[derive(Debug, Clone)]
+struct NodeState {
+    started_at: String,
+    connections: usize,
+    online_users: usize,
+    last_heartbeat: Instant,
+    last_heartbeat_at: DateTime<Utc>,
+}
+
+/// A node of the cluster, as reported by the admin statistics endpoint.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ClusterNodeResponse {
+    node_id: String,
+    // Whether this is the node answering the request.
+    local: bool,
+    // Whether its last heartbeat arrived within `cluster.presence_ttl_secs`.
+    alive: bool,
+    connections: usize,
+    online_users: usize,
+    started_at: String,
+    // When its last heartbeat arrived (RFC 3339); the local node's is always now.
+    last_heartbeat_at: String,
+}
+
+/// Membership of this node in a cluster of nodes serving the same users behind a load
+/// balancer. The peers are listed in `[cluster]`; a node joins by sending them heartbeats
+/// and is known to them from its first one, and counts as gone once its heartbeats stop
+/// for `presence_ttl_secs`. Requests between nodes are signed with the shared secret.
+#[derive(Debug)]
+pub struct Cluster {
+    client: reqwest::Client,
+    config: ClusterConfig,
+    started_at: DateTime<Utc>,
+    // Nodes heard from: node id -> state as of its last heartbeat.
+    nodes: Mutex<HashMap<String, NodeState>>,
+    // Users connected to the other nodes.
+    pub presence: ClusterPresence,
+}
+
+impl Cluster {
+    pub fn new(config: &ClusterConfig) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(5))
+            .user_agent(concat!("rust_chat-cluster/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .expect("failed to build cluster HTTP client");
+        Cluster {
+            client,
+            config: config.clone(),
+            started_at: Utc::now(),
+            nodes: Mutex::new(HashMap::new()),
+            presence: ClusterPresence::new(Duration::from_secs(config.presence_ttl_secs)),
+        }
+    }
+
+    /// Name of this node within the cluster.
+    pub fn node_id(&self) -> &str {
+        &self.config.node_id
+    }
+
+    /// How often this node sends its heartbeat.
+    pub fn heartbeat_interval(&self) -> Duration {
+        self.ttl() / 3
+    }
+
+    fn ttl(&self) -> Duration {
+        Duration::from_secs(self.config.presence_ttl_secs)
+    }
+
+    /// Every node of the cluster known here, this one first, with the connections each has.
+    pub async fn node_stats(&self, local_connections: usize, local_users: usize) -> Vec<ClusterNodeResponse> {
+        let now = Utc::now();
+        let mut nodes = vec![ClusterNodeResponse {
+            node_id: self.config.node_id.clone(),
+            local: true,
+            alive: true,
+            connections: local_connections,
+            online_users: local_users,
+            started_at: self.started_at.to_rfc3339(),
+            last_heartbeat_at: now.to_rfc3339(),
+        }];
+        let mut others: Vec<_> = self.nodes.lock().await.iter().map(|(node_id, node)| (node_id.clone(), node.clone())).collect();
+        others.sort_by(|(a, _), (b, _)| a.cmp(b));
+        nodes.extend(others.into_iter().map(|(node_id, node)| ClusterNodeResponse {
+            node_id,
+            local: false,
+            alive: node.last_heartbeat.elapsed() < self.ttl(),
+            connections: node.connections,
+            online_users: node.online_users,
+            started_at: node.started_at,
+            last_heartbeat_at: node.last_heartbeat_at.to_rfc3339(),
+        }));
+        nodes
+    }
+
+    /// Records a peer's heartbeat, returning the users whose status on other nodes changed.
+    async fn record_heartbeat(&self, heartbeat: Heartbeat) -> Vec<NodePresence> {
+        let state = NodeState {
+            started_at: heartbeat.started_at,
+            connections: heartbeat.connections,
+            online_users: heartbeat.users.len(),
+            last_heartbeat: Instant::now(),
+            last_heartbeat_at: Utc::now(),
+        };
+        if self.nodes.lock().await.insert(heartbeat.node_id.clone(), state).is_none() {
+            tracing::info!(node_id = %heartbeat.node_id, connections = heartbeat.connections, "Cluster node joined");
+        }
+        self.presence.apply(PresenceAnnouncement { node_id: heartbeat.node_id, users: heartbeat.users, complete: true }).await
+    }
+
+    /// Sends `body` to `path` on every peer in the background; a peer that cannot be reached
+    /// catches up with the next heartbeat.
+    pub(crate) fn send_to_peers(&self, path: &'static str, body: &impl Serialize) {
+        let Ok(body) = serde_json::to_string(body) else { return };
+        for peer in &self.config.peers {
+            let url = format!("{}{}", peer.trim_end_matches('/'), path);
+            let timestamp = Utc::now().timestamp().to_string();
+            let request = self
+                .client
+                .post(&url)
+                .header("content-type", "application/json")
+                .header("x-chat-node-id", &self.config.node_id)
+                .header("x-chat-timestamp", &timestamp)
+                .header("x-chat-signature", format!("sha256={}", webhooks::sign(&self.config.secret, &timestamp, &body)))
+                .body(body.clone());
+            tokio::spawn(async move {
+                match request.send().await {
+                    Ok(response) if response.status().is_success() => {}
+                    Ok(response) => tracing::warn!(peer = %url, status = %response.status(), "Cluster peer rejected request"),
+                    Err(e) => tracing::warn!(peer = %url, error = %e, "Cannot reach cluster peer"),
+                }
+            });
+        }
+    }
+
+    // Whether a request was signed with the cluster secret within the allowed clock skew.
+    fn verify(&self, timestamp: &str, signature: &str, body: &str) -> bool {
+        let Ok(signed_at) = timestamp.parse::<i64>() else { return false };
+        if Utc::now().timestamp().abs_diff(signed_at) > MAX_CLOCK_SKEW.as_secs() {
+            return false;
+        }
+        let expected = format!("sha256={}", webhooks::sign(&self.config.secret, timestamp, body));
+        // Compared in full so the time taken does not reveal how much of it matched.
+        expected.len() == signature.len() && expected.bytes().zip(signature.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+}
+
+/// Sends this node's heartbeat to its peers, and tells the local connections about the
+/// users of nodes whose heartbeats stopped. Scheduled every third of `cluster.presence_ttl_secs`.
+pub async fn heartbeat(app_state: Arc<AppState>) {
+    let Some(cluster) = &app_state.cluster else { return };
+    let connections = app_state.connections.snapshot().await;
+    let heartbeat = Heartbeat {
+        node_id: cluster.config.node_id.clone(),
+        started_at: cluster.started_at.to_rfc3339(),
+        connections: connections.len(),
+        users: presence::local_users(&app_state, connections).await,
+    };
+    cluster.send_to_peers("/cluster/heartbeat", &heartbeat);
+    let changed = cluster.presence.expire().await;
+    presence::show_remote_changes(&app_state, changed).await;
+}
+
+/// `POST /cluster/heartbeat` and `POST /cluster/presence`: requests from the other nodes.
+pub fn routes(app_state: Arc<AppState>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let signed = warp::post()
+        .and(warp::header::<String>("x-chat-timestamp"))
+        .and(warp::header::<String>("x-chat-signature"))
+        .and(warp::body::bytes())
+        .and(warp::any().map(move || app_state.clone()));
+
+    let heartbeat = warp::path!("cluster" / "heartbeat").and(signed.clone()).then(
+        |timestamp: String, signature: String, body: Bytes, app_state: Arc<AppState>| async move {
+            let (cluster, heartbeat) = match from_peer::<Heartbeat>(&app_state, &timestamp, &signature, &body) {
+                Ok(request) => request,
+                Err(status) => return status,
+            };
+            if heartbeat.node_id == cluster.node_id() {
+                return StatusCode::CONFLICT;
+            }
+            let changed = cluster.record_heartbeat(heartbeat).await;
+            presence::show_remote_changes(&app_state, changed).await;
+            StatusCode::NO_CONTENT
+        },
+    );
+
+    let presence = warp::path!("cluster" / "presence").and(signed).then(
+        |timestamp: String, signature: String, body: Bytes, app_state: Arc<AppState>| async move {
+            let (cluster, announcement) = match from_peer::<PresenceAnnouncement>(&app_state, &timestamp, &signature, &body) {
+                Ok(request) => request,
+                Err(status) => return status,
+            };
+            if announcement.node_id == cluster.node_id() {
+                return StatusCode::CONFLICT;
+            }
+            let changed = cluster.presence.apply(announcement).await;
+            presence::show_remote_changes(&app_state, changed).await;
+            StatusCode::NO_CONTENT
+        },
+    );
+
+    heartbeat.or(presence)
+}
+
+// Decodes the JSON body of a request signed by another node of the cluster. Fails with 404
+// when no cluster is configured, 401 when the signature does not match and 400 when the
+// body is not a `T`.
+fn from_peer<'a, T: DeserializeOwned>(app_state: &'a AppState, timestamp: &str, signature: &str, body: &[u8]) -> Result<(&'a Cluster, T), StatusCode> {
+    let cluster = app_state.cluster.as_ref().ok_or(StatusCode::NOT_FOUND)?;
+    let body = std::str::from_utf8(body).map_err(|_| StatusCode::BAD_REQUEST)?;
+    if !cluster.verify(timestamp, signature, body) {
+        tracing::warn!("Rejected cluster request with an invalid signature");
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    let request = serde_json::from_str(body).map_err(|_| StatusCode::BAD_REQUEST)?;
+    Ok((cluster, request))
+}
@@ -0,0 +1,1699 @@
+// src/config.rs
+
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::ws_handlers::AnnouncementSeverity;
+
+/// Config file read when neither `--config` nor `CHAT_CONFIG` is given (optional).
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/// Complete server configuration.
+///
+/// Values are layered: built-in defaults, then the TOML config file, then environment
+/// variables. The result is validated once at startup by `Config::load`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub bind_address: SocketAddr,
+    pub static_dir: PathBuf,
+    pub log: LogConfig,
+    pub limits: LimitsConfig,
+    pub auth: AuthConfig,
+    // Who may register: anyone, or only holders of an invite code.
+    pub registration: RegistrationConfig,
+    // Directory that password logins are checked against, provisioning accounts on first
+    // login (`ldap` cargo feature).
+    pub ldap: Option<LdapConfig>,
+    pub storage: StorageConfig,
+    pub tls: Option<TlsConfig>,
+    pub proxy: ProxyConfig,
+    // Client addresses refused before routing; replaceable at runtime by admins.
+    pub ip_filter: IpFilterConfig,
+    // Country database of client addresses, and the countries that may register or log in.
+    pub geoip: Option<GeoIpConfig>,
+    // Optional gRPC listener for server-to-server integrations (`grpc` cargo feature).
+    pub grpc: Option<GrpcConfig>,
+    // Announcement shown to every client when it connects.
+    pub banner: Option<BannerConfig>,
+    pub webhooks: WebhookConfig,
+    pub messages: MessagesConfig,
+    // Web Push notifications for messages that arrive while the recipient is offline.
+    pub web_push: Option<WebPushConfig>,
+    // Mobile push credentials; devices of an unconfigured platform are not notified.
+    pub fcm: Option<FcmConfig>,
+    pub apns: Option<ApnsConfig>,
+    // Matrix application service bridge relaying conversations with Matrix users.
+    pub matrix: Option<MatrixConfig>,
+    // Plain XMPP (c2s) listener for legacy XMPP clients.
+    pub xmpp: Option<XmppConfig>,
+    // MQTT broker that message and presence events are published to.
+    pub mqtt: Option<MqttConfig>,
+    // Other nodes serving the same users, which presence is shared with.
+    pub cluster: Option<ClusterConfig>,
+    // NATS server routing frames between nodes and mirroring the event bus (`nats` cargo feature).
+    pub nats: Option<NatsConfig>,
+    // Kafka REST proxy that message, presence and audit events are exported to (`kafka` cargo feature).
+    pub kafka: Option<KafkaConfig>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    // An `EnvFilter` directive such as "info" or "rust_chat=debug,warp=info".
+    pub level: String,
+    pub format: LogFormat,
+    // Debug builds only: log session keys, message bodies, contact lists and the like as
+    // they are instead of "[redacted]", to troubleshoot locally.
+    pub reveal_sensitive: bool,
+    // Log usernames and client IPs as stable pseudonyms (keyed hashes) instead of as they are.
+    pub pseudonymize: bool,
+    // Key of the pseudonyms; required with `pseudonymize`. Keep it to keep pseudonyms stable
+    // across restarts, and secret so they cannot be matched to known usernames or IPs.
+    pub pseudonym_key: Option<String>,
+    // The HTTP access log (`[log.access]`).
+    pub access: AccessLogConfig,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AccessLogConfig {
+    // Log HTTP requests under the `access` target.
+    pub enabled: bool,
+    // Fraction of requests logged, from 0.0 to 1.0. Slow requests and server errors are always logged.
+    pub sample_rate: f64,
+    // Sample rates by path prefix, without the `/api/v1` prefix (e.g. "/contacts" = 0.1);
+    // the longest matching prefix overrides `sample_rate`.
+    pub routes: BTreeMap<String, f64>,
+    // Requests taking at least this long are always logged, as warnings.
+    pub slow_request_ms: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Pretty,
+    Json,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    // Maximum length of a chat message body, in bytes.
+    pub max_message_length: usize,
+    // Maximum number of simultaneous WebSocket connections across the server.
+    pub max_connections: usize,
+    // Frames a single connection may send per minute; 0 disables the limit.
+    pub max_messages_per_minute: u32,
+    // Largest JSON request body accepted by the HTTP API, in bytes.
+    pub max_body_bytes: u64,
+    // HTTP API requests a single client IP may send per minute; 0 disables the limit.
+    pub http_requests_per_minute: u32,
+    // Seconds after the last "typing" event before a user is reported as no longer typing.
+    pub typing_timeout_secs: u64,
+    // Tasks delivering conversations in order; each conversation is always handled by the same one.
+    pub conversation_shards: usize,
+    // Tasks queuing broadcasts (e.g. presence) on many connections; each connection is always
+    // served by the same one.
+    pub fanout_workers: usize,
+    // Independently locked shards of the users, sessions and connections; each user or session
+    // always lives in the same one.
+    pub state_shards: usize,
+    // Frames queued on a connection above which it counts as saturated; 0 disables the check.
+    pub slow_consumer_queue_depth: usize,
+    // Seconds a connection may stay saturated before it is closed as a slow consumer.
+    pub slow_consumer_grace_secs: u64,
+    // Seconds writing one frame to a socket may take before the connection is closed as a slow consumer.
+    pub send_timeout_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub bcrypt_cost: u32,
+    // Sessions older than this are rejected and must log in again.
+    pub session_ttl_secs: u64,
+    // Shorter lifetime of sessions on devices their user has not marked as trusted; capped
+    // by `session_ttl_secs`.
+    pub untrusted_session_ttl_secs: u64,
+    // Sessions without an open connection that were not used for this long are expired.
+    pub session_idle_timeout_secs: u64,
+    // Whether WebSockets may pass their session key in the URL (`/ws?token=`). Without it,
+    // clients authenticate with an `auth` frame, which keeps the key out of proxy logs.
+    pub allow_query_token: bool,
+    // How long a WebSocket opened without a session key has to send its `auth` frame.
+    pub auth_frame_timeout_secs: u64,
+    // Origins (e.g. "https://chat.example.com") whose pages may open WebSockets. Browsers
+    // send cookies cross-site, so without it any page could connect as its visitor. Empty
+    // allows every origin; requests without an `Origin` header (non-browser clients) always pass.
+    pub allowed_origins: Vec<String>,
+    // Accounts given the admin role at startup and on every reload; registering one of these
+    // names does not make an admin.
+    pub admin_usernames: Vec<String>,
+    // Wrong passwords in a row that lock an account against password logins; 0 never locks.
+    pub max_failed_logins: u32,
+    // How long a locked account stays locked, unless its owner or an admin unlocks it sooner.
+    pub lockout_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegistrationConfig {
+    // Only registrations with a valid invite code, created by an existing user, are accepted.
+    pub invite_only: bool,
+    // Unused, unexpired invites a user may have at once; admins have no limit. 0 leaves
+    // inviting to admins.
+    pub invites_per_user: u32,
+    // How long an invite code stays valid.
+    pub invite_ttl_secs: u64,
+    // New accounts wait for an admin's approval before they can log in.
+    pub require_approval: bool,
+    // Anyone may start chatting as a guest: a passwordless account with a random username.
+    pub allow_guests: bool,
+    // How long a guest account lasts before it is deleted along with its data.
+    pub guest_ttl_secs: u64,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LdapConfig {
+    // ldap:// or ldaps:// URL of the directory server, e.g. "ldaps://ldap.example.com".
+    pub url: String,
+    // Upgrade an ldap:// connection with StartTLS before sending credentials.
+    #[serde(default)]
+    pub starttls: bool,
+    // DN users bind as, `{username}` standing for the login name, e.g.
+    // "uid={username},ou=people,dc=example,dc=com", or "{username}@corp.example.com" for
+    // Active Directory. Without it the user's entry is searched for under `base_dn`.
+    pub user_dn: Option<String>,
+    // Where users are searched, e.g. "ou=people,dc=example,dc=com".
+    pub base_dn: Option<String>,
+    // Filter finding a user's entry, e.g. "(sAMAccountName={username})" for Active Directory.
+    #[serde(default = "default_ldap_user_filter")]
+    pub user_filter: String,
+    // Account the search binds as; anonymous without it.
+    pub bind_dn: Option<String>,
+    pub bind_password: Option<String>,
+    // Longest a login may wait for the directory.
+    #[serde(default = "default_ldap_timeout_secs")]
+    pub timeout_secs: u64,
+    // Also let people register local accounts. Off by default, so nobody can take the
+    // username of a directory user who has not logged in yet.
+    #[serde(default)]
+    pub allow_registration: bool,
+}
+
+fn default_ldap_user_filter() -> String {
+    "(uid={username})".to_string()
+}
+
+fn default_ldap_timeout_secs() -> u64 {
+    5
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    // Where users and contacts are kept. Only "memory://" is supported for now.
+    pub dsn: String,
+    // File users and contacts are periodically saved to and loaded from at startup, so they
+    // survive a restart. Nothing is saved when unset.
+    pub snapshot_path: Option<PathBuf>,
+    // Seconds between snapshots.
+    pub snapshot_interval_secs: u64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProxyConfig {
+    // Reverse proxies (CIDR ranges) whose Forwarded / X-Forwarded-For headers are trusted.
+    pub trusted_proxies: Vec<IpNet>,
+}
+
+/// CIDR ranges of client addresses let in or turned away. Also the body of the admin IP rules
+/// endpoints, which replace them at runtime.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize, ToSchema)]
+#[serde(default, deny_unknown_fields)]
+pub struct IpFilterConfig {
+    // When not empty, only addresses in these ranges are let in.
+    #[schema(value_type = Vec<String>)]
+    pub allow: Vec<IpNet>,
+    // Addresses in these ranges are always turned away.
+    #[schema(value_type = Vec<String>)]
+    pub deny: Vec<IpNet>,
+}
+
+/// A MaxMind GeoIP2 or GeoLite2 country database in its CSV format, loaded at startup, and the
+/// countries let in when registering or logging in.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GeoIpConfig {
+    // Network blocks files, e.g. GeoLite2-Country-Blocks-IPv4.csv and GeoLite2-Country-Blocks-IPv6.csv.
+    pub blocks_paths: Vec<PathBuf>,
+    // Locations file naming the blocks' countries, e.g. GeoLite2-Country-Locations-en.csv.
+    pub locations_path: PathBuf,
+    #[serde(default)]
+    pub registration: CountryRules,
+    #[serde(default)]
+    pub login: CountryRules,
+}
+
+/// ISO 3166-1 alpha-2 codes of the countries let in or turned away, e.g. "DE".
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CountryRules {
+    // When not empty, only clients located in these countries are let in; clients whose
+    // country is unknown are turned away.
+    pub allow: Vec<String>,
+    // Clients located in these countries are always turned away.
+    pub deny: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookConfig {
+    // Delivery attempts per event before giving up (backoff doubles from 1s, capped at 60s).
+    pub max_attempts: u32,
+    // Timeout of a single delivery request.
+    pub timeout_secs: u64,
+    // Webhooks a user may register; global ones are not counted.
+    pub max_per_user: usize,
+    // Internal networks (CIDR ranges) webhooks may be delivered to. Loopback, private,
+    // link-local and other non-public addresses are refused unless listed here.
+    pub allow_networks: Vec<IpNet>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MessagesConfig {
+    // Attach sanitized HTML rendered from a markdown subset to chat messages, for web
+    // clients that don't render markdown themselves.
+    pub render_markdown: bool,
+    // Answer WebSocket frames of unknown types, or with unknown fields, with an error frame
+    // instead of dropping them or ignoring the fields. Meant for staging, to catch client
+    // protocol bugs early.
+    pub strict_parsing: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebPushConfig {
+    // P-256 private key (PEM) whose public half browsers subscribe with (VAPID).
+    pub vapid_private_key_path: PathBuf,
+    // Contact URI sent to push services in the VAPID `sub` claim ("mailto:..." or "https://...").
+    pub subject: String,
+    // How long push services keep a notification for a device that is offline.
+    #[serde(default = "default_push_ttl_secs")]
+    pub ttl_secs: u32,
+}
+
+fn default_push_ttl_secs() -> u32 {
+    24 * 60 * 60
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FcmConfig {
+    // Firebase service account key (JSON) allowed to send through Cloud Messaging.
+    pub service_account_path: PathBuf,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApnsConfig {
+    // Token-based authentication key (.p8) from the Apple developer account.
+    pub key_path: PathBuf,
+    pub key_id: String,
+    pub team_id: String,
+    // Bundle id of the iOS app.
+    pub topic: String,
+    // Send through the development environment (apps built for debugging).
+    #[serde(default)]
+    pub sandbox: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MatrixConfig {
+    // Client-Server API base URL of the homeserver, e.g. "https://matrix.example.org".
+    pub homeserver_url: String,
+    // Server name that appears in Matrix IDs (`@user:server_name`).
+    pub server_name: String,
+    // Tokens from the appservice registration file: `as_token` authenticates the bridge to
+    // the homeserver, `hs_token` authenticates the homeserver to the bridge.
+    pub as_token: String,
+    pub hs_token: String,
+    // Local users appear on Matrix as `@{user_prefix}{username}:{server_name}`.
+    #[serde(default = "default_matrix_user_prefix")]
+    pub user_prefix: String,
+    // Localpart of the bridge's own bot user (`sender_localpart` in the registration file).
+    #[serde(default = "default_matrix_sender_localpart")]
+    pub sender_localpart: String,
+}
+
+fn default_matrix_user_prefix() -> String {
+    "chat_".to_string()
+}
+
+fn default_matrix_sender_localpart() -> String {
+    "chatbridge".to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct XmppConfig {
+    pub bind_address: SocketAddr,
+    // Domain of the users' JIDs (`username@domain`); clients must open streams to it.
+    pub domain: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MqttConfig {
+    // Broker URL, "mqtt://[user:password@]host[:port]?client_id=..." or "mqtts://..." for TLS.
+    pub url: String,
+    // Topics are templates: "{user_id}" is replaced by the id of the user the event concerns
+    // (the recipient of a message, the user whose presence changed).
+    #[serde(default = "default_mqtt_message_topic")]
+    pub message_topic: String,
+    #[serde(default = "default_mqtt_presence_topic")]
+    pub presence_topic: String,
+    // Topic to receive messages on, sent with a bot API token; inbound messages are ignored
+    // when unset.
+    #[serde(default)]
+    pub inbound_topic: Option<String>,
+    // Quality of service of publications and the inbound subscription (0, 1 or 2).
+    #[serde(default = "default_mqtt_qos")]
+    pub qos: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterConfig {
+    // Name of this node, unique within the cluster.
+    pub node_id: String,
+    // Base URLs of the other nodes' HTTP listeners, e.g. "http://chat-2:3030".
+    pub peers: Vec<String>,
+    // Shared by every node; signs the presence announcements they send each other.
+    pub secret: String,
+    // Seconds a node's announcement that a user is connected lasts unless renewed. Nodes
+    // renew theirs every third of it, so a node that goes away is forgotten after this long.
+    #[serde(default = "default_cluster_presence_ttl_secs")]
+    pub presence_ttl_secs: u64,
+}
+
+fn default_cluster_presence_ttl_secs() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NatsConfig {
+    // Server URL, "nats://[user:password@|token@]host[:port]".
+    pub url: String,
+    // First token of every subject: frames for a user go to "{prefix}.users.{user_id}",
+    // domain events to "{prefix}.events.{event}" and queued frames to "{prefix}.offline.{user_id}".
+    #[serde(default = "default_nats_subject_prefix")]
+    pub subject_prefix: String,
+    // JetStream stream keeping the frames for users connected to no node until they connect;
+    // frames for offline users are dropped when unset. Requires `[cluster]`: a node only knows
+    // about connections to other nodes through it, and would otherwise queue frames that NATS
+    // also delivers.
+    #[serde(default)]
+    pub offline_stream: Option<String>,
+    // Seconds a queued frame is kept before it is discarded.
+    #[serde(default = "default_nats_offline_max_age_secs")]
+    pub offline_max_age_secs: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KafkaConfig {
+    // Base URL of a Confluent-compatible Kafka REST proxy (v2 API), e.g. "http://kafka-rest:8082".
+    pub rest_proxy_url: String,
+    #[serde(default = "default_kafka_message_topic")]
+    pub message_topic: String,
+    #[serde(default = "default_kafka_presence_topic")]
+    pub presence_topic: String,
+    #[serde(default = "default_kafka_audit_topic")]
+    pub audit_topic: String,
+    // Whether message records carry the text of chat messages, for compliance archiving.
+    #[serde(default)]
+    pub include_message_text: bool,
+    // Records per request to the proxy, and how long a partial batch waits for more.
+    #[serde(default = "default_kafka_batch_size")]
+    pub batch_size: usize,
+    #[serde(default = "default_kafka_linger_ms")]
+    pub linger_ms: u64,
+}
+
+fn default_kafka_message_topic() -> String {
+    "chat.messages".to_string()
+}
+
+fn default_kafka_presence_topic() -> String {
+    "chat.presence".to_string()
+}
+
+fn default_kafka_audit_topic() -> String {
+    "chat.audit".to_string()
+}
+
+fn default_kafka_batch_size() -> usize {
+    100
+}
+
+fn default_kafka_linger_ms() -> u64 {
+    500
+}
+
+fn default_nats_subject_prefix() -> String {
+    "chat".to_string()
+}
+
+fn default_nats_offline_max_age_secs() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+fn default_mqtt_message_topic() -> String {
+    "chat/users/{user_id}/messages".to_string()
+}
+
+fn default_mqtt_presence_topic() -> String {
+    "chat/users/{user_id}/presence".to_string()
+}
+
+fn default_mqtt_qos() -> u8 {
+    1
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GrpcConfig {
+    pub bind_address: SocketAddr,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct BannerConfig {
+    pub title: String,
+    pub body: String,
+    #[serde(default = "default_banner_severity")]
+    pub severity: AnnouncementSeverity,
+}
+
+fn default_banner_severity() -> AnnouncementSeverity {
+    AnnouncementSeverity::Info
+}
+
+/// The subset of the configuration that can be changed while the server is running
+/// (see `reload`). Everything else requires a restart.
+#[derive(Debug, Clone, PartialEq, Serialize, ToSchema)]
+pub struct RuntimeConfig {
+    pub log_level: String,
+    pub max_message_length: usize,
+    pub max_messages_per_minute: u32,
+    pub typing_timeout_secs: u64,
+    pub render_markdown: bool,
+    pub strict_parsing: bool,
+    pub banner: Option<BannerConfig>,
+}
+
+impl From<&Config> for RuntimeConfig {
+    fn from(config: &Config) -> Self {
+        RuntimeConfig {
+            log_level: config.log.level.clone(),
+            max_message_length: config.limits.max_message_length,
+            max_messages_per_minute: config.limits.max_messages_per_minute,
+            typing_timeout_secs: config.limits.typing_timeout_secs,
+            render_markdown: config.messages.render_markdown,
+            strict_parsing: config.messages.strict_parsing,
+            banner: config.banner.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            bind_address: SocketAddr::from(([0, 0, 0, 0], 3030)),
+            static_dir: PathBuf::from("static"),
+            log: LogConfig::default(),
+            limits: LimitsConfig::default(),
+            auth: AuthConfig::default(),
+            registration: RegistrationConfig::default(),
+            ldap: None,
+            storage: StorageConfig::default(),
+            tls: None,
+            proxy: ProxyConfig::default(),
+            ip_filter: IpFilterConfig::default(),
+            geoip: None,
+            grpc: None,
+            banner: None,
+            webhooks: WebhookConfig::default(),
+            messages: MessagesConfig::default(),
+            web_push: None,
+            fcm: None,
+            apns: None,
+            matrix: None,
+            xmpp: None,
+            mqtt: None,
+            cluster: None,
+            nats: None,
+            kafka: None,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: "info".to_string(),
+            format: LogFormat::Pretty,
+            reveal_sensitive: false,
+            pseudonymize: false,
+            pseudonym_key: None,
+            access: AccessLogConfig::default(),
+        }
+    }
+}
+
+impl Default for AccessLogConfig {
+    fn default() -> Self {
+        AccessLogConfig { enabled: true, sample_rate: 1.0, routes: BTreeMap::new(), slow_request_ms: 1000 }
+    }
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_message_length: 4096,
+            max_connections: 10_000,
+            max_messages_per_minute: 0,
+            max_body_bytes: 64 * 1024,
+            http_requests_per_minute: 0,
+            typing_timeout_secs: 10,
+            conversation_shards: 16,
+            fanout_workers: 4,
+            state_shards: 16,
+            slow_consumer_queue_depth: 1024,
+            slow_consumer_grace_secs: 10,
+            send_timeout_secs: 10,
+        }
+    }
+}
+
+impl Default for RegistrationConfig {
+    fn default() -> Self {
+        RegistrationConfig {
+            invite_only: false,
+            invites_per_user: 5,
+            invite_ttl_secs: 7 * 24 * 60 * 60,
+            require_approval: false,
+            allow_guests: false,
+            guest_ttl_secs: 60 * 60,
+        }
+    }
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        AuthConfig {
+            bcrypt_cost: bcrypt::DEFAULT_COST,
+            session_ttl_secs: 7 * 24 * 60 * 60,
+            untrusted_session_ttl_secs: 24 * 60 * 60,
+            session_idle_timeout_secs: 24 * 60 * 60,
+            allow_query_token: true,
+            auth_frame_timeout_secs: 10,
+            allowed_origins: Vec::new(),
+            admin_usernames: Vec::new(),
+            max_failed_logins: 10,
+            lockout_secs: 15 * 60,
+        }
+    }
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig { max_attempts: 5, timeout_secs: 10, max_per_user: 10, allow_networks: Vec::new() }
+    }
+}
+
+impl Default for StorageConfig {
+    fn default() -> Self {
+        StorageConfig { dsn: "memory://".to_string(), snapshot_path: None, snapshot_interval_secs: 60 }
+    }
+}
+
+/// Everything that can go wrong while loading the configuration.
+#[derive(Debug)]
+pub enum ConfigError {
+    Read { path: PathBuf, source: std::io::Error },
+    Parse { path: PathBuf, source: toml::de::Error },
+    InvalidEnv { var: &'static str, value: String, reason: String },
+    Invalid { field: &'static str, reason: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Read { path, source } => {
+                write!(f, "cannot read config file {}: {}", path.display(), source)
+            }
+            ConfigError::Parse { path, source } => {
+                write!(f, "invalid config file {}: {}", path.display(), source)
+            }
+            ConfigError::InvalidEnv { var, value, reason } => {
+                write!(f, "invalid value {:?} for environment variable {}: {}", value, var, reason)
+            }
+            ConfigError::Invalid { field, reason } => write!(f, "invalid config `{}`: {}", field, reason),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Config {
+    /// Loads the configuration for this process.
+    ///
+    /// The file path comes from `--config <path>`, then `CHAT_CONFIG`; if neither is set,
+    /// `config.toml` is used when it exists and defaults otherwise.
+    pub fn load() -> Result<Config, ConfigError> {
+        let explicit_path = config_path_from_args().or_else(|| std::env::var("CHAT_CONFIG").ok().map(PathBuf::from));
+
+        let mut config = match explicit_path {
+            Some(path) => Config::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => Config::from_file(Path::new(DEFAULT_CONFIG_PATH))?,
+            None => Config::default(),
+        };
+        config.apply_env_overrides()?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|source| ConfigError::Read { path: path.to_path_buf(), source })?;
+        toml::from_str(&contents).map_err(|source| ConfigError::Parse { path: path.to_path_buf(), source })
+    }
+
+    /// Applies environment variables on top of the file values.
+    /// `HOST` and `PORT` are kept for compatibility with the existing deployment scripts.
+    fn apply_env_overrides(&mut self) -> Result<(), ConfigError> {
+        if let Some(address) = env_parse::<SocketAddr>("CHAT_BIND_ADDRESS")? {
+            self.bind_address = address;
+        }
+        if let Some(host) = env_parse::<IpAddr>("HOST")? {
+            self.bind_address.set_ip(host);
+        }
+        if let Some(port) = env_parse::<u16>("PORT")? {
+            self.bind_address.set_port(port);
+        }
+        if let Some(dir) = env_var("CHAT_STATIC_DIR") {
+            self.static_dir = PathBuf::from(dir);
+        }
+        if let Some(level) = env_var("LOG_LEVEL") {
+            self.log.level = level;
+        }
+        if let Some(format) = env_var("LOG_FORMAT") {
+            self.log.format = match format.to_ascii_lowercase().as_str() {
+                "json" => LogFormat::Json,
+                "pretty" => LogFormat::Pretty,
+                _ => {
+                    return Err(ConfigError::InvalidEnv {
+                        var: "LOG_FORMAT",
+                        value: format,
+                        reason: "expected \"pretty\" or \"json\"".to_string(),
+                    })
+                }
+            };
+        }
+        if let Some(key) = env_var("CHAT_LOG_PSEUDONYM_KEY") {
+            self.log.pseudonymize = true;
+            self.log.pseudonym_key = Some(key);
+        }
+        if let Some(length) = env_parse("CHAT_MAX_MESSAGE_LENGTH")? {
+            self.limits.max_message_length = length;
+        }
+        if let Some(connections) = env_parse("CHAT_MAX_CONNECTIONS")? {
+            self.limits.max_connections = connections;
+        }
+        if let Some(rate) = env_parse("CHAT_MAX_MESSAGES_PER_MINUTE")? {
+            self.limits.max_messages_per_minute = rate;
+        }
+        if let Some(bytes) = env_parse("CHAT_MAX_BODY_BYTES")? {
+            self.limits.max_body_bytes = bytes;
+        }
+        if let Some(rate) = env_parse("CHAT_HTTP_REQUESTS_PER_MINUTE")? {
+            self.limits.http_requests_per_minute = rate;
+        }
+        if let Some(timeout) = env_parse("CHAT_TYPING_TIMEOUT_SECS")? {
+            self.limits.typing_timeout_secs = timeout;
+        }
+        if let Some(shards) = env_parse("CHAT_CONVERSATION_SHARDS")? {
+            self.limits.conversation_shards = shards;
+        }
+        if let Some(workers) = env_parse("CHAT_FANOUT_WORKERS")? {
+            self.limits.fanout_workers = workers;
+        }
+        if let Some(shards) = env_parse("CHAT_STATE_SHARDS")? {
+            self.limits.state_shards = shards;
+        }
+        if let Some(depth) = env_parse("CHAT_SLOW_CONSUMER_QUEUE_DEPTH")? {
+            self.limits.slow_consumer_queue_depth = depth;
+        }
+        if let Some(grace) = env_parse("CHAT_SLOW_CONSUMER_GRACE_SECS")? {
+            self.limits.slow_consumer_grace_secs = grace;
+        }
+        if let Some(timeout) = env_parse("CHAT_SEND_TIMEOUT_SECS")? {
+            self.limits.send_timeout_secs = timeout;
+        }
+        if let Some(cost) = env_parse("CHAT_BCRYPT_COST")? {
+            self.auth.bcrypt_cost = cost;
+        }
+        if let Some(ttl) = env_parse("CHAT_SESSION_TTL_SECS")? {
+            self.auth.session_ttl_secs = ttl;
+        }
+        if let Some(ttl) = env_parse("CHAT_UNTRUSTED_SESSION_TTL_SECS")? {
+            self.auth.untrusted_session_ttl_secs = ttl;
+        }
+        if let Some(idle) = env_parse("CHAT_SESSION_IDLE_TIMEOUT_SECS")? {
+            self.auth.session_idle_timeout_secs = idle;
+        }
+        if let Some(allow) = env_parse::<bool>("CHAT_ALLOW_QUERY_TOKEN")? {
+            self.auth.allow_query_token = allow;
+        }
+        if let Some(timeout) = env_parse("CHAT_AUTH_FRAME_TIMEOUT_SECS")? {
+            self.auth.auth_frame_timeout_secs = timeout;
+        }
+        if let Some(max) = env_parse("CHAT_MAX_FAILED_LOGINS")? {
+            self.auth.max_failed_logins = max;
+        }
+        if let Some(lockout) = env_parse("CHAT_LOCKOUT_SECS")? {
+            self.auth.lockout_secs = lockout;
+        }
+        if let Some(invite_only) = env_parse::<bool>("CHAT_INVITE_ONLY")? {
+            self.registration.invite_only = invite_only;
+        }
+        if let Some(invites) = env_parse("CHAT_INVITES_PER_USER")? {
+            self.registration.invites_per_user = invites;
+        }
+        if let Some(ttl) = env_parse("CHAT_INVITE_TTL_SECS")? {
+            self.registration.invite_ttl_secs = ttl;
+        }
+        if let Some(require) = env_parse::<bool>("CHAT_REQUIRE_APPROVAL")? {
+            self.registration.require_approval = require;
+        }
+        if let Some(allow) = env_parse::<bool>("CHAT_ALLOW_GUESTS")? {
+            self.registration.allow_guests = allow;
+        }
+        if let Some(ttl) = env_parse("CHAT_GUEST_TTL_SECS")? {
+            self.registration.guest_ttl_secs = ttl;
+        }
+        if let Some(names) = env_var("ADMIN_USERNAMES") {
+            self.auth.admin_usernames = names
+                .split(',')
+                .map(|name| name.trim().to_string())
+                .filter(|name| !name.is_empty())
+                .collect();
+        }
+        if let Some(origins) = env_var("CHAT_ALLOWED_ORIGINS") {
+            self.auth.allowed_origins = origins
+                .split(',')
+                .map(|origin| origin.trim().to_string())
+                .filter(|origin| !origin.is_empty())
+                .collect();
+        }
+        if let Some(proxies) = env_var("CHAT_TRUSTED_PROXIES") {
+            self.proxy.trusted_proxies = parse_ip_ranges("CHAT_TRUSTED_PROXIES", &proxies)?;
+        }
+        if let Some(ranges) = env_var("CHAT_IP_ALLOW") {
+            self.ip_filter.allow = parse_ip_ranges("CHAT_IP_ALLOW", &ranges)?;
+        }
+        if let Some(ranges) = env_var("CHAT_IP_DENY") {
+            self.ip_filter.deny = parse_ip_ranges("CHAT_IP_DENY", &ranges)?;
+        }
+        match (env_var("CHAT_GEOIP_BLOCKS_PATHS"), env_var("CHAT_GEOIP_LOCATIONS_PATH")) {
+            (Some(blocks), Some(locations)) => {
+                let (registration, login) = self.geoip.take().map(|geoip| (geoip.registration, geoip.login)).unwrap_or_default();
+                self.geoip = Some(GeoIpConfig {
+                    blocks_paths: blocks.split(',').map(str::trim).filter(|path| !path.is_empty()).map(PathBuf::from).collect(),
+                    locations_path: locations.into(),
+                    registration,
+                    login,
+                });
+            }
+            (None, None) => {}
+            _ => {
+                return Err(ConfigError::Invalid {
+                    field: "geoip",
+                    reason: "CHAT_GEOIP_BLOCKS_PATHS and CHAT_GEOIP_LOCATIONS_PATH must be set together".to_string(),
+                })
+            }
+        }
+        if let Some(address) = env_parse("CHAT_GRPC_BIND_ADDRESS")? {
+            self.grpc = Some(GrpcConfig { bind_address: address });
+        }
+        if let Some(attempts) = env_parse("CHAT_WEBHOOK_MAX_ATTEMPTS")? {
+            self.webhooks.max_attempts = attempts;
+        }
+        if let Some(timeout) = env_parse("CHAT_WEBHOOK_TIMEOUT_SECS")? {
+            self.webhooks.timeout_secs = timeout;
+        }
+        if let Some(max) = env_parse("CHAT_WEBHOOK_MAX_PER_USER")? {
+            self.webhooks.max_per_user = max;
+        }
+        if let Some(ranges) = env_var("CHAT_WEBHOOK_ALLOW_NETWORKS") {
+            self.webhooks.allow_networks = parse_ip_ranges("CHAT_WEBHOOK_ALLOW_NETWORKS", &ranges)?;
+        }
+        if let Some(render) = env_parse("CHAT_RENDER_MARKDOWN")? {
+            self.messages.render_markdown = render;
+        }
+        if let Some(strict) = env_parse("CHAT_STRICT_PARSING")? {
+            self.messages.strict_parsing = strict;
+        }
+        match (env_var("CHAT_VAPID_PRIVATE_KEY_PATH"), env_var("CHAT_VAPID_SUBJECT")) {
+            (Some(key_path), Some(subject)) => {
+                let ttl_secs = self.web_push.as_ref().map_or_else(default_push_ttl_secs, |push| push.ttl_secs);
+                self.web_push = Some(WebPushConfig { vapid_private_key_path: key_path.into(), subject, ttl_secs });
+            }
+            (None, None) => {}
+            _ => {
+                return Err(ConfigError::Invalid {
+                    field: "web_push",
+                    reason: "CHAT_VAPID_PRIVATE_KEY_PATH and CHAT_VAPID_SUBJECT must be set together".to_string(),
+                })
+            }
+        }
+        if let Some(path) = env_var("CHAT_FCM_SERVICE_ACCOUNT_PATH") {
+            self.fcm = Some(FcmConfig { service_account_path: path.into() });
+        }
+        let apns_vars = [
+            env_var("CHAT_APNS_KEY_PATH"),
+            env_var("CHAT_APNS_KEY_ID"),
+            env_var("CHAT_APNS_TEAM_ID"),
+            env_var("CHAT_APNS_TOPIC"),
+        ];
+        match apns_vars {
+            [Some(key_path), Some(key_id), Some(team_id), Some(topic)] => {
+                let sandbox = self.apns.as_ref().is_some_and(|apns| apns.sandbox);
+                self.apns = Some(ApnsConfig { key_path: key_path.into(), key_id, team_id, topic, sandbox });
+            }
+            [None, None, None, None] => {}
+            _ => {
+                return Err(ConfigError::Invalid {
+                    field: "apns",
+                    reason: "CHAT_APNS_KEY_PATH, CHAT_APNS_KEY_ID, CHAT_APNS_TEAM_ID and CHAT_APNS_TOPIC must be set together".to_string(),
+                })
+            }
+        }
+        if let Some(sandbox) = env_parse::<bool>("CHAT_APNS_SANDBOX")? {
+            if let Some(apns) = &mut self.apns {
+                apns.sandbox = sandbox;
+            }
+        }
+        let matrix_vars = [
+            env_var("CHAT_MATRIX_HOMESERVER_URL"),
+            env_var("CHAT_MATRIX_SERVER_NAME"),
+            env_var("CHAT_MATRIX_AS_TOKEN"),
+            env_var("CHAT_MATRIX_HS_TOKEN"),
+        ];
+        match matrix_vars {
+            [Some(homeserver_url), Some(server_name), Some(as_token), Some(hs_token)] => {
+                let (user_prefix, sender_localpart) = match &self.matrix {
+                    Some(matrix) => (matrix.user_prefix.clone(), matrix.sender_localpart.clone()),
+                    None => (default_matrix_user_prefix(), default_matrix_sender_localpart()),
+                };
+                self.matrix = Some(MatrixConfig { homeserver_url, server_name, as_token, hs_token, user_prefix, sender_localpart });
+            }
+            [None, None, None, None] => {}
+            _ => {
+                return Err(ConfigError::Invalid {
+                    field: "matrix",
+                    reason: "CHAT_MATRIX_HOMESERVER_URL, CHAT_MATRIX_SERVER_NAME, CHAT_MATRIX_AS_TOKEN and CHAT_MATRIX_HS_TOKEN must be set together".to_string(),
+                })
+            }
+        }
+        match (env_parse("CHAT_XMPP_BIND_ADDRESS")?, env_var("CHAT_XMPP_DOMAIN")) {
+            (Some(bind_address), Some(domain)) => self.xmpp = Some(XmppConfig { bind_address, domain }),
+            (None, None) => {}
+            _ => {
+                return Err(ConfigError::Invalid {
+                    field: "xmpp",
+                    reason: "CHAT_XMPP_BIND_ADDRESS and CHAT_XMPP_DOMAIN must be set together".to_string(),
+                })
+            }
+        }
+        match (env_var("CHAT_CLUSTER_NODE_ID"), env_var("CHAT_CLUSTER_PEERS"), env_var("CHAT_CLUSTER_SECRET")) {
+            (Some(node_id), Some(peers), Some(secret)) => {
+                let peers = peers.split(',').map(str::trim).filter(|peer| !peer.is_empty()).map(str::to_string).collect();
+                let presence_ttl_secs = self.cluster.as_ref().map_or_else(default_cluster_presence_ttl_secs, |cluster| cluster.presence_ttl_secs);
+                self.cluster = Some(ClusterConfig { node_id, peers, secret, presence_ttl_secs });
+            }
+            (None, None, None) => {}
+            _ => {
+                return Err(ConfigError::Invalid {
+                    field: "cluster",
+                    reason: "CHAT_CLUSTER_NODE_ID, CHAT_CLUSTER_PEERS and CHAT_CLUSTER_SECRET must be set together".to_string(),
+                })
+            }
+        }
+        if let Some(url) = env_var("CHAT_MQTT_URL") {
+            match &mut self.mqtt {
+                Some(mqtt) => mqtt.url = url,
+                None => {
+                    self.mqtt = Some(MqttConfig {
+                        url,
+                        message_topic: default_mqtt_message_topic(),
+                        presence_topic: default_mqtt_presence_topic(),
+                        inbound_topic: None,
+                        qos: default_mqtt_qos(),
+                    })
+                }
+            }
+        }
+        if let Some(topic) = env_var("CHAT_MQTT_INBOUND_TOPIC") {
+            if let Some(mqtt) = &mut self.mqtt {
+                mqtt.inbound_topic = Some(topic);
+            }
+        }
+        if let Some(url) = env_var("CHAT_NATS_URL") {
+            match &mut self.nats {
+                Some(nats) => nats.url = url,
+                None => {
+                    self.nats = Some(NatsConfig {
+                        url,
+                        subject_prefix: default_nats_subject_prefix(),
+                        offline_stream: None,
+                        offline_max_age_secs: default_nats_offline_max_age_secs(),
+                    })
+                }
+            }
+        }
+        if let Some(stream) = env_var("CHAT_NATS_OFFLINE_STREAM") {
+            if let Some(nats) = &mut self.nats {
+                nats.offline_stream = Some(stream);
+            }
+        }
+        if let Some(url) = env_var("CHAT_KAFKA_REST_PROXY_URL") {
+            match &mut self.kafka {
+                Some(kafka) => kafka.rest_proxy_url = url,
+                None => {
+                    self.kafka = Some(KafkaConfig {
+                        rest_proxy_url: url,
+                        message_topic: default_kafka_message_topic(),
+                        presence_topic: default_kafka_presence_topic(),
+                        audit_topic: default_kafka_audit_topic(),
+                        include_message_text: false,
+                        batch_size: default_kafka_batch_size(),
+                        linger_ms: default_kafka_linger_ms(),
+                    })
+                }
+            }
+        }
+        if let Some(url) = env_var("CHAT_LDAP_URL") {
+            match &mut self.ldap {
+                Some(ldap) => ldap.url = url,
+                None => {
+                    self.ldap = Some(LdapConfig {
+                        url,
+                        starttls: false,
+                        user_dn: None,
+                        base_dn: None,
+                        user_filter: default_ldap_user_filter(),
+                        bind_dn: None,
+                        bind_password: None,
+                        timeout_secs: default_ldap_timeout_secs(),
+                        allow_registration: false,
+                    })
+                }
+            }
+        }
+        if let Some(ldap) = &mut self.ldap {
+            if let Some(user_dn) = env_var("CHAT_LDAP_USER_DN") {
+                ldap.user_dn = Some(user_dn);
+            }
+            if let Some(base_dn) = env_var("CHAT_LDAP_BASE_DN") {
+                ldap.base_dn = Some(base_dn);
+            }
+            if let Some(bind_dn) = env_var("CHAT_LDAP_BIND_DN") {
+                ldap.bind_dn = Some(bind_dn);
+            }
+            if let Some(password) = env_var("CHAT_LDAP_BIND_PASSWORD") {
+                ldap.bind_password = Some(password);
+            }
+        }
+        if let Some(dsn) = env_var("CHAT_STORAGE_DSN") {
+            self.storage.dsn = dsn;
+        }
+        if let Some(path) = env_var("CHAT_STORAGE_SNAPSHOT_PATH") {
+            self.storage.snapshot_path = Some(path.into());
+        }
+        if let Some(secs) = env_parse("CHAT_STORAGE_SNAPSHOT_INTERVAL_SECS")? {
+            self.storage.snapshot_interval_secs = secs;
+        }
+        match (env_var("CHAT_TLS_CERT_PATH"), env_var("CHAT_TLS_KEY_PATH")) {
+            (Some(cert_path), Some(key_path)) => {
+                self.tls = Some(TlsConfig { cert_path: cert_path.into(), key_path: key_path.into() });
+            }
+            (None, None) => {}
+            _ => {
+                return Err(ConfigError::Invalid {
+                    field: "tls",
+                    reason: "CHAT_TLS_CERT_PATH and CHAT_TLS_KEY_PATH must be set together".to_string(),
+                })
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks values the types alone don't rule out; `load` does this already.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&self.log.level) {
+            return Err(invalid("log.level", e.to_string()));
+        }
+        if self.log.reveal_sensitive && !cfg!(debug_assertions) {
+            return Err(invalid("log.reveal_sensitive", "is only available in debug builds".to_string()));
+        }
+        if self.log.pseudonymize && self.log.pseudonym_key.as_deref().is_none_or(|key| key.len() < 16) {
+            return Err(invalid("log.pseudonym_key", "must be at least 16 characters with log.pseudonymize".to_string()));
+        }
+        if !(0.0..=1.0).contains(&self.log.access.sample_rate) {
+            return Err(invalid("log.access.sample_rate", "must be between 0.0 and 1.0".to_string()));
+        }
+        for (prefix, rate) in &self.log.access.routes {
+            if !prefix.starts_with('/') {
+                return Err(invalid("log.access.routes", format!("`{prefix}` must start with '/'")));
+            }
+            if !(0.0..=1.0).contains(rate) {
+                return Err(invalid("log.access.routes", format!("the rate of `{prefix}` must be between 0.0 and 1.0")));
+            }
+        }
+        if self.log.access.slow_request_ms == 0 {
+            return Err(invalid("log.access.slow_request_ms", "must be greater than zero".to_string()));
+        }
+        if self.limits.max_message_length == 0 {
+            return Err(invalid("limits.max_message_length", "must be greater than zero".to_string()));
+        }
+        if self.limits.max_connections == 0 {
+            return Err(invalid("limits.max_connections", "must be greater than zero".to_string()));
+        }
+        if self.limits.max_body_bytes < self.limits.max_message_length as u64 {
+            return Err(invalid("limits.max_body_bytes", "must be at least limits.max_message_length".to_string()));
+        }
+        if self.limits.typing_timeout_secs == 0 {
+            return Err(invalid("limits.typing_timeout_secs", "must be greater than zero".to_string()));
+        }
+        if self.limits.conversation_shards == 0 {
+            return Err(invalid("limits.conversation_shards", "must be greater than zero".to_string()));
+        }
+        if self.limits.fanout_workers == 0 {
+            return Err(invalid("limits.fanout_workers", "must be greater than zero".to_string()));
+        }
+        if self.limits.state_shards == 0 {
+            return Err(invalid("limits.state_shards", "must be greater than zero".to_string()));
+        }
+        if self.limits.send_timeout_secs == 0 {
+            return Err(invalid("limits.send_timeout_secs", "must be greater than zero".to_string()));
+        }
+        if !(4..=31).contains(&self.auth.bcrypt_cost) {
+            return Err(invalid("auth.bcrypt_cost", "must be between 4 and 31".to_string()));
+        }
+        if self.auth.session_ttl_secs == 0 {
+            return Err(invalid("auth.session_ttl_secs", "must be greater than zero".to_string()));
+        }
+        if self.auth.untrusted_session_ttl_secs == 0 {
+            return Err(invalid("auth.untrusted_session_ttl_secs", "must be greater than zero".to_string()));
+        }
+        if self.auth.session_idle_timeout_secs == 0 {
+            return Err(invalid("auth.session_idle_timeout_secs", "must be greater than zero".to_string()));
+        }
+        if self.auth.auth_frame_timeout_secs == 0 {
+            return Err(invalid("auth.auth_frame_timeout_secs", "must be greater than zero".to_string()));
+        }
+        if self.auth.max_failed_logins > 0 && self.auth.lockout_secs == 0 {
+            return Err(invalid("auth.lockout_secs", "must be greater than zero with auth.max_failed_logins".to_string()));
+        }
+        if self.registration.invite_ttl_secs == 0 {
+            return Err(invalid("registration.invite_ttl_secs", "must be greater than zero".to_string()));
+        }
+        if self.registration.allow_guests && self.registration.guest_ttl_secs == 0 {
+            return Err(invalid("registration.guest_ttl_secs", "must be greater than zero with registration.allow_guests".to_string()));
+        }
+        if let Some(origin) = self.auth.allowed_origins.iter().find(|origin| !is_origin(origin)) {
+            return Err(invalid("auth.allowed_origins", format!("`{origin}` is not an origin like https://chat.example.com")));
+        }
+        if self.storage.dsn != "memory://" {
+            return Err(invalid("storage.dsn", format!("unsupported storage backend {:?}; only \"memory://\" is available", self.storage.dsn)));
+        }
+        if self.storage.snapshot_interval_secs == 0 {
+            return Err(invalid("storage.snapshot_interval_secs", "must be greater than zero".to_string()));
+        }
+        if self.webhooks.max_attempts == 0 {
+            return Err(invalid("webhooks.max_attempts", "must be greater than zero".to_string()));
+        }
+        if self.webhooks.timeout_secs == 0 {
+            return Err(invalid("webhooks.timeout_secs", "must be greater than zero".to_string()));
+        }
+        if let Some(banner) = &self.banner {
+            if banner.title.trim().is_empty() || banner.body.trim().is_empty() {
+                return Err(invalid("banner", "title and body are required".to_string()));
+            }
+        }
+        if self.tls.is_some() && !cfg!(feature = "tls") {
+            return Err(invalid("tls", "this build does not include TLS support (enable the `tls` cargo feature)".to_string()));
+        }
+        if let Some(grpc) = &self.grpc {
+            if !cfg!(feature = "grpc") {
+                return Err(invalid("grpc", "this build does not include the gRPC service (enable the `grpc` cargo feature)".to_string()));
+            }
+            if grpc.bind_address == self.bind_address {
+                return Err(invalid("grpc.bind_address", "must differ from bind_address".to_string()));
+            }
+        }
+        if let Some(push) = &self.web_push {
+            if !cfg!(feature = "web-push") {
+                return Err(invalid("web_push", "this build does not include Web Push (enable the `web-push` cargo feature)".to_string()));
+            }
+            if !(push.subject.starts_with("mailto:") || push.subject.starts_with("https://")) {
+                return Err(invalid("web_push.subject", "must be a mailto: or https:// URI".to_string()));
+            }
+            if !push.vapid_private_key_path.is_file() {
+                return Err(invalid("web_push.vapid_private_key_path", format!("{} does not exist", push.vapid_private_key_path.display())));
+            }
+        }
+        if (self.fcm.is_some() || self.apns.is_some()) && !cfg!(feature = "mobile-push") {
+            let field = if self.fcm.is_some() { "fcm" } else { "apns" };
+            return Err(invalid(field, "this build does not include mobile push (enable the `mobile-push` cargo feature)".to_string()));
+        }
+        if let Some(fcm) = &self.fcm {
+            if !fcm.service_account_path.is_file() {
+                return Err(invalid("fcm.service_account_path", format!("{} does not exist", fcm.service_account_path.display())));
+            }
+        }
+        if let Some(apns) = &self.apns {
+            if apns.key_id.trim().is_empty() || apns.team_id.trim().is_empty() || apns.topic.trim().is_empty() {
+                return Err(invalid("apns", "key_id, team_id and topic are required".to_string()));
+            }
+            if !apns.key_path.is_file() {
+                return Err(invalid("apns.key_path", format!("{} does not exist", apns.key_path.display())));
+            }
+        }
+        if let Some(matrix) = &self.matrix {
+            if !cfg!(feature = "matrix") {
+                return Err(invalid("matrix", "this build does not include the Matrix bridge (enable the `matrix` cargo feature)".to_string()));
+            }
+            match reqwest::Url::parse(&matrix.homeserver_url) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") && !url.cannot_be_a_base() => {}
+                _ => return Err(invalid("matrix.homeserver_url", "must be an http:// or https:// URL".to_string())),
+            }
+            if matrix.server_name.trim().is_empty() || matrix.as_token.is_empty() || matrix.hs_token.is_empty() {
+                return Err(invalid("matrix", "server_name, as_token and hs_token are required".to_string()));
+            }
+            if matrix.user_prefix.is_empty() || matrix.sender_localpart.is_empty() {
+                return Err(invalid("matrix", "user_prefix and sender_localpart cannot be empty".to_string()));
+            }
+        }
+        if let Some(xmpp) = &self.xmpp {
+            if !cfg!(feature = "xmpp") {
+                return Err(invalid("xmpp", "this build does not include the XMPP gateway (enable the `xmpp` cargo feature)".to_string()));
+            }
+            if xmpp.domain.trim().is_empty() || xmpp.domain.contains(['@', '/']) {
+                return Err(invalid("xmpp.domain", "must be a plain domain name".to_string()));
+            }
+            let grpc_address = self.grpc.as_ref().map(|grpc| grpc.bind_address);
+            if xmpp.bind_address == self.bind_address || Some(xmpp.bind_address) == grpc_address {
+                return Err(invalid("xmpp.bind_address", "must differ from the HTTP and gRPC addresses".to_string()));
+            }
+        }
+        if let Some(mqtt) = &self.mqtt {
+            if !cfg!(feature = "mqtt") {
+                return Err(invalid("mqtt", "this build does not include the MQTT bridge (enable the `mqtt` cargo feature)".to_string()));
+            }
+            #[cfg(feature = "mqtt")]
+            if let Err(e) = rumqttc::MqttOptions::parse_url(mqtt.url.as_str()) {
+                return Err(invalid("mqtt.url", e.to_string()));
+            }
+            if mqtt.qos > 2 {
+                return Err(invalid("mqtt.qos", "must be 0, 1 or 2".to_string()));
+            }
+            for (field, topic) in [("mqtt.message_topic", &mqtt.message_topic), ("mqtt.presence_topic", &mqtt.presence_topic)] {
+                if topic.is_empty() || topic.contains(['+', '#']) {
+                    return Err(invalid(field, "must be a non-empty topic without wildcards".to_string()));
+                }
+            }
+            if mqtt.inbound_topic.as_deref().is_some_and(str::is_empty) {
+                return Err(invalid("mqtt.inbound_topic", "cannot be empty".to_string()));
+            }
+        }
+        if let Some(cluster) = &self.cluster {
+            if cluster.node_id.trim().is_empty() || cluster.secret.is_empty() {
+                return Err(invalid("cluster", "node_id and secret are required".to_string()));
+            }
+            for peer in &cluster.peers {
+                match reqwest::Url::parse(peer) {
+                    Ok(url) if matches!(url.scheme(), "http" | "https") && !url.cannot_be_a_base() => {}
+                    _ => return Err(invalid("cluster.peers", format!("{} is not an http:// or https:// URL", peer))),
+                }
+            }
+            if cluster.presence_ttl_secs < 3 {
+                return Err(invalid("cluster.presence_ttl_secs", "must be at least 3".to_string()));
+            }
+        }
+        if let Some(nats) = &self.nats {
+            if !cfg!(feature = "nats") {
+                return Err(invalid("nats", "this build does not include NATS routing (enable the `nats` cargo feature)".to_string()));
+            }
+            #[cfg(feature = "nats")]
+            if let Err(e) = crate::nats::NatsAddress::parse(&nats.url) {
+                return Err(invalid("nats.url", e));
+            }
+            let prefix = &nats.subject_prefix;
+            if prefix.is_empty() || prefix.starts_with('.') || prefix.ends_with('.') || prefix.contains(['*', '>', ' ', '\t']) {
+                return Err(invalid("nats.subject_prefix", "must be a non-empty subject without wildcards".to_string()));
+            }
+            if let Some(stream) = &nats.offline_stream {
+                if stream.is_empty() || stream.contains(['.', '*', '>', ' ', '\t', '/', '\\']) {
+                    return Err(invalid("nats.offline_stream", "must be a JetStream stream name".to_string()));
+                }
+                if self.cluster.is_none() {
+                    return Err(invalid("nats.offline_stream", "requires [cluster], to tell users connected to other nodes from offline ones".to_string()));
+                }
+            }
+            if nats.offline_max_age_secs == 0 {
+                return Err(invalid("nats.offline_max_age_secs", "must be at least 1".to_string()));
+            }
+        }
+        if let Some(kafka) = &self.kafka {
+            if !cfg!(feature = "kafka") {
+                return Err(invalid("kafka", "this build does not include the Kafka export (enable the `kafka` cargo feature)".to_string()));
+            }
+            match reqwest::Url::parse(&kafka.rest_proxy_url) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") && !url.cannot_be_a_base() => {}
+                _ => return Err(invalid("kafka.rest_proxy_url", "must be an http:// or https:// URL".to_string())),
+            }
+            let topics = [("kafka.message_topic", &kafka.message_topic), ("kafka.presence_topic", &kafka.presence_topic), ("kafka.audit_topic", &kafka.audit_topic)];
+            for (field, topic) in topics {
+                let legal = topic.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-'));
+                if topic.is_empty() || topic.len() > 249 || !legal || topic == "." || topic == ".." {
+                    return Err(invalid(field, "must be a Kafka topic name (letters, digits, '.', '_' and '-')".to_string()));
+                }
+            }
+            if kafka.batch_size == 0 {
+                return Err(invalid("kafka.batch_size", "must be at least 1".to_string()));
+            }
+        }
+        if let Some(ldap) = &self.ldap {
+            if !cfg!(feature = "ldap") {
+                return Err(invalid("ldap", "this build does not include LDAP authentication (enable the `ldap` cargo feature)".to_string()));
+            }
+            match reqwest::Url::parse(&ldap.url) {
+                Ok(url) if matches!(url.scheme(), "ldap" | "ldaps") && url.host().is_some() => {}
+                _ => return Err(invalid("ldap.url", "must be an ldap:// or ldaps:// URL".to_string())),
+            }
+            if ldap.starttls && ldap.url.starts_with("ldaps:") {
+                return Err(invalid("ldap.starttls", "cannot be used with an ldaps:// URL, which is encrypted already".to_string()));
+            }
+            match &ldap.user_dn {
+                Some(user_dn) if !user_dn.contains("{username}") => {
+                    return Err(invalid("ldap.user_dn", "must contain {username}".to_string()));
+                }
+                Some(_) => {}
+                None if ldap.base_dn.as_deref().is_none_or(str::is_empty) => {
+                    return Err(invalid("ldap.base_dn", "is required without ldap.user_dn".to_string()));
+                }
+                None if !ldap.user_filter.contains("{username}") => {
+                    return Err(invalid("ldap.user_filter", "must contain {username}".to_string()));
+                }
+                None => {}
+            }
+            if ldap.bind_dn.is_some() != ldap.bind_password.is_some() {
+                return Err(invalid("ldap.bind_password", "must be given together with ldap.bind_dn".to_string()));
+            }
+            if ldap.timeout_secs == 0 {
+                return Err(invalid("ldap.timeout_secs", "must be greater than zero".to_string()));
+            }
+        }
+        if let Some(geoip) = &self.geoip {
+            if geoip.blocks_paths.is_empty() {
+                return Err(invalid("geoip.blocks_paths", "must list at least one blocks file".to_string()));
+            }
+            let paths = geoip.blocks_paths.iter().map(|path| ("geoip.blocks_paths", path)).chain([("geoip.locations_path", &geoip.locations_path)]);
+            for (field, path) in paths {
+                if !path.is_file() {
+                    return Err(invalid(field, format!("{} does not exist", path.display())));
+                }
+            }
+            let rules = [
+                ("geoip.registration.allow", &geoip.registration.allow),
+                ("geoip.registration.deny", &geoip.registration.deny),
+                ("geoip.login.allow", &geoip.login.allow),
+                ("geoip.login.deny", &geoip.login.deny),
+            ];
+            for (field, countries) in rules {
+                if let Some(country) = countries.iter().find(|country| country.len() != 2 || !country.bytes().all(|c| c.is_ascii_uppercase())) {
+                    return Err(invalid(field, format!("{:?} is not an ISO 3166-1 alpha-2 country code such as \"DE\"", country)));
+                }
+            }
+        }
+        if let Some(tls) = &self.tls {
+            for (field, path) in [("tls.cert_path", &tls.cert_path), ("tls.key_path", &tls.key_path)] {
+                if !path.is_file() {
+                    return Err(invalid(field, format!("{} does not exist", path.display())));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Accepts either a CIDR range or a bare address (treated as a single-host range).
+// A scheme, host and optional port, without path: what browsers send as `Origin`.
+fn is_origin(origin: &str) -> bool {
+    match origin.split_once("://") {
+        Some((scheme, host)) => matches!(scheme, "http" | "https") && !host.is_empty() && !host.contains('/'),
+        None => false,
+    }
+}
+
+fn parse_ip_range(var: &'static str, entry: &str) -> Result<IpNet, ConfigError> {
+    entry
+        .parse::<IpNet>()
+        .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|e| ConfigError::InvalidEnv {
+            var,
+            value: entry.to_string(),
+            reason: e.to_string(),
+        })
+}
+
+// A comma-separated list of CIDR ranges or single addresses.
+fn parse_ip_ranges(var: &'static str, list: &str) -> Result<Vec<IpNet>, ConfigError> {
+    list.split(',').map(str::trim).filter(|entry| !entry.is_empty()).map(|entry| parse_ip_range(var, entry)).collect()
+}
+
+fn invalid(field: &'static str, reason: String) -> ConfigError {
+    ConfigError::Invalid { field, reason }
+}
+
+/// Returns the value following a `--config` argument, if present.
+fn config_path_from_args() -> Option<PathBuf> {
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
+
+fn env_var(var: &str) -> Option<String> {
+    std::env::var(var).ok().filter(|value| !value.is_empty())
+}
+
+fn env_parse<T>(var: &'static str) -> Result<Option<T>, ConfigError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match env_var(var) {
+        Some(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|e: T::Err| ConfigError::InvalidEnv { var, value, reason: e.to_string() }),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Mutex, MutexGuard};
+
+    // Environment variables are shared by the whole process, so tests setting them run one at a time.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// The variables of `vars`, set until the guard is dropped.
+    struct Env {
+        vars: Vec<&'static str>,
+        _lock: MutexGuard<'static, ()>,
+    }
+
+    impl Env {
+        fn set(vars: &[(&'static str, &str)]) -> Self {
+            let lock = ENV.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            for (var, value) in vars {
+                std::env::set_var(var, value);
+            }
+            Env { vars: vars.iter().map(|(var, _)| *var).collect(), _lock: lock }
+        }
+    }
+
+    impl Drop for Env {
+        fn drop(&mut self) {
+            for var in &self.vars {
+                std::env::remove_var(var);
+            }
+        }
+    }
+
+    fn parse(toml: &str) -> Config {
+        toml::from_str(toml).unwrap_or_else(|e| panic!("{toml}: {e}"))
+    }
+
+    fn with_env(config: &str, vars: &[(&'static str, &str)]) -> Result<Config, ConfigError> {
+        let mut config = parse(config);
+        let _env = Env::set(vars);
+        config.apply_env_overrides().map(|()| config)
+    }
+
+    // The field `validate` reports for `toml`.
+    fn invalid_field(toml: &str) -> &'static str {
+        match parse(toml).validate() {
+            Err(ConfigError::Invalid { field, .. }) => field,
+            other => panic!("{toml}: expected an invalid field, got {other:?}"),
+        }
+    }
+
+    // A file that exists, for the settings naming one.
+    fn existing_file() -> String {
+        concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml").to_string()
+    }
+
+    #[test]
+    fn the_defaults_are_valid() {
+        Config::default().validate().unwrap();
+        parse("").validate().unwrap();
+    }
+
+    #[test]
+    fn environment_variables_override_the_file() {
+        let file = r#"
+            bind_address = "127.0.0.1:4000"
+            [limits]
+            max_message_length = 1000
+            max_connections = 50
+            [auth]
+            admin_usernames = ["root"]
+        "#;
+        let config = with_env(file, &[("PORT", "5000"), ("CHAT_MAX_CONNECTIONS", "60"), ("CHAT_MAX_MESSAGE_LENGTH", "")]).unwrap();
+        assert_eq!(config.bind_address, "127.0.0.1:5000".parse().unwrap());
+        assert_eq!(config.limits.max_connections, 60);
+        // Empty variables are ignored, and settings of neither keep their default.
+        assert_eq!(config.limits.max_message_length, 1000);
+        assert_eq!(config.auth.admin_usernames, ["root"]);
+        assert_eq!(config.limits.fanout_workers, LimitsConfig::default().fanout_workers);
+
+        let config = with_env(file, &[("CHAT_BIND_ADDRESS", "10.0.0.1:80"), ("HOST", "10.0.0.2")]).unwrap();
+        assert_eq!(config.bind_address, "10.0.0.2:80".parse().unwrap());
+        let config = with_env("[ldap]\nurl = \"ldap://a\"\nstarttls = true", &[("CHAT_LDAP_URL", "ldap://b"), ("CHAT_LDAP_BASE_DN", "dc=example")]).unwrap();
+        let ldap = config.ldap.unwrap();
+        assert_eq!((ldap.url.as_str(), ldap.starttls, ldap.base_dn.as_deref()), ("ldap://b", true, Some("dc=example")));
+    }
+
+    #[test]
+    fn list_variables_are_split_on_commas() {
+        let vars = [
+            ("CHAT_ALLOWED_ORIGINS", " https://a.example, ,http://b.example:8080 "),
+            ("ADMIN_USERNAMES", "root, ops,"),
+            ("CHAT_TRUSTED_PROXIES", "10.0.0.0/8, 192.0.2.1"),
+            ("CHAT_IP_DENY", "2001:db8::/32"),
+            ("CHAT_CLUSTER_NODE_ID", "chat-1"),
+            ("CHAT_CLUSTER_PEERS", "http://chat-2:3030, http://chat-3:3030"),
+            ("CHAT_CLUSTER_SECRET", "s3cret"),
+            ("CHAT_GEOIP_BLOCKS_PATHS", "v4.csv,v6.csv"),
+            ("CHAT_GEOIP_LOCATIONS_PATH", "locations.csv"),
+        ];
+        let config = with_env("", &vars).unwrap();
+        assert_eq!(config.auth.allowed_origins, ["https://a.example", "http://b.example:8080"]);
+        assert_eq!(config.auth.admin_usernames, ["root", "ops"]);
+        assert_eq!(config.proxy.trusted_proxies, ["10.0.0.0/8".parse::<IpNet>().unwrap(), "192.0.2.1/32".parse().unwrap()]);
+        assert_eq!(config.ip_filter.deny, ["2001:db8::/32".parse::<IpNet>().unwrap()]);
+        assert_eq!(config.cluster.unwrap().peers, ["http://chat-2:3030", "http://chat-3:3030"]);
+        assert_eq!(config.geoip.unwrap().blocks_paths, [PathBuf::from("v4.csv"), PathBuf::from("v6.csv")]);
+    }
+
+    #[test]
+    fn malformed_or_incomplete_variables_are_refused() {
+        let cases: &[(&[(&'static str, &str)], &str)] = &[
+            (&[("PORT", "http")], "PORT"),
+            (&[("CHAT_BIND_ADDRESS", "localhost")], "CHAT_BIND_ADDRESS"),
+            (&[("LOG_FORMAT", "xml")], "LOG_FORMAT"),
+            (&[("CHAT_INVITE_ONLY", "yes")], "CHAT_INVITE_ONLY"),
+            (&[("CHAT_TRUSTED_PROXIES", "10.0.0.0/8, proxy")], "CHAT_TRUSTED_PROXIES"),
+            (&[("CHAT_WEBHOOK_ALLOW_NETWORKS", "10.0.0.0/33")], "CHAT_WEBHOOK_ALLOW_NETWORKS"),
+            (&[("CHAT_GEOIP_BLOCKS_PATHS", "v4.csv")], "geoip"),
+            (&[("CHAT_VAPID_SUBJECT", "mailto:ops@example.com")], "web_push"),
+            (&[("CHAT_APNS_KEY_PATH", "key.p8"), ("CHAT_APNS_KEY_ID", "ABC")], "apns"),
+            (&[("CHAT_MATRIX_HOMESERVER_URL", "https://matrix.example")], "matrix"),
+            (&[("CHAT_XMPP_DOMAIN", "chat.example")], "xmpp"),
+            (&[("CHAT_CLUSTER_NODE_ID", "chat-1"), ("CHAT_CLUSTER_SECRET", "s3cret")], "cluster"),
+            (&[("CHAT_TLS_CERT_PATH", "cert.pem")], "tls"),
+        ];
+        for (vars, expected) in cases {
+            match with_env("", vars) {
+                Err(ConfigError::InvalidEnv { var, .. }) => assert_eq!(var, *expected),
+                Err(ConfigError::Invalid { field, .. }) => assert_eq!(field, *expected),
+                other => panic!("{vars:?}: expected an error for {expected}, got {other:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn out_of_range_settings_are_refused() {
+        let cases = [
+            ("[log]\nlevel = \"info,=\"", "log.level"),
+            ("[log]\npseudonymize = true\npseudonym_key = \"short\"", "log.pseudonym_key"),
+            ("[log.access]\nsample_rate = 1.5", "log.access.sample_rate"),
+            ("[log.access.routes]\n\"api\" = 0.5", "log.access.routes"),
+            ("[log.access.routes]\n\"/api\" = -0.1", "log.access.routes"),
+            ("[log.access]\nslow_request_ms = 0", "log.access.slow_request_ms"),
+            ("[limits]\nmax_message_length = 0", "limits.max_message_length"),
+            ("[limits]\nmax_connections = 0", "limits.max_connections"),
+            ("[limits]\nmax_body_bytes = 100", "limits.max_body_bytes"),
+            ("[limits]\ntyping_timeout_secs = 0", "limits.typing_timeout_secs"),
+            ("[limits]\nconversation_shards = 0", "limits.conversation_shards"),
+            ("[limits]\nfanout_workers = 0", "limits.fanout_workers"),
+            ("[limits]\nstate_shards = 0", "limits.state_shards"),
+            ("[limits]\nsend_timeout_secs = 0", "limits.send_timeout_secs"),
+            ("[auth]\nbcrypt_cost = 3", "auth.bcrypt_cost"),
+            ("[auth]\nsession_ttl_secs = 0", "auth.session_ttl_secs"),
+            ("[auth]\nuntrusted_session_ttl_secs = 0", "auth.untrusted_session_ttl_secs"),
+            ("[auth]\nsession_idle_timeout_secs = 0", "auth.session_idle_timeout_secs"),
+            ("[auth]\nauth_frame_timeout_secs = 0", "auth.auth_frame_timeout_secs"),
+            ("[auth]\nmax_failed_logins = 3\nlockout_secs = 0", "auth.lockout_secs"),
+            ("[auth]\nallowed_origins = [\"https://chat.example.com/app\"]", "auth.allowed_origins"),
+            ("[auth]\nallowed_origins = [\"chat.example.com\"]", "auth.allowed_origins"),
+            ("[registration]\ninvite_ttl_secs = 0", "registration.invite_ttl_secs"),
+            ("[registration]\nallow_guests = true\nguest_ttl_secs = 0", "registration.guest_ttl_secs"),
+            ("[storage]\ndsn = \"postgres://db\"", "storage.dsn"),
+            ("[storage]\nsnapshot_interval_secs = 0", "storage.snapshot_interval_secs"),
+            ("[webhooks]\nmax_attempts = 0", "webhooks.max_attempts"),
+            ("[webhooks]\ntimeout_secs = 0", "webhooks.timeout_secs"),
+            ("[banner]\ntitle = \" \"\nbody = \"Maintenance at noon\"", "banner"),
+            ("[cluster]\nnode_id = \"\"\npeers = []\nsecret = \"s3cret\"", "cluster"),
+            ("[cluster]\nnode_id = \"chat-1\"\npeers = [\"chat-2:3030\"]\nsecret = \"s3cret\"", "cluster.peers"),
+            ("[cluster]\nnode_id = \"chat-1\"\npeers = []\nsecret = \"s3cret\"\npresence_ttl_secs = 2", "cluster.presence_ttl_secs"),
+        ];
+        for (toml, field) in cases {
+            assert_eq!(invalid_field(toml), field, "{toml}");
+        }
+        if !cfg!(debug_assertions) {
+            assert_eq!(invalid_field("[log]\nreveal_sensitive = true"), "log.reveal_sensitive");
+        }
+    }
+
+    #[test]
+    fn missing_files_and_country_codes_are_refused() {
+        let file = existing_file();
+        let cases = [
+            (format!("[geoip]\nblocks_paths = []\nlocations_path = {file:?}"), "geoip.blocks_paths"),
+            (format!("[geoip]\nblocks_paths = [\"missing.csv\"]\nlocations_path = {file:?}"), "geoip.blocks_paths"),
+            (format!("[geoip]\nblocks_paths = [{file:?}]\nlocations_path = \"missing.csv\""), "geoip.locations_path"),
+            (format!("[geoip]\nblocks_paths = [{file:?}]\nlocations_path = {file:?}\nregistration.allow = [\"de\"]"), "geoip.registration.allow"),
+            (format!("[geoip]\nblocks_paths = [{file:?}]\nlocations_path = {file:?}\nregistration.deny = [\"DEU\"]"), "geoip.registration.deny"),
+            (format!("[geoip]\nblocks_paths = [{file:?}]\nlocations_path = {file:?}\nlogin.allow = [\"\"]"), "geoip.login.allow"),
+            (format!("[geoip]\nblocks_paths = [{file:?}]\nlocations_path = {file:?}\nlogin.deny = [\"U1\"]"), "geoip.login.deny"),
+        ];
+        for (toml, field) in &cases {
+            assert_eq!(invalid_field(toml), *field, "{toml}");
+        }
+    }
+
+    // Sections of optional subsystems are refused by builds without their cargo feature, and
+    // checked by the others.
+    #[test]
+    fn optional_sections_are_checked_or_refused_without_their_feature() {
+        let file = existing_file();
+        let with = |enabled: bool, section: &'static str, field: &'static str| if enabled { field } else { section };
+        let tls = cfg!(feature = "tls");
+        let grpc = cfg!(feature = "grpc");
+        let web_push = cfg!(feature = "web-push");
+        let mobile_push = cfg!(feature = "mobile-push");
+        let matrix = cfg!(feature = "matrix");
+        let xmpp = cfg!(feature = "xmpp");
+        let mqtt = cfg!(feature = "mqtt");
+        let nats = cfg!(feature = "nats");
+        let kafka = cfg!(feature = "kafka");
+        let ldap = cfg!(feature = "ldap");
+        let matrix_section = "[matrix]\nhomeserver_url = \"https://matrix.example\"\nserver_name = \"example\"\nas_token = \"a\"\nhs_token = \"h\"";
+        let mqtt_section = "[mqtt]\nurl = \"mqtt://broker:1883?client_id=chat\"";
+        let cluster_section = "[cluster]\nnode_id = \"chat-1\"\npeers = []\nsecret = \"s3cret\"";
+        let cases = [
+            (format!("[tls]\ncert_path = \"missing.pem\"\nkey_path = {file:?}"), with(tls, "tls", "tls.cert_path")),
+            (format!("[tls]\ncert_path = {file:?}\nkey_path = \"missing.pem\""), with(tls, "tls", "tls.key_path")),
+            ("[grpc]\nbind_address = \"0.0.0.0:3030\"".to_string(), with(grpc, "grpc", "grpc.bind_address")),
+            (format!("[web_push]\nvapid_private_key_path = {file:?}\nsubject = \"ops@example.com\""), with(web_push, "web_push", "web_push.subject")),
+            ("[web_push]\nvapid_private_key_path = \"missing.pem\"\nsubject = \"mailto:ops@example.com\"".to_string(), with(web_push, "web_push", "web_push.vapid_private_key_path")),
+            ("[fcm]\nservice_account_path = \"missing.json\"".to_string(), with(mobile_push, "fcm", "fcm.service_account_path")),
+            (format!("[apns]\nkey_path = {file:?}\nkey_id = \" \"\nteam_id = \"T\"\ntopic = \"com.example.chat\""), with(mobile_push, "apns", "apns")),
+            ("[apns]\nkey_path = \"missing.p8\"\nkey_id = \"K\"\nteam_id = \"T\"\ntopic = \"com.example.chat\"".to_string(), with(mobile_push, "apns", "apns.key_path")),
+            (matrix_section.replace("https://matrix.example", "matrix.example"), with(matrix, "matrix", "matrix.homeserver_url")),
+            (matrix_section.replace("as_token = \"a\"", "as_token = \"\""), "matrix"),
+            (format!("{matrix_section}\nuser_prefix = \"\""), "matrix"),
+            ("[xmpp]\nbind_address = \"0.0.0.0:5222\"\ndomain = \"chat@example\"".to_string(), with(xmpp, "xmpp", "xmpp.domain")),
+            ("[xmpp]\nbind_address = \"0.0.0.0:3030\"\ndomain = \"chat.example\"".to_string(), with(xmpp, "xmpp", "xmpp.bind_address")),
+            ("[mqtt]\nurl = \"broker:1883\"".to_string(), with(mqtt, "mqtt", "mqtt.url")),
+            (format!("{mqtt_section}\nqos = 3"), with(mqtt, "mqtt", "mqtt.qos")),
+            (format!("{mqtt_section}\nmessage_topic = \"chat/+/messages\""), with(mqtt, "mqtt", "mqtt.message_topic")),
+            (format!("{mqtt_section}\npresence_topic = \"\""), with(mqtt, "mqtt", "mqtt.presence_topic")),
+            (format!("{mqtt_section}\ninbound_topic = \"\""), with(mqtt, "mqtt", "mqtt.inbound_topic")),
+            ("[nats]\nurl = \"http://nats:4222\"".to_string(), with(nats, "nats", "nats.url")),
+            ("[nats]\nurl = \"nats://nats:4222\"\nsubject_prefix = \"chat.*\"".to_string(), with(nats, "nats", "nats.subject_prefix")),
+            (format!("[nats]\nurl = \"nats://nats:4222\"\noffline_stream = \"chat.offline\"\n{cluster_section}"), with(nats, "nats", "nats.offline_stream")),
+            ("[nats]\nurl = \"nats://nats:4222\"\noffline_stream = \"offline\"".to_string(), with(nats, "nats", "nats.offline_stream")),
+            ("[nats]\nurl = \"nats://nats:4222\"\noffline_max_age_secs = 0".to_string(), with(nats, "nats", "nats.offline_max_age_secs")),
+            ("[kafka]\nrest_proxy_url = \"kafka:8082\"".to_string(), with(kafka, "kafka", "kafka.rest_proxy_url")),
+            ("[kafka]\nrest_proxy_url = \"http://kafka:8082\"\nmessage_topic = \"chat messages\"".to_string(), with(kafka, "kafka", "kafka.message_topic")),
+            ("[kafka]\nrest_proxy_url = \"http://kafka:8082\"\npresence_topic = \"..\"".to_string(), with(kafka, "kafka", "kafka.presence_topic")),
+            ("[kafka]\nrest_proxy_url = \"http://kafka:8082\"\naudit_topic = \"\"".to_string(), with(kafka, "kafka", "kafka.audit_topic")),
+            ("[kafka]\nrest_proxy_url = \"http://kafka:8082\"\nbatch_size = 0".to_string(), with(kafka, "kafka", "kafka.batch_size")),
+            ("[ldap]\nurl = \"https://directory\"\nbase_dn = \"dc=example\"".to_string(), with(ldap, "ldap", "ldap.url")),
+            ("[ldap]\nurl = \"ldaps://directory\"\nstarttls = true\nbase_dn = \"dc=example\"".to_string(), with(ldap, "ldap", "ldap.starttls")),
+            ("[ldap]\nurl = \"ldap://directory\"\nuser_dn = \"uid=alice,dc=example\"".to_string(), with(ldap, "ldap", "ldap.user_dn")),
+            ("[ldap]\nurl = \"ldap://directory\"".to_string(), with(ldap, "ldap", "ldap.base_dn")),
+            ("[ldap]\nurl = \"ldap://directory\"\nbase_dn = \"dc=example\"\nuser_filter = \"(uid=alice)\"".to_string(), with(ldap, "ldap", "ldap.user_filter")),
+            ("[ldap]\nurl = \"ldap://directory\"\nbase_dn = \"dc=example\"\nbind_dn = \"cn=reader\"".to_string(), with(ldap, "ldap", "ldap.bind_password")),
+            ("[ldap]\nurl = \"ldap://directory\"\nbase_dn = \"dc=example\"\ntimeout_secs = 0".to_string(), with(ldap, "ldap", "ldap.timeout_secs")),
+        ];
+        for (toml, field) in &cases {
+            assert_eq!(invalid_field(toml), *field, "{toml}");
+        }
+    }
+}
@@ -0,0 +1,553 @@
+// src/connections.rs
+
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use serde_json::Value;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, oneshot};
+use uuid::Uuid;
+use warp::ws::Message;
+
+use crate::device::Device;
+use crate::shards;
+use crate::stats::{QueueStats, QueueStatsResponse, WaitStatsResponse};
+use crate::ws_handlers::UserSession;
+
+/// How long closing a connection may wait on its socket.
+pub(crate) const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Why the server closed a WebSocket, sent to the client as the code and reason of the close
+/// frame. Codes 4000-4999 are this server's own; clients should log in again after
+/// `InvalidSession` and `SessionRevoked`, and may reconnect after the others.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    // A WebSocket opened without a session key did not send its `auth` frame first, in time.
+    AuthRequired,
+    // The session key is unknown or expired.
+    InvalidSession,
+    // The user logged in again, which ends the previous session.
+    SessionRevoked,
+    ClosedByAdmin,
+    // The guest account reached `registration.guest_ttl_secs` and was deleted.
+    GuestExpired,
+    // The client did not read its frames fast enough.
+    SlowConsumer,
+    // The client kept sending after exceeding the frame rate limit.
+    RateLimited,
+    // `limits.max_connections` are already open.
+    ServerFull,
+    ShuttingDown,
+}
+
+impl CloseReason {
+    /// The close code.
+    pub fn code(self) -> u16 {
+        match self {
+            CloseReason::AuthRequired => 4000,
+            CloseReason::InvalidSession => 4001,
+            CloseReason::SessionRevoked => 4002,
+            CloseReason::ClosedByAdmin => 4003,
+            CloseReason::GuestExpired => 4004,
+            CloseReason::SlowConsumer => 4008,
+            CloseReason::RateLimited => 4029,
+            // Standard codes: "try again later" and "going away".
+            CloseReason::ServerFull => 1013,
+            CloseReason::ShuttingDown => 1001,
+        }
+    }
+
+    /// The close reason, for humans and logs.
+    pub fn reason(self) -> &'static str {
+        match self {
+            CloseReason::AuthRequired => "authentication required",
+            CloseReason::InvalidSession => "invalid session",
+            CloseReason::SessionRevoked => "session revoked",
+            CloseReason::ClosedByAdmin => "closed by an administrator",
+            CloseReason::GuestExpired => "guest account expired",
+            CloseReason::SlowConsumer => "slow consumer",
+            CloseReason::RateLimited => "rate limited",
+            CloseReason::ServerFull => "server full",
+            CloseReason::ShuttingDown => "server shutting down",
+        }
+    }
+
+    /// The close frame.
+    pub fn frame(self) -> Message {
+        Message::close_with(self.code(), self.reason())
+    }
+}
+
+/// The sending half of an active connection, tagged with the session it belongs to.
+/// Wraps the mpsc sender so the number of queued, not-yet-written frames, and how long they
+/// wait to be written, can be observed.
+#[derive(Debug, Clone)]
+pub struct ConnectionHandle {
+    tx: mpsc::UnboundedSender<(Queued, Instant)>,
+    stats: Arc<QueueStats>,
+    // When the client last sent a frame.
+    last_activity: Arc<Mutex<DateTime<Utc>>>,
+    pub connection_id: Uuid,
+    pub user_id: Uuid,
+    pub username: String,
+    pub session_key: String,
+    pub client_ip: Option<IpAddr>,
+    // Country the session logged in from, when `[geoip]` knows it.
+    pub country: Option<String>,
+    // Device the session logged in from.
+    pub device: Device,
+    pub connected_at: DateTime<Utc>,
+}
+
+/// The receiving half matching a `ConnectionHandle`.
+#[derive(Debug)]
+pub struct ConnectionReceiver {
+    rx: mpsc::UnboundedReceiver<(Queued, Instant)>,
+    stats: Arc<QueueStats>,
+}
+
+/// A serialized text frame queued on many connections at once (a message and its echo,
+/// presence, announcements). Each encoding is produced at most once and shared by every
+/// recipient: the JSON up front, the parsed event the first time a bridge translating events
+/// (XMPP) asks for it.
+#[derive(Debug)]
+pub struct SharedFrame {
+    json: String,
+    event: OnceLock<Option<Value>>,
+}
+
+impl SharedFrame {
+    pub fn new(json: String) -> Arc<Self> {
+        Arc::new(SharedFrame { json, event: OnceLock::new() })
+    }
+
+    /// The frame as sent to WebSocket clients.
+    pub fn json(&self) -> &str {
+        &self.json
+    }
+
+    /// The frame parsed back into an event; `None` if it is not valid JSON.
+    pub fn event(&self) -> Option<&Value> {
+        self.event.get_or_init(|| serde_json::from_str(&self.json).ok()).as_ref()
+    }
+}
+
+// A frame waiting in a connection's queue. warp's `Message` owns its text, so a shared frame
+// is copied into one only when a WebSocket takes it to be written.
+#[derive(Debug)]
+enum Queued {
+    Shared(Arc<SharedFrame>),
+    Message(Message),
+}
+
+impl Queued {
+    fn into_message(self) -> Message {
+        match self {
+            Queued::Shared(frame) => Message::text(frame.json()),
+            Queued::Message(message) => message,
+        }
+    }
+
+    #[cfg(any(feature = "grpc", feature = "xmpp"))]
+    fn into_shared(self) -> Option<Arc<SharedFrame>> {
+        match self {
+            Queued::Shared(frame) => Some(frame),
+            Queued::Message(message) => message.to_str().ok().map(|json| SharedFrame::new(json.to_string())),
+        }
+    }
+}
+
+impl ConnectionReceiver {
+    /// Waits for the next queued frame; `None` once every handle has been dropped.
+    pub async fn recv(&mut self) -> Option<Message> {
+        let (frame, queued_at) = self.rx.recv().await?;
+        self.stats.dequeued(queued_at.elapsed());
+        Some(frame.into_message())
+    }
+
+    /// Waits for the next text frame, skipping close and other control frames, for consumers
+    /// that are not WebSockets; shared frames come out without being copied. `None` once
+    /// every handle has been dropped.
+    #[cfg(any(feature = "grpc", feature = "xmpp"))]
+    pub async fn recv_text(&mut self) -> Option<Arc<SharedFrame>> {
+        loop {
+            let (frame, queued_at) = self.rx.recv().await?;
+            self.stats.dequeued(queued_at.elapsed());
+            if let Some(frame) = frame.into_shared() {
+                return Some(frame);
+            }
+        }
+    }
+
+    /// Number of frames queued but not yet taken.
+    pub fn queue_depth(&self) -> usize {
+        self.stats.depth()
+    }
+
+    /// Takes the next queued frame without waiting; `None` if nothing is queued.
+    pub fn try_recv(&mut self) -> Option<Message> {
+        self.try_take().map(Queued::into_message)
+    }
+
+    /// Drops the next queued frame, without copying it out; false if nothing is queued.
+    pub fn discard(&mut self) -> bool {
+        self.try_take().is_some()
+    }
+
+    fn try_take(&mut self) -> Option<Queued> {
+        let (frame, queued_at) = self.rx.try_recv().ok()?;
+        self.stats.dequeued(queued_at.elapsed());
+        Some(frame)
+    }
+}
+
+/// Creates the outbound channel of a new connection owned by `session`, opened from `client_ip`.
+pub fn connection_channel(session: &UserSession, client_ip: Option<IpAddr>) -> (ConnectionHandle, ConnectionReceiver) {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let stats = Arc::new(QueueStats::default());
+    let connected_at = Utc::now();
+    let handle = ConnectionHandle {
+        tx,
+        stats: stats.clone(),
+        last_activity: Arc::new(Mutex::new(connected_at)),
+        connection_id: Uuid::new_v4(),
+        user_id: session.user_id,
+        username: session.username.clone(),
+        session_key: session.session_key.clone(),
+        client_ip,
+        country: session.country.clone(),
+        device: session.device.clone(),
+        connected_at,
+    };
+    (handle, ConnectionReceiver { rx, stats })
+}
+
+impl ConnectionHandle {
+    /// Queues a frame for delivery to this connection's WebSocket.
+    pub fn send(&self, message: Message) -> Result<(), mpsc::error::SendError<Message>> {
+        self.queue(Queued::Message(message)).map_err(|frame| mpsc::error::SendError(frame.into_message()))
+    }
+
+    fn queue(&self, frame: Queued) -> Result<(), Queued> {
+        self.stats.enqueued();
+        self.tx.send((frame, Instant::now())).map_err(|mpsc::error::SendError((frame, _))| {
+            self.stats.discarded();
+            frame
+        })
+    }
+
+    /// Number of frames queued but not yet written to the socket.
+    pub fn queue_depth(&self) -> usize {
+        self.stats.depth()
+    }
+
+    /// How long frames waited in the queue before they were written.
+    pub fn queue_wait(&self) -> WaitStatsResponse {
+        self.stats.wait.snapshot()
+    }
+
+    /// Records that the client just sent a frame.
+    pub fn record_activity(&self) {
+        *self.last_activity.lock().expect("activity lock") = Utc::now();
+    }
+
+    /// When the client last sent a frame, or connected if it has not sent any.
+    pub fn last_activity(&self) -> DateTime<Utc> {
+        *self.last_activity.lock().expect("activity lock")
+    }
+
+    /// Queues a text frame shared with other connections, inside its own delivery span.
+    pub(crate) fn deliver(&self, frame: &Arc<SharedFrame>) -> bool {
+        let _span = tracing::info_span!("deliver", to_user_id = %self.user_id).entered();
+        self.queue(Queued::Shared(frame.clone())).is_ok()
+    }
+}
+
+// Active connections: connection key -> connection handle. WebSockets are keyed by their
+// session key; other event consumers (gRPC streams, XMPP clients) use their own unique keys.
+type Connections = HashMap<String, ConnectionHandle>;
+
+enum Command {
+    // Runs on the registry task, inside the span of the caller; queued at the given instant.
+    Apply(Box<dyn FnOnce(&mut Connections) + Send>, tracing::Span, Instant),
+    // Keeps the registry busy, so concurrent fanouts queue up behind each other.
+    #[cfg(feature = "fault-injection")]
+    Stall(Duration, oneshot::Sender<()>),
+}
+
+/// Routes frames to the active connections. The connections are split by user into shards,
+/// each owned by a single task that handles one command at a time, so routing needs no lock
+/// shared between handlers and frames for different users are routed in parallel; each
+/// connection's own task writes what is queued on its handle to the socket.
+#[derive(Debug, Clone)]
+pub struct ConnectionRegistry {
+    shards: Arc<[RegistryShard]>,
+}
+
+#[derive(Debug)]
+struct RegistryShard {
+    commands: mpsc::UnboundedSender<Command>,
+    stats: Arc<QueueStats>,
+}
+
+impl RegistryShard {
+    // Commands are handled in the order they were sent, so a fire-and-forget `register` is
+    // seen by every later command for the same user.
+    fn apply(&self, command: impl FnOnce(&mut Connections) + Send + 'static) {
+        self.stats.enqueued();
+        if self.commands.send(Command::Apply(Box::new(command), tracing::Span::current(), Instant::now())).is_err() {
+            self.stats.discarded();
+        }
+    }
+
+    async fn query<R: Send + 'static>(&self, query: impl FnOnce(&mut Connections) -> R + Send + 'static) -> R {
+        let (reply, response) = oneshot::channel();
+        self.apply(move |connections| {
+            let _ = reply.send(query(connections));
+        });
+        response.await.expect("connection registry task is running")
+    }
+}
+
+impl ConnectionRegistry {
+    /// Starts `shards` registry tasks (at least one). They end once every `ConnectionRegistry`
+    /// clone is dropped.
+    pub(crate) fn spawn(shards: usize) -> Self {
+        let shards = (0..shards.max(1))
+            .map(|_| {
+                let (commands, receiver) = mpsc::unbounded_channel();
+                let stats = Arc::new(QueueStats::default());
+                tokio::spawn(run(receiver, stats.clone()));
+                RegistryShard { commands, stats }
+            })
+            .collect();
+        ConnectionRegistry { shards }
+    }
+
+    // The shard holding every connection of `user_id`.
+    fn shard(&self, user_id: Uuid) -> &RegistryShard {
+        &self.shards[shards::shard_of(&user_id, self.shards.len())]
+    }
+
+    // Runs `command` on every shard.
+    fn apply_all(&self, command: impl Fn(&mut Connections) + Send + Sync + 'static) {
+        let command = Arc::new(command);
+        for shard in self.shards.iter() {
+            let command = command.clone();
+            shard.apply(move |connections| command(connections));
+        }
+    }
+
+    // Runs `query` on every shard at once, returning the answers in shard order.
+    async fn query_all<R: Send + 'static>(&self, query: impl Fn(&mut Connections) -> R + Send + Sync + 'static) -> Vec<R> {
+        let query = Arc::new(query);
+        futures::future::join_all(self.shards.iter().map(|shard| {
+            let query = query.clone();
+            shard.query(move |connections| query(connections))
+        }))
+        .await
+    }
+
+    /// Adds a connection under `connection_key`, replacing any connection with the same key.
+    pub fn register(&self, connection_key: String, connection: ConnectionHandle) {
+        self.shard(connection.user_id).apply(move |connections| {
+            connections.insert(connection_key, connection);
+        });
+    }
+
+    /// Removes the connection under `connection_key`, if any.
+    pub fn remove(&self, connection_key: &str) {
+        let connection_key = connection_key.to_string();
+        self.apply_all(move |connections| {
+            connections.remove(&connection_key);
+        });
+    }
+
+    /// Removes every connection opened with `session_key`, closing its WebSocket with
+    /// `reason`, and returns how many there were.
+    pub async fn close_session(&self, session_key: &str, reason: CloseReason) -> usize {
+        let session_key = session_key.to_string();
+        self.query_all(move |connections| {
+            let keys: Vec<String> =
+                connections.iter().filter(|(_, connection)| connection.session_key == session_key).map(|(key, _)| key.clone()).collect();
+            for key in &keys {
+                close(key, connections.remove(key), reason);
+            }
+            keys.len()
+        })
+        .await
+        .into_iter()
+        .sum()
+    }
+
+    /// Removes the connection with `connection_id` and has it closed with `reason` once the
+    /// frames queued before are written. Returns whether it was found.
+    pub async fn close_connection(&self, connection_id: Uuid, reason: CloseReason) -> bool {
+        self.query_all(move |connections| {
+            let key = connections.iter().find(|(_, connection)| connection.connection_id == connection_id).map(|(key, _)| key.clone());
+            key.is_some_and(|key| close(&key, connections.remove(&key), reason))
+        })
+        .await
+        .contains(&true)
+    }
+
+    /// Closes every WebSocket with `reason`, once its queued frames are written, and removes
+    /// the other connections. WebSockets leave the registry as their connections end.
+    pub async fn close_all(&self, reason: CloseReason) {
+        self.query_all(move |connections| {
+            connections.retain(|key, connection| {
+                let websocket = is_websocket(key, connection);
+                if websocket {
+                    let _ = connection.send(reason.frame());
+                }
+                websocket
+            })
+        })
+        .await;
+    }
+
+    /// Whether a connection is registered under `connection_key`.
+    pub async fn contains(&self, connection_key: &str) -> bool {
+        let connection_key = connection_key.to_string();
+        self.query_all(move |connections| connections.contains_key(&connection_key)).await.contains(&true)
+    }
+
+    /// Number of active connections.
+    pub async fn count(&self) -> usize {
+        self.query_all(|connections| connections.len()).await.into_iter().sum()
+    }
+
+    /// Whether `user_id` has at least one active connection.
+    pub async fn is_online(&self, user_id: Uuid) -> bool {
+        self.shard(user_id).query(move |connections| connections.values().any(|connection| connection.user_id == user_id)).await
+    }
+
+    /// A copy of every active connection with its key.
+    pub async fn snapshot(&self) -> Vec<(String, ConnectionHandle)> {
+        let shards =
+            self.query_all(|connections| connections.iter().map(|(key, connection)| (key.clone(), connection.clone())).collect::<Vec<_>>());
+        shards.await.into_iter().flatten().collect()
+    }
+
+    /// Queues a serialized frame on every connection belonging to `user_id`, returning how
+    /// many connections it was queued on.
+    pub async fn send_to_user(&self, user_id: Uuid, json: &str) -> usize {
+        self.send_frame_to_user(user_id, &SharedFrame::new(json.to_string())).await
+    }
+
+    /// Like `send_to_user`, for a frame that is also queued elsewhere (e.g. a message and the
+    /// echo to its sender).
+    pub async fn send_frame_to_user(&self, user_id: Uuid, frame: &Arc<SharedFrame>) -> usize {
+        let frame = frame.clone();
+        self.shard(user_id).query(move |connections| {
+            let mut delivered = 0;
+            for connection in connections.values().filter(|connection| connection.user_id == user_id) {
+                connection.deliver(&frame);
+                delivered += 1;
+            }
+            delivered
+        })
+        .await
+    }
+
+    /// Queues a serialized frame on the connections of the session's user that belong to its
+    /// other sessions.
+    pub fn send_to_other_sessions(&self, session: &UserSession, json: &str) {
+        let (user_id, session_key, frame) = (session.user_id, session.session_key.clone(), SharedFrame::new(json.to_string()));
+        self.shard(user_id).apply(move |connections| {
+            for connection in connections
+                .values()
+                .filter(|connection| connection.user_id == user_id && connection.session_key != session_key)
+            {
+                connection.deliver(&frame);
+            }
+        });
+    }
+
+    /// Queues a serialized frame on every active connection, returning how many accepted it.
+    pub async fn broadcast(&self, json: String) -> usize {
+        let frame = SharedFrame::new(json);
+        let delivered = self.query_all(move |connections| connections.values().filter(|connection| connection.deliver(&frame)).count());
+        delivered.await.into_iter().sum()
+    }
+
+    /// Commands waiting for the registry tasks, and how long they waited, over all shards.
+    pub fn queue_stats(&self) -> QueueStatsResponse {
+        self.shards.iter().map(|shard| shard.stats.snapshot()).fold(QueueStatsResponse::default(), QueueStatsResponse::combine)
+    }
+
+    /// Keeps every registry shard from handling other commands for `hold`.
+    #[cfg(feature = "fault-injection")]
+    pub(crate) async fn stall(&self, hold: Duration) {
+        futures::future::join_all(self.shards.iter().map(|shard| async move {
+            let (done, stalled) = oneshot::channel();
+            if shard.commands.send(Command::Stall(hold, done)).is_ok() {
+                let _ = stalled.await;
+            }
+        }))
+        .await;
+    }
+}
+
+// WebSockets are registered under their session key; other consumers under keys of their own.
+pub(crate) fn is_websocket(connection_key: &str, connection: &ConnectionHandle) -> bool {
+    connection_key == connection.session_key
+}
+
+// Queues the close frame on a removed connection if it is a WebSocket; the other consumers
+// end once their handle is dropped. Returns whether there was a connection.
+fn close(connection_key: &str, connection: Option<ConnectionHandle>, reason: CloseReason) -> bool {
+    let Some(connection) = connection else { return false };
+    if is_websocket(connection_key, &connection) {
+        let _ = connection.send(reason.frame());
+    }
+    true
+}
+
+async fn run(mut commands: mpsc::UnboundedReceiver<Command>, stats: Arc<QueueStats>) {
+    let mut connections = Connections::new();
+    while let Some(command) = commands.recv().await {
+        match command {
+            Command::Apply(command, span, queued_at) => {
+                stats.dequeued(queued_at.elapsed());
+                let _entered = span.enter();
+                command(&mut connections);
+            }
+            #[cfg(feature = "fault-injection")]
+            Command::Stall(hold, done) => {
+                tokio::time::sleep(hold).await;
+                let _ = done.send(());
+            }
+        }
+    }
+}
+
+/// Per-connection rate limit on client frames over fixed one-minute windows.
+#[derive(Debug)]
+pub(crate) struct FrameRateLimit {
+    window_started: Instant,
+    frames_in_window: u32,
+}
+
+impl FrameRateLimit {
+    pub(crate) fn new() -> Self {
+        FrameRateLimit { window_started: Instant::now(), frames_in_window: 0 }
+    }
+
+    /// Counts a frame, returning whether it is within `max_per_minute` (0 means unlimited).
+    pub(crate) fn allow(&mut self, max_per_minute: u32) -> bool {
+        if self.window_started.elapsed() >= Duration::from_secs(60) {
+            self.window_started = Instant::now();
+            self.frames_in_window = 0;
+        }
+        self.frames_in_window = self.frames_in_window.saturating_add(1);
+        max_per_minute == 0 || self.frames_in_window <= max_per_minute
+    }
+
+    /// Whether the client sent twice `max_per_minute` frames in the current window, i.e. kept
+    /// going long after its frames started being dropped.
+    pub(crate) fn flooding(&self, max_per_minute: u32) -> bool {
+        max_per_minute != 0 && self.frames_in_window > max_per_minute.saturating_mul(2)
+    }
+}
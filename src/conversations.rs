@@ -0,0 +1,45 @@
+// src/conversations.rs
+
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Most message ids one `readReceipts` frame may acknowledge.
+pub const MAX_READ_RECEIPT_BATCH: usize = 500;
+
+/// One side of a 1:1 conversation: what its owner has not read yet.
+#[derive(Debug, Clone)]
+pub struct Conversation {
+    // Messages received from the partner since the owner last read the conversation.
+    pub unread_count: u32,
+    pub last_message_at: DateTime<Utc>,
+}
+
+/// Conversations each user took part in: (user, conversation partner) -> conversation.
+pub type ConversationRegistry = HashMap<(Uuid, Uuid), Conversation>;
+
+/// Records a message from `from_user_id` to `to_user_id`: it counts as unread for the
+/// recipient, and moves the conversation to the top for both.
+pub fn record_message(conversations: &mut ConversationRegistry, from_user_id: Uuid, to_user_id: Uuid) {
+    let now = Utc::now();
+    for (owner, partner) in [(from_user_id, to_user_id), (to_user_id, from_user_id)] {
+        let conversation = conversations.entry((owner, partner)).or_insert(Conversation { unread_count: 0, last_message_at: now });
+        conversation.last_message_at = now;
+        if owner == to_user_id && from_user_id != to_user_id {
+            conversation.unread_count = conversation.unread_count.saturating_add(1);
+        }
+    }
+}
+
+/// Forgets `user_id`'s side of the conversation with `partner_id`; the partner keeps theirs.
+/// Returns whether there was anything to forget.
+pub fn clear(conversations: &mut ConversationRegistry, user_id: Uuid, partner_id: Uuid) -> bool {
+    conversations.remove(&(user_id, partner_id)).is_some()
+}
+
+/// Resets the unread count of `user_id`'s conversation with `partner_id`.
+pub fn mark_read(conversations: &mut ConversationRegistry, user_id: Uuid, partner_id: Uuid) {
+    if let Some(conversation) = conversations.get_mut(&(user_id, partner_id)) {
+        conversation.unread_count = 0;
+    }
+}
@@ -0,0 +1,83 @@
+// src/device.rs
+
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::error::ApiError;
+
+/// Longest device name a client may give its login.
+const MAX_DEVICE_NAME_CHARS: usize = 64;
+/// User agents are cut to this length before being kept with the session.
+const MAX_USER_AGENT_CHARS: usize = 256;
+
+/// The device a session logged in from: the name its client gave it and what the request's
+/// user agent tells about it.
+#[derive(Debug, Clone, Default)]
+pub struct Device {
+    // Name chosen by the client (e.g. "Work laptop").
+    pub name: Option<String>,
+    pub user_agent: Option<String>,
+    // Operating system family recognized in the user agent.
+    pub platform: Option<&'static str>,
+}
+
+impl Device {
+    /// The device of a login with this `device_name` and `User-Agent` header, rejecting names
+    /// longer than `MAX_DEVICE_NAME_CHARS`. Blank values count as absent.
+    pub fn from_login(device_name: Option<&str>, user_agent: Option<&str>) -> Result<Self, ApiError> {
+        let name = device_name.map(str::trim).filter(|name| !name.is_empty());
+        if name.is_some_and(|name| name.chars().count() > MAX_DEVICE_NAME_CHARS) {
+            return Err(ApiError::invalid(format!("device_name must be at most {} characters.", MAX_DEVICE_NAME_CHARS)));
+        }
+        let user_agent = user_agent.map(str::trim).filter(|user_agent| !user_agent.is_empty());
+        Ok(Device {
+            name: name.map(str::to_string),
+            user_agent: user_agent.map(|user_agent| user_agent.chars().take(MAX_USER_AGENT_CHARS).collect()),
+            platform: user_agent.and_then(platform),
+        })
+    }
+
+    /// An identifier of the device, the same for every login giving the same name and user
+    /// agent; `None` when the client told nothing about its device.
+    pub fn fingerprint(&self) -> Option<String> {
+        if self.name.is_none() && self.user_agent.is_none() {
+            return None;
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(self.name.as_deref().unwrap_or_default().as_bytes());
+        hasher.update([0]);
+        hasher.update(self.user_agent.as_deref().unwrap_or_default().as_bytes());
+        Some(hasher.finalize().iter().take(8).map(|byte| format!("{:02x}", byte)).collect())
+    }
+}
+
+/// A device its user marked as trusted, by the fingerprint of the session it was marked from.
+#[derive(Debug, Clone)]
+pub struct TrustedDevice {
+    pub id: Uuid,
+    pub fingerprint: String,
+    pub name: Option<String>,
+    pub platform: Option<&'static str>,
+    pub trusted_at: DateTime<Utc>,
+}
+
+/// Trusted devices per user id, in the order they were trusted.
+pub type TrustedDeviceRegistry = HashMap<Uuid, Vec<TrustedDevice>>;
+
+// The operating system family named in a user agent. Mobile systems are checked first, as
+// their user agents also mention the desktop system they derive from.
+fn platform(user_agent: &str) -> Option<&'static str> {
+    const PLATFORMS: [(&str, &str); 8] = [
+        ("Android", "Android"),
+        ("iPhone", "iOS"),
+        ("iPad", "iOS"),
+        ("CrOS", "ChromeOS"),
+        ("Windows", "Windows"),
+        ("Mac OS X", "macOS"),
+        ("Macintosh", "macOS"),
+        ("Linux", "Linux"),
+    ];
+    PLATFORMS.iter().find(|(marker, _)| user_agent.contains(marker)).map(|(_, platform)| *platform)
+}
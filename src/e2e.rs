@@ -0,0 +1,102 @@
+// src/e2e.rs
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+/// One-time prekeys a user may have stored at once.
+pub const MAX_ONE_TIME_PREKEYS: usize = 100;
+
+/// A medium-term prekey, signed with the owner's identity key so peers can authenticate it.
+/// Keys and signatures are base64; the server never interprets them.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct SignedPrekey {
+    pub key_id: u32,
+    pub public_key: String,
+    pub signature: String,
+}
+
+/// A prekey handed out to at most one peer, then discarded.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct OneTimePrekey {
+    pub key_id: u32,
+    pub public_key: String,
+}
+
+/// The public keys a user published for others to start encrypted sessions with them.
+#[derive(Debug, Clone)]
+pub struct UserKeys {
+    pub identity_key: String,
+    pub signed_prekey: SignedPrekey,
+    pub one_time_prekeys: VecDeque<OneTimePrekey>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Published keys: user id -> keys.
+pub type KeyRegistry = HashMap<Uuid, UserKeys>;
+
+/// What a peer needs to open a session with a user (X3DH): the identity key, the signed
+/// prekey and, while the supply lasts, one one-time prekey.
+#[derive(Serialize, ToSchema, Debug)]
+pub struct PrekeyBundle {
+    pub user_id: Uuid,
+    pub identity_key: String,
+    pub signed_prekey: SignedPrekey,
+    pub one_time_prekey: Option<OneTimePrekey>,
+}
+
+impl UserKeys {
+    /// Builds the bundle for one peer, consuming the oldest one-time prekey.
+    pub fn take_bundle(&mut self, user_id: Uuid) -> PrekeyBundle {
+        PrekeyBundle {
+            user_id,
+            identity_key: self.identity_key.clone(),
+            signed_prekey: self.signed_prekey.clone(),
+            one_time_prekey: self.one_time_prekeys.pop_front(),
+        }
+    }
+}
+
+/// An end-to-end encrypted message. The server routes it without looking inside: `header`
+/// carries whatever the client protocol needs to decrypt (e.g. ratchet or prekey message
+/// header), `ciphertext` the encrypted body, both base64.
+#[derive(Serialize, Deserialize, ToSchema, Clone)]
+pub struct EncryptedMessage {
+    pub ciphertext: String,
+    pub header: String,
+}
+
+// Never print payloads, even at trace level.
+impl fmt::Debug for EncryptedMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptedMessage")
+            .field("ciphertext_len", &self.ciphertext.len())
+            .field("header_len", &self.header.len())
+            .finish()
+    }
+}
+
+/// Whether `value` is non-empty, valid base64.
+pub fn is_base64(value: &str) -> bool {
+    !value.is_empty() && STANDARD.decode(value).is_ok()
+}
+
+/// Parses a base64 Ed25519 public key, as registered at login for message signing.
+pub fn parse_signing_key(value: &str) -> Option<VerifyingKey> {
+    let bytes: [u8; 32] = STANDARD.decode(value).ok()?.try_into().ok()?;
+    VerifyingKey::from_bytes(&bytes).ok()
+}
+
+/// Whether `signature` (base64) is a valid Ed25519 signature of the UTF-8 bytes of `message`.
+pub fn verify_signature(key: &VerifyingKey, message: &str, signature: &str) -> bool {
+    let Some(bytes) = STANDARD.decode(signature).ok().and_then(|bytes| <[u8; 64]>::try_from(bytes).ok()) else {
+        return false;
+    };
+    key.verify(message.as_bytes(), &Signature::from_bytes(&bytes)).is_ok()
+}
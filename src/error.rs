@@ -0,0 +1,257 @@
+// src/error.rs
+
+use serde::Serialize;
+use thiserror::Error;
+use utoipa::ToSchema;
+use warp::http::StatusCode;
+use warp::reject::Reject;
+
+/// Why an API request failed. Every variant has a stable `code` that clients can branch on;
+/// the message is meant for humans and may change between releases.
+#[derive(Debug, Error)]
+pub enum ApiError {
+    #[error("Not Found")]
+    NotFound,
+    #[error("Method Not Allowed")]
+    MethodNotAllowed,
+    // No session for the `x-session-key` header, or its user no longer exists.
+    #[error("Unauthorized: Invalid session key.")]
+    InvalidSession,
+    #[error("Unauthorized: Invalid API token.")]
+    InvalidApiToken,
+    #[error("Forbidden: API token lacks the required scope.")]
+    InsufficientScope,
+    #[error("Forbidden: Insufficient role.")]
+    InsufficientRole,
+    // A browser page of an origin outside `auth.allowed_origins` tried to open a WebSocket.
+    #[error("Forbidden: Origin not allowed.")]
+    OriginNotAllowed,
+    // The client address is outside the allowed ranges, or inside a denied one.
+    #[error("Forbidden: Your address is not allowed.")]
+    IpNotAllowed,
+    // The client address resolves to a country `[geoip]` does not let register or log in.
+    #[error("Forbidden: Not available in your country.")]
+    CountryNotAllowed,
+    #[error("Invalid username or password.")]
+    InvalidCredentials,
+    // Too many wrong passwords in a row; password logins are refused until the lock ends.
+    #[error("The account is temporarily locked after too many failed logins.")]
+    AccountLocked,
+    // Invite-only registration without a valid invite code: unknown, used or expired.
+    #[error("Forbidden: Registration requires a valid invite code.")]
+    InviteRequired,
+    // The caller already has `registration.invites_per_user` invites waiting to be used.
+    #[error("You have no invites left until one of yours is used or expires.")]
+    InviteQuotaExceeded,
+    // The caller already has `webhooks.max_per_user` webhooks.
+    #[error("You have registered as many webhooks as allowed; remove one first.")]
+    WebhookQuotaExceeded,
+    // The account was registered but an admin has not approved it yet.
+    #[error("Forbidden: Your registration is waiting for an administrator's approval.")]
+    RegistrationPending,
+    // Guest accounts are temporary, so they may not create what would outlive them (bots, invites).
+    #[error("Forbidden: Guest accounts cannot do this.")]
+    GuestNotAllowed,
+    // Accounts come from a directory (`[ldap]`) and `ldap.allow_registration` is off.
+    #[error("Forbidden: Accounts come from the directory; log in with your directory credentials.")]
+    RegistrationDisabled,
+    #[error("Username already exists.")]
+    UsernameTaken,
+    #[error("Usernames of the form @user:server or guest-... are reserved.")]
+    UsernameReserved,
+    #[error("You cannot add yourself as a contact.")]
+    SelfContact,
+    #[error("You cannot remove your own admin role.")]
+    SelfDemotion,
+    #[error("The user is not one of your contacts.")]
+    NotAContact,
+    #[error("User not found")]
+    UserNotFound,
+    #[error("Poll not found")]
+    PollNotFound,
+    #[error("Live location not found or expired")]
+    LiveLocationNotFound,
+    #[error("Broadcast list not found")]
+    BroadcastListNotFound,
+    #[error("Conversation not found")]
+    ConversationNotFound,
+    #[error("Webhook not found")]
+    WebhookNotFound,
+    #[error("Bot not found")]
+    BotNotFound,
+    #[error("Registration not found")]
+    RegistrationNotFound,
+    #[error("Invite not found")]
+    InviteNotFound,
+    #[error("Token not found")]
+    TokenNotFound,
+    #[error("Push subscription not found")]
+    PushSubscriptionNotFound,
+    #[error("Device not found")]
+    DeviceNotFound,
+    #[error("Connection not found")]
+    ConnectionNotFound,
+    #[error("No encryption keys published; publish them with PUT /keys first.")]
+    KeysNotPublished,
+    #[error("Message exceeds the maximum length of {max} bytes.")]
+    MessageTooLong { max: usize },
+    #[error("Signed messages require a signing key registered at login.")]
+    SigningKeyRequired,
+    #[error("Invalid message signature.")]
+    InvalidSignature,
+    // A message interceptor refused the message; the message carries its reason.
+    #[error("Message rejected: {0}")]
+    MessageRejected(String),
+    // An optional subsystem (Web Push, FCM, APNs) the request needs is not set up.
+    #[error("{0} is not configured on this server.")]
+    NotConfigured(String),
+    // The LDAP server could not be reached or refused the search; the password was not checked.
+    #[error("The directory server is unavailable; try again later.")]
+    DirectoryUnavailable,
+    #[error("Configuration reload failed: {0}")]
+    ConfigReloadFailed(String),
+    // The JSON body could not be parsed; the message carries the parser's description.
+    #[error("Malformed request body: {0}")]
+    MalformedBody(String),
+    #[error("Missing request header `{0}`.")]
+    MissingHeader(String),
+    #[error("Request body exceeds the maximum of {max} bytes.")]
+    BodyTooLarge { max: u64 },
+    // The client IP sent more than `limits.http_requests_per_minute` requests this minute.
+    #[error("Too many requests; try again in a minute.")]
+    RateLimited,
+    // A field is missing, empty, malformed or out of range; the message says which.
+    #[error("{0}")]
+    Validation(String),
+    #[error("Internal error: {0}")]
+    Internal(String),
+}
+
+impl ApiError {
+    /// Shorthand for a `Validation` error.
+    pub fn invalid(message: impl Into<String>) -> Self {
+        ApiError::Validation(message.into())
+    }
+
+    /// The stable, machine-readable identifier sent as `code`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::NotFound => "NOT_FOUND",
+            ApiError::MethodNotAllowed => "METHOD_NOT_ALLOWED",
+            ApiError::InvalidSession => "INVALID_SESSION",
+            ApiError::InvalidApiToken => "INVALID_API_TOKEN",
+            ApiError::InsufficientScope => "INSUFFICIENT_SCOPE",
+            ApiError::InsufficientRole => "INSUFFICIENT_ROLE",
+            ApiError::OriginNotAllowed => "ORIGIN_NOT_ALLOWED",
+            ApiError::IpNotAllowed => "IP_NOT_ALLOWED",
+            ApiError::CountryNotAllowed => "COUNTRY_NOT_ALLOWED",
+            ApiError::InvalidCredentials => "INVALID_CREDENTIALS",
+            ApiError::AccountLocked => "ACCOUNT_LOCKED",
+            ApiError::InviteRequired => "INVITE_REQUIRED",
+            ApiError::InviteQuotaExceeded => "INVITE_QUOTA_EXCEEDED",
+            ApiError::WebhookQuotaExceeded => "WEBHOOK_QUOTA_EXCEEDED",
+            ApiError::RegistrationPending => "REGISTRATION_PENDING",
+            ApiError::GuestNotAllowed => "GUEST_NOT_ALLOWED",
+            ApiError::RegistrationDisabled => "REGISTRATION_DISABLED",
+            ApiError::UsernameTaken => "USERNAME_TAKEN",
+            ApiError::UsernameReserved => "USERNAME_RESERVED",
+            ApiError::SelfContact => "SELF_CONTACT",
+            ApiError::SelfDemotion => "SELF_DEMOTION",
+            ApiError::NotAContact => "NOT_A_CONTACT",
+            ApiError::UserNotFound => "USER_NOT_FOUND",
+            ApiError::PollNotFound => "POLL_NOT_FOUND",
+            ApiError::LiveLocationNotFound => "LIVE_LOCATION_NOT_FOUND",
+            ApiError::BroadcastListNotFound => "BROADCAST_LIST_NOT_FOUND",
+            ApiError::ConversationNotFound => "CONVERSATION_NOT_FOUND",
+            ApiError::WebhookNotFound => "WEBHOOK_NOT_FOUND",
+            ApiError::BotNotFound => "BOT_NOT_FOUND",
+            ApiError::RegistrationNotFound => "REGISTRATION_NOT_FOUND",
+            ApiError::InviteNotFound => "INVITE_NOT_FOUND",
+            ApiError::TokenNotFound => "TOKEN_NOT_FOUND",
+            ApiError::PushSubscriptionNotFound => "PUSH_SUBSCRIPTION_NOT_FOUND",
+            ApiError::DeviceNotFound => "DEVICE_NOT_FOUND",
+            ApiError::ConnectionNotFound => "CONNECTION_NOT_FOUND",
+            ApiError::KeysNotPublished => "KEYS_NOT_PUBLISHED",
+            ApiError::MessageTooLong { .. } => "MESSAGE_TOO_LONG",
+            ApiError::SigningKeyRequired => "SIGNING_KEY_REQUIRED",
+            ApiError::InvalidSignature => "INVALID_SIGNATURE",
+            ApiError::MessageRejected(_) => "MESSAGE_REJECTED",
+            ApiError::NotConfigured(_) => "NOT_CONFIGURED",
+            ApiError::DirectoryUnavailable => "DIRECTORY_UNAVAILABLE",
+            ApiError::ConfigReloadFailed(_) => "CONFIG_RELOAD_FAILED",
+            ApiError::MalformedBody(_) => "MALFORMED_BODY",
+            ApiError::MissingHeader(_) => "MISSING_HEADER",
+            ApiError::BodyTooLarge { .. } => "BODY_TOO_LARGE",
+            ApiError::RateLimited => "RATE_LIMITED",
+            ApiError::Validation(_) => "VALIDATION_FAILED",
+            ApiError::Internal(_) => "INTERNAL_ERROR",
+        }
+    }
+
+    /// The HTTP status the error is answered with.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::NotFound
+            | ApiError::UserNotFound
+            | ApiError::PollNotFound
+            | ApiError::LiveLocationNotFound
+            | ApiError::BroadcastListNotFound
+            | ApiError::ConversationNotFound
+            | ApiError::WebhookNotFound
+            | ApiError::BotNotFound
+            | ApiError::RegistrationNotFound
+            | ApiError::InviteNotFound
+            | ApiError::TokenNotFound
+            | ApiError::PushSubscriptionNotFound
+            | ApiError::DeviceNotFound
+            | ApiError::ConnectionNotFound
+            | ApiError::KeysNotPublished => StatusCode::NOT_FOUND,
+            ApiError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            ApiError::InvalidSession | ApiError::InvalidApiToken | ApiError::InvalidCredentials => StatusCode::UNAUTHORIZED,
+            ApiError::InsufficientScope
+            | ApiError::InsufficientRole
+            | ApiError::OriginNotAllowed
+            | ApiError::IpNotAllowed
+            | ApiError::CountryNotAllowed
+            | ApiError::InviteRequired
+            | ApiError::InviteQuotaExceeded
+            | ApiError::WebhookQuotaExceeded
+            | ApiError::RegistrationPending
+            | ApiError::GuestNotAllowed
+            | ApiError::RegistrationDisabled
+            | ApiError::SelfDemotion
+            | ApiError::NotAContact
+            | ApiError::MessageRejected(_) => StatusCode::FORBIDDEN,
+            ApiError::UsernameTaken => StatusCode::CONFLICT,
+            ApiError::AccountLocked => StatusCode::LOCKED,
+            ApiError::MessageTooLong { .. } | ApiError::BodyTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::UsernameReserved
+            | ApiError::SelfContact
+            | ApiError::SigningKeyRequired
+            | ApiError::InvalidSignature
+            | ApiError::MissingHeader(_)
+            | ApiError::Validation(_) => StatusCode::BAD_REQUEST,
+            ApiError::MalformedBody(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::NotConfigured(_) => StatusCode::NOT_IMPLEMENTED,
+            ApiError::DirectoryUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::ConfigReloadFailed(_) | ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl Reject for ApiError {}
+
+/// Body of every API error response.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorResponse {
+    // Stable identifier, e.g. `USER_NOT_FOUND`.
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl From<&ApiError> for ErrorResponse {
+    fn from(error: &ApiError) -> Self {
+        ErrorResponse { code: error.code(), message: error.to_string() }
+    }
+}
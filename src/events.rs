@@ -0,0 +1,191 @@
+// src/events.rs
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::{Arc, Weak};
+use tokio::sync::broadcast::{self, error::RecvError};
+use uuid::Uuid;
+
+use crate::analytics;
+use crate::push::{self, PushNotification};
+use crate::redact::pseudonym;
+use crate::webhooks;
+use crate::ws_handlers::AppState;
+
+/// Events buffered for a subscriber that falls behind; it misses older ones.
+const BUS_CAPACITY: usize = 4096;
+
+/// What a `MessageSent` event carries.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageKind {
+    Chat,
+    Encrypted,
+    Poll,
+    Location,
+}
+
+/// Something that happened in the chat, published once it took effect.
+#[derive(Debug, Clone)]
+pub enum DomainEvent {
+    UserRegistered {
+        user_id: Uuid,
+        username: String,
+        // Country the user registered from, when `[geoip]` knows it.
+        country: Option<String>,
+    },
+    // Someone registered while `registration.require_approval` is set; the account is created
+    // (and `UserRegistered` published) once an admin approves it.
+    RegistrationRequested {
+        registration_id: Uuid,
+        username: String,
+        country: Option<String>,
+    },
+    MessageSent {
+        kind: MessageKind,
+        message_id: String,
+        from_user_id: Uuid,
+        from_username: String,
+        to_user_id: Uuid,
+        // The text of a chat message; the server does not see (or share) any other kind's content.
+        text: Option<String>,
+        // Whether a connection of the recipient received it.
+        delivered: bool,
+        // What the recipient is told if they were offline.
+        notification: PushNotification,
+    },
+    UserOnline {
+        user_id: Uuid,
+        username: String,
+    },
+    ContactAdded {
+        user_id: Uuid,
+        contact_user_id: Uuid,
+        contact_username: String,
+    },
+    // Repeated wrong passwords locked the account against password logins.
+    AccountLocked {
+        user_id: Uuid,
+        username: String,
+        locked_until: DateTime<Utc>,
+    },
+    // A user logged in from a device they had not logged in from recently (or, for clients
+    // not telling their device, an address). The token
+    // revokes the new session with one click.
+    UnfamiliarLogin {
+        user_id: Uuid,
+        username: String,
+        client_ip: Option<IpAddr>,
+        country: Option<String>,
+        device_name: Option<String>,
+        platform: Option<&'static str>,
+        revoke_token: String,
+    },
+    // A locked account was unlocked before its lock ended, by its owner or an admin.
+    AccountUnlocked {
+        user_id: Uuid,
+        username: String,
+        unlocked_by: Uuid,
+    },
+}
+
+/// Broadcasts domain events to every subscriber. Handlers publish what happened; webhooks,
+/// offline pushes, metrics, analytics and the audit log react to it.
+#[derive(Debug)]
+pub struct EventBus {
+    sender: broadcast::Sender<Arc<DomainEvent>>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        EventBus { sender: broadcast::channel(BUS_CAPACITY).0 }
+    }
+}
+
+impl EventBus {
+    /// Receives every event published from now on.
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<DomainEvent>> {
+        self.sender.subscribe()
+    }
+
+    /// Publishes `event`. Without subscribers it is dropped.
+    pub(crate) fn publish(&self, event: DomainEvent) {
+        let _ = self.sender.send(Arc::new(event));
+    }
+}
+
+/// Starts the built-in subscribers.
+pub(crate) fn spawn_subscribers(app_state: &Arc<AppState>) {
+    spawn_subscriber(app_state, "webhooks", webhooks::on_event);
+    spawn_subscriber(app_state, "push", push::on_event);
+    spawn_subscriber(app_state, "metrics", record_metrics);
+    spawn_subscriber(app_state, "audit", audit);
+    spawn_subscriber(app_state, "analytics", analytics::on_event);
+    #[cfg(feature = "nats")]
+    spawn_subscriber(app_state, "nats", crate::nats::on_event);
+    #[cfg(feature = "kafka")]
+    spawn_subscriber(app_state, "kafka", crate::kafka::on_event);
+}
+
+/// Runs `handler` on every event, one at a time. The task holds the state weakly and ends
+/// once the server is dropped.
+fn spawn_subscriber<F, Fut>(app_state: &Arc<AppState>, name: &'static str, handler: F)
+where
+    F: Fn(Arc<AppState>, Arc<DomainEvent>) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    let mut receiver = app_state.events.subscribe();
+    let app_state: Weak<AppState> = Arc::downgrade(app_state);
+    tokio::spawn(async move {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => {
+                    let Some(app_state) = app_state.upgrade() else { break };
+                    handler(app_state, event).await;
+                }
+                Err(RecvError::Lagged(missed)) => {
+                    tracing::warn!(subscriber = name, missed, "Event subscriber fell behind; events were dropped");
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    });
+}
+
+async fn record_metrics(app_state: Arc<AppState>, event: Arc<DomainEvent>) {
+    if let DomainEvent::MessageSent { kind: MessageKind::Chat | MessageKind::Encrypted, .. } = *event {
+        app_state.stats.record_message_routed();
+    }
+}
+
+/// Logs every event under the `audit` target, without message contents.
+async fn audit(_app_state: Arc<AppState>, event: Arc<DomainEvent>) {
+    match &*event {
+        DomainEvent::UserRegistered { user_id, username, country } => {
+            tracing::info!(target: "audit", user_id = %user_id, username = %pseudonym(username), country = ?country, "User registered");
+        }
+        DomainEvent::RegistrationRequested { registration_id, username, country } => {
+            tracing::info!(target: "audit", registration_id = %registration_id, username = %pseudonym(username), country = ?country, "Registration awaiting approval");
+        }
+        DomainEvent::MessageSent { kind, message_id, from_user_id, to_user_id, delivered, .. } => {
+            tracing::info!(target: "audit", kind = ?kind, message_id = %message_id, from_user_id = %from_user_id, to_user_id = %to_user_id, delivered, "Message sent");
+        }
+        DomainEvent::UserOnline { user_id, .. } => {
+            tracing::info!(target: "audit", user_id = %user_id, "User online");
+        }
+        DomainEvent::ContactAdded { user_id, contact_user_id, .. } => {
+            tracing::info!(target: "audit", user_id = %user_id, contact_user_id = %contact_user_id, "Contact added");
+        }
+        DomainEvent::AccountLocked { user_id, locked_until, .. } => {
+            tracing::warn!(target: "audit", user_id = %user_id, locked_until = %locked_until.to_rfc3339(), "Account locked after failed logins");
+        }
+        DomainEvent::AccountUnlocked { user_id, unlocked_by, .. } => {
+            tracing::info!(target: "audit", user_id = %user_id, unlocked_by = %unlocked_by, "Account unlocked");
+        }
+        DomainEvent::UnfamiliarLogin { user_id, client_ip, country, platform, .. } => {
+            tracing::warn!(target: "audit", user_id = %user_id, client_ip = ?client_ip.map(pseudonym), country = ?country, platform = ?platform, "Login from an unfamiliar device");
+        }
+    }
+}
@@ -0,0 +1,99 @@
+// src/fanout.rs
+
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::connections::{ConnectionHandle, SharedFrame};
+use crate::stats::{QueueStats, QueueStatsResponse};
+
+/// Jobs a worker may have queued; submitting more waits until it catches up.
+const WORKER_QUEUE_CAPACITY: usize = 256;
+
+enum Job {
+    // Queues the text frame on each connection; submitted at the given instant.
+    Deliver(Vec<ConnectionHandle>, Arc<SharedFrame>, Instant),
+    // Answers once the jobs submitted before it are done.
+    Flush(oneshot::Sender<()>),
+}
+
+/// Queues frames meant for many connections (presence broadcasts, and the like) on a fixed
+/// set of worker tasks, so the task handling a client does not loop over every connection.
+/// Each connection is always served by the same worker, so the frames fanned out to it
+/// arrive in the order they were submitted.
+#[derive(Debug)]
+pub struct FanoutPool {
+    workers: Vec<Worker>,
+}
+
+#[derive(Debug)]
+struct Worker {
+    jobs: mpsc::Sender<Job>,
+    stats: Arc<QueueStats>,
+}
+
+impl FanoutPool {
+    /// Starts `workers` worker tasks (at least one). They end once this is dropped.
+    pub(crate) fn spawn(workers: usize) -> Self {
+        let workers = (0..workers.max(1))
+            .map(|_| {
+                let (jobs, receiver) = mpsc::channel(WORKER_QUEUE_CAPACITY);
+                let stats = Arc::new(QueueStats::default());
+                tokio::spawn(run_worker(receiver, stats.clone()));
+                Worker { jobs, stats }
+            })
+            .collect();
+        FanoutPool { workers }
+    }
+
+    /// Hands the text frame to the workers to queue on every connection in `recipients`; the
+    /// connections share its buffer. Returns once the workers have it, not once it is queued
+    /// everywhere.
+    pub(crate) async fn deliver(&self, recipients: Vec<ConnectionHandle>, frame: Arc<SharedFrame>) {
+        let mut shares: Vec<Vec<ConnectionHandle>> = vec![Vec::new(); self.workers.len()];
+        for connection in recipients {
+            shares[self.worker_of(&connection)].push(connection);
+        }
+        for (worker, share) in self.workers.iter().zip(shares).filter(|(_, share)| !share.is_empty()) {
+            worker.stats.enqueued();
+            if worker.jobs.send(Job::Deliver(share, frame.clone(), Instant::now())).await.is_err() {
+                worker.stats.discarded();
+            }
+        }
+    }
+
+    /// Waits until the workers are done with every frame handed to them before.
+    pub async fn flush(&self) {
+        for worker in &self.workers {
+            let (done, flushed) = oneshot::channel();
+            if worker.jobs.send(Job::Flush(done)).await.is_ok() {
+                let _ = flushed.await;
+            }
+        }
+    }
+
+    /// Jobs waiting for a worker, and how long they waited, over all workers.
+    pub fn queue_stats(&self) -> QueueStatsResponse {
+        self.workers.iter().map(|worker| worker.stats.snapshot()).fold(QueueStatsResponse::default(), QueueStatsResponse::combine)
+    }
+
+    fn worker_of(&self, connection: &ConnectionHandle) -> usize {
+        (connection.connection_id.as_u128() % self.workers.len() as u128) as usize
+    }
+}
+
+async fn run_worker(mut jobs: mpsc::Receiver<Job>, stats: Arc<QueueStats>) {
+    while let Some(job) = jobs.recv().await {
+        match job {
+            Job::Deliver(recipients, frame, queued_at) => {
+                stats.dequeued(queued_at.elapsed());
+                for connection in &recipients {
+                    connection.deliver(&frame);
+                }
+            }
+            Job::Flush(done) => {
+                let _ = done.send(());
+            }
+        }
+    }
+}
@@ -0,0 +1,65 @@
+// src/faults.rs
+
+//! Fault injection for resilience tests (`fault-injection` feature). The fanout of chat
+//! messages, conversation events and presence broadcasts consults the server's
+//! `FaultInjector`, which can delay it, stall the connection registry to force contention,
+//! and drop every Nth send. Faults are deterministic: no randomness, so tests can count on them.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::connections::ConnectionRegistry;
+
+/// Faults to inject, all disabled by default. Changes apply to the next fanout.
+#[derive(Debug, Default)]
+pub struct FaultInjector {
+    latency_ms: AtomicU64,
+    lock_hold_ms: AtomicU64,
+    drop_every: AtomicU64,
+    sends: AtomicU64,
+}
+
+impl FaultInjector {
+    /// Delays every fanout by `latency` before it reaches the connection registry.
+    pub fn set_latency(&self, latency: Duration) {
+        self.latency_ms.store(latency.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Stalls the connection registry for `hold` before every fanout, so concurrent fanouts
+    /// and connects queue up behind it.
+    pub fn set_lock_contention(&self, hold: Duration) {
+        self.lock_hold_ms.store(hold.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Drops every `n`th send, counting from the next one: a chat message or conversation
+    /// event to its recipient, or a presence update to one connection. 0 stops dropping.
+    pub fn drop_every_nth_send(&self, n: u64) {
+        self.sends.store(0, Ordering::Relaxed);
+        self.drop_every.store(n, Ordering::Relaxed);
+    }
+
+    /// Disables every fault.
+    pub fn reset(&self) {
+        self.set_latency(Duration::ZERO);
+        self.set_lock_contention(Duration::ZERO);
+        self.drop_every_nth_send(0);
+    }
+
+    /// Applies the configured latency and registry contention ahead of a fanout over `connections`.
+    pub(crate) async fn before_fanout(&self, connections: &ConnectionRegistry) {
+        let latency = self.latency_ms.load(Ordering::Relaxed);
+        if latency > 0 {
+            tokio::time::sleep(Duration::from_millis(latency)).await;
+        }
+        let hold = self.lock_hold_ms.load(Ordering::Relaxed);
+        if hold > 0 {
+            connections.stall(Duration::from_millis(hold)).await;
+        }
+    }
+
+    /// Whether the current send should be dropped.
+    pub(crate) fn drop_send(&self) -> bool {
+        let every = self.drop_every.load(Ordering::Relaxed);
+        every > 0 && (self.sends.fetch_add(1, Ordering::Relaxed) + 1).is_multiple_of(every)
+    }
+}
@@ -0,0 +1,72 @@
+// src/fuzzing.rs
+
+//! Entry points of the cargo-fuzz targets in `fuzz/`. Each takes arbitrary bytes and must
+//! return without panicking. Not a stable API.
+
+use tokio::runtime::Runtime;
+
+use crate::config::{AuthConfig, Config, LogConfig};
+use crate::testing::{SimulatedConnection, TestServer};
+use crate::ws_handlers;
+#[cfg(feature = "xmpp")]
+use crate::xmpp;
+
+/// Feeds WebSocket frames to the client message handlers of one long-lived server, as
+/// `handle_ws` does but without its panic guard. Frames come from a simulated connection;
+/// `$self` and `$peer` in a frame are replaced with the user ids of the sender and of a
+/// second connected user, so inputs can address real conversations.
+pub struct FrameFuzzer {
+    runtime: Runtime,
+    server: TestServer,
+    sender: SimulatedConnection,
+    peer: SimulatedConnection,
+}
+
+impl FrameFuzzer {
+    pub fn new() -> Self {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().expect("tokio runtime");
+        let config = Config {
+            log: LogConfig { level: "off".to_string(), ..LogConfig::default() },
+            auth: AuthConfig { bcrypt_cost: 4, ..AuthConfig::default() },
+            ..Config::default()
+        };
+        let (server, mut connections) = runtime.block_on(async {
+            let server = TestServer::with_config(config).await;
+            let connections = server.simulate_connections(2).await;
+            (server, connections)
+        });
+        let peer = connections.pop().expect("two simulated connections");
+        let sender = connections.pop().expect("two simulated connections");
+        FrameFuzzer { runtime, server, sender, peer }
+    }
+
+    /// Handles `data` as one text frame from the sender.
+    pub fn frame(&mut self, data: &[u8]) {
+        let Ok(text) = std::str::from_utf8(data) else { return };
+        let text = text.replace("$self", &self.sender.user_id().to_string()).replace("$peer", &self.peer.user_id().to_string());
+        let state = self.server.state();
+        self.runtime.block_on(ws_handlers::handle_client_frame(&text, &self.sender.session, &state));
+        self.sender.drain();
+        self.peer.drain();
+    }
+}
+
+impl Default for FrameFuzzer {
+    fn default() -> Self {
+        FrameFuzzer::new()
+    }
+}
+
+/// Parses `data` as the client side of an XMPP stream.
+#[cfg(feature = "xmpp")]
+pub fn xmpp_stream(data: &[u8]) {
+    futures::executor::block_on(xmpp::parse_stream(data));
+}
+
+/// Decodes `data` as a SASL PLAIN response.
+#[cfg(feature = "xmpp")]
+pub fn sasl_plain(data: &[u8]) {
+    if let Ok(response) = std::str::from_utf8(data) {
+        let _ = xmpp::decode_plain(response, "localhost");
+    }
+}
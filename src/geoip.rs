@@ -0,0 +1,144 @@
+// src/geoip.rs
+
+use ipnet::IpNet;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+
+use crate::config::{CountryRules, GeoIpConfig};
+use crate::error::ApiError;
+use crate::redact::pseudonym;
+use crate::ws_handlers::AppState;
+
+/// Where a client's country is checked.
+#[derive(Debug, Clone, Copy)]
+pub enum Gate {
+    Registration,
+    Login,
+}
+
+/// Countries of client addresses, from a MaxMind GeoIP2 or GeoLite2 country database in its
+/// CSV format, with the countries `[geoip]` lets register and log in.
+#[derive(Debug)]
+pub struct GeoIp {
+    // Networks sorted by first address, with their country code; MaxMind's do not overlap.
+    networks: Vec<(IpNet, String)>,
+    registration: CountryRules,
+    login: CountryRules,
+}
+
+impl GeoIp {
+    /// Reads the database files named by `config`.
+    pub fn load(config: &GeoIpConfig) -> io::Result<Self> {
+        let countries = read_locations(&config.locations_path)?;
+        let mut networks = Vec::new();
+        for path in &config.blocks_paths {
+            read_blocks(path, &countries, &mut networks)?;
+        }
+        networks.sort_by_key(|(network, _)| network.network());
+        tracing::info!(networks = networks.len(), countries = countries.len(), "Loaded GeoIP database");
+        Ok(GeoIp { networks, registration: config.registration.clone(), login: config.login.clone() })
+    }
+
+    /// The country code of the network `ip` is in, if the database has one.
+    pub fn country(&self, ip: IpAddr) -> Option<&str> {
+        let ip = ip.to_canonical();
+        let after = self.networks.partition_point(|(network, _)| network.network() <= ip);
+        let (network, country) = self.networks.get(after.checked_sub(1)?)?;
+        network.contains(&ip).then_some(country.as_str())
+    }
+}
+
+/// The country `client_ip` is located in, when `[geoip]` is configured and the address is in
+/// its database, or `CountryNotAllowed` if the rules of `gate` turn that country away.
+pub fn check(app_state: &AppState, client_ip: Option<IpAddr>, gate: Gate) -> Result<Option<String>, ApiError> {
+    let Some(geoip) = &app_state.geoip else { return Ok(None) };
+    let country = client_ip.and_then(|ip| geoip.country(ip));
+    let rules = match gate {
+        Gate::Registration => &geoip.registration,
+        Gate::Login => &geoip.login,
+    };
+    if permits(rules, country) {
+        Ok(country.map(str::to_string))
+    } else {
+        tracing::warn!(gate = ?gate, country, client_ip = ?client_ip.map(pseudonym), "Request refused: country not allowed");
+        Err(ApiError::CountryNotAllowed)
+    }
+}
+
+// Whether `rules` let in a client located in `country`; an unknown country only gets in
+// without an allow list.
+fn permits(rules: &CountryRules, country: Option<&str>) -> bool {
+    match country {
+        Some(country) => !rules.deny.iter().any(|denied| denied == country) && (rules.allow.is_empty() || rules.allow.iter().any(|allowed| allowed == country)),
+        None => rules.allow.is_empty(),
+    }
+}
+
+// geoname_id -> country_iso_code, from a locations file. Continents have no country code and
+// are left out.
+fn read_locations(path: &Path) -> io::Result<HashMap<String, String>> {
+    let mut countries = HashMap::new();
+    read_csv(path, &["geoname_id", "country_iso_code"], |row| {
+        if !row[1].is_empty() {
+            countries.insert(row[0].clone(), row[1].clone());
+        }
+        Ok(())
+    })?;
+    Ok(countries)
+}
+
+// Appends the networks of a blocks file located in a known country. A network without a
+// location of its own falls back to the country it is registered in.
+fn read_blocks(path: &Path, countries: &HashMap<String, String>, networks: &mut Vec<(IpNet, String)>) -> io::Result<()> {
+    read_csv(path, &["network", "geoname_id", "registered_country_geoname_id"], |row| {
+        let network = row[0].parse::<IpNet>().map_err(|e| format!("invalid network {:?}: {}", row[0], e))?;
+        let geoname_id = if row[1].is_empty() { &row[2] } else { &row[1] };
+        if let Some(country) = countries.get(geoname_id) {
+            networks.push((network, country.clone()));
+        }
+        Ok(())
+    })
+}
+
+// Calls `row` with the `columns` of every record of a CSV file with a header line, in that order.
+fn read_csv<F>(path: &Path, columns: &[&str], mut row: F) -> io::Result<()>
+where
+    F: FnMut(&[String]) -> Result<(), String>,
+{
+    let invalid = |line: usize, reason: String| io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: {}", path.display(), line, reason));
+    let contents = fs::read_to_string(path)?;
+    let mut lines = contents.lines().enumerate().filter(|(_, line)| !line.trim().is_empty());
+    let header = lines.next().map(|(_, line)| split_record(line)).unwrap_or_default();
+    let indexes = columns
+        .iter()
+        .map(|column| header.iter().position(|name| name == column).ok_or_else(|| invalid(1, format!("no {} column", column))))
+        .collect::<io::Result<Vec<_>>>()?;
+    for (index, line) in lines {
+        let fields = split_record(line);
+        let selected: Vec<String> = indexes.iter().map(|&i| fields.get(i).cloned().unwrap_or_default()).collect();
+        row(&selected).map_err(|reason| invalid(index + 1, reason))?;
+    }
+    Ok(())
+}
+
+// The fields of a CSV record; fields may be quoted, with "" standing for a quote.
+fn split_record(line: &str) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.trim_end_matches('\r').chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().expect("fields is never empty").push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(String::new()),
+            c => fields.last_mut().expect("fields is never empty").push(c),
+        }
+    }
+    fields
+}
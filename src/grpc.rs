@@ -0,0 +1,126 @@
+// src/grpc.rs
+
+use futures::Stream;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use tonic::{Request, Response, Status};
+use uuid::Uuid;
+
+use crate::connections;
+use crate::handlers;
+use crate::ws_handlers::{self, AppState, UserSession};
+
+pub mod proto {
+    tonic::include_proto!("rust_chat.v1");
+}
+
+use proto::chat_service_server::{ChatService, ChatServiceServer};
+use proto::{
+    Contact, Event, GetContactsRequest, GetContactsResponse, SendMessageRequest, SendMessageResponse,
+    StreamEventsRequest,
+};
+
+/// gRPC front end sharing `AppState` with the warp server.
+pub struct ChatGrpcService {
+    app_state: Arc<AppState>,
+}
+
+impl ChatGrpcService {
+    /// Resolves the `x-session-key` metadata entry to a live session.
+    async fn authenticate<T>(&self, request: &Request<T>) -> Result<UserSession, Status> {
+        let session_key = request
+            .metadata()
+            .get("x-session-key")
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| Status::unauthenticated("Missing x-session-key metadata."))?;
+        self.app_state
+            .session_for_key(session_key)
+            .await
+            .ok_or_else(|| Status::unauthenticated("Invalid session key."))
+    }
+}
+
+type EventStream = Pin<Box<dyn Stream<Item = Result<Event, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl ChatService for ChatGrpcService {
+    #[tracing::instrument(name = "grpc_send_message", skip_all)]
+    async fn send_message(
+        &self,
+        request: Request<SendMessageRequest>,
+    ) -> Result<Response<SendMessageResponse>, Status> {
+        let session = self.authenticate(&request).await?;
+        let request = request.into_inner();
+        let to_user_id = Uuid::parse_str(&request.to_user_id)
+            .map_err(|_| Status::invalid_argument("to_user_id is not a valid UUID."))?;
+
+        let message_id = ws_handlers::route_chat_message(&self.app_state, &session, to_user_id, request.message)
+            .await
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        Ok(Response::new(SendMessageResponse { message_id }))
+    }
+
+    #[tracing::instrument(name = "grpc_get_contacts", skip_all)]
+    async fn get_contacts(
+        &self,
+        request: Request<GetContactsRequest>,
+    ) -> Result<Response<GetContactsResponse>, Status> {
+        let session = self.authenticate(&request).await?;
+        let contacts = handlers::contacts::list_contacts(&self.app_state, &session)
+            .await
+            .map_err(|e| Status::not_found(e.to_string()))?;
+        let contacts = contacts
+            .into_iter()
+            .map(|contact| Contact { id: contact.id.to_string(), username: contact.username })
+            .collect();
+        Ok(Response::new(GetContactsResponse { contacts }))
+    }
+
+    type StreamEventsStream = EventStream;
+
+    #[tracing::instrument(name = "grpc_stream_events", skip_all)]
+    async fn stream_events(
+        &self,
+        request: Request<StreamEventsRequest>,
+    ) -> Result<Response<Self::StreamEventsStream>, Status> {
+        let session = self.authenticate(&request).await?;
+
+        // Register the stream like any other connection, but under its own key so it does not
+        // replace the user's WebSocket, and without announcing the user as online.
+        let connection_key = format!("grpc:{}", Uuid::new_v4());
+        let (connection, receiver) = connections::connection_channel(&session, request.remote_addr().map(|addr| addr.ip()));
+        ws_handlers::register_connection(&self.app_state, connection_key.clone(), connection);
+        tracing::info!(user_id = %session.user_id, connection_key = %connection_key, "gRPC event stream opened");
+
+        let registration = StreamRegistration { app_state: self.app_state.clone(), connection_key, user_id: session.user_id };
+        let events = futures::stream::unfold((receiver, registration), |(mut receiver, registration)| async move {
+            let frame = receiver.recv_text().await?;
+            Some((Ok(Event { json: frame.json().to_string() }), (receiver, registration)))
+        });
+        Ok(Response::new(Box::pin(events)))
+    }
+}
+
+/// Removes a gRPC event stream from the active connections once the client goes away.
+struct StreamRegistration {
+    app_state: Arc<AppState>,
+    connection_key: String,
+    user_id: Uuid,
+}
+
+impl Drop for StreamRegistration {
+    fn drop(&mut self) {
+        ws_handlers::remove_connection(&self.app_state, &self.connection_key, self.user_id);
+        tracing::info!(connection_key = %self.connection_key, "gRPC event stream closed");
+    }
+}
+
+/// Serves the gRPC API on `addr` until the process exits.
+pub async fn serve(app_state: Arc<AppState>, addr: SocketAddr) {
+    tracing::info!(addr = %addr, "Starting gRPC server");
+    let service = ChatServiceServer::new(ChatGrpcService { app_state });
+    if let Err(e) = tonic::transport::Server::builder().add_service(service).serve(addr).await {
+        tracing::error!(error = %e, "gRPC server stopped");
+    }
+}
@@ -0,0 +1,98 @@
+// src/guests.rs
+
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashSet;
+use std::sync::Arc;
+use uuid::Uuid;
+
+use crate::connections::CloseReason;
+use crate::redact::pseudonym;
+use crate::webhooks::WebhookOwner;
+use crate::ws_handlers::{AppState, User};
+
+/// Prefix of guest usernames. Registering a name with it is refused, so a guest cannot be
+/// mistaken for a regular account.
+pub const USERNAME_PREFIX: &str = "guest-";
+
+/// A random username for a new guest, e.g. `guest-3f9a1c2e`.
+pub fn generate_username() -> String {
+    let suffix = Uuid::new_v4().simple().to_string();
+    format!("{}{}", USERNAME_PREFIX, &suffix[..8])
+}
+
+/// Whether `username` is of the form reserved for guests.
+pub fn is_guest_name(username: &str) -> bool {
+    username.starts_with(USERNAME_PREFIX)
+}
+
+/// When a guest account created now expires, `registration.guest_ttl_secs` from now.
+pub fn expiry(app_state: &AppState) -> DateTime<Utc> {
+    Utc::now() + Duration::seconds(app_state.config.registration.guest_ttl_secs as i64)
+}
+
+/// Deletes the guest accounts past their expiry and everything kept about them, returning
+/// how many there were.
+pub async fn purge_expired(app_state: &AppState) -> usize {
+    let now = Utc::now();
+    let expired = app_state.users.filter(|user| user.guest_until.is_some_and(|until| until <= now));
+    for guest in &expired {
+        purge(app_state, guest).await;
+    }
+    expired.len()
+}
+
+/// Scheduled every minute: purges the expired guest accounts.
+pub async fn sweep(app_state: Arc<AppState>) {
+    let purged = purge_expired(&app_state).await;
+    if purged > 0 {
+        tracing::info!(purged, "Purged expired guest accounts");
+    }
+}
+
+// Deletes `guest`: the account, its sessions (closing their connections) and its side of
+// every contact, conversation, draft, poll, live location, list and setting. Calls in
+// progress end through the closed connections, as on any disconnect.
+async fn purge(app_state: &AppState, guest: &User) {
+    let id = guest.id;
+    app_state.users.lock(&guest.username).await.remove(&guest.username);
+
+    for shard in app_state.user_sessions.shards() {
+        let mut sessions = shard.lock().await;
+        let keys: Vec<String> = sessions.iter().filter(|(_, session)| session.user_id == id).map(|(key, _)| key.clone()).collect();
+        for key in keys {
+            sessions.remove(&key);
+            app_state.connections.close_session(&key, CloseReason::GuestExpired).await;
+        }
+    }
+
+    let contact_ids: HashSet<Uuid> = guest.contacts.lock().await.keys().copied().collect();
+    for contact in contact_ids.iter().filter_map(|contact_id| app_state.users.get_by_id(contact_id)) {
+        contact.contacts.lock().await.remove(&id);
+    }
+
+    app_state.conversations.lock().await.retain(|(owner, partner), _| *owner != id && *partner != id);
+    app_state.drafts.lock().await.retain(|(owner, partner), _| *owner != id && *partner != id);
+    app_state.typing.lock().await.retain(|(typist, recipient), _| *typist != id && *recipient != id);
+    app_state.polls.lock().await.retain(|_, poll| !poll.is_participant(id));
+    app_state.live_locations.lock().await.retain(|_, share| share.sender_id != id && share.to_user_id != id);
+    app_state.call_history.lock().await.remove(&id);
+    {
+        let mut broadcast_lists = app_state.broadcast_lists.lock().await;
+        broadcast_lists.retain(|_, list| list.owner_user_id != id);
+        for list in broadcast_lists.values_mut() {
+            list.recipient_ids.retain(|recipient_id| *recipient_id != id);
+        }
+    }
+    app_state.webhooks.lock().await.retain(|_, webhook| !matches!(webhook.owner, WebhookOwner::User(owner) if owner == id));
+    app_state.incoming_webhooks.lock().await.retain(|_, webhook| webhook.owner_user_id != id && webhook.to_user_id != id);
+    app_state.push_subscriptions.lock().await.retain(|_, subscription| subscription.user_id != id);
+    app_state.device_tokens.lock().await.retain(|_, device| device.user_id != id);
+    app_state.notification_settings.lock().await.remove(&id);
+    app_state.dnd_presence.lock().await.remove(&id);
+    app_state.e2e_keys.lock().await.remove(&id);
+    app_state.trusted_devices.lock().await.remove(&id);
+    app_state.login_failures.lock().await.remove(&id);
+    app_state.login_alerts.lock().await.forget(id);
+
+    tracing::info!(user_id = %id, username = %pseudonym(&guest.username), "Guest account expired and deleted");
+}
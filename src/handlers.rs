@@ -0,0 +1,16 @@
+// src/handlers.rs
+
+//! Handlers of the HTTP API, one module per OpenAPI tag. The WebSocket protocol, the shared
+//! state and message routing stay in `ws_handlers`.
+
+pub mod admin; // Moderation, statistics, connections, runtime config and IP rules
+pub mod auth; // Registration, login, sessions, passwords, trusted devices and invites
+pub mod bots; // Bot accounts and their API tokens
+pub mod broadcasts; // Broadcast lists and the messages sent to them
+pub mod calls; // Log of WebRTC calls
+pub mod contacts; // Contact list
+pub mod conversations; // Conversation list, read state and per-conversation notifications
+pub mod keys; // Identity keys and prekey bundles for end-to-end encryption
+pub mod messages; // Sending messages without a WebSocket
+pub mod push; // Web Push subscriptions, mobile devices and notification settings
+pub mod webhooks; // Outgoing webhooks, incoming webhooks and the admin's global webhooks
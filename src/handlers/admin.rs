@@ -0,0 +1,568 @@
+// src/handlers/admin.rs
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::{Rejection, Reply};
+
+use crate::analytics::{AnalyticsBucket, AnalyticsPeriod, RETENTION_DAYS};
+use crate::cluster::ClusterNodeResponse;
+use crate::config::{IpFilterConfig, RuntimeConfig};
+use crate::connections::{CloseReason, ConnectionHandle};
+use crate::error::{ApiError, ErrorResponse};
+use crate::events::DomainEvent;
+use crate::handlers::auth::{password_verified, record_wrong_password, unlock_account, ReauthPayload, UnlockResponse};
+use crate::ip_filter;
+use crate::redact::pseudonym;
+use crate::registrations::PendingRegistration;
+use crate::scheduler::ScheduledTaskResponse;
+use crate::stats::{LockStatsResponse, QueueStatsResponse, WaitStatsResponse};
+use crate::wal::{self, Mutation};
+use crate::ws_handlers::{broadcast_announcement, AnnouncementSeverity, AppState, Role, ServerMessage, UserSession};
+
+#[derive(Deserialize, ToSchema)]
+pub struct SetRolePayload {
+    role: Role,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct AnnouncementPayload {
+    title: String,
+    body: String,
+    #[serde(default = "default_announcement_severity")]
+    #[schema(default = "info")]
+    severity: AnnouncementSeverity,
+}
+
+fn default_announcement_severity() -> AnnouncementSeverity {
+    AnnouncementSeverity::Info
+}
+
+// Snapshot returned by the admin statistics endpoint.
+#[derive(Serialize, ToSchema)]
+pub struct StatsResponse {
+    registered_users: usize,
+    active_sessions: usize,
+    open_connections: usize,
+    messages_routed_total: u64,
+    messages_routed_last_minute: u64,
+    queued_frames_total: usize,
+    max_connection_queue_depth: usize,
+    // Connections closed as slow consumers since startup.
+    slow_consumers_disconnected: u64,
+    // Sessions expired since startup for outliving their TTL or going unused.
+    sessions_expired: u64,
+    // How long frames waited in the queues of the open connections before being written.
+    connection_queue_wait: WaitStatsResponse,
+    // Commands waiting for the connection registry tasks, over all shards.
+    connection_registry: QueueStatsResponse,
+    // Conversation fanouts waiting for their ordering shard.
+    conversation_shards: QueueStatsResponse,
+    // Broadcasts waiting for a fanout worker.
+    fanout_workers: QueueStatsResponse,
+    // Wait times of the locks on the shared state, to spot contention.
+    locks: Vec<LockStatsResponse>,
+    // Runs of the recurring background jobs.
+    scheduled_tasks: Vec<ScheduledTaskResponse>,
+    // Nodes of the cluster (this one first) with their connections; empty without `[cluster]`.
+    cluster_nodes: Vec<ClusterNodeResponse>,
+}
+
+// A live WebSocket connection, as listed by the admin connections endpoint.
+#[derive(Serialize, ToSchema)]
+pub struct ConnectionResponse {
+    id: Uuid,
+    user_id: Uuid,
+    username: String,
+    // The first characters of the session key: enough to tell sessions apart, not to use one.
+    session_key: String,
+    // Real client address, resolved through trusted proxies.
+    client_ip: Option<String>,
+    // Country the session logged in from (ISO 3166-1 alpha-2), when `[geoip]` knows it.
+    country: Option<String>,
+    // Device the session logged in from: the name its client gave, the operating system
+    // recognized in its user agent, and the user agent itself.
+    device_name: Option<String>,
+    platform: Option<&'static str>,
+    user_agent: Option<String>,
+    connected_at: String,
+    // When the client last sent a frame (RFC 3339).
+    last_activity_at: String,
+    // Frames queued but not yet written to the socket.
+    queue_depth: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct AnnouncementResponse {
+    // Number of active connections the announcement was queued on.
+    delivered: usize,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct RoleResponse {
+    username: String,
+    role: Role,
+}
+
+// A registration waiting for an admin's approval.
+#[derive(Serialize, ToSchema)]
+pub struct PendingRegistrationResponse {
+    id: Uuid,
+    username: String,
+    country: Option<String>,
+    requested_at: String,
+}
+
+impl From<&PendingRegistration> for PendingRegistrationResponse {
+    fn from(registration: &PendingRegistration) -> Self {
+        PendingRegistrationResponse {
+            id: registration.id,
+            username: registration.user.username.clone(),
+            country: registration.country.clone(),
+            requested_at: registration.requested_at.to_rfc3339(),
+        }
+    }
+}
+
+// The account created by approving a registration.
+#[derive(Serialize, ToSchema)]
+pub struct ApprovedRegistrationResponse {
+    user_id: Uuid,
+    username: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/admin/announcements",
+    tag = "admin",
+    request_body = AnnouncementPayload,
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "Announcement broadcast", body = AnnouncementResponse),
+        (status = 400, description = "Missing fields", body = ErrorResponse),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 403, description = "Insufficient role", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn announcement_handler(
+    payload: AnnouncementPayload,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    if payload.title.trim().is_empty() || payload.body.trim().is_empty() {
+        return Err(warp::reject::custom(ApiError::invalid("Announcement title and body are required.")));
+    }
+
+    let announcement = ServerMessage::Announcement {
+        title: payload.title,
+        body: payload.body,
+        severity: payload.severity,
+    };
+    let delivered = broadcast_announcement(&app_state, &announcement).await;
+    tracing::info!(user_id = %session.user_id, delivered, announcement = ?announcement, "Announcement broadcast");
+
+    Ok(warp::reply::json(&AnnouncementResponse { delivered }))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/admin/users/{username}/role",
+    tag = "admin",
+    params(("username" = String, Path, description = "User whose role is changed")),
+    request_body = SetRolePayload,
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "Role updated", body = RoleResponse),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 403, description = "Insufficient role or self-demotion", body = ErrorResponse),
+        (status = 404, description = "Unknown user", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn set_role_handler(
+    username: String,
+    payload: SetRolePayload,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    if username == session.username && payload.role < Role::Admin {
+        tracing::warn!(user_id = %session.user_id, "Set role failed: admin tried to demote themselves");
+        return Err(warp::reject::custom(ApiError::SelfDemotion));
+    }
+
+    let mut users = app_state.users.lock(&username).await;
+    match users.get_mut(&username) {
+        Some(user) => {
+            user.role = payload.role;
+            let mutation = Mutation::RoleChanged { user_id: user.id, role: payload.role };
+            drop(users);
+            wal::record(&app_state, mutation).await;
+            tracing::info!(user_id = %session.user_id, target_username = %pseudonym(&username), role = ?payload.role, "Admin changed user role");
+            Ok(warp::reply::json(&RoleResponse { username, role: payload.role }))
+        }
+        None => {
+            tracing::warn!(user_id = %session.user_id, target_username = %pseudonym(&username), "Set role failed: user not found");
+            Err(warp::reject::custom(ApiError::UserNotFound))
+        }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/admin/users/{username}/unlock",
+    tag = "admin",
+    params(("username" = String, Path, description = "User whose account is unlocked")),
+    request_body = ReauthPayload,
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "Account unlocked, or was not locked", body = UnlockResponse),
+        (status = 401, description = "Invalid session or wrong password of the admin", body = ErrorResponse),
+        (status = 503, description = "The `[ldap]` directory could not check the password", body = ErrorResponse),
+        (status = 403, description = "Insufficient role", body = ErrorResponse),
+        (status = 404, description = "Unknown user", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn unlock_user_handler(
+    username: String,
+    payload: ReauthPayload,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    // The admin re-enters their own password, so a stolen admin session cannot undo lockouts.
+    let admin = app_state.users.get(&session.username).ok_or_else(|| warp::reject::custom(ApiError::InvalidSession))?;
+    if !password_verified(&app_state, &admin, &payload.password).await.map_err(warp::reject::custom)? {
+        tracing::warn!(user_id = %session.user_id, "Unlock failed: wrong admin password");
+        record_wrong_password(&app_state, session.user_id, &session.username).await;
+        return Err(warp::reject::custom(ApiError::InvalidCredentials));
+    }
+    let user = app_state.users.get(&username).ok_or_else(|| warp::reject::custom(ApiError::UserNotFound))?;
+    let was_locked = unlock_account(&app_state, user.id, &username, session.user_id).await;
+    tracing::info!(user_id = %session.user_id, target_user_id = %user.id, was_locked, "Admin unlocked an account");
+    Ok(warp::reply::json(&UnlockResponse { username, was_locked }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/admin/registrations",
+    tag = "admin",
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "Registrations waiting for approval, oldest first", body = [PendingRegistrationResponse]),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 403, description = "Insufficient role", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn list_registrations_handler(session: UserSession, app_state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let registrations = app_state.registrations.lock().await;
+    let mut pending: Vec<&PendingRegistration> = registrations.values().collect();
+    pending.sort_by_key(|registration| registration.requested_at);
+    let response: Vec<PendingRegistrationResponse> = pending.into_iter().map(PendingRegistrationResponse::from).collect();
+    Ok(warp::reply::json(&response))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/admin/registrations/{id}/approve",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "Registration to approve")),
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "Account created; its owner can log in", body = ApprovedRegistrationResponse),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 403, description = "Insufficient role", body = ErrorResponse),
+        (status = 404, description = "No such pending registration", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn approve_registration_handler(id: Uuid, session: UserSession, app_state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let username = match app_state.registrations.lock().await.get(&id) {
+        Some(registration) => registration.user.username.clone(),
+        None => return Err(warp::reject::custom(ApiError::RegistrationNotFound)),
+    };
+    // Users before registrations, the order registration takes them in.
+    let mut users = app_state.users.lock(&username).await;
+    let registration = app_state.registrations.lock().await.remove(&id).ok_or_else(|| warp::reject::custom(ApiError::RegistrationNotFound))?;
+    let user = registration.user;
+    let response = ApprovedRegistrationResponse { user_id: user.id, username: user.username.clone() };
+    let mutation = Mutation::user_created(&user);
+    users.insert(user.username.clone(), user);
+    drop(users);
+    wal::record(&app_state, mutation).await;
+    tracing::info!(user_id = %session.user_id, target_user_id = %response.user_id, registration_id = %id, "Admin approved a registration");
+    app_state.events.publish(DomainEvent::UserRegistered { user_id: response.user_id, username: response.username.clone(), country: registration.country });
+    Ok(warp::reply::json(&response))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/admin/registrations/{id}",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "Registration to reject")),
+    security(("session_key" = [])),
+    responses(
+        (status = 204, description = "Registration rejected; the username is free again"),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 403, description = "Insufficient role", body = ErrorResponse),
+        (status = 404, description = "No such pending registration", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn reject_registration_handler(id: Uuid, session: UserSession, app_state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    app_state.registrations.lock().await.remove(&id).ok_or_else(|| warp::reject::custom(ApiError::RegistrationNotFound))?;
+    tracing::info!(user_id = %session.user_id, registration_id = %id, "Admin rejected a registration");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/admin/stats",
+    tag = "admin",
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "Current server statistics", body = StatsResponse),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 403, description = "Insufficient role", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn stats_handler(
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let registered_users = app_state.users.len();
+    let active_sessions = app_state.user_sessions.len();
+
+    let connections = app_state.connections.snapshot().await;
+    let queue_depths: Vec<usize> = connections.iter().map(|(_, connection)| connection.queue_depth()).collect();
+    let connection_queue_wait =
+        connections.iter().map(|(_, connection)| connection.queue_wait()).fold(WaitStatsResponse::default(), WaitStatsResponse::combine);
+    let cluster_nodes = match &app_state.cluster {
+        Some(cluster) => {
+            let online_users = connections.iter().map(|(_, connection)| connection.user_id).collect::<HashSet<_>>().len();
+            cluster.node_stats(connections.len(), online_users).await
+        }
+        None => Vec::new(),
+    };
+
+    let response = StatsResponse {
+        registered_users,
+        active_sessions,
+        open_connections: connections.len(),
+        messages_routed_total: app_state.stats.messages_routed_total.load(Ordering::Relaxed),
+        messages_routed_last_minute: app_state.stats.messages_routed.per_minute(),
+        queued_frames_total: queue_depths.iter().sum(),
+        max_connection_queue_depth: queue_depths.iter().copied().max().unwrap_or(0),
+        slow_consumers_disconnected: app_state.stats.slow_consumers_disconnected.load(Ordering::Relaxed),
+        sessions_expired: app_state.stats.sessions_expired.load(Ordering::Relaxed),
+        connection_queue_wait,
+        connection_registry: app_state.connections.queue_stats(),
+        conversation_shards: app_state.ordering.queue_stats(),
+        fanout_workers: app_state.fanout.queue_stats(),
+        locks: app_state.lock_stats(),
+        scheduled_tasks: app_state.scheduler.task_stats(),
+        cluster_nodes,
+    };
+    Ok(warp::reply::json(&response))
+}
+
+// Activity aggregates returned by the admin analytics endpoint.
+#[derive(Serialize, ToSchema)]
+pub struct AnalyticsResponse {
+    period: AnalyticsPeriod,
+    // Oldest first; the last one is today, or the current week so far.
+    buckets: Vec<AnalyticsBucket>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/admin/analytics",
+    tag = "admin",
+    security(("session_key" = [])),
+    params(
+        ("period" = Option<AnalyticsPeriod>, Query, description = "\"daily\" (default) or \"weekly\" (Monday to Sunday)"),
+        ("count" = Option<usize>, Query, description = "Days or weeks reported, up to today (7 by default, within the last 90 days)"),
+    ),
+    responses(
+        (status = 200, description = "Active users, messages and peak connections per day or week", body = AnalyticsResponse),
+        (status = 400, description = "Unknown period or count out of range", body = ErrorResponse),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 403, description = "Insufficient role", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn analytics_handler(
+    query: HashMap<String, String>,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let period = match query.get("period").map(String::as_str) {
+        None | Some("daily") => AnalyticsPeriod::Daily,
+        Some("weekly") => AnalyticsPeriod::Weekly,
+        Some(_) => return Err(warp::reject::custom(ApiError::invalid("The period must be \"daily\" or \"weekly\"."))),
+    };
+    let max_count = match period {
+        AnalyticsPeriod::Daily => RETENTION_DAYS,
+        AnalyticsPeriod::Weekly => RETENTION_DAYS.div_ceil(7),
+    } as usize;
+    let count = match query.get("count").map(|count| count.parse::<usize>()) {
+        None => 7.min(max_count),
+        Some(Ok(count)) if (1..=max_count).contains(&count) => count,
+        Some(_) => return Err(warp::reject::custom(ApiError::invalid(format!("The count must be between 1 and {}.", max_count)))),
+    };
+    let buckets = app_state.analytics.lock().await.report(period, count);
+    Ok(warp::reply::json(&AnalyticsResponse { period, buckets }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/admin/connections",
+    tag = "admin",
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "Live WebSocket connections, oldest first", body = [ConnectionResponse]),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 403, description = "Insufficient role", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn list_connections_handler(
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let mut connections: Vec<ConnectionHandle> = app_state
+        .connections
+        .snapshot()
+        .await
+        .into_iter()
+        // WebSockets are registered under their session key; other consumers under keys of their own.
+        .filter(|(key, connection)| *key == connection.session_key)
+        .map(|(_, connection)| connection)
+        .collect();
+    connections.sort_by_key(|connection| connection.connected_at);
+    let response: Vec<ConnectionResponse> = connections
+        .iter()
+        .map(|connection| ConnectionResponse {
+            id: connection.connection_id,
+            user_id: connection.user_id,
+            username: connection.username.clone(),
+            session_key: format!("{}…", connection.session_key.chars().take(8).collect::<String>()),
+            client_ip: connection.client_ip.map(|ip| ip.to_string()),
+            country: connection.country.clone(),
+            device_name: connection.device.name.clone(),
+            platform: connection.device.platform,
+            user_agent: connection.device.user_agent.clone(),
+            connected_at: connection.connected_at.to_rfc3339(),
+            last_activity_at: connection.last_activity().to_rfc3339(),
+            queue_depth: connection.queue_depth(),
+        })
+        .collect();
+    Ok(warp::reply::json(&response))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/admin/connections/{id}",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "Connection to close")),
+    security(("session_key" = [])),
+    responses(
+        (status = 204, description = "Connection closed"),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 403, description = "Insufficient role", body = ErrorResponse),
+        (status = 404, description = "Unknown connection", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn close_connection_handler(
+    id: Uuid,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    if !app_state.connections.close_connection(id, CloseReason::ClosedByAdmin).await {
+        return Err(warp::reject::custom(ApiError::ConnectionNotFound));
+    }
+    tracing::info!(connection_id = %id, "Connection closed by an administrator");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/admin/config/reload",
+    tag = "admin",
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "Runtime settings now in effect", body = RuntimeConfig),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 403, description = "Insufficient role", body = ErrorResponse),
+        (status = 500, description = "Invalid configuration file", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn reload_config_handler(
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    match crate::reload::reload(&app_state).await {
+        Ok(runtime) => Ok(warp::reply::json(&runtime)),
+        Err(e) => {
+            tracing::error!(user_id = %session.user_id, error = %e, "Configuration reload failed; keeping previous settings");
+            Err(warp::reject::custom(ApiError::ConfigReloadFailed(e.to_string())))
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/admin/ip-rules",
+    tag = "admin",
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "Client address ranges currently let in and turned away", body = IpFilterConfig),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 403, description = "Insufficient role", body = ErrorResponse),
+    )
+)]
+pub async fn get_ip_rules_handler(
+    _session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let rules = app_state.ip_rules.lock().await.clone();
+    Ok(warp::reply::json(&rules))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/admin/ip-rules",
+    tag = "admin",
+    request_body = IpFilterConfig,
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "Rules replaced; WebSockets from addresses they refuse were closed", body = IpFilterConfig),
+        (status = 400, description = "The rules would refuse the caller's own address", body = ErrorResponse),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 403, description = "Insufficient role", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn set_ip_rules_handler(
+    rules: IpFilterConfig,
+    client_ip: Option<IpAddr>,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    // Refused here rather than applied, so an admin cannot lock themselves out by mistake.
+    if !ip_filter::permits(&rules, client_ip) {
+        return Err(warp::reject::custom(ApiError::invalid("The rules would refuse your own address.")));
+    }
+    let closed = ip_filter::replace_rules(&app_state, rules.clone()).await;
+    tracing::info!(user_id = %session.user_id, allow = rules.allow.len(), deny = rules.deny.len(), closed, "IP rules replaced");
+    Ok(warp::reply::json(&rules))
+}
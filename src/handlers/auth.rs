@@ -0,0 +1,859 @@
+// src/handlers/auth.rs
+
+use ed25519_dalek::VerifyingKey;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::Mutex;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::{Rejection, Reply};
+
+use crate::connections::CloseReason;
+use crate::device::{Device, TrustedDevice};
+use crate::e2e;
+use crate::error::{ApiError, ErrorResponse};
+use crate::events::DomainEvent;
+use crate::geoip::{self, Gate};
+use crate::guests;
+use crate::invites::{self, Invite};
+use crate::ldap;
+use crate::lockout;
+use crate::redact::{pseudonym, redacted};
+use crate::shards::ShardGuard;
+use crate::registrations::{self, PendingRegistration};
+use crate::wal::{self, Mutation};
+use crate::ws_handlers::{is_matrix_id, AppState, LastActive, Role, ServerMessage, User, UserSession};
+
+#[derive(Deserialize, ToSchema)]
+pub struct AuthPayload {
+    username: String,
+    password: String,
+    // Base64 Ed25519 public key for signing chat messages during this session.
+    #[serde(default)]
+    signing_key: Option<String>,
+    // Name of the device logging in (e.g. "Work laptop"), shown with its session.
+    #[serde(default)]
+    device_name: Option<String>,
+    // Invite code from `POST /invites`; registration requires one with `registration.invite_only`.
+    #[serde(default)]
+    invite_code: Option<String>,
+}
+
+// One-click revocation of a session, with the token of the alert sent about it.
+#[derive(Deserialize, ToSchema)]
+pub struct RevokeSessionPayload {
+    token: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ChangePasswordPayload {
+    current_password: String,
+    new_password: String,
+}
+
+// The caller's own password, re-verified before a sensitive change.
+#[derive(Deserialize, ToSchema)]
+pub struct ReauthPayload {
+    pub(crate) password: String,
+}
+
+// Struct for a consistent successful authentication response.
+#[derive(Serialize, ToSchema)]
+pub struct AuthResponse {
+    message: String,
+    session_key: String,
+    user_id: Uuid,
+    username: String,
+    role: Role,
+    // When a guest account is deleted (RFC 3339); absent for regular accounts.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_at: Option<String>,
+}
+
+// An invite code created by the caller.
+#[derive(Serialize, ToSchema)]
+pub struct InviteResponse {
+    code: String,
+    created_at: String,
+    expires_at: String,
+    // Username of the account registered with it, once used.
+    used_by: Option<String>,
+}
+
+impl From<&Invite> for InviteResponse {
+    fn from(invite: &Invite) -> Self {
+        InviteResponse {
+            code: invite.code.clone(),
+            created_at: invite.created_at.to_rfc3339(),
+            expires_at: invite.expires_at.to_rfc3339(),
+            used_by: invite.used_by.clone(),
+        }
+    }
+}
+
+// A registration accepted into the approval queue, in place of a session.
+#[derive(Serialize, ToSchema)]
+pub struct RegistrationPendingResponse {
+    registration_id: Uuid,
+    username: String,
+    // Always "pending".
+    status: &'static str,
+}
+
+// A device the caller marked as trusted.
+#[derive(Serialize, ToSchema)]
+pub struct TrustedDeviceResponse {
+    id: Uuid,
+    name: Option<String>,
+    platform: Option<&'static str>,
+    trusted_at: String,
+    // Whether it is the device of the session making the request.
+    current: bool,
+}
+
+impl TrustedDeviceResponse {
+    fn new(device: &TrustedDevice, session: &UserSession) -> Self {
+        TrustedDeviceResponse {
+            id: device.id,
+            name: device.name.clone(),
+            platform: device.platform,
+            trusted_at: device.trusted_at.to_rfc3339(),
+            current: session.device.fingerprint().as_deref() == Some(device.fingerprint.as_str()),
+        }
+    }
+}
+
+// Answer of the unlock endpoints.
+#[derive(Serialize, ToSchema)]
+pub struct UnlockResponse {
+    pub(crate) username: String,
+    // Whether the account was locked; unlocking an unlocked account changes nothing.
+    pub(crate) was_locked: bool,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/register",
+    tag = "auth",
+    request_body = AuthPayload,
+    responses(
+        (status = 200, description = "User registered and logged in", body = AuthResponse),
+        (status = 202, description = "Registration waiting for an admin's approval (`registration.require_approval`); no session is created", body = RegistrationPendingResponse),
+        (status = 400, description = "Missing fields or reserved username", body = ErrorResponse),
+        (status = 403, description = "Registration is not allowed from the client's country, needs a valid invite code, or is disabled by `[ldap]`", body = ErrorResponse),
+        (status = 409, description = "Username taken", body = ErrorResponse),
+        (status = 422, description = "Body is not a valid AuthPayload", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(username = %pseudonym(&payload.username)))]
+pub async fn register_handler(
+    payload: AuthPayload,
+    client_ip: Option<IpAddr>,
+    user_agent: Option<String>,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    if payload.username.is_empty() || payload.password.is_empty() {
+        return Err(warp::reject::custom(ApiError::invalid("Username and password are required.")));
+    }
+    if app_state.directory.is_some() && !app_state.config.ldap.as_ref().is_some_and(|ldap| ldap.allow_registration) {
+        return Err(warp::reject::custom(ApiError::RegistrationDisabled));
+    }
+
+    // Matrix IDs name the bridge's ghost users, and the guest prefix guest accounts.
+    if is_matrix_id(&payload.username) || guests::is_guest_name(&payload.username) {
+        return Err(warp::reject::custom(ApiError::UsernameReserved));
+    }
+
+    let signing_key = parse_signing_key_payload(payload.signing_key.as_deref()).map_err(warp::reject::custom)?;
+    let device = Device::from_login(payload.device_name.as_deref(), user_agent.as_deref()).map_err(warp::reject::custom)?;
+    let country = geoip::check(&app_state, client_ip, Gate::Registration).map_err(warp::reject::custom)?;
+    let mut users = app_state.users.lock(&payload.username).await;
+    if users.contains_key(&payload.username) || username_pending(&app_state, &payload.username).await {
+        return Err(warp::reject::custom(ApiError::UsernameTaken));
+    }
+
+    // Securely hash the password before storing.
+    let password_hash = match bcrypt::hash(&payload.password, app_state.config.auth.bcrypt_cost) {
+        Ok(hash) => hash,
+        Err(_) => return Err(warp::reject::custom(ApiError::Internal("Failed to hash password.".to_string()))),
+    };
+
+    let user = User {
+        id: Uuid::new_v4(),
+        username: payload.username.clone(),
+        password_hash,
+        role: Role::User,
+        contacts: Arc::new(Mutex::new(HashMap::new())),
+        bot_owner: None,
+        guest_until: None,
+        ldap: false,
+    };
+
+    // Used up last, once nothing else can refuse the registration.
+    let invited_by = if app_state.config.registration.invite_only {
+        match invites::redeem(&app_state, payload.invite_code.as_deref(), &payload.username).await {
+            Ok(invited_by) => Some(invited_by),
+            Err(error) => {
+                tracing::warn!(username = %pseudonym(&payload.username), client_ip = ?client_ip.map(pseudonym), "Registration refused: no valid invite code");
+                return Err(warp::reject::custom(error));
+            }
+        }
+    } else {
+        None
+    };
+
+    if app_state.config.registration.require_approval {
+        let response = queue_registration(&app_state, user, country, invited_by).await;
+        drop(users);
+        return Ok(warp::reply::with_status(warp::reply::json(&response), StatusCode::ACCEPTED));
+    }
+
+    let response = create_session(&user, client_ip, country.clone(), device, signing_key, app_state.clone()).await;
+    let mutation = Mutation::user_created(&user);
+    users.insert(payload.username.to_string(), user);
+    drop(users);
+    wal::record(&app_state, mutation).await;
+    tracing::info!(user_id = %response.user_id, username = %pseudonym(&payload.username), client_ip = ?client_ip.map(pseudonym), country = ?country, invited_by = ?invited_by, "Registered user");
+    app_state.events.publish(DomainEvent::UserRegistered { user_id: response.user_id, username: payload.username.clone(), country });
+    Ok(warp::reply::with_status(warp::reply::json(&response), StatusCode::OK))
+}
+
+/// Whether a registration of `username` is waiting for approval, which reserves the name.
+pub(crate) async fn username_pending(app_state: &AppState, username: &str) -> bool {
+    registrations::find_by_username(&*app_state.registrations.lock().await, username).is_some()
+}
+
+/// Puts the registration of `user` in the approval queue and tells the admins about it.
+async fn queue_registration(app_state: &AppState, user: User, country: Option<String>, invited_by: Option<Uuid>) -> RegistrationPendingResponse {
+    let registration = PendingRegistration { id: Uuid::new_v4(), user, country, requested_at: chrono::Utc::now() };
+    let (registration_id, username) = (registration.id, registration.user.username.clone());
+    let notice = ServerMessage::RegistrationPending {
+        registration_id,
+        username: username.clone(),
+        requested_at: registration.requested_at.to_rfc3339(),
+    };
+    let country = registration.country.clone();
+    app_state.registrations.lock().await.insert(registration_id, registration);
+    tracing::info!(registration_id = %registration_id, username = %pseudonym(&username), country = ?country, invited_by = ?invited_by, "Registration awaiting approval");
+    if let Ok(json) = serde_json::to_string(&notice) {
+        for moderator in app_state.users.filter(|user| user.role >= Role::Moderator) {
+            app_state.connections.send_to_user(moderator.id, &json).await;
+        }
+    }
+    app_state.events.publish(DomainEvent::RegistrationRequested { registration_id, username: username.clone(), country });
+    RegistrationPendingResponse { registration_id, username, status: "pending" }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/guests",
+    tag = "auth",
+    responses(
+        (status = 200, description = "Guest account created and logged in; `expires_at` tells when it is deleted", body = AuthResponse),
+        (status = 403, description = "Registration is not allowed from the client's country", body = ErrorResponse),
+        (status = 501, description = "Guest accounts are not enabled (`registration.allow_guests`)", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all)]
+pub async fn create_guest_handler(
+    client_ip: Option<IpAddr>,
+    user_agent: Option<String>,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    if !app_state.config.registration.allow_guests {
+        return Err(warp::reject::custom(ApiError::NotConfigured("Guest access".to_string())));
+    }
+    let device = Device::from_login(None, user_agent.as_deref()).map_err(warp::reject::custom)?;
+    let country = geoip::check(&app_state, client_ip, Gate::Registration).map_err(warp::reject::custom)?;
+
+    // Guest names are reserved, so only another guest can hold one already.
+    let (mut users, username) = loop {
+        let username = guests::generate_username();
+        let users = app_state.users.lock(&username).await;
+        if !users.contains_key(&username) {
+            break (users, username);
+        }
+    };
+    let user = User {
+        id: Uuid::new_v4(),
+        username: username.clone(),
+        password_hash: String::new(),
+        role: Role::User,
+        contacts: Arc::new(Mutex::new(HashMap::new())),
+        bot_owner: None,
+        guest_until: Some(guests::expiry(&app_state)),
+        ldap: false,
+    };
+
+    // Neither logged nor announced as a registration: guests are gone after a restart anyway.
+    let response = create_session(&user, client_ip, country.clone(), device, None, app_state.clone()).await;
+    users.insert(username.clone(), user);
+    drop(users);
+    tracing::info!(user_id = %response.user_id, username = %pseudonym(&username), client_ip = ?client_ip.map(pseudonym), country = ?country, "Guest account created");
+    Ok(warp::reply::json(&response))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/login",
+    tag = "auth",
+    request_body = AuthPayload,
+    responses(
+        (status = 200, description = "Logged in; any previous session is revoked", body = AuthResponse),
+        (status = 400, description = "Missing fields", body = ErrorResponse),
+        (status = 401, description = "Invalid username or password", body = ErrorResponse),
+        (status = 403, description = "Login is not allowed from the client's country, or the account waits for an admin's approval", body = ErrorResponse),
+        (status = 422, description = "Body is not a valid AuthPayload", body = ErrorResponse),
+        (status = 503, description = "The `[ldap]` directory could not check the password", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(username = %pseudonym(&payload.username)))]
+pub async fn login_handler(
+    payload: AuthPayload,
+    client_ip: Option<IpAddr>,
+    user_agent: Option<String>,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+     if payload.username.is_empty() || payload.password.is_empty() {
+        return Err(warp::reject::custom(ApiError::invalid("Username and password are required.")));
+    }
+    let signing_key = parse_signing_key_payload(payload.signing_key.as_deref()).map_err(warp::reject::custom)?;
+    let device = Device::from_login(payload.device_name.as_deref(), user_agent.as_deref()).map_err(warp::reject::custom)?;
+    let country = geoip::check(&app_state, client_ip, Gate::Login).map_err(warp::reject::custom)?;
+
+    // Not holding the users shard while a directory checks the password.
+    let user = app_state.users.lock(&payload.username).await.get(&payload.username).cloned();
+    match user {
+        Some(user) => {
+            verify_login_password(&app_state, &user, &payload.password, client_ip).await.map_err(warp::reject::custom)?;
+            let users = lock_unchanged(&app_state, &user).await.ok_or_else(|| warp::reject::custom(ApiError::InvalidCredentials))?;
+            let platform = device.platform;
+            let response = create_session(&user, client_ip, country.clone(), device, signing_key, app_state.clone()).await;
+            drop(users);
+            tracing::info!(target: "audit", user_id = %response.user_id, username = %pseudonym(&payload.username), client_ip = ?client_ip.map(pseudonym), country = ?country, platform, "Logged in user");
+            Ok(warp::reply::json(&response))
+        }
+        None => {
+            // Only told to whoever knows the password, so the queue does not reveal usernames.
+            let registrations = app_state.registrations.lock().await;
+            let pending = registrations::find_by_username(&registrations, &payload.username);
+            if pending.is_some_and(|registration| password_matches(&registration.user, &payload.password)) {
+                tracing::info!(username = %pseudonym(&payload.username), client_ip = ?client_ip.map(pseudonym), "Failed login: registration awaiting approval");
+                return Err(warp::reject::custom(ApiError::RegistrationPending));
+            }
+            drop(registrations);
+            if let Some(user) = provision_ldap_user(&app_state, &payload.username, &payload.password, country.clone()).await.map_err(warp::reject::custom)? {
+                let users = lock_unchanged(&app_state, &user).await.ok_or_else(|| warp::reject::custom(ApiError::InvalidCredentials))?;
+                let platform = device.platform;
+                let response = create_session(&user, client_ip, country.clone(), device, signing_key, app_state.clone()).await;
+                drop(users);
+                tracing::info!(target: "audit", user_id = %response.user_id, username = %pseudonym(&payload.username), client_ip = ?client_ip.map(pseudonym), country = ?country, platform, "Logged in user");
+                return Ok(warp::reply::json(&response));
+            }
+            tracing::warn!(username = %pseudonym(&payload.username), client_ip = ?client_ip.map(pseudonym), "Failed login: unknown user");
+            Err(warp::reject::custom(ApiError::InvalidCredentials))
+        }
+    }
+}
+
+/// Parses the optional signing key of a login or registration request.
+fn parse_signing_key_payload(signing_key: Option<&str>) -> Result<Option<VerifyingKey>, ApiError> {
+    signing_key
+        .map(|key| {
+            e2e::parse_signing_key(key)
+                .ok_or_else(|| ApiError::invalid("signing_key must be a base64 Ed25519 public key."))
+        })
+        .transpose()
+}
+
+/// Securely verifies a password against the stored hash. Bot accounts and bridged users
+/// have no password and never match.
+fn password_matches(user: &User, password: &str) -> bool {
+    user.bot_owner.is_none() && bcrypt::verify(password, &user.password_hash).unwrap_or(false)
+}
+
+/// Checks `password` against the directory for accounts provisioned from it, and
+/// against the stored hash for the others.
+pub(crate) async fn password_verified(app_state: &AppState, user: &User, password: &str) -> Result<bool, ApiError> {
+    if user.ldap {
+        ldap::authenticate(app_state, &user.username, password).await
+    } else {
+        Ok(password_matches(user, password))
+    }
+}
+
+/// Creates the account of a directory user logging in for the first time, once the directory
+/// accepts their password. None when the directory refuses it (or is not configured), and
+/// when the name was taken meanwhile.
+async fn provision_ldap_user(app_state: &AppState, username: &str, password: &str, country: Option<String>) -> Result<Option<User>, ApiError> {
+    if !ldap::authenticate(app_state, username, password).await? {
+        return Ok(None);
+    }
+    // The directory answered without the users shard held, so check the name again.
+    let mut users = app_state.users.lock(username).await;
+    if users.contains_key(username) || username_pending(app_state, username).await {
+        return Ok(None);
+    }
+    let user = User {
+        id: Uuid::new_v4(),
+        username: username.to_string(),
+        password_hash: String::new(),
+        role: Role::User,
+        contacts: Arc::new(Mutex::new(HashMap::new())),
+        bot_owner: None,
+        guest_until: None,
+        ldap: true,
+    };
+    let mutation = Mutation::user_created(&user);
+    users.insert(username.to_string(), user.clone());
+    drop(users);
+    wal::record(app_state, mutation).await;
+    tracing::info!(user_id = %user.id, username = %pseudonym(username), country = ?country, "Provisioned user from the directory");
+    app_state.events.publish(DomainEvent::UserRegistered { user_id: user.id, username: username.to_string(), country });
+    Ok(Some(user))
+}
+
+/// Locks `user`'s shard of `users` again once their password checked out, for
+/// [`create_session`]. None when the account was deleted or replaced meanwhile.
+async fn lock_unchanged<'a>(app_state: &'a AppState, user: &User) -> Option<ShardGuard<'a, String, User>> {
+    let users = app_state.users.lock(&user.username).await;
+    users.get(&user.username).is_some_and(|current| current.id == user.id).then_some(users)
+}
+
+/// Checks the password of a login, unless the account is locked. Wrong passwords count
+/// towards `auth.max_failed_logins`.
+async fn verify_login_password(app_state: &AppState, user: &User, password: &str, client_ip: Option<IpAddr>) -> Result<(), ApiError> {
+    if lockout::locked_until(app_state, user.id).await.is_some() {
+        tracing::warn!(user_id = %user.id, client_ip = ?client_ip.map(pseudonym), "Failed login: account locked");
+        return Err(ApiError::AccountLocked);
+    }
+    if password_verified(app_state, user, password).await? {
+        lockout::clear(app_state, user.id).await;
+        return Ok(());
+    }
+    tracing::warn!(username = %pseudonym(&user.username), client_ip = ?client_ip.map(pseudonym), "Failed login: wrong password");
+    record_wrong_password(app_state, user.id, &user.username).await;
+    Err(ApiError::InvalidCredentials)
+}
+
+/// Counts a wrong password of `user_id`, given at login or re-entered in a session. The one
+/// reaching `auth.max_failed_logins` locks the account and tells its owner.
+pub(crate) async fn record_wrong_password(app_state: &AppState, user_id: Uuid, username: &str) {
+    if let Some(locked_until) = lockout::record_failure(app_state, user_id).await {
+        let notice = ServerMessage::AccountLocked { locked_until: locked_until.to_rfc3339() };
+        if let Ok(json) = serde_json::to_string(&notice) {
+            app_state.connections.send_to_user(user_id, &json).await;
+        }
+        app_state.events.publish(DomainEvent::AccountLocked { user_id, username: username.to_string(), locked_until });
+    }
+}
+
+/// Lifts the lock of `user_id`'s account on behalf of `unlocked_by`, telling the user's
+/// sessions when it was locked. Returns whether it was.
+pub(crate) async fn unlock_account(app_state: &AppState, user_id: Uuid, username: &str, unlocked_by: Uuid) -> bool {
+    if !lockout::clear(app_state, user_id).await {
+        return false;
+    }
+    let notice = ServerMessage::AccountUnlocked { by_admin: unlocked_by != user_id };
+    if let Ok(json) = serde_json::to_string(&notice) {
+        app_state.connections.send_to_user(user_id, &json).await;
+    }
+    app_state.events.publish(DomainEvent::AccountUnlocked { user_id, username: username.to_string(), unlocked_by });
+    true
+}
+
+/// Logs in with a username and password outside the HTTP API (e.g. the XMPP gateway). Like
+/// `POST /login`, this replaces any existing session of the user.
+#[cfg(feature = "xmpp")]
+pub async fn password_login(
+    app_state: &Arc<AppState>,
+    username: &str,
+    password: &str,
+    client_ip: Option<IpAddr>,
+) -> Option<UserSession> {
+    let country = geoip::check(app_state, client_ip, Gate::Login).ok()?;
+    let user = app_state.users.lock(username).await.get(username).cloned();
+    let user = match user {
+        Some(user) => {
+            verify_login_password(app_state, &user, password, client_ip).await.ok()?;
+            user
+        }
+        None => provision_ldap_user(app_state, username, password, country.clone()).await.ok()??,
+    };
+    let users = lock_unchanged(app_state, &user).await?;
+    let response = create_session(&user, client_ip, country, Device::default(), None, app_state.clone()).await;
+    drop(users);
+    app_state.session_for_key(&response.session_key).await
+}
+
+/// Tells `user`'s connected sessions and push devices of a login from a device (or, for
+/// clients not telling theirs, an address) they have not logged in from recently, with a
+/// token revoking the session it opens.
+async fn alert_unfamiliar_login(app_state: &AppState, user: &User, client_ip: Option<IpAddr>, country: Option<String>, device: &Device, session_key: &str) {
+    let ttl = chrono::Duration::seconds(app_state.config.auth.session_ttl_secs as i64);
+    let revoke_token = app_state.login_alerts.lock().await.issue_revoke_token(user.id, session_key, ttl);
+    let alert = ServerMessage::SecurityAlert {
+        kind: "new_login",
+        client_ip,
+        country: country.clone(),
+        device_name: device.name.clone(),
+        platform: device.platform,
+        logged_in_at: chrono::Utc::now().to_rfc3339(),
+        revoke_token: revoke_token.clone(),
+    };
+    if let Ok(json) = serde_json::to_string(&alert) {
+        app_state.connections.send_to_user(user.id, &json).await;
+    }
+    tracing::warn!(user_id = %user.id, client_ip = ?client_ip.map(pseudonym), country = ?country, platform = device.platform, "Login from an unfamiliar device");
+    app_state.events.publish(DomainEvent::UnfamiliarLogin {
+        user_id: user.id,
+        username: user.username.clone(),
+        client_ip,
+        country,
+        device_name: device.name.clone(),
+        platform: device.platform,
+        revoke_token,
+    });
+}
+
+/// Removes every session of `user` but `keep`, closing the connections opened with them.
+async fn revoke_sessions(app_state: &AppState, user: &User, keep: Option<&str>) {
+    // Sessions are sharded by key, so each shard is searched in turn.
+    for shard in app_state.user_sessions.shards() {
+        let mut user_sessions_guard = shard.lock().await;
+
+        // Collect session keys to remove
+        let session_keys_to_remove: Vec<String> = user_sessions_guard
+            .iter()
+            .filter(|(session_key, session)| session.user_id == user.id && Some(session_key.as_str()) != keep)
+            .map(|(session_key, _)| session_key.clone())
+            .collect();
+
+        for old_session_key in session_keys_to_remove {
+            user_sessions_guard.remove(&old_session_key);
+            // Drop every connection opened with the old session (its WebSocket, told why, and any event streams).
+            if app_state.connections.close_session(&old_session_key, CloseReason::SessionRevoked).await > 0 {
+                tracing::info!(user_id = %user.id, username = %pseudonym(&user.username), session_key = %redacted(&old_session_key), "Closed old WebSocket connection");
+            }
+        }
+    }
+}
+
+/// Helper function to create a new session for a user.
+async fn create_session(
+    user: &User,
+    client_ip: Option<IpAddr>,
+    country: Option<String>,
+    device: Device,
+    signing_key: Option<VerifyingKey>,
+    app_state: Arc<AppState>,
+) -> AuthResponse {
+    let new_session_key = Uuid::new_v4().to_string();
+
+    // Warn the sessions about to be replaced while they are still connected, unless the new
+    // one is on a device the user trusts.
+    let fingerprint = device.fingerprint();
+    let trusted = match &fingerprint {
+        Some(fingerprint) => app_state.trusted_devices.lock().await.get(&user.id).is_some_and(|devices| devices.iter().any(|device| device.fingerprint == *fingerprint)),
+        None => false,
+    };
+    if app_state.login_alerts.lock().await.record_login(user.id, client_ip, fingerprint.as_deref()) && !trusted {
+        alert_unfamiliar_login(&app_state, user, client_ip, country.clone(), &device, &new_session_key).await;
+    }
+    
+    // Callers hold the lock of the user's shard of `users`, so two logins of the same user
+    // cannot interleave here.
+    revoke_sessions(&app_state, user, None).await;
+
+    let new_session = UserSession {
+        user_id: user.id,
+        username: user.username.clone(),
+        session_key: new_session_key.clone(),
+        created_at: Instant::now(),
+        client_ip,
+        country,
+        device,
+        trusted_device: Arc::new(AtomicBool::new(trusted)),
+        signing_key,
+        last_active: LastActive::now(),
+    };
+    app_state.user_sessions.lock(&new_session_key).await.insert(new_session_key.clone(), new_session);
+
+    AuthResponse {
+        message: "Authentication successful".to_string(),
+        session_key: new_session_key,
+        user_id: user.id,
+        username: user.username.clone(),
+        role: user.role,
+        expires_at: user.guest_until.map(|until| until.to_rfc3339()),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/sessions/revoke",
+    tag = "auth",
+    request_body = RevokeSessionPayload,
+    responses(
+        (status = 204, description = "Session revoked, or already ended"),
+        (status = 404, description = "Unknown, used or expired token", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all)]
+pub async fn revoke_session_handler(payload: RevokeSessionPayload, app_state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    // The token is the credential: the alert it came with reached the account's owner, who
+    // may not have a session any more once the new login replaced theirs.
+    let revoke = app_state.login_alerts.lock().await.redeem(&payload.token);
+    let revoke = revoke.ok_or_else(|| warp::reject::custom(ApiError::TokenNotFound))?;
+    app_state.user_sessions.lock(&revoke.session_key).await.remove(&revoke.session_key);
+    app_state.connections.close_session(&revoke.session_key, CloseReason::SessionRevoked).await;
+    tracing::info!(user_id = %revoke.user_id, session_key = %redacted(&revoke.session_key), "Session revoked from a new-login alert");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/invites",
+    tag = "auth",
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "A new invite code", body = InviteResponse),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 403, description = "The caller has as many pending invites as `registration.invites_per_user` allows", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn create_invite_handler(session: UserSession, app_state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let user = app_state.users.get(&session.username).ok_or_else(|| warp::reject::custom(ApiError::InvalidSession))?;
+    if user.guest_until.is_some() {
+        return Err(warp::reject::custom(ApiError::GuestNotAllowed));
+    }
+    let invite = invites::create(&app_state, session.user_id, user.role == Role::Admin).await.map_err(warp::reject::custom)?;
+    tracing::info!(user_id = %session.user_id, expires_at = %invite.expires_at, "Invite created");
+    Ok(warp::reply::json(&InviteResponse::from(&invite)))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/invites",
+    tag = "auth",
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "The caller's invites, used and pending, oldest first", body = [InviteResponse]),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn list_invites_handler(session: UserSession, app_state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let invites = app_state.invites.lock().await;
+    let mut own: Vec<&Invite> = invites.values().filter(|invite| invite.created_by == session.user_id).collect();
+    own.sort_by_key(|invite| invite.created_at);
+    let response: Vec<InviteResponse> = own.into_iter().map(InviteResponse::from).collect();
+    Ok(warp::reply::json(&response))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/invites/{code}",
+    tag = "auth",
+    params(("code" = String, Path, description = "Invite to withdraw")),
+    security(("session_key" = [])),
+    responses(
+        (status = 204, description = "Invite withdrawn"),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 404, description = "No such invite of the caller", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn delete_invite_handler(code: String, session: UserSession, app_state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let mut invites = app_state.invites.lock().await;
+    match invites.get(&code) {
+        Some(invite) if invite.created_by == session.user_id => {
+            invites.remove(&code);
+            tracing::info!(user_id = %session.user_id, "Invite withdrawn");
+            Ok(StatusCode::NO_CONTENT)
+        }
+        _ => Err(warp::reject::custom(ApiError::InviteNotFound)),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/me/password",
+    tag = "auth",
+    request_body = ChangePasswordPayload,
+    security(("session_key" = [])),
+    responses(
+        (status = 204, description = "Password changed; the user's other sessions are revoked"),
+        (status = 400, description = "Empty new password, or an account of the `[ldap]` directory", body = ErrorResponse),
+        (status = 401, description = "Invalid session or wrong current password", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn change_password_handler(
+    payload: ChangePasswordPayload,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    if payload.new_password.is_empty() {
+        return Err(warp::reject::custom(ApiError::invalid("new_password cannot be empty.")));
+    }
+    let mut users = app_state.users.lock(&session.username).await;
+    let user = users.get_mut(&session.username).ok_or_else(|| warp::reject::custom(ApiError::InvalidSession))?;
+    if user.ldap {
+        return Err(warp::reject::custom(ApiError::invalid("Passwords of directory accounts are changed in the directory.")));
+    }
+    if !password_matches(user, &payload.current_password) {
+        tracing::warn!(user_id = %session.user_id, "Password change failed: wrong current password");
+        record_wrong_password(&app_state, session.user_id, &session.username).await;
+        return Err(warp::reject::custom(ApiError::InvalidCredentials));
+    }
+    user.password_hash = bcrypt::hash(&payload.new_password, app_state.config.auth.bcrypt_cost)
+        .map_err(|_| warp::reject::custom(ApiError::Internal("Failed to hash password.".to_string())))?;
+    let mutation = Mutation::PasswordChanged { user_id: user.id, password_hash: user.password_hash.clone() };
+    // Whoever else knew the old password loses the sessions they opened with it.
+    revoke_sessions(&app_state, user, Some(&session.session_key)).await;
+    drop(users);
+    wal::record(&app_state, mutation).await;
+    tracing::info!(target: "audit", user_id = %session.user_id, "Password changed");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/me/trusted-devices",
+    tag = "auth",
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "The caller's trusted devices, oldest first", body = [TrustedDeviceResponse]),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn list_trusted_devices_handler(session: UserSession, app_state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let trusted_devices = app_state.trusted_devices.lock().await;
+    let response: Vec<TrustedDeviceResponse> = trusted_devices
+        .get(&session.user_id)
+        .into_iter()
+        .flatten()
+        .map(|device| TrustedDeviceResponse::new(device, &session))
+        .collect();
+    Ok(warp::reply::json(&response))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/me/trusted-devices",
+    tag = "auth",
+    request_body = ReauthPayload,
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "The session's device is trusted", body = TrustedDeviceResponse),
+        (status = 400, description = "The session's client did not tell its device", body = ErrorResponse),
+        (status = 401, description = "Invalid session or wrong password", body = ErrorResponse),
+        (status = 503, description = "The `[ldap]` directory could not check the password", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn trust_device_handler(
+    payload: ReauthPayload,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let user = app_state.users.get(&session.username).ok_or_else(|| warp::reject::custom(ApiError::InvalidSession))?;
+    if !password_verified(&app_state, &user, &payload.password).await.map_err(warp::reject::custom)? {
+        tracing::warn!(user_id = %session.user_id, "Trust device failed: wrong password");
+        record_wrong_password(&app_state, session.user_id, &session.username).await;
+        return Err(warp::reject::custom(ApiError::InvalidCredentials));
+    }
+    let Some(fingerprint) = session.device.fingerprint() else {
+        return Err(warp::reject::custom(ApiError::invalid("The session's client sent neither a device_name nor a User-Agent.")));
+    };
+    let mut trusted_devices = app_state.trusted_devices.lock().await;
+    let devices = trusted_devices.entry(session.user_id).or_default();
+    if !devices.iter().any(|device| device.fingerprint == fingerprint) {
+        devices.push(TrustedDevice {
+            id: Uuid::new_v4(),
+            fingerprint: fingerprint.clone(),
+            name: session.device.name.clone(),
+            platform: session.device.platform,
+            trusted_at: chrono::Utc::now(),
+        });
+        tracing::info!(user_id = %session.user_id, platform = session.device.platform, "Device trusted");
+    }
+    let device = devices.iter().find(|device| device.fingerprint == fingerprint).expect("trusted above");
+    session.trusted_device.store(true, Ordering::Relaxed);
+    Ok(warp::reply::json(&TrustedDeviceResponse::new(device, &session)))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/me/trusted-devices/{id}",
+    tag = "auth",
+    params(("id" = Uuid, Path, description = "Trusted device to distrust")),
+    security(("session_key" = [])),
+    responses(
+        (status = 204, description = "Device no longer trusted"),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 404, description = "Unknown trusted device", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn distrust_device_handler(
+    id: Uuid,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let mut trusted_devices = app_state.trusted_devices.lock().await;
+    let devices = trusted_devices.get_mut(&session.user_id).ok_or_else(|| warp::reject::custom(ApiError::DeviceNotFound))?;
+    let index = devices.iter().position(|device| device.id == id).ok_or_else(|| warp::reject::custom(ApiError::DeviceNotFound))?;
+    let device = devices.remove(index);
+    drop(trusted_devices);
+    // Sessions on the device fall back to the untrusted TTL, which may already have run out.
+    for shard in app_state.user_sessions.shards() {
+        let sessions = shard.lock().await;
+        for user_session in sessions.values().filter(|user_session| user_session.user_id == session.user_id) {
+            if user_session.device.fingerprint().as_deref() == Some(device.fingerprint.as_str()) {
+                user_session.trusted_device.store(false, Ordering::Relaxed);
+            }
+        }
+    }
+    tracing::info!(user_id = %session.user_id, device_id = %id, "Device no longer trusted");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/me/unlock",
+    tag = "auth",
+    request_body = ReauthPayload,
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "Account unlocked, or was not locked", body = UnlockResponse),
+        (status = 401, description = "Invalid session or wrong password", body = ErrorResponse),
+        (status = 503, description = "The `[ldap]` directory could not check the password", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn unlock_self_handler(
+    payload: ReauthPayload,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    // A lock only refuses password logins, so its owner can still unlock it from a session
+    // that was open before, by proving they know the password.
+    let user = app_state.users.get(&session.username).ok_or_else(|| warp::reject::custom(ApiError::InvalidSession))?;
+    if !password_verified(&app_state, &user, &payload.password).await.map_err(warp::reject::custom)? {
+        tracing::warn!(user_id = %session.user_id, "Unlock failed: wrong password");
+        record_wrong_password(&app_state, session.user_id, &session.username).await;
+        return Err(warp::reject::custom(ApiError::InvalidCredentials));
+    }
+    let was_locked = unlock_account(&app_state, session.user_id, &session.username, session.user_id).await;
+    tracing::info!(user_id = %session.user_id, was_locked, "User unlocked their account");
+    Ok(warp::reply::json(&UnlockResponse { username: session.username, was_locked }))
+}
@@ -0,0 +1,244 @@
+// src/handlers/bots.rs
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::{Rejection, Reply};
+
+use crate::bots::{self, ApiToken, TokenScope};
+use crate::error::{ApiError, ErrorResponse};
+use crate::handlers::auth::username_pending;
+use crate::wal::{self, Mutation};
+use crate::ws_handlers::{AppState, Role, User, UserSession};
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateBotPayload {
+    username: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateApiTokenPayload {
+    scopes: Vec<TokenScope>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct BotResponse {
+    id: Uuid,
+    username: String,
+}
+
+// An API token. The secret is only returned when the token is issued.
+#[derive(Serialize, ToSchema)]
+pub struct ApiTokenResponse {
+    id: Uuid,
+    scopes: Vec<TokenScope>,
+    created_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token: Option<String>,
+}
+
+impl From<&ApiToken> for ApiTokenResponse {
+    fn from(token: &ApiToken) -> Self {
+        ApiTokenResponse { id: token.id, scopes: token.scopes.clone(), created_at: token.created_at.to_rfc3339(), token: None }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/bots",
+    tag = "bots",
+    request_body = CreateBotPayload,
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "Bot account created and added to the owner's contacts", body = BotResponse),
+        (status = 400, description = "Missing username", body = ErrorResponse),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 409, description = "Username taken", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn create_bot_handler(
+    payload: CreateBotPayload,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let username = payload.username.trim().to_string();
+    if username.is_empty() {
+        return Err(warp::reject::custom(ApiError::invalid("username cannot be empty")));
+    }
+
+    let owner = match app_state.users.get(&session.username) {
+        Some(owner) => owner,
+        None => return Err(warp::reject::custom(ApiError::InvalidSession)),
+    };
+    if owner.guest_until.is_some() {
+        return Err(warp::reject::custom(ApiError::GuestNotAllowed));
+    }
+    let mut users = app_state.users.lock(&username).await;
+    if users.contains_key(&username) || username_pending(&app_state, &username).await {
+        return Err(warp::reject::custom(ApiError::UsernameTaken));
+    }
+
+    let bot = User {
+        id: Uuid::new_v4(),
+        username: username.clone(),
+        password_hash: String::new(),
+        role: Role::User,
+        contacts: Arc::new(Mutex::new(HashMap::from([(owner.id, owner.username.clone())]))),
+        bot_owner: Some(owner.id),
+        guest_until: None,
+        ldap: false,
+    };
+    // The owner and the bot are mutual contacts so they can message each other right away.
+    owner.contacts.lock().await.insert(bot.id, bot.username.clone());
+    let response = BotResponse { id: bot.id, username: bot.username.clone() };
+    let mutation = Mutation::user_created(&bot);
+    users.insert(username, bot);
+    drop(users);
+    wal::record(&app_state, mutation).await;
+    wal::record(&app_state, Mutation::ContactAdded { user_id: owner.id, contact_id: response.id }).await;
+
+    tracing::info!(user_id = %session.user_id, bot_user_id = %response.id, bot_username = %response.username, "Bot account created");
+    Ok(warp::reply::json(&response))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/bots",
+    tag = "bots",
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "Bots owned by the user", body = [BotResponse]),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn list_bots_handler(
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let bots: Vec<BotResponse> = app_state
+        .users
+        .filter(|user| user.bot_owner == Some(session.user_id))
+        .into_iter()
+        .map(|bot| BotResponse { id: bot.id, username: bot.username })
+        .collect();
+    Ok(warp::reply::json(&bots))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/bots/{id}/tokens",
+    tag = "bots",
+    params(("id" = Uuid, Path, description = "Bot the token authenticates")),
+    request_body = CreateApiTokenPayload,
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "Token issued; the response includes its secret", body = ApiTokenResponse),
+        (status = 400, description = "Empty scope list", body = ErrorResponse),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 404, description = "Unknown bot", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id, bot_user_id = %bot_id))]
+pub async fn create_api_token_handler(
+    bot_id: Uuid,
+    payload: CreateApiTokenPayload,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    if payload.scopes.is_empty() {
+        return Err(warp::reject::custom(ApiError::invalid("At least one token scope is required.")));
+    }
+    let bot_username = owned_bot_username(&app_state, &session, bot_id).await?;
+
+    let secret = bots::generate_token();
+    let token = ApiToken {
+        id: Uuid::new_v4(),
+        bot_user_id: bot_id,
+        bot_username,
+        scopes: payload.scopes,
+        created_at: Utc::now(),
+    };
+    tracing::info!(user_id = %session.user_id, bot_user_id = %bot_id, token_id = %token.id, scopes = ?token.scopes, "API token issued");
+
+    let response = ApiTokenResponse { token: Some(secret.clone()), ..ApiTokenResponse::from(&token) };
+    app_state.api_tokens.lock().await.insert(secret, token);
+    Ok(warp::reply::json(&response))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/bots/{id}/tokens",
+    tag = "bots",
+    params(("id" = Uuid, Path, description = "Bot whose tokens are listed")),
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "The bot's tokens", body = [ApiTokenResponse]),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 404, description = "Unknown bot", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id, bot_user_id = %bot_id))]
+pub async fn list_api_tokens_handler(
+    bot_id: Uuid,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    owned_bot_username(&app_state, &session, bot_id).await?;
+    let tokens = app_state.api_tokens.lock().await;
+    let response: Vec<ApiTokenResponse> = tokens
+        .values()
+        .filter(|token| token.bot_user_id == bot_id)
+        .map(ApiTokenResponse::from)
+        .collect();
+    Ok(warp::reply::json(&response))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/bots/{id}/tokens/{token_id}",
+    tag = "bots",
+    params(
+        ("id" = Uuid, Path, description = "Bot the token belongs to"),
+        ("token_id" = Uuid, Path, description = "Token to revoke"),
+    ),
+    security(("session_key" = [])),
+    responses(
+        (status = 204, description = "Token revoked"),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 404, description = "Unknown bot or token", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id, bot_user_id = %bot_id))]
+pub async fn revoke_api_token_handler(
+    bot_id: Uuid,
+    token_id: Uuid,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    owned_bot_username(&app_state, &session, bot_id).await?;
+    let mut tokens = app_state.api_tokens.lock().await;
+    let before = tokens.len();
+    tokens.retain(|_, token| !(token.id == token_id && token.bot_user_id == bot_id));
+    if tokens.len() == before {
+        return Err(warp::reject::custom(ApiError::TokenNotFound));
+    }
+    tracing::info!(user_id = %session.user_id, bot_user_id = %bot_id, token_id = %token_id, "API token revoked");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Looks up a bot owned by the session's user, returning its username.
+async fn owned_bot_username(app_state: &Arc<AppState>, session: &UserSession, bot_id: Uuid) -> Result<String, Rejection> {
+    app_state
+        .users
+        .get_by_id(&bot_id)
+        .filter(|user| user.bot_owner == Some(session.user_id))
+        .map(|bot| bot.username)
+        .ok_or_else(|| warp::reject::custom(ApiError::BotNotFound))
+}
@@ -0,0 +1,192 @@
+// src/handlers/broadcasts.rs
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::{Rejection, Reply};
+
+use crate::broadcasts::{BroadcastList, MAX_BROADCAST_RECIPIENTS};
+use crate::error::{ApiError, ErrorResponse};
+use crate::ws_handlers::{send_broadcast, AppState, UserSession};
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateBroadcastListPayload {
+    name: String,
+    // Contacts the list's messages go to.
+    recipient_ids: Vec<Uuid>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct BroadcastMessagePayload {
+    message: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct BroadcastListResponse {
+    id: Uuid,
+    name: String,
+    recipient_ids: Vec<Uuid>,
+    created_at: String,
+}
+
+impl From<&BroadcastList> for BroadcastListResponse {
+    fn from(list: &BroadcastList) -> Self {
+        BroadcastListResponse {
+            id: list.id,
+            name: list.name.clone(),
+            recipient_ids: list.recipient_ids.clone(),
+            created_at: list.created_at.to_rfc3339(),
+        }
+    }
+}
+
+// Delivery of a broadcast message to one recipient.
+#[derive(Serialize, ToSchema)]
+pub struct BroadcastReceiptResponse {
+    pub(crate) to_user_id: Uuid,
+    pub(crate) message_id: String,
+    // Whether one of the recipient's connections received it (otherwise it was pushed).
+    pub(crate) delivered: bool,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct BroadcastMessageResponse {
+    receipts: Vec<BroadcastReceiptResponse>,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/broadcast-lists",
+    tag = "broadcasts",
+    request_body = CreateBroadcastListPayload,
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "Broadcast list created", body = BroadcastListResponse),
+        (status = 400, description = "Empty name, or no or too many recipients", body = ErrorResponse),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 403, description = "A recipient is not a contact", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn create_broadcast_list_handler(
+    payload: CreateBroadcastListPayload,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let name = payload.name.trim();
+    if name.is_empty() {
+        return Err(warp::reject::custom(ApiError::invalid("name cannot be empty")));
+    }
+    let mut recipient_ids = payload.recipient_ids;
+    recipient_ids.sort_unstable();
+    recipient_ids.dedup();
+    if recipient_ids.is_empty() || recipient_ids.len() > MAX_BROADCAST_RECIPIENTS {
+        return Err(warp::reject::custom(ApiError::invalid(format!("A broadcast list needs between 1 and {} recipients.", MAX_BROADCAST_RECIPIENTS))));
+    }
+
+    let user = app_state.users.get(&session.username);
+    let all_contacts = match user {
+        Some(user) => {
+            let contacts = user.contacts.lock().await;
+            recipient_ids.iter().all(|id| contacts.contains_key(id))
+        }
+        None => false,
+    };
+    if !all_contacts {
+        tracing::warn!(user_id = %session.user_id, "Create broadcast list failed: a recipient is not a contact");
+        return Err(warp::reject::custom(ApiError::NotAContact));
+    }
+
+    let list = BroadcastList {
+        id: Uuid::new_v4(),
+        owner_user_id: session.user_id,
+        name: name.to_string(),
+        recipient_ids,
+        created_at: Utc::now(),
+    };
+    tracing::info!(user_id = %session.user_id, list_id = %list.id, recipients = list.recipient_ids.len(), "Broadcast list created");
+    let response = BroadcastListResponse::from(&list);
+    app_state.broadcast_lists.lock().await.insert(list.id, list);
+    Ok(warp::reply::json(&response))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/broadcast-lists",
+    tag = "broadcasts",
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "The user's broadcast lists", body = [BroadcastListResponse]),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn list_broadcast_lists_handler(
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let lists = app_state.broadcast_lists.lock().await;
+    let response: Vec<BroadcastListResponse> = lists
+        .values()
+        .filter(|list| list.owner_user_id == session.user_id)
+        .map(BroadcastListResponse::from)
+        .collect();
+    Ok(warp::reply::json(&response))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/broadcast-lists/{id}",
+    tag = "broadcasts",
+    params(("id" = Uuid, Path, description = "Broadcast list to delete")),
+    security(("session_key" = [])),
+    responses(
+        (status = 204, description = "Broadcast list deleted"),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 404, description = "Unknown list", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn delete_broadcast_list_handler(
+    id: Uuid,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let mut lists = app_state.broadcast_lists.lock().await;
+    match lists.get(&id) {
+        Some(list) if list.owner_user_id == session.user_id => {
+            lists.remove(&id);
+            tracing::info!(user_id = %session.user_id, list_id = %id, "Broadcast list deleted");
+            Ok(StatusCode::NO_CONTENT)
+        }
+        _ => Err(warp::reject::custom(ApiError::BroadcastListNotFound)),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/broadcast-lists/{id}/messages",
+    tag = "broadcasts",
+    params(("id" = Uuid, Path, description = "Broadcast list to send to")),
+    request_body = BroadcastMessagePayload,
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "Message routed to each recipient's conversation", body = BroadcastMessageResponse),
+        (status = 400, description = "Empty message", body = ErrorResponse),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 404, description = "Unknown list", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn send_broadcast_message_handler(
+    id: Uuid,
+    payload: BroadcastMessagePayload,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let receipts = send_broadcast(&app_state, &session, id, payload.message).await.map_err(warp::reject::custom)?;
+    Ok(warp::reply::json(&BroadcastMessageResponse { receipts }))
+}
@@ -0,0 +1,57 @@
+// src/handlers/calls.rs
+
+use serde::Serialize;
+use std::sync::Arc;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use warp::{Rejection, Reply};
+
+use crate::calls::{CallOutcome, CallRecord};
+use crate::error::ErrorResponse;
+use crate::ws_handlers::{AppState, UserSession};
+
+// An entry of the call log, as returned by `GET /calls`.
+#[derive(Serialize, ToSchema)]
+pub struct CallRecordResponse {
+    call_id: Uuid,
+    caller_id: Uuid,
+    callee_id: Uuid,
+    started_at: String,
+    answered_at: Option<String>,
+    ended_at: String,
+    outcome: CallOutcome,
+}
+
+impl From<&CallRecord> for CallRecordResponse {
+    fn from(record: &CallRecord) -> Self {
+        CallRecordResponse {
+            call_id: record.call_id,
+            caller_id: record.caller_id,
+            callee_id: record.callee_id,
+            started_at: record.started_at.to_rfc3339(),
+            answered_at: record.answered_at.map(|answered_at| answered_at.to_rfc3339()),
+            ended_at: record.ended_at.to_rfc3339(),
+            outcome: record.outcome,
+        }
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/calls",
+    tag = "calls",
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "The user's ended calls, most recent first", body = [CallRecordResponse]),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn list_calls_handler(
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let history = app_state.call_history.lock().await;
+    let calls: Vec<CallRecordResponse> = history.get(&session.user_id).into_iter().flatten().rev().map(CallRecordResponse::from).collect();
+    Ok(warp::reply::json(&calls))
+}
@@ -0,0 +1,152 @@
+// src/handlers/contacts.rs
+
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::{Rejection, Reply};
+
+use crate::error::{ApiError, ErrorResponse};
+use crate::events::DomainEvent;
+#[cfg(feature = "matrix")]
+use crate::matrix;
+use crate::redact::{pseudonym, redacted};
+use crate::wal::{self, Mutation};
+#[cfg(feature = "matrix")]
+use crate::ws_handlers::is_matrix_id;
+use crate::ws_handlers::{AppState, UserSession};
+
+#[derive(Deserialize, ToSchema)]
+pub struct AddContactPayload {
+    contact_username: String,
+}
+
+// One entry of a user's contact list.
+#[derive(Serialize, Debug, ToSchema)]
+pub struct ContactResponse {
+    pub id: Uuid,
+    pub username: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/contacts",
+    tag = "contacts",
+    request_body = AddContactPayload,
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "Both users added to each other's contacts"),
+        (status = 400, description = "Empty username or self-add", body = ErrorResponse),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 404, description = "Unknown user", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn add_contact_handler(
+    payload: AddContactPayload,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let contact_username = payload.contact_username;
+
+    if contact_username.is_empty() {
+        tracing::warn!(user_id = %session.user_id, "Add contact failed: contact_username is empty");
+        return Err(warp::reject::custom(ApiError::invalid("contact_username cannot be empty")));
+    }
+    
+    if contact_username == session.username {
+        tracing::warn!(user_id = %session.user_id, "Add contact failed: user tried to add themselves as a contact");
+        return Err(warp::reject::custom(ApiError::SelfContact));
+    }
+
+    // Adding a Matrix user by their Matrix ID creates the ghost user that stands in for them.
+    #[cfg(feature = "matrix")]
+    if is_matrix_id(&contact_username) {
+        matrix::ensure_ghost(&app_state, &contact_username).await;
+    }
+
+    // Both users are copied from the published users map, so no lock on it is held while
+    // we acquire independent locks on the inner `contacts` HashMaps later.
+    let current_user_opt = app_state.users.get(&session.username);
+    let contact_to_add_opt = app_state.users.get(&contact_username);
+
+    let current_user = match current_user_opt {
+        Some(u) => u,
+        None => {
+            tracing::warn!(user_id = %session.user_id, username = %pseudonym(&session.username), "Add contact failed: current user not found in users map (session might be invalid)");
+            return Err(warp::reject::custom(ApiError::InvalidSession));
+        }
+    };
+
+    let contact_to_add = match contact_to_add_opt {
+        Some(c) => c,
+        None => {
+            tracing::warn!(user_id = %session.user_id, contact_username = %pseudonym(&contact_username), "Add contact failed: contact user not found");
+            return Err(warp::reject::custom(ApiError::UserNotFound));
+        }
+    };
+
+    // Now, acquire mutable locks on the individual `contacts` HashMaps.
+    let mut current_user_contacts = current_user.contacts.lock().await;
+    let mut contact_to_add_contacts = contact_to_add.contacts.lock().await;
+
+    // Add each user to the other's contact list for a mutual connection.
+    current_user_contacts.insert(contact_to_add.id, contact_to_add.username.clone());
+    contact_to_add_contacts.insert(current_user.id, current_user.username.clone());
+
+    tracing::info!(
+        user_id = %session.user_id,
+        contact_user_id = %contact_to_add.id,
+        contact_username = %pseudonym(&contact_username),
+        "Contact added"
+    );
+    tracing::debug!(user_id = %session.user_id, contacts = ?redacted(current_user_contacts.keys().collect::<Vec<_>>()), "Contacts after adding");
+
+    let event = DomainEvent::ContactAdded {
+        user_id: current_user.id,
+        contact_user_id: contact_to_add.id,
+        contact_username: contact_to_add.username.clone(),
+    };
+    drop(contact_to_add_contacts);
+    drop(current_user_contacts);
+    wal::record(&app_state, Mutation::ContactAdded { user_id: current_user.id, contact_id: contact_to_add.id }).await;
+    app_state.events.publish(event);
+
+    Ok(StatusCode::OK)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/contacts",
+    tag = "contacts",
+    security(("session_key" = []), ("api_token" = [])),
+    responses(
+        (status = 200, description = "The user's contacts", body = [ContactResponse]),
+        (status = 401, description = "Invalid session or API token", body = ErrorResponse),
+        (status = 403, description = "API token without the read_contacts scope", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn get_contacts_handler(
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let contacts_list = list_contacts(&app_state, &session).await.map_err(warp::reject::custom)?;
+    Ok(warp::reply::json(&contacts_list))
+}
+
+/// Returns the contact list of the session's user.
+pub async fn list_contacts(app_state: &Arc<AppState>, session: &UserSession) -> Result<Vec<ContactResponse>, ApiError> {
+    if let Some(user) = app_state.users.get(&session.username) {
+        let contacts_map = user.contacts.lock().await;
+        let contacts_list: Vec<_> = contacts_map.iter().map(|(id, username)| {
+            ContactResponse { id: *id, username: username.clone() }
+        }).collect();
+        tracing::debug!(user_id = %session.user_id, contacts = ?redacted(&contacts_list), "Retrieving contacts");
+        Ok(contacts_list)
+    } else {
+        tracing::warn!(user_id = %session.user_id, username = %pseudonym(&session.username), "Get contacts failed: user not found in users map during contacts retrieval");
+        Err(ApiError::InvalidSession)
+    }
+}
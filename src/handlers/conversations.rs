@@ -0,0 +1,192 @@
+// src/handlers/conversations.rs
+
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::{Rejection, Reply};
+
+use crate::conversations::{self, Conversation};
+use crate::error::{ApiError, ErrorResponse};
+use crate::push::NotificationLevel;
+use crate::ws_handlers::{send_read_receipt, AppState, UserSession};
+
+// One of the user's conversations, as returned by `GET /conversations`.
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct ConversationResponse {
+    // The conversation partner.
+    user_id: Uuid,
+    unread_count: u32,
+    last_message_at: String,
+}
+
+impl ConversationResponse {
+    pub(crate) fn new(user_id: Uuid, conversation: &Conversation) -> Self {
+        ConversationResponse {
+            user_id,
+            unread_count: conversation.unread_count,
+            last_message_at: conversation.last_message_at.to_rfc3339(),
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct ConversationNotificationsPayload {
+    level: NotificationLevel,
+}
+
+// Notification level of one conversation; `nothing` when the partner is muted.
+#[derive(Serialize, ToSchema)]
+pub struct ConversationNotificationsResponse {
+    // The conversation partner.
+    user_id: Uuid,
+    level: NotificationLevel,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct MarkReadPayload {
+    // Latest message read; everything the partner sent up to it counts as read.
+    message_id: String,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/conversations",
+    tag = "conversations",
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "The user's conversations with their unread counts, most recently active first", body = [ConversationResponse]),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn list_conversations_handler(
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let conversations = app_state.conversations.lock().await;
+    let mut entries: Vec<(&Uuid, &Conversation)> = conversations
+        .iter()
+        .filter(|((user_id, _), _)| *user_id == session.user_id)
+        .map(|((_, partner_id), conversation)| (partner_id, conversation))
+        .collect();
+    entries.sort_by_key(|(_, conversation)| std::cmp::Reverse(conversation.last_message_at));
+    let response: Vec<ConversationResponse> =
+        entries.into_iter().map(|(partner_id, conversation)| ConversationResponse::new(*partner_id, conversation)).collect();
+    Ok(warp::reply::json(&response))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/conversations/{id}/read",
+    tag = "conversations",
+    params(("id" = Uuid, Path, description = "User id of the conversation partner")),
+    request_body = MarkReadPayload,
+    security(("session_key" = [])),
+    responses(
+        (status = 204, description = "Conversation marked as read; the partner receives a read receipt"),
+        (status = 400, description = "Empty message id", body = ErrorResponse),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn mark_conversation_read_handler(
+    id: Uuid,
+    payload: MarkReadPayload,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    if payload.message_id.is_empty() {
+        return Err(warp::reject::custom(ApiError::invalid("message_id must not be empty.")));
+    }
+    send_read_receipt(&app_state, &session, id, vec![payload.message_id]).await.map_err(warp::reject::custom)?;
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/conversations/{id}/messages",
+    tag = "conversations",
+    params(("id" = Uuid, Path, description = "User id of the conversation partner")),
+    security(("session_key" = [])),
+    responses(
+        (status = 204, description = "Conversation cleared for the requesting user; the partner keeps their copy"),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 404, description = "Unknown conversation", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn clear_conversation_handler(
+    id: Uuid,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    if !conversations::clear(&mut *app_state.conversations.lock().await, session.user_id, id) {
+        return Err(warp::reject::custom(ApiError::ConversationNotFound));
+    }
+    app_state.drafts.lock().await.remove(&(session.user_id, id));
+    tracing::info!(user_id = %session.user_id, partner_id = %id, "Conversation cleared");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/conversations/{id}/notifications",
+    tag = "conversations",
+    params(("id" = Uuid, Path, description = "User id of the conversation partner")),
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "The conversation's notification level", body = ConversationNotificationsResponse),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn get_conversation_notifications_handler(
+    id: Uuid,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let level = app_state
+        .notification_settings
+        .lock()
+        .await
+        .get(&session.user_id)
+        .map(|settings| settings.level_for(id))
+        .unwrap_or_default();
+    Ok(warp::reply::json(&ConversationNotificationsResponse { user_id: id, level }))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/conversations/{id}/notifications",
+    tag = "conversations",
+    params(("id" = Uuid, Path, description = "User id of the conversation partner")),
+    request_body = ConversationNotificationsPayload,
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "Level set; it applies to pushes and to the user's webhooks", body = ConversationNotificationsResponse),
+        (status = 400, description = "Invalid level", body = ErrorResponse),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn set_conversation_notifications_handler(
+    id: Uuid,
+    payload: ConversationNotificationsPayload,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let mut all_settings = app_state.notification_settings.lock().await;
+    let settings = all_settings.entry(session.user_id).or_default();
+    // The level replaces a mute set through the push settings.
+    settings.muted_user_ids.remove(&id);
+    if payload.level == NotificationLevel::All {
+        settings.conversation_levels.remove(&id);
+    } else {
+        settings.conversation_levels.insert(id, payload.level);
+    }
+    drop(all_settings);
+    tracing::info!(user_id = %session.user_id, partner_id = %id, level = ?payload.level, "Conversation notification level updated");
+    Ok(warp::reply::json(&ConversationNotificationsResponse { user_id: id, level: payload.level }))
+}
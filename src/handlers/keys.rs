@@ -0,0 +1,191 @@
+// src/handlers/keys.rs
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use warp::{Rejection, Reply};
+
+use crate::e2e::{self, OneTimePrekey, PrekeyBundle, SignedPrekey, UserKeys};
+use crate::error::{ApiError, ErrorResponse};
+use crate::handlers::contacts::list_contacts;
+use crate::ws_handlers::{AppState, UserSession};
+
+// Publishes the user's keys, replacing any previously uploaded ones (including all unused
+// one-time prekeys).
+#[derive(Deserialize, ToSchema)]
+pub struct UploadKeysPayload {
+    identity_key: String,
+    signed_prekey: SignedPrekey,
+    #[serde(default)]
+    one_time_prekeys: Vec<OneTimePrekey>,
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct AddPrekeysPayload {
+    one_time_prekeys: Vec<OneTimePrekey>,
+}
+
+// The user's own published keys; clients upload more one-time prekeys when they run low.
+#[derive(Serialize, ToSchema)]
+pub struct KeysStatusResponse {
+    identity_key: String,
+    signed_prekey_id: u32,
+    one_time_prekeys: usize,
+    updated_at: String,
+}
+
+impl From<&UserKeys> for KeysStatusResponse {
+    fn from(keys: &UserKeys) -> Self {
+        KeysStatusResponse {
+            identity_key: keys.identity_key.clone(),
+            signed_prekey_id: keys.signed_prekey.key_id,
+            one_time_prekeys: keys.one_time_prekeys.len(),
+            updated_at: keys.updated_at.to_rfc3339(),
+        }
+    }
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/keys",
+    tag = "keys",
+    request_body = UploadKeysPayload,
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "Keys published, replacing earlier ones", body = KeysStatusResponse),
+        (status = 400, description = "Keys not valid base64 or too many one-time prekeys", body = ErrorResponse),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn upload_keys_handler(
+    payload: UploadKeysPayload,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let signed_prekey = &payload.signed_prekey;
+    if !e2e::is_base64(&payload.identity_key) || !e2e::is_base64(&signed_prekey.public_key) || !e2e::is_base64(&signed_prekey.signature) {
+        return Err(warp::reject::custom(ApiError::invalid("identity_key and signed_prekey must be base64.")));
+    }
+    validate_one_time_prekeys(&payload.one_time_prekeys, 0)?;
+
+    let keys = UserKeys {
+        identity_key: payload.identity_key,
+        signed_prekey: payload.signed_prekey,
+        one_time_prekeys: payload.one_time_prekeys.into(),
+        updated_at: Utc::now(),
+    };
+    let response = KeysStatusResponse::from(&keys);
+    app_state.e2e_keys.lock().await.insert(session.user_id, keys);
+    tracing::info!(user_id = %session.user_id, one_time_prekeys = response.one_time_prekeys, "Encryption keys published");
+    Ok(warp::reply::json(&response))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/keys",
+    tag = "keys",
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "The user's published keys and remaining one-time prekeys", body = KeysStatusResponse),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 404, description = "No keys published", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn get_keys_handler(
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let keys = app_state.e2e_keys.lock().await;
+    match keys.get(&session.user_id) {
+        Some(keys) => Ok(warp::reply::json(&KeysStatusResponse::from(keys))),
+        None => Err(warp::reject::custom(ApiError::KeysNotPublished)),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/keys/prekeys",
+    tag = "keys",
+    request_body = AddPrekeysPayload,
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "One-time prekeys added; key ids already stored are skipped", body = KeysStatusResponse),
+        (status = 400, description = "Invalid or too many prekeys", body = ErrorResponse),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 404, description = "No keys published", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn add_prekeys_handler(
+    payload: AddPrekeysPayload,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let mut keys = app_state.e2e_keys.lock().await;
+    let Some(keys) = keys.get_mut(&session.user_id) else {
+        return Err(warp::reject::custom(ApiError::KeysNotPublished));
+    };
+    let new_prekeys: Vec<OneTimePrekey> = payload
+        .one_time_prekeys
+        .into_iter()
+        .filter(|prekey| keys.one_time_prekeys.iter().all(|stored| stored.key_id != prekey.key_id))
+        .collect();
+    validate_one_time_prekeys(&new_prekeys, keys.one_time_prekeys.len())?;
+
+    keys.one_time_prekeys.extend(new_prekeys);
+    keys.updated_at = Utc::now();
+    let response = KeysStatusResponse::from(&*keys);
+    tracing::info!(user_id = %session.user_id, one_time_prekeys = response.one_time_prekeys, "One-time prekeys added");
+    Ok(warp::reply::json(&response))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/users/{user_id}/prekey-bundle",
+    tag = "keys",
+    params(("user_id" = Uuid, Path, description = "Contact to start an encrypted session with")),
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "Prekey bundle; the one-time prekey in it is handed out only once", body = PrekeyBundle),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 403, description = "Not a contact", body = ErrorResponse),
+        (status = 404, description = "No keys published", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn get_prekey_bundle_handler(
+    user_id: Uuid,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    // Only contacts may fetch bundles, so strangers cannot drain the one-time prekeys.
+    let contacts = list_contacts(&app_state, &session).await.map_err(warp::reject::custom)?;
+    if user_id != session.user_id && !contacts.iter().any(|contact| contact.id == user_id) {
+        return Err(warp::reject::custom(ApiError::NotAContact));
+    }
+
+    let mut keys = app_state.e2e_keys.lock().await;
+    let Some(keys) = keys.get_mut(&user_id) else {
+        return Err(warp::reject::custom(ApiError::KeysNotPublished));
+    };
+    let bundle = keys.take_bundle(user_id);
+    if bundle.one_time_prekey.is_none() {
+        tracing::warn!(user_id = %session.user_id, peer_user_id = %user_id, "Prekey bundle served without a one-time prekey");
+    }
+    tracing::info!(user_id = %session.user_id, peer_user_id = %user_id, remaining = keys.one_time_prekeys.len(), "Prekey bundle fetched");
+    Ok(warp::reply::json(&bundle))
+}
+
+fn validate_one_time_prekeys(prekeys: &[OneTimePrekey], stored: usize) -> Result<(), Rejection> {
+    if prekeys.iter().any(|prekey| !e2e::is_base64(&prekey.public_key)) {
+        return Err(warp::reject::custom(ApiError::invalid("One-time prekeys must be base64.")));
+    }
+    if stored + prekeys.len() > e2e::MAX_ONE_TIME_PREKEYS {
+        return Err(warp::reject::custom(ApiError::invalid(format!("At most {} one-time prekeys can be stored.", e2e::MAX_ONE_TIME_PREKEYS))));
+    }
+    Ok(())
+}
@@ -0,0 +1,96 @@
+// src/handlers/messages.rs
+
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use warp::{Rejection, Reply};
+
+use crate::e2e::EncryptedMessage;
+use crate::error::{ApiError, ErrorResponse};
+use crate::ws_handlers::{route_encrypted_message, route_signed_chat_message, AppState, UserSession};
+
+#[derive(Deserialize, ToSchema)]
+pub struct SendMessagePayload {
+    to_user_id: Uuid,
+    message: String,
+    // Base64 Ed25519 signature of `message`; requires a signing key registered at login.
+    #[serde(default)]
+    signature: Option<String>,
+}
+
+// An end-to-end encrypted message; both fields are passed through untouched.
+#[derive(Deserialize, ToSchema)]
+pub struct SendEncryptedMessagePayload {
+    to_user_id: Uuid,
+    ciphertext: String,
+    header: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct SendMessageResponse {
+    pub(crate) message_id: String,
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/messages",
+    tag = "messages",
+    request_body = SendMessagePayload,
+    security(("session_key" = []), ("api_token" = [])),
+    responses(
+        (status = 200, description = "Message routed to the recipient's connections", body = SendMessageResponse),
+        (status = 400, description = "Empty message, missing signing key or invalid signature", body = ErrorResponse),
+        (status = 401, description = "Invalid session or API token", body = ErrorResponse),
+        (status = 403, description = "API token without the send_messages scope, or message rejected by an interceptor", body = ErrorResponse),
+        (status = 413, description = "Oversized message", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id, message_id = tracing::field::Empty))]
+pub async fn send_message_handler(
+    payload: SendMessagePayload,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    if payload.message.trim().is_empty() {
+        return Err(warp::reject::custom(ApiError::invalid("message cannot be empty")));
+    }
+
+    let message_id = route_signed_chat_message(&app_state, &session, payload.to_user_id, payload.message, payload.signature)
+        .await
+        .map_err(warp::reject::custom)?
+        .message_id;
+    tracing::info!(user_id = %session.user_id, to_user_id = %payload.to_user_id, message_id = %message_id, "Message sent via HTTP");
+    Ok(warp::reply::json(&SendMessageResponse { message_id }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/messages/encrypted",
+    tag = "messages",
+    request_body = SendEncryptedMessagePayload,
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "Encrypted message routed to the recipient's connections", body = SendMessageResponse),
+        (status = 400, description = "Empty message", body = ErrorResponse),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 413, description = "Oversized message", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id, message_id = tracing::field::Empty))]
+pub async fn send_encrypted_message_handler(
+    payload: SendEncryptedMessagePayload,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    if payload.ciphertext.is_empty() {
+        return Err(warp::reject::custom(ApiError::invalid("ciphertext cannot be empty")));
+    }
+
+    let encrypted = EncryptedMessage { ciphertext: payload.ciphertext, header: payload.header };
+    let message_id = route_encrypted_message(&app_state, &session, payload.to_user_id, encrypted)
+        .await
+        .map_err(warp::reject::custom)?;
+    tracing::info!(user_id = %session.user_id, to_user_id = %payload.to_user_id, message_id = %message_id, "Encrypted message sent via HTTP");
+    Ok(warp::reply::json(&SendMessageResponse { message_id }))
+}
@@ -0,0 +1,452 @@
+// src/handlers/push.rs
+
+use chrono::{NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::{Rejection, Reply};
+
+use crate::error::{ApiError, ErrorResponse};
+use crate::push::{DevicePlatform, DeviceToken, NotificationSettings, PushSubscription, QuietHours};
+#[cfg(feature = "web-push")]
+use crate::webpush::WebPushSender;
+use crate::ws_handlers::{AppState, UserSession};
+
+// Keys of a browser push subscription, as in `PushSubscription.toJSON()`.
+#[derive(Deserialize, ToSchema)]
+pub struct PushSubscriptionKeys {
+    p256dh: String,
+    auth: String,
+}
+
+// The browser's `PushSubscription.toJSON()`; other fields such as `expirationTime` are ignored.
+#[derive(Deserialize, ToSchema)]
+pub struct CreatePushSubscriptionPayload {
+    endpoint: String,
+    keys: PushSubscriptionKeys,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct PushSubscriptionResponse {
+    id: Uuid,
+    endpoint: String,
+    created_at: String,
+}
+
+impl From<&PushSubscription> for PushSubscriptionResponse {
+    fn from(subscription: &PushSubscription) -> Self {
+        PushSubscriptionResponse {
+            id: subscription.id,
+            endpoint: subscription.endpoint.clone(),
+            created_at: subscription.created_at.to_rfc3339(),
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct RegisterDevicePayload {
+    platform: DevicePlatform,
+    // FCM registration token or APNs device token (hex).
+    token: String,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct DeviceResponse {
+    id: Uuid,
+    platform: DevicePlatform,
+    created_at: String,
+}
+
+impl From<&DeviceToken> for DeviceResponse {
+    fn from(device: &DeviceToken) -> Self {
+        DeviceResponse { id: device.id, platform: device.platform, created_at: device.created_at.to_rfc3339() }
+    }
+}
+
+// Replaces the user's notification settings as a whole.
+#[derive(Deserialize, ToSchema)]
+pub struct NotificationSettingsPayload {
+    // Conversation partners whose messages are never pushed.
+    #[serde(default)]
+    muted_user_ids: Vec<Uuid>,
+    // RFC 3339 time until which nothing is pushed (do not disturb); null turns it off.
+    dnd_until: Option<String>,
+    // Recurring do not disturb; null turns it off.
+    #[serde(default)]
+    quiet_hours: Option<QuietHoursSchedule>,
+}
+
+// Daily do-not-disturb ranges in an IANA timezone, e.g. `Europe/Madrid`.
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct QuietHoursSchedule {
+    timezone: String,
+    ranges: Vec<TimeRange>,
+}
+
+// `HH:MM` times; a range ending before it starts runs past midnight.
+#[derive(Serialize, Deserialize, ToSchema)]
+pub struct TimeRange {
+    start: String,
+    end: String,
+}
+
+impl TryFrom<QuietHoursSchedule> for QuietHours {
+    type Error = ApiError;
+
+    fn try_from(schedule: QuietHoursSchedule) -> Result<Self, Self::Error> {
+        let timezone = schedule
+            .timezone
+            .parse()
+            .map_err(|_| ApiError::invalid(format!("Unknown timezone '{}'.", schedule.timezone)))?;
+        let parse_time = |time: &str| {
+            NaiveTime::parse_from_str(time, "%H:%M")
+                .map_err(|_| ApiError::invalid(format!("Invalid time '{}'; expected HH:MM.", time)))
+        };
+        let mut ranges = Vec::with_capacity(schedule.ranges.len());
+        for range in &schedule.ranges {
+            let (start, end) = (parse_time(&range.start)?, parse_time(&range.end)?);
+            if start == end {
+                return Err(ApiError::invalid("A quiet hours range must not start and end at the same time."));
+            }
+            ranges.push((start, end));
+        }
+        Ok(QuietHours { timezone, ranges })
+    }
+}
+
+impl From<&QuietHours> for QuietHoursSchedule {
+    fn from(quiet_hours: &QuietHours) -> Self {
+        QuietHoursSchedule {
+            timezone: quiet_hours.timezone.name().to_string(),
+            ranges: quiet_hours
+                .ranges
+                .iter()
+                .map(|(start, end)| TimeRange { start: start.format("%H:%M").to_string(), end: end.format("%H:%M").to_string() })
+                .collect(),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct NotificationSettingsResponse {
+    muted_user_ids: Vec<Uuid>,
+    dnd_until: Option<String>,
+    quiet_hours: Option<QuietHoursSchedule>,
+}
+
+impl From<&NotificationSettings> for NotificationSettingsResponse {
+    fn from(settings: &NotificationSettings) -> Self {
+        NotificationSettingsResponse {
+            muted_user_ids: settings.muted_user_ids.iter().copied().collect(),
+            dnd_until: settings.dnd_until.map(|until| until.to_rfc3339()),
+            quiet_hours: settings.quiet_hours.as_ref().map(QuietHoursSchedule::from),
+        }
+    }
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct VapidPublicKeyResponse {
+    // Pass as `applicationServerKey` to `PushManager.subscribe()`.
+    public_key: String,
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/push/vapid-public-key",
+    tag = "push",
+    responses(
+        (status = 200, description = "The server's VAPID public key", body = VapidPublicKeyResponse),
+        (status = 501, description = "Web Push is not configured", body = ErrorResponse),
+    )
+)]
+pub async fn vapid_public_key_handler(app_state: Arc<AppState>) -> Result<impl Reply, Rejection> {
+    let public_key = vapid_public_key(&app_state)?;
+    Ok(warp::reply::json(&VapidPublicKeyResponse { public_key }))
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/push/subscriptions",
+    tag = "push",
+    request_body = CreatePushSubscriptionPayload,
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "Subscription registered; re-registering an endpoint replaces it", body = PushSubscriptionResponse),
+        (status = 400, description = "Invalid subscription", body = ErrorResponse),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 501, description = "Web Push is not configured", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn create_push_subscription_handler(
+    payload: CreatePushSubscriptionPayload,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    vapid_public_key(&app_state)?;
+    let endpoint = payload.endpoint.trim().to_string();
+    if !endpoint.starts_with("https://") {
+        return Err(warp::reject::custom(ApiError::invalid("Push endpoint must start with https://.")));
+    }
+    if payload.keys.p256dh.is_empty() || payload.keys.auth.is_empty() {
+        return Err(warp::reject::custom(ApiError::invalid("Push subscription keys are required.")));
+    }
+
+    let subscription = PushSubscription {
+        id: Uuid::new_v4(),
+        user_id: session.user_id,
+        endpoint,
+        p256dh: payload.keys.p256dh,
+        auth: payload.keys.auth,
+        created_at: Utc::now(),
+    };
+    let response = PushSubscriptionResponse::from(&subscription);
+
+    let mut subscriptions = app_state.push_subscriptions.lock().await;
+    // A browser keeps its endpoint across page loads; replace instead of duplicating.
+    subscriptions.retain(|_, existing| existing.endpoint != subscription.endpoint);
+    subscriptions.insert(subscription.id, subscription);
+    tracing::info!(user_id = %session.user_id, subscription_id = %response.id, "Push subscription registered");
+    Ok(warp::reply::json(&response))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/push/subscriptions",
+    tag = "push",
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "The user's push subscriptions", body = [PushSubscriptionResponse]),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn list_push_subscriptions_handler(
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let subscriptions = app_state.push_subscriptions.lock().await;
+    let response: Vec<PushSubscriptionResponse> = subscriptions
+        .values()
+        .filter(|subscription| subscription.user_id == session.user_id)
+        .map(PushSubscriptionResponse::from)
+        .collect();
+    Ok(warp::reply::json(&response))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/push/subscriptions/{id}",
+    tag = "push",
+    params(("id" = Uuid, Path, description = "Subscription to remove")),
+    security(("session_key" = [])),
+    responses(
+        (status = 204, description = "Subscription removed"),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 404, description = "Unknown subscription", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn delete_push_subscription_handler(
+    id: Uuid,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let mut subscriptions = app_state.push_subscriptions.lock().await;
+    match subscriptions.get(&id) {
+        Some(subscription) if subscription.user_id == session.user_id => {
+            subscriptions.remove(&id);
+            tracing::info!(user_id = %session.user_id, subscription_id = %id, "Push subscription removed");
+            Ok(StatusCode::NO_CONTENT)
+        }
+        _ => Err(warp::reject::custom(ApiError::PushSubscriptionNotFound)),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/push/devices",
+    tag = "push",
+    request_body = RegisterDevicePayload,
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "Device registered for mobile pushes; re-registering a token moves it to this user", body = DeviceResponse),
+        (status = 400, description = "Empty token", body = ErrorResponse),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 501, description = "Platform not configured", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn register_device_handler(
+    payload: RegisterDevicePayload,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let token = payload.token.trim().to_string();
+    if token.is_empty() {
+        return Err(warp::reject::custom(ApiError::invalid("Device token cannot be empty.")));
+    }
+    if !supports_mobile_platform(&app_state, payload.platform) {
+        return Err(warp::reject::custom(ApiError::NotConfigured(format!("{:?} push", payload.platform))));
+    }
+
+    let device = DeviceToken {
+        id: Uuid::new_v4(),
+        user_id: session.user_id,
+        platform: payload.platform,
+        token,
+        created_at: Utc::now(),
+    };
+    let response = DeviceResponse::from(&device);
+
+    let mut devices = app_state.device_tokens.lock().await;
+    // A token identifies one app install; whoever registered it last receives its pushes.
+    devices.retain(|_, existing| !(existing.platform == device.platform && existing.token == device.token));
+    devices.insert(device.id, device);
+    tracing::info!(user_id = %session.user_id, device_id = %response.id, platform = ?response.platform, "Device registered for mobile push");
+    Ok(warp::reply::json(&response))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/push/devices",
+    tag = "push",
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "The user's registered devices", body = [DeviceResponse]),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn list_devices_handler(
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let devices = app_state.device_tokens.lock().await;
+    let response: Vec<DeviceResponse> = devices
+        .values()
+        .filter(|device| device.user_id == session.user_id)
+        .map(DeviceResponse::from)
+        .collect();
+    Ok(warp::reply::json(&response))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/push/devices/{id}",
+    tag = "push",
+    params(("id" = Uuid, Path, description = "Device to unregister")),
+    security(("session_key" = [])),
+    responses(
+        (status = 204, description = "Device unregistered"),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 404, description = "Unknown device", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn delete_device_handler(
+    id: Uuid,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let mut devices = app_state.device_tokens.lock().await;
+    match devices.get(&id) {
+        Some(device) if device.user_id == session.user_id => {
+            devices.remove(&id);
+            tracing::info!(user_id = %session.user_id, device_id = %id, "Device unregistered");
+            Ok(StatusCode::NO_CONTENT)
+        }
+        _ => Err(warp::reject::custom(ApiError::DeviceNotFound)),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/push/settings",
+    tag = "push",
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "The user's notification settings", body = NotificationSettingsResponse),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn get_notification_settings_handler(
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let settings = app_state.notification_settings.lock().await;
+    let response = settings
+        .get(&session.user_id)
+        .map(NotificationSettingsResponse::from)
+        .unwrap_or_else(|| NotificationSettingsResponse::from(&NotificationSettings::default()));
+    Ok(warp::reply::json(&response))
+}
+
+#[utoipa::path(
+    put,
+    path = "/api/v1/push/settings",
+    tag = "push",
+    request_body = NotificationSettingsPayload,
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "Settings replaced; they apply to Web Push and mobile pushes", body = NotificationSettingsResponse),
+        (status = 400, description = "Invalid dnd_until or quiet hours", body = ErrorResponse),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn set_notification_settings_handler(
+    payload: NotificationSettingsPayload,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let dnd_until = match payload.dnd_until.as_deref() {
+        Some(until) => match chrono::DateTime::parse_from_rfc3339(until) {
+            Ok(until) => Some(until.with_timezone(&Utc)),
+            Err(_) => return Err(warp::reject::custom(ApiError::invalid("dnd_until must be an RFC 3339 timestamp."))),
+        },
+        None => None,
+    };
+    let quiet_hours = payload.quiet_hours.map(QuietHours::try_from).transpose().map_err(warp::reject::custom)?;
+    let mut all_settings = app_state.notification_settings.lock().await;
+    // Per-conversation levels are managed through their own endpoints and kept as they are.
+    let settings = all_settings.entry(session.user_id).or_default();
+    settings.muted_user_ids = payload.muted_user_ids.into_iter().collect();
+    settings.dnd_until = dnd_until;
+    settings.quiet_hours = quiet_hours;
+    let response = NotificationSettingsResponse::from(&*settings);
+    drop(all_settings);
+    tracing::info!(user_id = %session.user_id, muted = response.muted_user_ids.len(), dnd_until = ?response.dnd_until, "Notification settings updated");
+    Ok(warp::reply::json(&response))
+}
+
+/// The VAPID public key; `NotConfigured` when `[web_push]` is not configured.
+#[cfg(feature = "web-push")]
+fn vapid_public_key(app_state: &AppState) -> Result<String, Rejection> {
+    app_state
+        .web_push
+        .as_ref()
+        .map(WebPushSender::public_key)
+        .ok_or_else(|| warp::reject::custom(ApiError::NotConfigured("Web Push".to_string())))
+}
+
+/// Without the `web-push` feature there is never a VAPID key.
+#[cfg(not(feature = "web-push"))]
+fn vapid_public_key(_app_state: &AppState) -> Result<String, Rejection> {
+    Err(warp::reject::custom(ApiError::NotConfigured("Web Push".to_string())))
+}
+
+/// Whether credentials for `platform` are configured.
+#[cfg(feature = "mobile-push")]
+fn supports_mobile_platform(app_state: &AppState, platform: DevicePlatform) -> bool {
+    app_state.mobile_push.supports(platform)
+}
+
+/// Without the `mobile-push` feature no platform can be notified.
+#[cfg(not(feature = "mobile-push"))]
+fn supports_mobile_platform(_app_state: &AppState, _platform: DevicePlatform) -> bool {
+    false
+}
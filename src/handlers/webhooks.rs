@@ -0,0 +1,414 @@
+// src/handlers/webhooks.rs
+
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Instant;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::{Rejection, Reply};
+
+use crate::device::Device;
+use crate::error::{ApiError, ErrorResponse};
+use crate::handlers::messages::SendMessageResponse;
+use crate::redact::pseudonym;
+use crate::webhooks::{self, IncomingWebhook, Webhook, WebhookEventKind, WebhookOwner};
+use crate::ws_handlers::{route_chat_message, AppState, LastActive, UserSession};
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateWebhookPayload {
+    url: String,
+    events: Vec<WebhookEventKind>,
+    // Signing secret; a random one is generated when omitted.
+    secret: Option<String>,
+}
+
+// A registered webhook. The secret is only returned when the webhook is created.
+#[derive(Serialize, ToSchema)]
+pub struct WebhookResponse {
+    id: Uuid,
+    owner: WebhookOwner,
+    url: String,
+    events: Vec<WebhookEventKind>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret: Option<String>,
+}
+
+impl From<&Webhook> for WebhookResponse {
+    fn from(webhook: &Webhook) -> Self {
+        WebhookResponse {
+            id: webhook.id,
+            owner: webhook.owner,
+            url: webhook.url.clone(),
+            events: webhook.events.clone(),
+            secret: None,
+        }
+    }
+}
+
+#[derive(Deserialize, ToSchema)]
+pub struct CreateIncomingWebhookPayload {
+    // Conversation partner the posted messages go to; must be a contact (or yourself).
+    to_user_id: Uuid,
+    // Sender name shown on posted messages.
+    name: Option<String>,
+}
+
+// Body accepted by an incoming webhook URL (compatible with Slack's `text` field).
+#[derive(Deserialize, ToSchema)]
+pub struct IncomingWebhookMessagePayload {
+    text: String,
+}
+
+// An incoming webhook. The URL is only returned when the webhook is created.
+#[derive(Serialize, ToSchema)]
+pub struct IncomingWebhookResponse {
+    id: Uuid,
+    name: String,
+    to_user_id: Uuid,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<String>,
+}
+
+impl From<&IncomingWebhook> for IncomingWebhookResponse {
+    fn from(webhook: &IncomingWebhook) -> Self {
+        IncomingWebhookResponse { id: webhook.id, name: webhook.name.clone(), to_user_id: webhook.to_user_id, url: None }
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/webhooks",
+    tag = "webhooks",
+    request_body = CreateWebhookPayload,
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "Webhook registered; the response includes its signing secret", body = WebhookResponse),
+        (status = 400, description = "Invalid URL, internal destination or event list", body = ErrorResponse),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 403, description = "Webhook quota reached", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn create_webhook_handler(
+    payload: CreateWebhookPayload,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    create_webhook(WebhookOwner::User(session.user_id), payload, &app_state).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/webhooks",
+    tag = "webhooks",
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "The user's webhooks", body = [WebhookResponse]),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn list_webhooks_handler(
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    list_webhooks(WebhookOwner::User(session.user_id), &app_state).await
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/webhooks/{id}",
+    tag = "webhooks",
+    params(("id" = Uuid, Path, description = "Webhook to remove")),
+    security(("session_key" = [])),
+    responses(
+        (status = 204, description = "Webhook removed"),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 404, description = "Unknown webhook", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn delete_webhook_handler(
+    id: Uuid,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    delete_webhook(WebhookOwner::User(session.user_id), id, &app_state).await
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/admin/webhooks",
+    tag = "admin",
+    request_body = CreateWebhookPayload,
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "Global webhook registered; the response includes its signing secret", body = WebhookResponse),
+        (status = 400, description = "Invalid URL, internal destination or event list", body = ErrorResponse),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 403, description = "Insufficient role", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn create_global_webhook_handler(
+    payload: CreateWebhookPayload,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    create_webhook(WebhookOwner::Global, payload, &app_state).await
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/admin/webhooks",
+    tag = "admin",
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "Global webhooks", body = [WebhookResponse]),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 403, description = "Insufficient role", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn list_global_webhooks_handler(
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    list_webhooks(WebhookOwner::Global, &app_state).await
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/admin/webhooks/{id}",
+    tag = "admin",
+    params(("id" = Uuid, Path, description = "Webhook to remove")),
+    security(("session_key" = [])),
+    responses(
+        (status = 204, description = "Webhook removed"),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 403, description = "Insufficient role", body = ErrorResponse),
+        (status = 404, description = "Unknown webhook", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn delete_global_webhook_handler(
+    id: Uuid,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    delete_webhook(WebhookOwner::Global, id, &app_state).await
+}
+
+async fn create_webhook(
+    owner: WebhookOwner,
+    payload: CreateWebhookPayload,
+    app_state: &Arc<AppState>,
+) -> Result<warp::reply::Json, Rejection> {
+    let url = payload.url.trim().to_string();
+    if let Err(reason) = webhooks::check_destination(&url, &app_state.config.webhooks.allow_networks).await {
+        return Err(warp::reject::custom(ApiError::invalid(reason)));
+    }
+    if payload.events.is_empty() {
+        return Err(warp::reject::custom(ApiError::invalid("At least one webhook event is required.")));
+    }
+    let secret = match payload.secret {
+        Some(secret) if secret.is_empty() => {
+            return Err(warp::reject::custom(ApiError::invalid("Webhook secret cannot be empty.")));
+        }
+        Some(secret) => secret,
+        None => webhooks::generate_secret(),
+    };
+
+    let mut registry = app_state.webhooks.lock().await;
+    if let WebhookOwner::User(_) = owner {
+        if registry.values().filter(|webhook| webhook.owner == owner).count() >= app_state.config.webhooks.max_per_user {
+            return Err(warp::reject::custom(ApiError::WebhookQuotaExceeded));
+        }
+    }
+    let webhook = Webhook { id: Uuid::new_v4(), owner, url, secret, events: payload.events };
+    // The URL is left out: it often carries a token of the receiving service.
+    tracing::info!(webhook_id = %webhook.id, owner = ?webhook.owner, events = ?webhook.events, "Webhook registered");
+
+    let response = WebhookResponse { secret: Some(webhook.secret.clone()), ..WebhookResponse::from(&webhook) };
+    registry.insert(webhook.id, webhook);
+    Ok(warp::reply::json(&response))
+}
+
+async fn list_webhooks(owner: WebhookOwner, app_state: &Arc<AppState>) -> Result<warp::reply::Json, Rejection> {
+    let webhooks = app_state.webhooks.lock().await;
+    let response: Vec<WebhookResponse> = webhooks
+        .values()
+        .filter(|webhook| webhook.owner == owner)
+        .map(WebhookResponse::from)
+        .collect();
+    Ok(warp::reply::json(&response))
+}
+
+async fn delete_webhook(owner: WebhookOwner, id: Uuid, app_state: &Arc<AppState>) -> Result<StatusCode, Rejection> {
+    let mut webhooks = app_state.webhooks.lock().await;
+    match webhooks.get(&id) {
+        Some(webhook) if webhook.owner == owner => {
+            webhooks.remove(&id);
+            tracing::info!(webhook_id = %id, owner = ?owner, "Webhook removed");
+            Ok(StatusCode::NO_CONTENT)
+        }
+        _ => Err(warp::reject::custom(ApiError::WebhookNotFound)),
+    }
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/incoming-webhooks",
+    tag = "webhooks",
+    request_body = CreateIncomingWebhookPayload,
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "Incoming webhook created; the response includes its secret URL", body = IncomingWebhookResponse),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 403, description = "Recipient is not a contact", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn create_incoming_webhook_handler(
+    payload: CreateIncomingWebhookPayload,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    if payload.to_user_id != session.user_id {
+        let user = app_state.users.get(&session.username);
+        let is_contact = match user {
+            Some(user) => user.contacts.lock().await.contains_key(&payload.to_user_id),
+            None => false,
+        };
+        if !is_contact {
+            tracing::warn!(user_id = %session.user_id, to_user_id = %payload.to_user_id, "Create incoming webhook failed: recipient is not a contact");
+            return Err(warp::reject::custom(ApiError::NotAContact));
+        }
+    }
+
+    let name = match payload.name.as_deref().map(str::trim) {
+        Some(name) if !name.is_empty() => name.to_string(),
+        _ => "Incoming webhook".to_string(),
+    };
+    let webhook = IncomingWebhook {
+        id: Uuid::new_v4(),
+        token: webhooks::generate_incoming_token(),
+        owner_user_id: session.user_id,
+        to_user_id: payload.to_user_id,
+        name,
+    };
+    tracing::info!(user_id = %session.user_id, webhook_id = %webhook.id, to_user_id = %webhook.to_user_id, "Incoming webhook created");
+
+    let response = IncomingWebhookResponse {
+        url: Some(format!("/api/v1/hooks/{}", webhook.token)),
+        ..IncomingWebhookResponse::from(&webhook)
+    };
+    app_state.incoming_webhooks.lock().await.insert(webhook.token.clone(), webhook);
+    Ok(warp::reply::json(&response))
+}
+
+#[utoipa::path(
+    get,
+    path = "/api/v1/incoming-webhooks",
+    tag = "webhooks",
+    security(("session_key" = [])),
+    responses(
+        (status = 200, description = "The user's incoming webhooks", body = [IncomingWebhookResponse]),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn list_incoming_webhooks_handler(
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let webhooks = app_state.incoming_webhooks.lock().await;
+    let response: Vec<IncomingWebhookResponse> = webhooks
+        .values()
+        .filter(|webhook| webhook.owner_user_id == session.user_id)
+        .map(IncomingWebhookResponse::from)
+        .collect();
+    Ok(warp::reply::json(&response))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/api/v1/incoming-webhooks/{id}",
+    tag = "webhooks",
+    params(("id" = Uuid, Path, description = "Incoming webhook to revoke")),
+    security(("session_key" = [])),
+    responses(
+        (status = 204, description = "Incoming webhook revoked; its URL stops working"),
+        (status = 401, description = "Invalid session", body = ErrorResponse),
+        (status = 404, description = "Unknown webhook", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(user_id = %session.user_id))]
+pub async fn delete_incoming_webhook_handler(
+    id: Uuid,
+    session: UserSession,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let mut webhooks = app_state.incoming_webhooks.lock().await;
+    let before = webhooks.len();
+    webhooks.retain(|_, webhook| !(webhook.id == id && webhook.owner_user_id == session.user_id));
+    if webhooks.len() == before {
+        return Err(warp::reject::custom(ApiError::WebhookNotFound));
+    }
+    tracing::info!(user_id = %session.user_id, webhook_id = %id, "Incoming webhook revoked");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[utoipa::path(
+    post,
+    path = "/api/v1/hooks/{token}",
+    tag = "webhooks",
+    params(("token" = String, Path, description = "Secret token of the incoming webhook URL")),
+    request_body = IncomingWebhookMessagePayload,
+    responses(
+        (status = 200, description = "Message posted into the conversation", body = SendMessageResponse),
+        (status = 400, description = "Empty message", body = ErrorResponse),
+        (status = 404, description = "Unknown webhook token", body = ErrorResponse),
+        (status = 413, description = "Oversized message", body = ErrorResponse),
+    )
+)]
+#[tracing::instrument(skip_all, fields(webhook_id = tracing::field::Empty, message_id = tracing::field::Empty))]
+pub async fn post_incoming_webhook_handler(
+    token: String,
+    payload: IncomingWebhookMessagePayload,
+    client_ip: Option<IpAddr>,
+    app_state: Arc<AppState>,
+) -> Result<impl Reply, Rejection> {
+    let webhook = match app_state.incoming_webhooks.lock().await.get(&token).cloned() {
+        Some(webhook) => webhook,
+        None => {
+            tracing::warn!(client_ip = ?client_ip.map(pseudonym), "Incoming webhook post rejected: unknown token");
+            return Err(warp::reject::custom(ApiError::WebhookNotFound));
+        }
+    };
+    tracing::Span::current().record("webhook_id", tracing::field::display(webhook.id));
+    if payload.text.trim().is_empty() {
+        return Err(warp::reject::custom(ApiError::invalid("text cannot be empty")));
+    }
+
+    // Messages are posted on behalf of the owner, under the webhook's name.
+    let sender = UserSession {
+        user_id: webhook.owner_user_id,
+        username: webhook.name.clone(),
+        session_key: format!("hook:{}", webhook.id),
+        created_at: Instant::now(),
+        client_ip,
+        country: None,
+        device: Device::default(),
+        trusted_device: Arc::default(),
+        signing_key: None,
+        last_active: LastActive::now(),
+    };
+    let message_id = route_chat_message(&app_state, &sender, webhook.to_user_id, payload.text)
+        .await
+        .map_err(warp::reject::custom)?;
+    tracing::info!(webhook_id = %webhook.id, to_user_id = %webhook.to_user_id, message_id = %message_id, client_ip = ?client_ip.map(pseudonym), "Message posted via incoming webhook");
+    Ok(warp::reply::json(&SendMessageResponse { message_id }))
+}
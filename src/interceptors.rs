@@ -0,0 +1,68 @@
+// src/interceptors.rs
+
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use uuid::Uuid;
+
+/// A chat message on its way through the server, as interceptors see it.
+#[derive(Debug, Clone)]
+pub struct InterceptedMessage {
+    pub message_id: String,
+    pub from_user_id: Uuid,
+    pub from_username: String,
+    pub to_user_id: Uuid,
+    // What the recipient receives; `pre_send` hooks may rewrite it.
+    pub text: String,
+}
+
+/// Hooks into the routing of chat messages, whichever way they arrive (WebSocket, HTTP API,
+/// bots, bridges), for features such as content filtering, metrics, translation or archiving.
+/// Hooks run inline on the routing path: hand slow work (network calls, disk writes) to a
+/// spawned task.
+pub trait MessageInterceptor: Send + Sync {
+    /// Runs before the message is routed. `Err(reason)` rejects it with `MESSAGE_REJECTED`.
+    /// Rewriting `message.text` changes what recipients see; a signed message whose text was
+    /// rewritten reaches them as unverified.
+    fn pre_send(&self, _message: &mut InterceptedMessage) -> Result<(), String> {
+        Ok(())
+    }
+
+    /// Runs once the message was routed. `delivered` tells whether a connection of the
+    /// recipient received it.
+    fn post_receive(&self, _message: &InterceptedMessage, _delivered: bool) {}
+}
+
+/// The interceptors registered on a server. Hooks run in registration order.
+#[derive(Default)]
+pub struct Interceptors {
+    registered: RwLock<Vec<Arc<dyn MessageInterceptor>>>,
+}
+
+impl fmt::Debug for Interceptors {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Interceptors").field("registered", &self.snapshot().len()).finish()
+    }
+}
+
+impl Interceptors {
+    /// Adds `interceptor` after the ones already registered. Applies from the next message.
+    pub fn register(&self, interceptor: Arc<dyn MessageInterceptor>) {
+        self.registered.write().expect("interceptor lock").push(interceptor);
+    }
+
+    // Hooks run on a copy of the list so they may register interceptors themselves.
+    fn snapshot(&self) -> Vec<Arc<dyn MessageInterceptor>> {
+        self.registered.read().expect("interceptor lock").clone()
+    }
+
+    /// Runs every `pre_send` hook, stopping at the first rejection.
+    pub(crate) fn pre_send(&self, message: &mut InterceptedMessage) -> Result<(), String> {
+        self.snapshot().iter().try_for_each(|interceptor| interceptor.pre_send(message))
+    }
+
+    pub(crate) fn post_receive(&self, message: &InterceptedMessage, delivered: bool) {
+        for interceptor in self.snapshot() {
+            interceptor.post_receive(message, delivered);
+        }
+    }
+}
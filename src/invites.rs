@@ -0,0 +1,63 @@
+// src/invites.rs
+
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::error::ApiError;
+use crate::ws_handlers::AppState;
+
+/// A code letting one person register while `registration.invite_only` is set.
+#[derive(Debug, Clone)]
+pub struct Invite {
+    pub code: String,
+    pub created_by: Uuid,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    // Username of the account registered with it.
+    pub used_by: Option<String>,
+}
+
+impl Invite {
+    /// Whether the invite can still be used.
+    pub fn is_pending(&self, now: DateTime<Utc>) -> bool {
+        self.used_by.is_none() && self.expires_at > now
+    }
+}
+
+/// Invites by code. Expired invites nobody used are dropped when the next one is created.
+pub type InviteRegistry = HashMap<String, Invite>;
+
+/// Creates an invite of `user_id`, valid for `registration.invite_ttl_secs`, unless they
+/// already have `registration.invites_per_user` pending ones. `unlimited` lifts the quota.
+pub async fn create(app_state: &AppState, user_id: Uuid, unlimited: bool) -> Result<Invite, ApiError> {
+    let registration = &app_state.config.registration;
+    let now = Utc::now();
+    let mut invites = app_state.invites.lock().await;
+    invites.retain(|_, invite| invite.used_by.is_some() || invite.expires_at > now);
+    let pending = invites.values().filter(|invite| invite.created_by == user_id && invite.is_pending(now)).count();
+    if !unlimited && pending >= registration.invites_per_user as usize {
+        return Err(ApiError::InviteQuotaExceeded);
+    }
+    let invite = Invite {
+        code: Uuid::new_v4().simple().to_string(),
+        created_by: user_id,
+        created_at: now,
+        expires_at: now + Duration::seconds(registration.invite_ttl_secs as i64),
+        used_by: None,
+    };
+    invites.insert(invite.code.clone(), invite.clone());
+    Ok(invite)
+}
+
+/// Uses up the invite `code` for the registration of `username`, returning who created it,
+/// or `InviteRequired` when it is missing, unknown, used or expired.
+pub async fn redeem(app_state: &AppState, code: Option<&str>, username: &str) -> Result<Uuid, ApiError> {
+    let mut invites = app_state.invites.lock().await;
+    let invite = code
+        .and_then(|code| invites.get_mut(code))
+        .filter(|invite| invite.is_pending(Utc::now()))
+        .ok_or(ApiError::InviteRequired)?;
+    invite.used_by = Some(username.to_string());
+    Ok(invite.created_by)
+}
@@ -0,0 +1,53 @@
+// src/ip_filter.rs
+
+use std::net::IpAddr;
+use std::sync::Arc;
+use warp::{Filter, Rejection};
+
+use crate::client_ip::with_client_ip;
+use crate::config::IpFilterConfig;
+use crate::connections::CloseReason;
+use crate::error::ApiError;
+use crate::middleware::with_app_state;
+use crate::redact::pseudonym;
+use crate::ws_handlers::AppState;
+
+/// Whether `rules` let `client_ip` in: it must be outside every `deny` range and, when `allow`
+/// lists any, inside one of them. An unknown address only gets in without an allow list.
+pub fn permits(rules: &IpFilterConfig, client_ip: Option<IpAddr>) -> bool {
+    let Some(ip) = client_ip.map(|ip| ip.to_canonical()) else { return rules.allow.is_empty() };
+    !rules.deny.iter().any(|net| net.contains(&ip)) && (rules.allow.is_empty() || rules.allow.iter().any(|net| net.contains(&ip)))
+}
+
+/// A filter turning away requests, WebSocket upgrades included, from client addresses that
+/// the current IP rules do not permit.
+pub fn with_permitted_ip(app_state: Arc<AppState>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    with_client_ip(app_state.clone())
+        .and(with_app_state(app_state))
+        .and_then(|client_ip: Option<IpAddr>, app_state_ip: Arc<AppState>| async move {
+            if permits(&*app_state_ip.ip_rules.lock().await, client_ip) {
+                Ok(())
+            } else {
+                tracing::debug!(client_ip = ?client_ip.map(pseudonym), "Request refused: IP not allowed");
+                Err(warp::reject::custom(ApiError::IpNotAllowed))
+            }
+        })
+        .untuple_one()
+}
+
+/// Replaces the IP rules and closes the WebSockets whose address they no longer permit,
+/// returning how many were closed.
+pub async fn replace_rules(app_state: &AppState, rules: IpFilterConfig) -> usize {
+    *app_state.ip_rules.lock().await = rules.clone();
+    let mut closed = 0;
+    for (key, connection) in app_state.connections.snapshot().await {
+        // WebSockets are registered under their session key; other consumers under keys of their own.
+        if key == connection.session_key
+            && !permits(&rules, connection.client_ip)
+            && app_state.connections.close_connection(connection.connection_id, CloseReason::ClosedByAdmin).await
+        {
+            closed += 1;
+        }
+    }
+    closed
+}
@@ -0,0 +1,227 @@
+// src/kafka.rs
+
+use chrono::Utc;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use uuid::Uuid;
+
+use crate::config::KafkaConfig;
+use crate::events::{DomainEvent, MessageKind};
+use crate::ws_handlers::{AppState, UserSession};
+
+/// Records buffered for the REST proxy while it is slow or unreachable; beyond this they are dropped.
+const QUEUE_CAPACITY: usize = 10_000;
+/// Attempts to produce a batch before it is dropped (backoff doubles from 1s).
+const MAX_ATTEMPTS: u32 = 3;
+
+/// Published to `message_topic` for every message sent, keyed by conversation so each
+/// conversation's messages stay in order within a partition.
+#[derive(Serialize)]
+struct MessageRecord<'a> {
+    message_id: &'a str,
+    kind: MessageKind,
+    from_user_id: Uuid,
+    from_username: &'a str,
+    to_user_id: Uuid,
+    // Only with `include_message_text`, and only for chat messages.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    text: Option<&'a str>,
+    delivered: bool,
+    timestamp: String,
+}
+
+/// Published to `presence_topic` when a user's status changes on this node, keyed by user.
+#[derive(Serialize)]
+struct PresenceRecord<'a> {
+    user_id: Uuid,
+    username: &'a str,
+    status: &'a str,
+    timestamp: String,
+}
+
+/// Published to `audit_topic` for every domain event, without message contents.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum AuditRecord<'a> {
+    UserRegistered { user_id: Uuid, username: &'a str, country: Option<&'a str>, timestamp: String },
+    RegistrationRequested { registration_id: Uuid, username: &'a str, country: Option<&'a str>, timestamp: String },
+    MessageSent { message_id: &'a str, kind: MessageKind, from_user_id: Uuid, to_user_id: Uuid, delivered: bool, timestamp: String },
+    UserOnline { user_id: Uuid, username: &'a str, timestamp: String },
+    ContactAdded { user_id: Uuid, contact_user_id: Uuid, timestamp: String },
+    AccountLocked { user_id: Uuid, locked_until: String, timestamp: String },
+    AccountUnlocked { user_id: Uuid, unlocked_by: Uuid, timestamp: String },
+    UnfamiliarLogin { user_id: Uuid, country: Option<&'a str>, timestamp: String },
+}
+
+/// A record waiting to be produced.
+#[derive(Debug)]
+struct Record {
+    topic: String,
+    key: String,
+    value: Value,
+}
+
+/// Exports chat activity to Kafka through a Confluent-compatible REST proxy, so analytics and
+/// compliance pipelines can consume it. Publishing never waits: records are queued and
+/// produced in batches by `run`.
+#[derive(Debug)]
+pub struct KafkaExporter {
+    config: KafkaConfig,
+    records: mpsc::Sender<Record>,
+}
+
+/// The producing side of the exporter, handed to `run`.
+pub struct KafkaProducer {
+    client: reqwest::Client,
+    records: mpsc::Receiver<Record>,
+}
+
+impl KafkaExporter {
+    /// Creates the exporter and the producer that `run` must drive for anything to be sent.
+    pub fn new(config: &KafkaConfig) -> (Self, KafkaProducer) {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .user_agent(concat!("rust_chat-kafka/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .expect("failed to build Kafka REST proxy HTTP client");
+        let (records, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        (KafkaExporter { config: config.clone(), records }, KafkaProducer { client, records: receiver })
+    }
+
+    fn publish<T: Serialize>(&self, topic: &str, key: String, record: &T) {
+        let value = match serde_json::to_value(record) {
+            Ok(value) => value,
+            Err(e) => {
+                tracing::error!(error = %e, "Error serializing Kafka record");
+                return;
+            }
+        };
+        if let Err(e) = self.records.try_send(Record { topic: topic.to_string(), key, value }) {
+            tracing::warn!(topic = %topic, error = %e, "Dropping Kafka record");
+        }
+    }
+}
+
+/// Publishes a user's status change on this node to the presence topic.
+pub fn publish_presence(app_state: &AppState, session: &UserSession, status: &str) {
+    let Some(kafka) = &app_state.kafka else { return };
+    let record = PresenceRecord { user_id: session.user_id, username: &session.username, status, timestamp: Utc::now().to_rfc3339() };
+    kafka.publish(&kafka.config.presence_topic, session.user_id.to_string(), &record);
+}
+
+/// Event-bus subscriber: publishes sent messages to the message topic and every event to
+/// the audit topic.
+pub async fn on_event(app_state: Arc<AppState>, event: Arc<DomainEvent>) {
+    let Some(kafka) = &app_state.kafka else { return };
+    let timestamp = Utc::now().to_rfc3339();
+    let (key, audit) = match &*event {
+        DomainEvent::UserRegistered { user_id, username, country } => {
+            (*user_id, AuditRecord::UserRegistered { user_id: *user_id, username, country: country.as_deref(), timestamp })
+        }
+        DomainEvent::RegistrationRequested { registration_id, username, country } => {
+            let audit = AuditRecord::RegistrationRequested { registration_id: *registration_id, username, country: country.as_deref(), timestamp };
+            (*registration_id, audit)
+        }
+        DomainEvent::MessageSent { kind, message_id, from_user_id, from_username, to_user_id, text, delivered, .. } => {
+            let message = MessageRecord {
+                message_id,
+                kind: *kind,
+                from_user_id: *from_user_id,
+                from_username,
+                to_user_id: *to_user_id,
+                text: text.as_deref().filter(|_| kafka.config.include_message_text),
+                delivered: *delivered,
+                timestamp: timestamp.clone(),
+            };
+            kafka.publish(&kafka.config.message_topic, conversation_key(*from_user_id, *to_user_id), &message);
+            let audit = AuditRecord::MessageSent {
+                message_id,
+                kind: *kind,
+                from_user_id: *from_user_id,
+                to_user_id: *to_user_id,
+                delivered: *delivered,
+                timestamp,
+            };
+            (*from_user_id, audit)
+        }
+        DomainEvent::UserOnline { user_id, username } => (*user_id, AuditRecord::UserOnline { user_id: *user_id, username, timestamp }),
+        DomainEvent::ContactAdded { user_id, contact_user_id, .. } => {
+            (*user_id, AuditRecord::ContactAdded { user_id: *user_id, contact_user_id: *contact_user_id, timestamp })
+        }
+        DomainEvent::AccountLocked { user_id, locked_until, .. } => {
+            (*user_id, AuditRecord::AccountLocked { user_id: *user_id, locked_until: locked_until.to_rfc3339(), timestamp })
+        }
+        DomainEvent::AccountUnlocked { user_id, unlocked_by, .. } => {
+            (*user_id, AuditRecord::AccountUnlocked { user_id: *user_id, unlocked_by: *unlocked_by, timestamp })
+        }
+        DomainEvent::UnfamiliarLogin { user_id, country, .. } => {
+            (*user_id, AuditRecord::UnfamiliarLogin { user_id: *user_id, country: country.as_deref(), timestamp })
+        }
+    };
+    kafka.publish(&kafka.config.audit_topic, key.to_string(), &audit);
+}
+
+// The same key for both directions of a conversation.
+fn conversation_key(a: Uuid, b: Uuid) -> String {
+    let (low, high) = if a <= b { (a, b) } else { (b, a) };
+    format!("{}:{}", low, high)
+}
+
+/// Produces the queued records until the process exits, in batches of up to `batch_size`
+/// per request, waiting up to `linger_ms` for a batch to fill.
+pub async fn run(app_state: Arc<AppState>, mut producer: KafkaProducer) {
+    let Some(kafka) = &app_state.kafka else { return };
+    tracing::info!(rest_proxy = %kafka.config.rest_proxy_url, "Starting Kafka export");
+    let linger = Duration::from_millis(kafka.config.linger_ms);
+    while let Some(first) = producer.records.recv().await {
+        let mut batch = vec![first];
+        let deadline = tokio::time::Instant::now() + linger;
+        while batch.len() < kafka.config.batch_size {
+            match tokio::time::timeout_at(deadline, producer.records.recv()).await {
+                Ok(Some(record)) => batch.push(record),
+                Ok(None) | Err(_) => break,
+            }
+        }
+        let mut topics: HashMap<String, Vec<Value>> = HashMap::new();
+        for record in batch {
+            topics.entry(record.topic).or_default().push(json!({ "key": record.key, "value": record.value }));
+        }
+        for (topic, records) in topics {
+            produce(&producer.client, &kafka.config.rest_proxy_url, &topic, records).await;
+        }
+    }
+}
+
+// Sends one topic's records to the REST proxy, retrying failures with backoff.
+async fn produce(client: &reqwest::Client, rest_proxy_url: &str, topic: &str, records: Vec<Value>) {
+    let url = format!("{}/topics/{}", rest_proxy_url.trim_end_matches('/'), topic);
+    let count = records.len();
+    let body = json!({ "records": records }).to_string();
+    let mut backoff = Duration::from_secs(1);
+    for attempt in 1..=MAX_ATTEMPTS {
+        let result = client
+            .post(&url)
+            .header("content-type", "application/vnd.kafka.json.v2+json")
+            .header("accept", "application/vnd.kafka.v2+json")
+            .body(body.clone())
+            .send()
+            .await;
+        match result {
+            Ok(response) if response.status().is_success() => {
+                tracing::debug!(topic = %topic, records = count, "Produced Kafka records");
+                return;
+            }
+            Ok(response) => tracing::warn!(topic = %topic, attempt, status = %response.status(), "Kafka REST proxy rejected records"),
+            Err(e) => tracing::warn!(topic = %topic, attempt, error = %e, "Cannot reach the Kafka REST proxy"),
+        }
+        if attempt < MAX_ATTEMPTS {
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+    }
+    tracing::error!(topic = %topic, records = count, "Dropping Kafka records after {} attempts", MAX_ATTEMPTS);
+}
@@ -0,0 +1,99 @@
+// src/ldap.rs
+
+use futures::future::BoxFuture;
+#[cfg(feature = "ldap")]
+use ldap3::{dn_escape, ldap_escape, Ldap, LdapConnAsync, LdapConnSettings, LdapError, Scope, SearchEntry};
+#[cfg(feature = "ldap")]
+use std::time::Duration;
+
+#[cfg(feature = "ldap")]
+use crate::config::LdapConfig;
+use crate::error::ApiError;
+use crate::guests;
+use crate::ws_handlers::{is_matrix_id, AppState};
+
+/// A user directory checking passwords, such as the `[ldap]` server. A server with one
+/// creates the accounts of directory users on their first login and sends their later
+/// logins to it; `ChatServerBuilder::directory` sets another, e.g. a fake one in tests.
+pub trait Directory: std::fmt::Debug + Send + Sync {
+    /// Whether the directory accepts `password` for `username`. `Err(reason)` when it could
+    /// not be asked, or did not answer in time.
+    fn authenticate<'a>(&'a self, username: &'a str, password: &'a str) -> BoxFuture<'a, Result<bool, String>>;
+}
+
+/// Whether the directory accepts `password` for `username`. `Ok(false)` without a directory,
+/// for names that belong to bridged users or guests, and for wrong passwords;
+/// `DirectoryUnavailable` when the directory could not be asked.
+pub(crate) async fn authenticate(app_state: &AppState, username: &str, password: &str) -> Result<bool, ApiError> {
+    let Some(directory) = &app_state.directory else { return Ok(false) };
+    // An empty password makes an unauthenticated bind, which servers accept for any DN.
+    if password.is_empty() || is_matrix_id(username) || guests::is_guest_name(username) {
+        return Ok(false);
+    }
+    directory.authenticate(username, password).await.map_err(|e| {
+        tracing::error!(error = %e, "Cannot check a password against the directory");
+        ApiError::DirectoryUnavailable
+    })
+}
+
+/// The LDAP or Active Directory server of `[ldap]`.
+#[cfg(feature = "ldap")]
+#[derive(Debug)]
+pub(crate) struct LdapDirectory {
+    config: LdapConfig,
+}
+
+#[cfg(feature = "ldap")]
+impl LdapDirectory {
+    pub(crate) fn new(config: &LdapConfig) -> Self {
+        LdapDirectory { config: config.clone() }
+    }
+}
+
+#[cfg(feature = "ldap")]
+impl Directory for LdapDirectory {
+    fn authenticate<'a>(&'a self, username: &'a str, password: &'a str) -> BoxFuture<'a, Result<bool, String>> {
+        Box::pin(async move {
+            let config = &self.config;
+            match tokio::time::timeout(Duration::from_secs(config.timeout_secs), bind_as_user(config, username, password)).await {
+                Ok(result) => result.map_err(|e| format!("{}: {}", config.url, e)),
+                Err(_) => Err(format!("{} did not answer in {} seconds", config.url, config.timeout_secs)),
+            }
+        })
+    }
+}
+
+// Binds as the user, at the DN of `ldap.user_dn` or the one a search finds.
+#[cfg(feature = "ldap")]
+async fn bind_as_user(config: &LdapConfig, username: &str, password: &str) -> Result<bool, LdapError> {
+    let settings = LdapConnSettings::new().set_starttls(config.starttls);
+    let (connection, mut ldap) = LdapConnAsync::with_settings(settings, &config.url).await?;
+    ldap3::drive!(connection);
+    let dn = match &config.user_dn {
+        Some(template) => Some(template.replace("{username}", &dn_escape(username))),
+        None => find_user_dn(&mut ldap, config, username).await?,
+    };
+    let accepted = match dn {
+        Some(dn) => ldap.simple_bind(&dn, password).await?.success().is_ok(),
+        None => false,
+    };
+    let _ = ldap.unbind().await;
+    Ok(accepted)
+}
+
+// The DN of `username`'s entry under `ldap.base_dn`, searched as `ldap.bind_dn`. None unless
+// exactly one entry matches: an ambiguous filter must not let one user log in as another.
+#[cfg(feature = "ldap")]
+async fn find_user_dn(ldap: &mut Ldap, config: &LdapConfig, username: &str) -> Result<Option<String>, LdapError> {
+    if let (Some(bind_dn), Some(bind_password)) = (&config.bind_dn, &config.bind_password) {
+        ldap.simple_bind(bind_dn, bind_password).await?.success()?;
+    }
+    let base_dn = config.base_dn.as_deref().unwrap_or_default();
+    let filter = config.user_filter.replace("{username}", &ldap_escape(username));
+    // "1.1" asks for no attributes; only the DN is needed.
+    let (mut entries, _) = ldap.search(base_dn, Scope::Subtree, &filter, vec!["1.1"]).await?.success()?;
+    if entries.len() != 1 {
+        return Ok(None);
+    }
+    Ok(entries.pop().map(|entry| SearchEntry::construct(entry).dn))
+}
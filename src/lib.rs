@@ -0,0 +1,93 @@
+// src/lib.rs
+
+//! The chat server as a library: `run` starts it from a configuration, `ChatServerBuilder`
+//! configures one programmatically and `build_routes` gives the route tree for a prepared
+//! `AppState`, so the server can be embedded or tested in-process.
+
+// The combined warp route filter nests deeper than the default limit allows.
+#![recursion_limit = "256"]
+
+mod access_log; // Sampled HTTP access log with slow requests highlighted
+mod analytics; // Daily activity aggregates (active users, messages, peak connections) for the admin analytics endpoint
+mod api_docs; // OpenAPI specification and Swagger UI served at /docs
+mod bots; // Bot accounts and their scoped API tokens
+mod broadcasts; // Broadcast lists for sending one message to several contacts
+mod calls; // State of 1:1 WebRTC calls signaled over the WebSocket
+mod client_ip; // Real client address resolution behind trusted reverse proxies
+mod clock; // Hybrid logical clock stamping delivered messages in a never-decreasing order
+mod cluster; // Membership of the node in a cluster: peers, heartbeats and signed requests between nodes
+pub mod config; // Typed server configuration loaded from TOML with env overrides
+mod connections; // Registry task owning the active connections and routing frames to them
+mod conversations; // Per-conversation unread counts and last activity
+mod device; // Devices of sessions: client-given names, user agents and fingerprints telling them apart
+mod e2e; // Prekey bundles and opaque payloads for end-to-end encryption
+mod error; // API error type with stable machine-readable codes
+pub mod events; // Domain events broadcast to webhooks, pushes, metrics and the audit log
+mod fanout; // Worker tasks queuing frames meant for many connections off the handlers' tasks
+#[cfg(feature = "fault-injection")]
+pub mod faults; // Deterministic latency, registry contention and dropped sends for resilience tests
+pub mod fuzzing; // Entry points of the cargo-fuzz targets in fuzz/
+mod geoip; // Countries of client addresses from a MaxMind CSV database, restricting registration and login
+mod guests; // Temporary passwordless guest accounts, deleted with their data when they expire
+#[cfg(feature = "grpc")]
+mod grpc; // Optional tonic gRPC service for server-to-server integrations
+mod handlers; // HTTP API handlers, one module per area of the API
+pub mod interceptors; // Hooks into chat message routing for filters, metrics and archiving
+mod invites; // Invite codes for invite-only registration, with per-user quotas and expiry
+mod ip_filter; // CIDR allow and deny lists of client addresses, checked before routing
+#[cfg(feature = "kafka")]
+mod kafka; // Export of message, presence and audit events to Kafka through its REST proxy
+mod ldap; // Password checks against an LDAP or Active Directory server, provisioning accounts on first login
+mod location; // Shared locations and live-location updates
+mod lockout; // Failed login counting and temporary account lockout
+mod login_alerts; // Familiar login addresses and one-click tokens revoking sessions from unfamiliar ones
+mod markdown; // Optional sanitized HTML rendering of chat message markdown
+#[cfg(feature = "matrix")]
+mod matrix; // Matrix appservice bridge relaying conversations with Matrix users
+mod middleware; // Per-route middleware stacks: policies such as rate limits, body limits and authentication
+#[cfg(feature = "mobile-push")]
+mod mobile_push; // FCM/APNs pushes to registered mobile devices
+#[cfg(feature = "mqtt")]
+mod mqtt; // MQTT bridge publishing message/presence events for IoT integrations
+#[cfg(feature = "nats")]
+mod nats; // NATS routing of frames between nodes, offline queues in JetStream and mirrored events
+mod ordering; // Shard tasks delivering each conversation's messages in order
+mod polls; // Polls posted in conversations and their live tallies
+mod presence; // Presence of the users connected to the other nodes of a cluster
+mod push; // Push subscriptions, devices and notification settings for offline recipients
+mod redact; // Sensitive values (session keys, message bodies, contacts) kept out of the logs
+mod registrations; // Registrations waiting for an admin's approval
+mod reload; // Runtime reload of the tunable config subset (SIGHUP / admin endpoint)
+mod routes; // Route tree of the HTTP API and WebSocket endpoint
+mod scheduler; // Named recurring background tasks with jitter, statistics and shutdown
+mod server; // Builder wiring the configuration, state and subsystems into a runnable server
+mod shards; // State maps split into independently locked shards by key
+mod snapshot; // Periodic snapshot of users and contacts to disk, loaded at startup
+mod stats; // Counters backing the admin statistics endpoint
+mod telemetry; // Tracing subscriber and optional OpenTelemetry export
+pub mod testing; // In-process test server, user helpers and a WebSocket test client
+mod wal; // Write-ahead log of user and contact changes between snapshots
+mod webhooks; // Signed outgoing webhook deliveries with retry/backoff
+#[cfg(feature = "web-push")]
+mod webpush; // VAPID-signed Web Push notifications to browser subscriptions
+mod ws_handlers; // Declare your WebSocket handlers module
+#[cfg(feature = "xmpp")]
+mod xmpp; // XMPP (c2s subset) gateway for legacy XMPP clients
+
+pub use crate::config::Config;
+pub use crate::ldap::Directory;
+pub use crate::routes::build_routes;
+pub use crate::server::{ChatServer, ChatServerBuilder, ServerError};
+pub use crate::ws_handlers::AppState;
+
+/// Starts the server described by `config` and serves until Ctrl-C. Startup
+/// failures (unreadable credentials, a corrupt snapshot, ...) are reported and end the process.
+pub async fn run(config: Config) {
+    match ChatServerBuilder::from_config(config).build().await {
+        Ok(server) => server.run().await,
+        Err(e) => {
+            eprintln!("Cannot start the server: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,26 @@
+// src/location.rs
+
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Longest a live location may be shared for.
+pub const MAX_LIVE_DURATION: Duration = Duration::hours(8);
+
+/// A location shared with live updates, until it expires or its sender stops it.
+#[derive(Debug, Clone)]
+pub struct LiveLocation {
+    pub sender_id: Uuid,
+    pub to_user_id: Uuid,
+    pub live_until: DateTime<Utc>,
+}
+
+/// Live locations being shared: location id (the id of the original message) -> share.
+pub type LiveLocationRegistry = HashMap<Uuid, LiveLocation>;
+
+/// Whether the coordinates (in degrees) and accuracy radius (in meters) are plausible.
+pub fn is_valid_position(lat: f64, lon: f64, accuracy: Option<f64>) -> bool {
+    (-90.0..=90.0).contains(&lat)
+        && (-180.0..=180.0).contains(&lon)
+        && accuracy.is_none_or(|accuracy| accuracy.is_finite() && accuracy >= 0.0)
+}
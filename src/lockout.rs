@@ -0,0 +1,59 @@
+// src/lockout.rs
+
+use chrono::{DateTime, Duration, Utc};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::ws_handlers::AppState;
+
+/// Consecutive failed password logins of a user, and until when their account is locked.
+#[derive(Debug, Clone, Default)]
+pub struct LoginFailures {
+    pub count: u32,
+    pub locked_until: Option<DateTime<Utc>>,
+}
+
+/// Failed logins per user id; users whose last login succeeded have no entry.
+pub type LoginFailureRegistry = HashMap<Uuid, LoginFailures>;
+
+/// When the lock of `user_id`'s account ends, if it is locked now. An expired lock is
+/// forgotten along with the failures that caused it.
+pub async fn locked_until(app_state: &AppState, user_id: Uuid) -> Option<DateTime<Utc>> {
+    let mut failures = app_state.login_failures.lock().await;
+    let until = failures.get(&user_id)?.locked_until?;
+    if until > Utc::now() {
+        Some(until)
+    } else {
+        failures.remove(&user_id);
+        None
+    }
+}
+
+/// Counts a wrong password for `user_id`, locking the account for `auth.lockout_secs` once
+/// `auth.max_failed_logins` follow each other. Returns when the lock ends if this failure
+/// locked it.
+pub async fn record_failure(app_state: &AppState, user_id: Uuid) -> Option<DateTime<Utc>> {
+    let auth = &app_state.config.auth;
+    if auth.max_failed_logins == 0 {
+        return None;
+    }
+    let mut failures = app_state.login_failures.lock().await;
+    let entry = failures.entry(user_id).or_default();
+    if entry.locked_until.is_some_and(|until| until > Utc::now()) {
+        return None;
+    }
+    entry.count = entry.count.saturating_add(1);
+    if entry.count < auth.max_failed_logins {
+        return None;
+    }
+    let until = Utc::now() + Duration::seconds(auth.lockout_secs as i64);
+    *entry = LoginFailures { count: 0, locked_until: Some(until) };
+    Some(until)
+}
+
+/// Forgets the failed logins of `user_id` and lifts any lock, returning whether the account
+/// was locked.
+pub async fn clear(app_state: &AppState, user_id: Uuid) -> bool {
+    let removed = app_state.login_failures.lock().await.remove(&user_id);
+    removed.and_then(|failures| failures.locked_until).is_some_and(|until| until > Utc::now())
+}
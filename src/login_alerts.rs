@@ -0,0 +1,89 @@
+// src/login_alerts.rs
+
+use chrono::{DateTime, Duration, Utc};
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use uuid::Uuid;
+
+/// Devices and addresses remembered per user, from their latest logins.
+const RECENT_LOGINS: usize = 10;
+
+/// A session that can be revoked with a one-click token sent in a security alert.
+#[derive(Debug, Clone)]
+pub struct RevokeToken {
+    pub user_id: Uuid,
+    pub session_key: String,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Devices (by fingerprint) and addresses of a user's latest logins, most recent last.
+#[derive(Debug, Default)]
+struct LoginHistory {
+    devices: VecDeque<String>,
+    addresses: VecDeque<IpAddr>,
+}
+
+/// What new-login alerts need: which devices and addresses each user logged in from, and the
+/// tokens revoking the sessions alerts were sent about. Kept in memory only, so after a
+/// restart each user's first login is familiar again.
+#[derive(Debug, Default)]
+pub struct LoginAlerts {
+    history: HashMap<Uuid, LoginHistory>,
+    // One-click revoke token -> the session it revokes.
+    revoke_tokens: HashMap<String, RevokeToken>,
+}
+
+impl LoginAlerts {
+    /// Remembers a login of `user_id` from the device with `fingerprint` at `client_ip`,
+    /// returning whether it is unfamiliar: the user logged in before, never from this device.
+    /// A re-login from a known device is familiar wherever it comes from; logins of clients
+    /// telling nothing about their device are compared by address instead, and not at all
+    /// when that is unknown too.
+    pub fn record_login(&mut self, user_id: Uuid, client_ip: Option<IpAddr>, fingerprint: Option<&str>) -> bool {
+        let client_ip = client_ip.map(|ip| ip.to_canonical());
+        let history = self.history.entry(user_id).or_default();
+        let first = history.devices.is_empty() && history.addresses.is_empty();
+        let familiar = match (fingerprint, client_ip) {
+            (Some(fingerprint), _) => history.devices.iter().any(|device| device == fingerprint),
+            (None, Some(ip)) => history.addresses.contains(&ip),
+            (None, None) => true,
+        };
+        if let Some(fingerprint) = fingerprint {
+            remember(&mut history.devices, fingerprint.to_string());
+        }
+        if let Some(ip) = client_ip {
+            remember(&mut history.addresses, ip);
+        }
+        !first && !familiar
+    }
+
+    /// A single-use token revoking `session_key` of `user_id`, valid for `ttl` (the session's
+    /// own lifetime). Expired tokens are dropped on the way.
+    pub fn issue_revoke_token(&mut self, user_id: Uuid, session_key: &str, ttl: Duration) -> String {
+        let now = Utc::now();
+        self.revoke_tokens.retain(|_, token| token.expires_at > now);
+        let token = Uuid::new_v4().simple().to_string();
+        self.revoke_tokens.insert(token.clone(), RevokeToken { user_id, session_key: session_key.to_string(), expires_at: now + ttl });
+        token
+    }
+
+    /// Uses up `token`, returning the session it revokes unless it is unknown or expired.
+    pub fn redeem(&mut self, token: &str) -> Option<RevokeToken> {
+        self.revoke_tokens.remove(token).filter(|token| token.expires_at > Utc::now())
+    }
+
+    /// Forgets the logins of `user_id` and the tokens revoking their sessions.
+    pub fn forget(&mut self, user_id: Uuid) {
+        self.history.remove(&user_id);
+        self.revoke_tokens.retain(|_, token| token.user_id != user_id);
+    }
+}
+
+// Moves `item` to the most recent end of `recent`, forgetting the oldest beyond `RECENT_LOGINS`.
+fn remember<T: PartialEq>(recent: &mut VecDeque<T>, item: T) {
+    recent.retain(|known| *known != item);
+    recent.push_back(item);
+    if recent.len() > RECENT_LOGINS {
+        recent.pop_front();
+    }
+}
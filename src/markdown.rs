@@ -0,0 +1,75 @@
+// src/markdown.rs
+
+use ammonia::Builder;
+use pulldown_cmark::{html, Event, Options, Parser, Tag, TagEnd};
+use std::collections::HashSet;
+use std::sync::LazyLock;
+
+/// Tags the rendered HTML may contain: inline formatting, links, code, lists and quotes.
+/// Anything else (headings, images, tables, ...) is reduced to its text.
+const ALLOWED_TAGS: [&str; 12] = ["p", "br", "em", "strong", "del", "code", "pre", "a", "ul", "ol", "li", "blockquote"];
+
+static SANITIZER: LazyLock<Builder<'static>> = LazyLock::new(|| {
+    let mut builder = Builder::empty();
+    builder
+        .tags(HashSet::from(ALLOWED_TAGS))
+        .tag_attributes([("a", HashSet::from(["href"]))].into())
+        .url_schemes(HashSet::from(["http", "https", "mailto"]))
+        .link_rel(Some("noopener noreferrer nofollow"));
+    builder
+});
+
+/// Renders the markdown of a chat message to sanitized HTML. Raw HTML in the message is
+/// shown as text rather than interpreted.
+pub fn render(message: &str) -> String {
+    let parser = Parser::new_ext(message, Options::ENABLE_STRIKETHROUGH).filter_map(|event| match event {
+        Event::Html(html) | Event::InlineHtml(html) => Some(Event::Text(html)),
+        // Only the description of an image is kept, which would otherwise go into its `alt`.
+        Event::Start(Tag::Image { .. }) | Event::End(TagEnd::Image) => None,
+        event => Some(event),
+    });
+    let mut rendered = String::new();
+    html::push_html(&mut rendered, parser);
+    SANITIZER.clean(&rendered).to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inline_scripts_are_shown_as_text() {
+        let rendered = render("hi <script>alert(1)</script> there");
+        assert!(!rendered.contains("<script"), "{}", rendered);
+        assert!(rendered.contains("&lt;script&gt;alert(1)&lt;/script&gt;"), "{}", rendered);
+    }
+
+    #[test]
+    fn javascript_links_lose_their_target() {
+        let rendered = render("[click](javascript:alert(1))");
+        assert!(!rendered.contains("javascript:"), "{}", rendered);
+        assert!(rendered.contains("click"), "{}", rendered);
+        let rendered = render("[site](https://example.com)");
+        assert!(rendered.contains(r#"<a href="https://example.com" rel="noopener noreferrer nofollow">site</a>"#), "{}", rendered);
+    }
+
+    #[test]
+    fn images_are_reduced_to_their_text() {
+        let rendered = render("![logo](https://example.com/x.png)");
+        assert!(!rendered.contains("<img"), "{}", rendered);
+        assert!(!rendered.contains("example.com"), "{}", rendered);
+        assert!(rendered.contains("logo"), "{}", rendered);
+    }
+
+    #[test]
+    fn raw_html_blocks_are_shown_as_text() {
+        let rendered = render("<div onclick=\"steal()\">\n<iframe src=\"https://evil.example\"></iframe>\n</div>");
+        assert!(!rendered.contains("<div") && !rendered.contains("<iframe"), "{}", rendered);
+        assert!(rendered.contains("&lt;iframe"), "{}", rendered);
+    }
+
+    #[test]
+    fn inline_formatting_is_kept() {
+        assert_eq!(render("**bold** _it_ ~~gone~~ `code`"), "<p><strong>bold</strong> <em>it</em> <del>gone</del> <code>code</code></p>\n");
+    }
+}
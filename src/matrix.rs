@@ -0,0 +1,482 @@
+// src/matrix.rs
+
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::{Filter, Rejection, Reply};
+
+use crate::config::MatrixConfig;
+use crate::device::Device;
+use crate::redact::pseudonym;
+use crate::wal::{self, Mutation};
+use crate::ws_handlers::{self, is_matrix_id, AppState, LastActive, Role, User, UserSession};
+
+/// Transaction ids remembered to acknowledge homeserver retries without replaying them.
+const SEEN_TRANSACTIONS: usize = 1000;
+
+/// A Matrix DM room bridged to the conversation between a local user and a Matrix user.
+#[derive(Debug, Clone)]
+struct Portal {
+    room_id: String,
+    local_user_id: Uuid,
+    matrix_user_id: String,
+}
+
+/// Everything that can go wrong while talking to the homeserver.
+#[derive(Debug)]
+pub enum MatrixError {
+    Request(reqwest::Error),
+    Homeserver { status: u16, errcode: String },
+}
+
+impl fmt::Display for MatrixError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MatrixError::Request(e) => write!(f, "homeserver request failed: {}", e),
+            MatrixError::Homeserver { status, errcode } => write!(f, "homeserver responded with {} {}", status, errcode),
+        }
+    }
+}
+
+impl std::error::Error for MatrixError {}
+
+impl From<reqwest::Error> for MatrixError {
+    fn from(e: reqwest::Error) -> Self {
+        MatrixError::Request(e)
+    }
+}
+
+/// Application service bridging local users and conversations to a Matrix homeserver.
+///
+/// Local users appear on Matrix as puppets `@{user_prefix}{username}:{server_name}`; Matrix
+/// users appear locally as ghost users named by their Matrix ID, so they can be added as
+/// contacts. Each local/Matrix pair of users shares one portal room.
+#[derive(Debug)]
+pub struct MatrixBridge {
+    client: reqwest::Client,
+    config: MatrixConfig,
+    // Bridged rooms: room id -> portal.
+    portals: Mutex<HashMap<String, Portal>>,
+    // Ghost users standing in for Matrix users: local user id -> Matrix ID.
+    ghosts: Mutex<HashMap<Uuid, String>>,
+    // Puppets already registered on the homeserver.
+    registered_puppets: Mutex<HashSet<String>>,
+    seen_transactions: Mutex<VecDeque<String>>,
+}
+
+impl MatrixBridge {
+    pub fn new(config: &MatrixConfig) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .user_agent(concat!("rust_chat-matrix/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .expect("failed to build Matrix HTTP client");
+        MatrixBridge {
+            client,
+            config: config.clone(),
+            portals: Mutex::new(HashMap::new()),
+            ghosts: Mutex::new(HashMap::new()),
+            registered_puppets: Mutex::new(HashSet::new()),
+            seen_transactions: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    fn puppet_localpart(&self, username: &str) -> String {
+        format!("{}{}", self.config.user_prefix, escape_localpart(username))
+    }
+
+    /// The Matrix ID of the puppet representing local user `username`.
+    fn puppet_id(&self, username: &str) -> String {
+        format!("@{}:{}", self.puppet_localpart(username), self.config.server_name)
+    }
+
+    /// Whether `matrix_id` belongs to the bridge itself (its bot or one of its puppets).
+    fn is_bridge_user(&self, matrix_id: &str) -> bool {
+        let Some(localpart) = matrix_id
+            .strip_prefix('@')
+            .and_then(|rest| rest.strip_suffix(&format!(":{}", self.config.server_name)))
+        else {
+            return false;
+        };
+        localpart == self.config.sender_localpart || localpart.starts_with(&self.config.user_prefix)
+    }
+
+    /// The local (non-ghost) user a puppet Matrix ID stands for.
+    async fn puppeted_user(&self, app_state: &AppState, matrix_id: &str) -> Option<User> {
+        app_state
+            .users
+            .find(|user| !is_matrix_id(&user.username) && self.puppet_id(&user.username) == matrix_id)
+    }
+
+    /// A Client-Server API URL, acting as `as_user` when given (appservice identity assertion).
+    fn client_url(&self, segments: &[&str], as_user: Option<&str>) -> reqwest::Url {
+        let mut url = reqwest::Url::parse(&self.config.homeserver_url).expect("homeserver_url is validated at startup");
+        url.path_segments_mut()
+            .expect("homeserver_url is validated at startup")
+            .pop_if_empty()
+            .extend(["_matrix", "client", "v3"])
+            .extend(segments);
+        if let Some(user_id) = as_user {
+            url.query_pairs_mut().append_pair("user_id", user_id);
+        }
+        url
+    }
+
+    async fn call(&self, method: reqwest::Method, url: reqwest::Url, body: &Value) -> Result<Value, MatrixError> {
+        let response = self
+            .client
+            .request(method, url)
+            .bearer_auth(&self.config.as_token)
+            .json(body)
+            .send()
+            .await?;
+        let status = response.status();
+        let body: Value = response.json().await.unwrap_or(Value::Null);
+        if status.is_success() {
+            Ok(body)
+        } else {
+            let errcode = body["errcode"].as_str().unwrap_or("M_UNKNOWN").to_string();
+            Err(MatrixError::Homeserver { status: status.as_u16(), errcode })
+        }
+    }
+
+    /// Registers the puppet of a local user on the homeserver (once) and sets its display name.
+    async fn ensure_puppet(&self, username: &str) -> Result<String, MatrixError> {
+        let puppet = self.puppet_id(username);
+        if self.registered_puppets.lock().await.contains(&puppet) {
+            return Ok(puppet);
+        }
+
+        let register = json!({ "type": "m.login.application_service", "username": self.puppet_localpart(username) });
+        match self.call(reqwest::Method::POST, self.client_url(&["register"], None), &register).await {
+            Ok(_) => {}
+            Err(MatrixError::Homeserver { errcode, .. }) if errcode == "M_USER_IN_USE" => {}
+            Err(e) => return Err(e),
+        }
+        let displayname = json!({ "displayname": username });
+        let url = self.client_url(&["profile", &puppet, "displayname"], Some(&puppet));
+        if let Err(e) = self.call(reqwest::Method::PUT, url, &displayname).await {
+            tracing::warn!(puppet = %puppet, error = %e, "Cannot set Matrix puppet display name");
+        }
+
+        self.registered_puppets.lock().await.insert(puppet.clone());
+        Ok(puppet)
+    }
+
+    /// Finds the portal between a local user and a Matrix user, creating the DM room if needed.
+    async fn portal_room(&self, local_user_id: Uuid, puppet: &str, matrix_user_id: &str) -> Result<String, MatrixError> {
+        let existing = self
+            .portals
+            .lock()
+            .await
+            .values()
+            .find(|portal| portal.local_user_id == local_user_id && portal.matrix_user_id == matrix_user_id)
+            .map(|portal| portal.room_id.clone());
+        if let Some(room_id) = existing {
+            return Ok(room_id);
+        }
+
+        let create = json!({ "invite": [matrix_user_id], "is_direct": true, "preset": "trusted_private_chat" });
+        let created = self.call(reqwest::Method::POST, self.client_url(&["createRoom"], Some(puppet)), &create).await?;
+        let room_id = created["room_id"].as_str().unwrap_or_default().to_string();
+        tracing::info!(room_id = %room_id, local_user_id = %local_user_id, matrix_user_id = %matrix_user_id, "Matrix portal room created");
+        self.portals.lock().await.insert(
+            room_id.clone(),
+            Portal { room_id: room_id.clone(), local_user_id, matrix_user_id: matrix_user_id.to_string() },
+        );
+        Ok(room_id)
+    }
+
+    /// Sends a local user's message into the portal room shared with `matrix_user_id`.
+    async fn send_to_matrix(
+        &self,
+        local_user_id: Uuid,
+        username: &str,
+        matrix_user_id: &str,
+        message_id: &str,
+        body: &str,
+    ) -> Result<(), MatrixError> {
+        let puppet = self.ensure_puppet(username).await?;
+        let room_id = self.portal_room(local_user_id, &puppet, matrix_user_id).await?;
+        let content = json!({ "msgtype": "m.text", "body": body });
+        // The local message id doubles as the transaction id, making retries idempotent.
+        let url = self.client_url(&["rooms", &room_id, "send", "m.room.message", message_id], Some(&puppet));
+        self.call(reqwest::Method::PUT, url, &content).await?;
+        Ok(())
+    }
+
+    /// Records a transaction id, returning false if it was already processed.
+    async fn first_delivery(&self, txn_id: &str) -> bool {
+        let mut seen = self.seen_transactions.lock().await;
+        if seen.iter().any(|seen_id| seen_id == txn_id) {
+            return false;
+        }
+        if seen.len() >= SEEN_TRANSACTIONS {
+            seen.pop_front();
+        }
+        seen.push_back(txn_id.to_string());
+        true
+    }
+}
+
+/// Maps a username onto the characters allowed in a Matrix localpart, reversibly: upper-case
+/// letters become `_` plus the lower-case letter, `_` is doubled and anything else outside
+/// `[a-z0-9.=/-]` is written as `=` and the hex value of each UTF-8 byte.
+fn escape_localpart(username: &str) -> String {
+    let mut escaped = String::with_capacity(username.len());
+    for c in username.chars() {
+        match c {
+            'a'..='z' | '0'..='9' | '.' | '-' | '/' => escaped.push(c),
+            'A'..='Z' => {
+                escaped.push('_');
+                escaped.push(c.to_ascii_lowercase());
+            }
+            '_' => escaped.push_str("__"),
+            _ => {
+                let mut utf8 = [0; 4];
+                for byte in c.encode_utf8(&mut utf8).bytes() {
+                    escaped.push_str(&format!("={:02x}", byte));
+                }
+            }
+        }
+    }
+    escaped
+}
+
+/// Returns the local ghost user standing in for Matrix user `matrix_user_id`, creating it on
+/// first use. `None` when the bridge is disabled or the ID belongs to one of its own puppets.
+pub async fn ensure_ghost(app_state: &Arc<AppState>, matrix_user_id: &str) -> Option<User> {
+    let bridge = app_state.matrix.as_ref()?;
+    if !is_matrix_id(matrix_user_id) || bridge.is_bridge_user(matrix_user_id) {
+        return None;
+    }
+
+    let mut users = app_state.users.lock(matrix_user_id).await;
+    if let Some(ghost) = users.get(matrix_user_id) {
+        return Some(ghost.clone());
+    }
+    // Ghosts have no password hash, so they can never log in.
+    let ghost = User {
+        id: Uuid::new_v4(),
+        username: matrix_user_id.to_string(),
+        password_hash: String::new(),
+        role: Role::User,
+        contacts: Arc::new(Mutex::new(HashMap::new())),
+        bot_owner: None,
+        guest_until: None,
+        ldap: false,
+    };
+    users.insert(ghost.username.clone(), ghost.clone());
+    drop(users);
+    bridge.ghosts.lock().await.insert(ghost.id, ghost.username.clone());
+    wal::record(app_state, Mutation::user_created(&ghost)).await;
+    tracing::info!(user_id = %ghost.id, matrix_user_id = %matrix_user_id, "Matrix ghost user created");
+    Some(ghost)
+}
+
+/// Relays a routed chat message to Matrix when its recipient is a ghost user. Sending happens
+/// in the background so local delivery is never held up by the homeserver.
+pub async fn relay_outbound(app_state: &Arc<AppState>, sender_session: &UserSession, to_user_id: Uuid, message_id: &str, message: &str) {
+    let Some(bridge) = &app_state.matrix else { return };
+    let Some(matrix_user_id) = bridge.ghosts.lock().await.get(&to_user_id).cloned() else { return };
+    // Messages posted on a user's behalf (incoming webhooks) carry a display name rather than
+    // the username, so the puppet is chosen by user id.
+    let sender_username = app_state
+        .users
+        .get_by_id(&sender_session.user_id)
+        .map(|user| user.username);
+    let Some(sender_username) = sender_username else { return };
+
+    let app_state = app_state.clone();
+    let sender_user_id = sender_session.user_id;
+    let message_id = message_id.to_string();
+    let message = message.to_string();
+    tokio::spawn(async move {
+        let Some(bridge) = &app_state.matrix else { return };
+        match bridge.send_to_matrix(sender_user_id, &sender_username, &matrix_user_id, &message_id, &message).await {
+            Ok(()) => tracing::debug!(message_id = %message_id, matrix_user_id = %matrix_user_id, "Message relayed to Matrix"),
+            Err(e) => tracing::warn!(message_id = %message_id, matrix_user_id = %matrix_user_id, error = %e, "Relaying message to Matrix failed"),
+        }
+    });
+}
+
+/// A homeserver event pushed to the application service.
+#[derive(Deserialize, Debug)]
+struct MatrixEvent {
+    #[serde(rename = "type")]
+    kind: String,
+    room_id: Option<String>,
+    sender: String,
+    state_key: Option<String>,
+    #[serde(default)]
+    content: Value,
+}
+
+#[derive(Deserialize)]
+struct Transaction {
+    #[serde(default)]
+    events: Vec<MatrixEvent>,
+}
+
+/// Handles one event of a transaction: invites to puppets open portals, messages in portals
+/// are routed to the local user like any other chat message.
+async fn handle_event(app_state: &Arc<AppState>, bridge: &MatrixBridge, event: MatrixEvent) {
+    let Some(room_id) = event.room_id else { return };
+    if bridge.is_bridge_user(&event.sender) {
+        // Echo of something the bridge sent itself.
+        return;
+    }
+
+    match event.kind.as_str() {
+        "m.room.member" if event.content["membership"] == "invite" => {
+            let Some(puppet) = event.state_key else { return };
+            let local_user = bridge.puppeted_user(app_state, &puppet).await;
+            let (Some(local_user), Some(ghost)) = (local_user, ensure_ghost(app_state, &event.sender).await) else {
+                tracing::debug!(room_id = %room_id, puppet = %puppet, "Ignoring Matrix invite for an unknown user");
+                return;
+            };
+
+            if let Err(e) = bridge.ensure_puppet(&local_user.username).await {
+                tracing::warn!(room_id = %room_id, error = %e, "Cannot register Matrix puppet");
+                return;
+            }
+            let url = bridge.client_url(&["rooms", &room_id, "join"], Some(&puppet));
+            if let Err(e) = bridge.call(reqwest::Method::POST, url, &json!({})).await {
+                tracing::warn!(room_id = %room_id, puppet = %puppet, error = %e, "Cannot join Matrix portal room");
+                return;
+            }
+
+            // The Matrix user shows up in the local user's contacts like anyone else.
+            local_user.contacts.lock().await.insert(ghost.id, ghost.username.clone());
+            ghost.contacts.lock().await.insert(local_user.id, local_user.username.clone());
+            wal::record(app_state, Mutation::ContactAdded { user_id: local_user.id, contact_id: ghost.id }).await;
+            bridge.portals.lock().await.insert(
+                room_id.clone(),
+                Portal { room_id: room_id.clone(), local_user_id: local_user.id, matrix_user_id: event.sender.clone() },
+            );
+            tracing::info!(room_id = %room_id, local_user_id = %local_user.id, matrix_user_id = %pseudonym(&event.sender), "Joined Matrix portal room");
+        }
+        "m.room.message" => {
+            let Some(portal) = bridge.portals.lock().await.get(&room_id).cloned() else { return };
+            let Some(body) = event.content["body"].as_str().filter(|body| !body.trim().is_empty()) else { return };
+            let Some(ghost) = ensure_ghost(app_state, &event.sender).await else { return };
+
+            let sender = UserSession {
+                user_id: ghost.id,
+                username: ghost.username.clone(),
+                session_key: format!("matrix:{}", portal.room_id),
+                created_at: Instant::now(),
+                client_ip: None,
+                country: None,
+                device: Device::default(),
+                trusted_device: Arc::default(),
+                signing_key: None,
+                last_active: LastActive::now(),
+            };
+            match ws_handlers::route_chat_message(app_state, &sender, portal.local_user_id, body.to_string()).await {
+                Ok(message_id) => tracing::info!(room_id = %room_id, matrix_user_id = %pseudonym(&event.sender), message_id = %message_id, "Message relayed from Matrix"),
+                Err(e) => tracing::warn!(room_id = %room_id, matrix_user_id = %pseudonym(&event.sender), reason = %e, "Dropping Matrix message"),
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Rejects requests that do not carry the homeserver token (`Authorization: Bearer` or the
+/// legacy `access_token` query parameter), and every request while the bridge is disabled.
+fn with_homeserver_auth(
+    app_state: Arc<AppState>,
+) -> impl Filter<Extract = (Arc<AppState>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(move |authorization: Option<String>, query: HashMap<String, String>| {
+            let app_state = app_state.clone();
+            async move {
+                let Some(bridge) = &app_state.matrix else { return Err(warp::reject::not_found()) };
+                let token = authorization
+                    .as_deref()
+                    .and_then(|value| value.strip_prefix("Bearer "))
+                    .or(query.get("access_token").map(String::as_str));
+                if token == Some(bridge.config.hs_token.as_str()) {
+                    Ok(app_state)
+                } else {
+                    Err(warp::reject::custom(MatrixForbidden))
+                }
+            }
+        })
+}
+
+/// Rejection for appservice requests with a missing or wrong homeserver token.
+#[derive(Debug)]
+struct MatrixForbidden;
+
+impl warp::reject::Reject for MatrixForbidden {}
+
+fn matrix_error(errcode: &str, status: StatusCode) -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(warp::reply::json(&json!({ "errcode": errcode })), status)
+}
+
+/// The application service API the homeserver calls, mounted at `/_matrix/app/v1`.
+pub fn appservice_routes(app_state: Arc<AppState>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    let transactions = warp::path!("_matrix" / "app" / "v1" / "transactions" / String)
+        .and(warp::put())
+        .and(with_homeserver_auth(app_state.clone()))
+        .and(warp::body::json())
+        .then(|txn_id: String, app_state: Arc<AppState>, transaction: Transaction| async move {
+            let bridge = app_state.matrix.as_ref().expect("checked by with_homeserver_auth");
+            if bridge.first_delivery(&txn_id).await {
+                tracing::debug!(txn_id = %txn_id, events = transaction.events.len(), "Matrix transaction received");
+                for event in transaction.events {
+                    handle_event(&app_state, bridge, event).await;
+                }
+            }
+            warp::reply::json(&json!({}))
+        });
+
+    // The homeserver asks whether a user in the bridge's namespace exists before using it.
+    let users = warp::path!("_matrix" / "app" / "v1" / "users" / String)
+        .and(warp::get())
+        .and(with_homeserver_auth(app_state.clone()))
+        .then(|matrix_user_id: String, app_state: Arc<AppState>| async move {
+            let bridge = app_state.matrix.as_ref().expect("checked by with_homeserver_auth");
+            match bridge.puppeted_user(&app_state, &matrix_user_id).await {
+                Some(local_user) => match bridge.ensure_puppet(&local_user.username).await {
+                    Ok(_) => warp::reply::with_status(warp::reply::json(&json!({})), StatusCode::OK),
+                    Err(e) => {
+                        tracing::warn!(matrix_user_id = %matrix_user_id, error = %e, "Cannot register Matrix puppet");
+                        matrix_error("M_UNKNOWN", StatusCode::INTERNAL_SERVER_ERROR)
+                    }
+                },
+                None => matrix_error("M_NOT_FOUND", StatusCode::NOT_FOUND),
+            }
+        });
+
+    // Portals are only opened through invites; aliases are not bridged.
+    let rooms = warp::path!("_matrix" / "app" / "v1" / "rooms" / String)
+        .and(warp::get())
+        .and(with_homeserver_auth(app_state.clone()))
+        .map(|_alias: String, _app_state: Arc<AppState>| matrix_error("M_NOT_FOUND", StatusCode::NOT_FOUND));
+
+    let ping = warp::path!("_matrix" / "app" / "v1" / "ping")
+        .and(warp::post())
+        .and(with_homeserver_auth(app_state))
+        .map(|_app_state: Arc<AppState>| warp::reply::json(&json!({})));
+
+    transactions
+        .or(users)
+        .or(rooms)
+        .or(ping)
+        .recover(|err: Rejection| async move {
+            if err.find::<MatrixForbidden>().is_some() {
+                Ok(matrix_error("M_FORBIDDEN", StatusCode::FORBIDDEN))
+            } else {
+                Err(err)
+            }
+        })
+}
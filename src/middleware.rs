@@ -0,0 +1,199 @@
+// src/middleware.rs
+
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use warp::filters::BoxedFilter;
+use warp::reject::PayloadTooLarge;
+use warp::{Filter, Rejection};
+
+use crate::bots::TokenScope;
+use crate::client_ip::with_client_ip;
+use crate::error::ApiError;
+use crate::ws_handlers::{AppState, Role, UserSession};
+
+/// Who may call a route.
+#[derive(Debug, Clone, Copy)]
+pub enum Auth {
+    // A user session from the `x-session-key` header.
+    Session,
+    // A user session, or a bot API token carrying the scope.
+    SessionOrToken(TokenScope),
+    // A user session of at least this role.
+    Role(Role),
+}
+
+/// The middleware a group of routes runs before its handlers, declared once in
+/// `build_routes` and shared by the routes that use it:
+///
+/// ```ignore
+/// let api = Stack::new(app_state.clone()).rate_limit("api", 60);
+/// let route = warp::path("contacts")
+///     .and(warp::post())
+///     .and(api.json())
+///     .and(api.authenticated(Auth::Session))
+///     .and_then(handler);
+/// ```
+///
+/// Policies added with `layer` run in declaration order, after the route's path and method
+/// matched, through `public` or `authenticated`; each route uses exactly one of the two.
+#[derive(Clone)]
+pub struct Stack {
+    app_state: Arc<AppState>,
+    // Policies every request through the stack passes, in declaration order.
+    layers: BoxedFilter<()>,
+    // Largest JSON body accepted, in bytes.
+    body_limit: u64,
+}
+
+impl Stack {
+    /// An empty stack accepting JSON bodies up to `limits.max_body_bytes`.
+    pub fn new(app_state: Arc<AppState>) -> Self {
+        let body_limit = app_state.config.limits.max_body_bytes;
+        Stack { app_state, layers: warp::any().boxed(), body_limit }
+    }
+
+    /// Adds a policy: a filter that lets the request through or rejects it.
+    pub fn layer<F>(self, layer: F) -> Self
+    where
+        F: Filter<Extract = (), Error = Rejection> + Clone + Send + Sync + 'static,
+    {
+        Stack { layers: self.layers.and(layer).boxed(), ..self }
+    }
+
+    /// Allows each client IP `max_per_minute` requests through the routes of this stack per
+    /// minute (0 means unlimited), counted under `name` together for all of them.
+    pub fn rate_limit(self, name: &'static str, max_per_minute: u32) -> Self {
+        if max_per_minute == 0 {
+            return self;
+        }
+        let layer = with_client_ip(self.app_state.clone())
+            .and(with_app_state(self.app_state.clone()))
+            .and_then(move |client_ip: Option<IpAddr>, app_state_limit: Arc<AppState>| async move {
+                if app_state_limit.rate_limits.lock().await.allow(name, client_ip, max_per_minute) {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(ApiError::RateLimited))
+                }
+            })
+            .untuple_one();
+        self.layer(layer)
+    }
+
+    /// The stack's policies, for routes open to anyone.
+    pub fn public(&self) -> BoxedFilter<()> {
+        self.layers.clone()
+    }
+
+    /// The stack's policies followed by `auth`, handing the caller's session to the handler.
+    pub fn authenticated(&self, auth: Auth) -> BoxedFilter<(UserSession,)> {
+        let app_state = self.app_state.clone();
+        let auth = match auth {
+            Auth::Session => with_authenticated_session(app_state).boxed(),
+            Auth::SessionOrToken(scope) => with_authenticated_session(app_state.clone()).or(with_api_token(app_state, scope)).unify().boxed(),
+            Auth::Role(role) => require_role(app_state, role).boxed(),
+        };
+        self.layers.clone().and(auth).boxed()
+    }
+
+    /// The request's JSON body, limited to the stack's body size.
+    pub fn json<T: DeserializeOwned + Send + 'static>(&self) -> BoxedFilter<(T,)> {
+        let max = self.body_limit;
+        warp::body::content_length_limit(max)
+            .or_else(move |rejection: Rejection| async move {
+                match rejection.find::<PayloadTooLarge>() {
+                    Some(_) => Err(warp::reject::custom(ApiError::BodyTooLarge { max })),
+                    None => Err(rejection),
+                }
+            })
+            .and(warp::body::json())
+            .boxed()
+    }
+}
+
+/// Requests counted by the `rate_limit` layers, per stack and client IP, over fixed one-minute
+/// windows shared by every client so the whole table is dropped when a window ends. Requests
+/// without a known IP share one count.
+#[derive(Debug)]
+pub struct RateLimits {
+    window_started: Instant,
+    requests: HashMap<(&'static str, Option<IpAddr>), u32>,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits { window_started: Instant::now(), requests: HashMap::new() }
+    }
+}
+
+impl RateLimits {
+    /// Counts a request, returning whether it is within `max_per_minute`.
+    fn allow(&mut self, stack: &'static str, client_ip: Option<IpAddr>, max_per_minute: u32) -> bool {
+        if self.window_started.elapsed() >= Duration::from_secs(60) {
+            self.window_started = Instant::now();
+            self.requests.clear();
+        }
+        let count = self.requests.entry((stack, client_ip)).or_default();
+        *count = count.saturating_add(1);
+        *count <= max_per_minute
+    }
+}
+
+/// A filter that provides the `AppState` to handlers.
+pub fn with_app_state(
+    app_state: Arc<AppState>,
+) -> impl Filter<Extract = (Arc<AppState>,), Error = std::convert::Infallible> + Clone {
+    warp::any().map(move || app_state.clone())
+}
+
+// A combined filter to extract the session key and authenticate the user.
+// This filter is specifically designed for HTTP requests where the session key is in a header.
+fn with_authenticated_session(
+    app_state: Arc<AppState>,
+) -> impl Filter<Extract = (UserSession,), Error = Rejection> + Clone {
+    warp::header::header::<String>("x-session-key")
+        .and(with_app_state(app_state))
+        .and_then(|session_key: String, app_state_auth: Arc<AppState>| async move {
+            match app_state_auth.session_for_key(&session_key).await {
+                Some(session) => Ok(session),
+                None => Err(warp::reject::custom(ApiError::InvalidSession)),
+            }
+        })
+}
+
+// A filter authenticating a bot by its `Authorization: Bearer <token>` API token. The token
+// must carry `scope`; the bot is then handed to handlers as a session of its own.
+fn with_api_token(
+    app_state: Arc<AppState>,
+    scope: TokenScope,
+) -> impl Filter<Extract = (UserSession,), Error = Rejection> + Clone {
+    warp::header::header::<String>("authorization")
+        .and(with_app_state(app_state))
+        .and_then(move |authorization: String, app_state_auth: Arc<AppState>| async move {
+            let secret = authorization.strip_prefix("Bearer ").unwrap_or_default().trim();
+            let tokens = app_state_auth.api_tokens.lock().await;
+            match tokens.get(secret) {
+                Some(token) if token.scopes.contains(&scope) => Ok(token.session()),
+                Some(_) => Err(warp::reject::custom(ApiError::InsufficientScope)),
+                None => Err(warp::reject::custom(ApiError::InvalidApiToken)),
+            }
+        })
+}
+
+// A filter that authenticates the session and additionally requires the user to hold
+// at least `required` role. Used to gate admin and moderation routes.
+fn require_role(
+    app_state: Arc<AppState>,
+    required: Role,
+) -> impl Filter<Extract = (UserSession,), Error = Rejection> + Clone {
+    with_authenticated_session(app_state.clone())
+        .and(with_app_state(app_state))
+        .and_then(move |session: UserSession, app_state_role: Arc<AppState>| async move {
+            match app_state_role.users.get(&session.username) {
+                Some(user) if user.role >= required => Ok(session),
+                _ => Err(warp::reject::custom(ApiError::InsufficientRole)),
+            }
+        })
+}
@@ -0,0 +1,319 @@
+// src/mobile_push.rs
+
+use chrono::Utc;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use serde::Deserialize;
+use serde_json::json;
+use std::fmt;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::config::{ApnsConfig, FcmConfig};
+use crate::push::{DevicePlatform, DeviceToken, PushNotification};
+use crate::ws_handlers::AppState;
+
+/// OAuth scope of the FCM HTTP v1 API.
+const FCM_SCOPE: &str = "https://www.googleapis.com/auth/firebase.messaging";
+/// APNs rejects provider tokens older than an hour; refresh well before that.
+const APNS_TOKEN_LIFETIME: Duration = Duration::from_secs(50 * 60);
+
+/// Everything that can go wrong while setting up or sending a mobile push.
+#[derive(Debug)]
+pub enum MobilePushError {
+    // The configured credentials could not be loaded.
+    Credentials(String),
+    // The push service no longer accepts the device token; it should be forgotten.
+    Unregistered,
+    Failed(String),
+}
+
+impl fmt::Display for MobilePushError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MobilePushError::Credentials(reason) => write!(f, "invalid push credentials: {}", reason),
+            MobilePushError::Unregistered => write!(f, "device token is no longer registered"),
+            MobilePushError::Failed(reason) => write!(f, "push failed: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for MobilePushError {}
+
+impl From<reqwest::Error> for MobilePushError {
+    fn from(e: reqwest::Error) -> Self {
+        MobilePushError::Failed(e.to_string())
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for MobilePushError {
+    fn from(e: jsonwebtoken::errors::Error) -> Self {
+        MobilePushError::Failed(format!("cannot sign token: {}", e))
+    }
+}
+
+/// A bearer token together with the moment it stops being usable.
+struct CachedToken {
+    value: String,
+    expires_at: Instant,
+}
+
+/// The fields of a Firebase service account key file used here.
+#[derive(Deserialize)]
+struct ServiceAccount {
+    project_id: String,
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+/// Sends through the FCM HTTP v1 API, authenticated with a service account.
+struct FcmSender {
+    project_id: String,
+    client_email: String,
+    token_uri: String,
+    key: EncodingKey,
+    access_token: Mutex<Option<CachedToken>>,
+}
+
+/// Sends through APNs with token-based (.p8) authentication.
+struct ApnsSender {
+    base_url: &'static str,
+    key: EncodingKey,
+    key_id: String,
+    team_id: String,
+    topic: String,
+    provider_token: Mutex<Option<CachedToken>>,
+}
+
+/// Sends FCM and APNs notifications for the platforms that have credentials configured.
+pub struct MobilePushDispatcher {
+    client: reqwest::Client,
+    fcm: Option<FcmSender>,
+    apns: Option<ApnsSender>,
+}
+
+impl fmt::Debug for MobilePushDispatcher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MobilePushDispatcher")
+            .field("fcm", &self.fcm.as_ref().map(|fcm| &fcm.project_id))
+            .field("apns", &self.apns.as_ref().map(|apns| &apns.topic))
+            .finish_non_exhaustive()
+    }
+}
+
+impl MobilePushDispatcher {
+    /// Loads the credentials of every configured platform.
+    pub fn new(fcm: Option<&FcmConfig>, apns: Option<&ApnsConfig>) -> Result<Self, MobilePushError> {
+        // APNs only speaks HTTP/2, which is negotiated via ALPN.
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .user_agent(concat!("rust_chat-push/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .expect("failed to build push HTTP client");
+        Ok(MobilePushDispatcher {
+            client,
+            fcm: fcm.map(FcmSender::new).transpose()?,
+            apns: apns.map(ApnsSender::new).transpose()?,
+        })
+    }
+
+    /// Whether credentials for `platform` are configured.
+    pub fn supports(&self, platform: DevicePlatform) -> bool {
+        match platform {
+            DevicePlatform::Fcm => self.fcm.is_some(),
+            DevicePlatform::Apns => self.apns.is_some(),
+        }
+    }
+
+    async fn send(&self, device: &DeviceToken, notification: &PushNotification) -> Result<(), MobilePushError> {
+        match device.platform {
+            DevicePlatform::Fcm => match &self.fcm {
+                Some(fcm) => fcm.send(&self.client, &device.token, notification).await,
+                None => Err(MobilePushError::Failed("FCM is not configured".to_string())),
+            },
+            DevicePlatform::Apns => match &self.apns {
+                Some(apns) => apns.send(&self.client, &device.token, notification).await,
+                None => Err(MobilePushError::Failed("APNs is not configured".to_string())),
+            },
+        }
+    }
+}
+
+impl FcmSender {
+    fn new(config: &FcmConfig) -> Result<Self, MobilePushError> {
+        let contents = std::fs::read_to_string(&config.service_account_path)
+            .map_err(|e| MobilePushError::Credentials(format!("{}: {}", config.service_account_path.display(), e)))?;
+        let account: ServiceAccount = serde_json::from_str(&contents)
+            .map_err(|e| MobilePushError::Credentials(format!("{}: {}", config.service_account_path.display(), e)))?;
+        let key = EncodingKey::from_rsa_pem(account.private_key.as_bytes())
+            .map_err(|e| MobilePushError::Credentials(format!("service account private key: {}", e)))?;
+        Ok(FcmSender {
+            project_id: account.project_id,
+            client_email: account.client_email,
+            token_uri: account.token_uri,
+            key,
+            access_token: Mutex::new(None),
+        })
+    }
+
+    /// Returns a cached OAuth access token, exchanging a fresh signed assertion when it expired.
+    async fn access_token(&self, client: &reqwest::Client) -> Result<String, MobilePushError> {
+        let mut cached = self.access_token.lock().await;
+        if let Some(token) = cached.as_ref().filter(|token| token.expires_at > Instant::now()) {
+            return Ok(token.value.clone());
+        }
+
+        let now = Utc::now().timestamp();
+        let claims = json!({ "iss": self.client_email, "scope": FCM_SCOPE, "aud": self.token_uri, "iat": now, "exp": now + 3600 });
+        let assertion = jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &self.key)?;
+        let response = client
+            .post(&self.token_uri)
+            .form(&[("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"), ("assertion", assertion.as_str())])
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(MobilePushError::Failed(format!("OAuth token request rejected with status {}", response.status())));
+        }
+
+        #[derive(Deserialize)]
+        struct TokenResponse {
+            access_token: String,
+            expires_in: u64,
+        }
+        let token: TokenResponse = response.json().await?;
+        let lifetime = Duration::from_secs(token.expires_in.saturating_sub(60));
+        *cached = Some(CachedToken { value: token.access_token.clone(), expires_at: Instant::now() + lifetime });
+        Ok(token.access_token)
+    }
+
+    async fn send(&self, client: &reqwest::Client, token: &str, notification: &PushNotification) -> Result<(), MobilePushError> {
+        let access_token = self.access_token(client).await?;
+        let body = json!({
+            "message": {
+                "token": token,
+                "notification": { "title": notification.title, "body": notification.body },
+                "data": {
+                    "message_id": notification.message_id,
+                    "from_user_id": notification.from_user_id.to_string(),
+                    "revoke_token": notification.revoke_token.clone().unwrap_or_default(),
+                },
+                // Unread messages of one conversation replace each other on the device.
+                "android": { "collapse_key": notification.collapse_key, "priority": "high" },
+                "apns": { "headers": { "apns-collapse-id": notification.collapse_key } },
+            }
+        });
+        let response = client
+            .post(format!("https://fcm.googleapis.com/v1/projects/{}/messages:send", self.project_id))
+            .bearer_auth(access_token)
+            .json(&body)
+            .send()
+            .await?;
+        match response.status().as_u16() {
+            200..=299 => Ok(()),
+            404 => Err(MobilePushError::Unregistered),
+            status => Err(MobilePushError::Failed(format!("FCM responded with status {}", status))),
+        }
+    }
+}
+
+impl ApnsSender {
+    fn new(config: &ApnsConfig) -> Result<Self, MobilePushError> {
+        let pem = std::fs::read(&config.key_path)
+            .map_err(|e| MobilePushError::Credentials(format!("{}: {}", config.key_path.display(), e)))?;
+        let key = EncodingKey::from_ec_pem(&pem)
+            .map_err(|e| MobilePushError::Credentials(format!("{}: {}", config.key_path.display(), e)))?;
+        Ok(ApnsSender {
+            base_url: if config.sandbox { "https://api.sandbox.push.apple.com" } else { "https://api.push.apple.com" },
+            key,
+            key_id: config.key_id.clone(),
+            team_id: config.team_id.clone(),
+            topic: config.topic.clone(),
+            provider_token: Mutex::new(None),
+        })
+    }
+
+    /// Returns the cached provider token (an ES256 JWT), signing a new one when it is due.
+    async fn provider_token(&self) -> Result<String, MobilePushError> {
+        let mut cached = self.provider_token.lock().await;
+        if let Some(token) = cached.as_ref().filter(|token| token.expires_at > Instant::now()) {
+            return Ok(token.value.clone());
+        }
+        let header = Header { kid: Some(self.key_id.clone()), ..Header::new(Algorithm::ES256) };
+        let claims = json!({ "iss": self.team_id, "iat": Utc::now().timestamp() });
+        let token = jsonwebtoken::encode(&header, &claims, &self.key)?;
+        *cached = Some(CachedToken { value: token.clone(), expires_at: Instant::now() + APNS_TOKEN_LIFETIME });
+        Ok(token)
+    }
+
+    async fn send(&self, client: &reqwest::Client, token: &str, notification: &PushNotification) -> Result<(), MobilePushError> {
+        let provider_token = self.provider_token().await?;
+        let body = json!({
+            "aps": {
+                "alert": { "title": notification.title, "body": notification.body },
+                "sound": "default",
+                "thread-id": notification.collapse_key,
+            },
+            "message_id": notification.message_id,
+            "from_user_id": notification.from_user_id,
+            "revoke_token": notification.revoke_token,
+        });
+        let response = client
+            .post(format!("{}/3/device/{}", self.base_url, token))
+            .header("authorization", format!("bearer {}", provider_token))
+            .header("apns-topic", &self.topic)
+            .header("apns-push-type", "alert")
+            .header("apns-priority", "10")
+            .header("apns-collapse-id", &notification.collapse_key)
+            .json(&body)
+            .send()
+            .await?;
+
+        let status = response.status().as_u16();
+        if (200..300).contains(&status) {
+            return Ok(());
+        }
+        #[derive(Deserialize)]
+        struct ApnsError {
+            reason: String,
+        }
+        let reason = response.json::<ApnsError>().await.map(|e| e.reason).unwrap_or_default();
+        match (status, reason.as_str()) {
+            (410, _) | (400, "BadDeviceToken") => Err(MobilePushError::Unregistered),
+            _ => Err(MobilePushError::Failed(format!("APNs responded with status {} {}", status, reason))),
+        }
+    }
+}
+
+/// Sends `notification` to every mobile device of `user_id` whose platform is configured.
+/// Tokens the push service reports as unregistered are removed.
+pub async fn notify(app_state: &Arc<AppState>, user_id: Uuid, notification: &PushNotification) {
+    let devices: Vec<DeviceToken> = app_state
+        .device_tokens
+        .lock()
+        .await
+        .values()
+        .filter(|device| device.user_id == user_id && app_state.mobile_push.supports(device.platform))
+        .cloned()
+        .collect();
+
+    for device in devices {
+        let app_state = app_state.clone();
+        let notification = notification.clone();
+        tokio::spawn(async move {
+            match app_state.mobile_push.send(&device, &notification).await {
+                Ok(()) => {
+                    tracing::debug!(user_id = %device.user_id, device_id = %device.id, platform = ?device.platform, "Mobile push sent");
+                }
+                Err(MobilePushError::Unregistered) => {
+                    app_state.device_tokens.lock().await.remove(&device.id);
+                    tracing::info!(user_id = %device.user_id, device_id = %device.id, platform = ?device.platform, "Removed unregistered device token");
+                }
+                Err(e) => {
+                    tracing::warn!(user_id = %device.user_id, device_id = %device.id, platform = ?device.platform, error = %e, "Mobile push failed");
+                }
+            }
+        });
+    }
+}
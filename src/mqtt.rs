@@ -0,0 +1,165 @@
+// src/mqtt.rs
+
+use rumqttc::{AsyncClient, ClientError, Event, EventLoop, MqttOptions, OptionError, Packet, QoS};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+use crate::bots::TokenScope;
+use crate::config::MqttConfig;
+use crate::ws_handlers::{self, AppState, UserSession};
+
+/// Requests buffered for the broker while it is unreachable; publishing never waits for it.
+const QUEUE_CAPACITY: usize = 256;
+/// Pause before reconnecting after the broker connection fails.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Published to `message_topic` for every routed chat message.
+#[derive(Serialize)]
+struct MessageEvent<'a> {
+    message_id: &'a str,
+    from_user_id: Uuid,
+    from_username: &'a str,
+    to_user_id: Uuid,
+    message: &'a str,
+    timestamp: &'a str,
+}
+
+/// Published (retained) to `presence_topic` when a user comes online or goes offline.
+#[derive(Serialize)]
+struct PresenceEvent<'a> {
+    user_id: Uuid,
+    username: &'a str,
+    status: &'a str,
+}
+
+/// Payload accepted on `inbound_topic`.
+#[derive(Deserialize)]
+struct InboundMessage {
+    // Secret of a bot API token with the `send_messages` scope; the message is sent as the bot.
+    token: String,
+    to_user_id: Uuid,
+    message: String,
+}
+
+/// Client side of the MQTT bridge. The connection itself is driven by `run`.
+#[derive(Debug)]
+pub struct MqttBridge {
+    client: AsyncClient,
+    config: MqttConfig,
+    qos: QoS,
+}
+
+impl MqttBridge {
+    /// Creates the bridge and the event loop that `run` must drive for anything to be sent.
+    pub fn new(config: &MqttConfig) -> Result<(Self, EventLoop), OptionError> {
+        let options = MqttOptions::parse_url(config.url.as_str())?;
+        let (client, event_loop) = AsyncClient::new(options, QUEUE_CAPACITY);
+        let qos = match config.qos {
+            0 => QoS::AtMostOnce,
+            1 => QoS::AtLeastOnce,
+            _ => QoS::ExactlyOnce,
+        };
+        Ok((MqttBridge { client, config: config.clone(), qos }, event_loop))
+    }
+
+    fn publish<T: Serialize>(&self, topic_template: &str, user_id: Uuid, retain: bool, event: &T) {
+        let topic = topic_template.replace("{user_id}", &user_id.to_string());
+        let payload = match serde_json::to_vec(event) {
+            Ok(payload) => payload,
+            Err(e) => {
+                tracing::error!(error = %e, "Error serializing MQTT event");
+                return;
+            }
+        };
+        if let Err(e) = self.client.try_publish(&topic, self.qos, retain, payload) {
+            tracing::warn!(topic = %topic, error = %e, "Dropping MQTT event");
+        }
+    }
+}
+
+/// Publishes a routed chat message to the recipient's message topic.
+pub fn publish_message(
+    app_state: &AppState,
+    sender_session: &UserSession,
+    to_user_id: Uuid,
+    message_id: &str,
+    message: &str,
+    timestamp: &str,
+) {
+    let Some(bridge) = &app_state.mqtt else { return };
+    let event = MessageEvent {
+        message_id,
+        from_user_id: sender_session.user_id,
+        from_username: &sender_session.username,
+        to_user_id,
+        message,
+        timestamp,
+    };
+    bridge.publish(&bridge.config.message_topic, to_user_id, false, &event);
+}
+
+/// Publishes a user's presence, retained so new subscribers learn the current state.
+pub fn publish_presence(app_state: &AppState, session: &UserSession, status: &str) {
+    let Some(bridge) = &app_state.mqtt else { return };
+    let event = PresenceEvent { user_id: session.user_id, username: &session.username, status };
+    bridge.publish(&bridge.config.presence_topic, session.user_id, true, &event);
+}
+
+/// Routes a message received on the inbound topic as the bot whose token it carries.
+async fn handle_inbound(app_state: &Arc<AppState>, payload: &[u8]) {
+    let inbound: InboundMessage = match serde_json::from_slice(payload) {
+        Ok(inbound) => inbound,
+        Err(e) => {
+            tracing::warn!(error = %e, "Ignoring malformed inbound MQTT message");
+            return;
+        }
+    };
+    let session = match app_state.api_tokens.lock().await.get(&inbound.token) {
+        Some(token) if token.scopes.contains(&TokenScope::SendMessages) => token.session(),
+        _ => {
+            tracing::warn!("Ignoring inbound MQTT message: invalid API token or missing send_messages scope");
+            return;
+        }
+    };
+    match ws_handlers::route_chat_message(app_state, &session, inbound.to_user_id, inbound.message).await {
+        Ok(message_id) => tracing::debug!(user_id = %session.user_id, message_id = %message_id, "Message received over MQTT"),
+        Err(e) => tracing::warn!(user_id = %session.user_id, reason = %e, "Dropping inbound MQTT message"),
+    }
+}
+
+fn subscribe_inbound(bridge: &MqttBridge) -> Result<(), ClientError> {
+    match &bridge.config.inbound_topic {
+        Some(topic) => bridge.client.try_subscribe(topic, bridge.qos),
+        None => Ok(()),
+    }
+}
+
+/// Drives the broker connection until the process exits, reconnecting after failures and
+/// (re)subscribing to the inbound topic on every connection.
+pub async fn run(app_state: Arc<AppState>, mut event_loop: EventLoop) {
+    let Some(bridge) = &app_state.mqtt else { return };
+    tracing::info!(broker = ?event_loop.mqtt_options.broker_address(), "Starting MQTT bridge");
+    loop {
+        match event_loop.poll().await {
+            Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                tracing::info!("Connected to MQTT broker");
+                if let Err(e) = subscribe_inbound(bridge) {
+                    tracing::warn!(error = %e, "Cannot subscribe to the inbound MQTT topic");
+                }
+            }
+            Ok(Event::Incoming(Packet::Publish(publish))) => {
+                let inbound = bridge.config.inbound_topic.as_deref();
+                if inbound.is_some_and(|filter| rumqttc::matches(&publish.topic, filter)) {
+                    handle_inbound(&app_state, &publish.payload).await;
+                }
+            }
+            Ok(_) => {}
+            Err(e) => {
+                tracing::warn!(error = %e, "MQTT connection failed; reconnecting");
+                tokio::time::sleep(RECONNECT_DELAY).await;
+            }
+        }
+    }
+}
@@ -0,0 +1,603 @@
+// src/nats.rs
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::{mpsc, oneshot};
+use uuid::Uuid;
+
+use crate::config::NatsConfig;
+use crate::connections::SharedFrame;
+use crate::events::DomainEvent;
+use crate::ws_handlers::AppState;
+
+/// Protocol commands buffered for the server while it is unreachable; publishing never waits for it.
+const QUEUE_CAPACITY: usize = 1024;
+/// Pause before reconnecting after the server connection fails.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+/// How long a JetStream API request waits for its reply.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Default port of a `nats://` URL.
+const DEFAULT_PORT: u16 = 4222;
+/// Subscription ids: replies to this node's requests, then one per user connected here.
+const INBOX_SID: u64 = 1;
+const FIRST_USER_SID: u64 = 2;
+
+/// Where a `nats://` URL points, and the credentials it carries.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NatsAddress {
+    host: String,
+    port: u16,
+    user: Option<String>,
+    password: Option<String>,
+}
+
+impl NatsAddress {
+    /// Parses "nats://[user:password@|token@]host[:port]".
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let url = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+        if url.scheme() != "nats" {
+            return Err("must be a nats:// URL".to_string());
+        }
+        let host = url.host_str().filter(|host| !host.is_empty()).ok_or("has no host")?;
+        if !matches!(url.path(), "" | "/") || url.query().is_some() {
+            return Err("cannot have a path or query".to_string());
+        }
+        Ok(NatsAddress {
+            host: host.trim_start_matches('[').trim_end_matches(']').to_string(),
+            port: url.port().unwrap_or(DEFAULT_PORT),
+            user: Some(url.username()).filter(|user| !user.is_empty()).map(str::to_string),
+            password: url.password().map(str::to_string),
+        })
+    }
+
+    // The CONNECT options: a user alone in the URL is an auth token.
+    fn connect_options(&self, name: &str) -> Value {
+        let mut options = json!({
+            "verbose": false,
+            "pedantic": false,
+            "headers": true,
+            "no_responders": true,
+            // This node delivers its own frames locally; the server does not echo them back.
+            "echo": false,
+            "lang": "rust",
+            "version": env!("CARGO_PKG_VERSION"),
+            "name": name,
+        });
+        match (&self.user, &self.password) {
+            (Some(user), Some(password)) => {
+                options["user"] = json!(user);
+                options["pass"] = json!(password);
+            }
+            (Some(token), None) => options["auth_token"] = json!(token),
+            _ => {}
+        }
+        options
+    }
+}
+
+/// A message received on one of this node's subscriptions.
+#[derive(Debug)]
+struct NatsMessage {
+    subject: String,
+    // Status of a reply sent by the server itself, e.g. 503 when nothing answers a request.
+    status: Option<u16>,
+    payload: Vec<u8>,
+}
+
+/// What the server sent, as far as this client cares.
+#[derive(Debug)]
+enum Incoming {
+    Message(NatsMessage),
+    Ping,
+    Error(String),
+    // INFO, +OK, PONG.
+    Other,
+}
+
+/// A message read back from the offline stream.
+#[derive(Deserialize)]
+struct StoredMessage {
+    seq: u64,
+    // Base64 of the frame.
+    data: String,
+}
+
+/// Routing through a NATS server, an alternative to peers posting to each other: every node
+/// subscribes to the frames for the users connected to it ("{prefix}.users.{user_id}") and
+/// queues them on their connections, frames for users connected to no node wait in a
+/// JetStream stream until they connect, and domain events are mirrored to "{prefix}.events.*".
+/// The connection itself is driven by `run`.
+#[derive(Debug)]
+pub struct NatsRouter {
+    config: NatsConfig,
+    address: NatsAddress,
+    // Name of this node in the server's connection list.
+    name: String,
+    // Subject prefix of the replies to this node's requests.
+    inbox: String,
+    outgoing: mpsc::Sender<Vec<u8>>,
+    // Subscription changes and pongs, which must reach the server however many publications
+    // are waiting; sent ahead of them.
+    control: mpsc::UnboundedSender<Vec<u8>>,
+    // Requests waiting for their reply: reply subject -> waiter.
+    replies: Mutex<HashMap<String, oneshot::Sender<NatsMessage>>>,
+    next_request: AtomicU64,
+    // Users connected here: user id -> (subscription id, connections).
+    subscriptions: Mutex<HashMap<Uuid, (u64, usize)>>,
+    next_sid: AtomicU64,
+}
+
+/// The commands for the server, handed to `run`.
+pub struct NatsConnection {
+    outgoing: mpsc::Receiver<Vec<u8>>,
+    control: mpsc::UnboundedReceiver<Vec<u8>>,
+}
+
+impl NatsRouter {
+    /// Creates the router and the connection that `run` must drive for anything to be sent.
+    pub fn new(config: &NatsConfig, node_id: &str) -> Result<(Self, NatsConnection), String> {
+        let address = NatsAddress::parse(&config.url)?;
+        let (outgoing, receiver) = mpsc::channel(QUEUE_CAPACITY);
+        let (control, control_receiver) = mpsc::unbounded_channel();
+        let router = NatsRouter {
+            config: config.clone(),
+            address,
+            name: format!("rust_chat {}", node_id),
+            inbox: format!("_INBOX.{}", Uuid::new_v4().simple()),
+            outgoing,
+            control,
+            replies: Mutex::new(HashMap::new()),
+            next_request: AtomicU64::new(0),
+            subscriptions: Mutex::new(HashMap::new()),
+            next_sid: AtomicU64::new(FIRST_USER_SID),
+        };
+        Ok((router, NatsConnection { outgoing: receiver, control: control_receiver }))
+    }
+
+    fn user_subject(&self, user_id: Uuid) -> String {
+        format!("{}.users.{}", self.config.subject_prefix, user_id)
+    }
+
+    fn offline_subject(&self, user_id: Uuid) -> String {
+        format!("{}.offline.{}", self.config.subject_prefix, user_id)
+    }
+
+    fn send(&self, command: Vec<u8>) {
+        if let Err(e) = self.outgoing.try_send(command) {
+            tracing::warn!(error = %e, "Dropping NATS publication");
+        }
+    }
+
+    // Answers a PING; a server that misses its pongs closes the connection.
+    fn pong(&self) {
+        let _ = self.control.send(b"PONG\r\n".to_vec());
+    }
+
+    /// Subscribes to the frames for `user_id` when its first connection here opens.
+    pub fn subscribe_user(&self, user_id: Uuid) {
+        let mut subscriptions = self.subscriptions.lock().expect("NATS subscriptions lock poisoned");
+        let (sid, connections) = subscriptions.entry(user_id).or_insert_with(|| (self.next_sid.fetch_add(1, Ordering::Relaxed), 0));
+        *connections += 1;
+        if *connections == 1 {
+            let _ = self.control.send(format!("SUB {} {}\r\n", self.user_subject(user_id), sid).into_bytes());
+        }
+    }
+
+    /// Unsubscribes from the frames for `user_id` when its last connection here closes.
+    pub fn unsubscribe_user(&self, user_id: Uuid) {
+        let mut subscriptions = self.subscriptions.lock().expect("NATS subscriptions lock poisoned");
+        let Some((sid, connections)) = subscriptions.get_mut(&user_id) else { return };
+        *connections -= 1;
+        if *connections == 0 {
+            let _ = self.control.send(format!("UNSUB {}\r\n", sid).into_bytes());
+            subscriptions.remove(&user_id);
+        }
+    }
+
+    fn publish(&self, subject: &str, reply_to: Option<&str>, payload: &[u8]) {
+        let mut command = match reply_to {
+            Some(reply_to) => format!("PUB {} {} {}\r\n", subject, reply_to, payload.len()),
+            None => format!("PUB {} {}\r\n", subject, payload.len()),
+        }
+        .into_bytes();
+        command.extend_from_slice(payload);
+        command.extend_from_slice(b"\r\n");
+        self.send(command);
+    }
+
+    /// Sends a JetStream API request, returning its JSON reply, or `None` when nothing
+    /// answered in time (JetStream is not enabled, or the server is unreachable).
+    async fn request(&self, subject: &str, body: &Value) -> Option<Value> {
+        let reply_to = format!("{}.{}", self.inbox, self.next_request.fetch_add(1, Ordering::Relaxed));
+        let (sender, receiver) = oneshot::channel();
+        self.replies.lock().expect("NATS replies lock poisoned").insert(reply_to.clone(), sender);
+        self.publish(subject, Some(&reply_to), body.to_string().as_bytes());
+        let reply = tokio::time::timeout(REQUEST_TIMEOUT, receiver).await.ok().and_then(Result::ok);
+        self.replies.lock().expect("NATS replies lock poisoned").remove(&reply_to);
+        match reply {
+            Some(NatsMessage { status: None, payload, .. }) => serde_json::from_slice(&payload).ok(),
+            Some(NatsMessage { status: Some(status), .. }) => {
+                tracing::warn!(subject = %subject, status, "NATS request failed");
+                None
+            }
+            None => {
+                tracing::warn!(subject = %subject, "NATS request timed out");
+                None
+            }
+        }
+    }
+
+    // What the client sends once connected: who it is, and its subscriptions. Subscription
+    // changes and pongs left over from the previous connection are dropped, as the handshake
+    // subscribes for the users connected now.
+    fn handshake(&self, control: &mut mpsc::UnboundedReceiver<Vec<u8>>) -> Vec<u8> {
+        let subscriptions = self.subscriptions.lock().expect("NATS subscriptions lock poisoned");
+        while control.try_recv().is_ok() {}
+        let mut handshake = format!("CONNECT {}\r\nSUB {}.* {}\r\n", self.address.connect_options(&self.name), self.inbox, INBOX_SID);
+        for (user_id, (sid, _)) in subscriptions.iter() {
+            handshake.push_str(&format!("SUB {} {}\r\n", self.user_subject(*user_id), sid));
+        }
+        handshake.push_str("PING\r\n");
+        handshake.into_bytes()
+    }
+
+    /// Creates the offline stream, or updates its settings if it exists.
+    async fn ensure_offline_stream(&self) {
+        let Some(stream) = &self.config.offline_stream else { return };
+        let settings = json!({
+            "name": stream,
+            "subjects": [format!("{}.offline.*", self.config.subject_prefix)],
+            "retention": "limits",
+            "storage": "file",
+            "discard": "old",
+            "max_age": Duration::from_secs(self.config.offline_max_age_secs).as_nanos() as u64,
+        });
+        let created = self.request(&format!("$JS.API.STREAM.CREATE.{}", stream), &settings).await;
+        let created = match created {
+            Some(reply) if reply.get("error").is_some() => self.request(&format!("$JS.API.STREAM.UPDATE.{}", stream), &settings).await,
+            reply => reply,
+        };
+        match created {
+            Some(reply) if reply.get("error").is_none() => tracing::info!(stream = %stream, "NATS offline stream ready"),
+            Some(reply) => tracing::warn!(stream = %stream, error = %reply["error"], "Cannot set up the NATS offline stream"),
+            None => tracing::warn!(stream = %stream, "Cannot set up the NATS offline stream: is JetStream enabled?"),
+        }
+    }
+
+    // Hands a received message to the request waiting for it, or to the user's connections here.
+    async fn dispatch(&self, app_state: &AppState, message: NatsMessage) {
+        if message.subject.starts_with(&self.inbox) {
+            if let Some(waiter) = self.replies.lock().expect("NATS replies lock poisoned").remove(&message.subject) {
+                let _ = waiter.send(message);
+            }
+            return;
+        }
+        let user_id = message.subject.rsplit('.').next().and_then(|user_id| Uuid::parse_str(user_id).ok());
+        match (user_id, String::from_utf8(message.payload)) {
+            (Some(user_id), Ok(json)) => {
+                app_state.connections.send_frame_to_user(user_id, &SharedFrame::new(json)).await;
+            }
+            _ => tracing::warn!(subject = %message.subject, "Ignoring malformed NATS message"),
+        }
+    }
+}
+
+/// Hands a frame already queued on `local_connections` connections of `user_id` here to the
+/// other nodes, or to the offline stream when the user is connected to none. Returns whether
+/// the user is connected to another node.
+pub async fn route_frame(app_state: &AppState, user_id: Uuid, frame: &SharedFrame, local_connections: usize) -> bool {
+    let Some(nats) = &app_state.nats else { return false };
+    nats.publish(&nats.user_subject(user_id), None, frame.json().as_bytes());
+    let elsewhere = match &app_state.cluster {
+        Some(cluster) => cluster.presence.remote_status(user_id).await.is_some(),
+        None => false,
+    };
+    if local_connections == 0 && !elsewhere && nats.config.offline_stream.is_some() {
+        nats.publish(&nats.offline_subject(user_id), None, frame.json().as_bytes());
+    }
+    elsewhere
+}
+
+/// Delivers the frames queued for `user_id` while it was connected to no node, oldest first.
+/// Each frame is removed from the stream before it is delivered, so only one node delivers it.
+async fn deliver_offline(app_state: &AppState, user_id: Uuid) {
+    let Some(nats) = &app_state.nats else { return };
+    let Some(stream) = &nats.config.offline_stream else { return };
+    let subject = nats.offline_subject(user_id);
+    let mut next_seq = 1;
+    let mut delivered = 0;
+    loop {
+        let request = json!({ "seq": next_seq, "next_by_subj": subject });
+        let Some(reply) = nats.request(&format!("$JS.API.STREAM.MSG.GET.{}", stream), &request).await else { break };
+        // An error here is most often "no message found": the queue is empty.
+        let Some(stored) = reply.get("message").and_then(|message| StoredMessage::deserialize(message).ok()) else { break };
+        next_seq = stored.seq + 1;
+        let removed = nats.request(&format!("$JS.API.STREAM.MSG.DELETE.{}", stream), &json!({ "seq": stored.seq })).await;
+        if !removed.is_some_and(|reply| reply["success"] == json!(true)) {
+            // Another node is delivering it.
+            continue;
+        }
+        let Some(json) = STANDARD.decode(&stored.data).ok().and_then(|frame| String::from_utf8(frame).ok()) else { continue };
+        if app_state.connections.send_frame_to_user(user_id, &SharedFrame::new(json.clone())).await == 0 {
+            // Gone again before it arrived; it waits for the next connection.
+            nats.publish(&subject, None, json.as_bytes());
+            break;
+        }
+        delivered += 1;
+    }
+    if delivered > 0 {
+        tracing::debug!(user_id = %user_id, delivered, "Delivered frames queued in NATS");
+    }
+}
+
+/// Mirrors domain events to "{prefix}.events.{event}" (without message contents), and
+/// delivers the frames queued for a user who comes online.
+pub async fn on_event(app_state: Arc<AppState>, event: Arc<DomainEvent>) {
+    let Some(nats) = &app_state.nats else { return };
+    let (name, payload) = match &*event {
+        DomainEvent::UserRegistered { user_id, username, country } => {
+            ("user_registered", json!({ "user_id": user_id, "username": username, "country": country }))
+        }
+        DomainEvent::RegistrationRequested { registration_id, username, country } => (
+            "registration_requested",
+            json!({ "registration_id": registration_id, "username": username, "country": country }),
+        ),
+        DomainEvent::MessageSent { kind, message_id, from_user_id, to_user_id, delivered, .. } => (
+            "message_sent",
+            json!({
+                "kind": kind,
+                "message_id": message_id,
+                "from_user_id": from_user_id,
+                "to_user_id": to_user_id,
+                "delivered": delivered,
+            }),
+        ),
+        DomainEvent::UserOnline { user_id, username } => ("user_online", json!({ "user_id": user_id, "username": username })),
+        DomainEvent::ContactAdded { user_id, contact_user_id, .. } => {
+            ("contact_added", json!({ "user_id": user_id, "contact_user_id": contact_user_id }))
+        }
+        DomainEvent::AccountLocked { user_id, locked_until, .. } => {
+            ("account_locked", json!({ "user_id": user_id, "locked_until": locked_until.to_rfc3339() }))
+        }
+        DomainEvent::AccountUnlocked { user_id, unlocked_by, .. } => {
+            ("account_unlocked", json!({ "user_id": user_id, "unlocked_by": unlocked_by }))
+        }
+        DomainEvent::UnfamiliarLogin { user_id, country, .. } => ("unfamiliar_login", json!({ "user_id": user_id, "country": country })),
+    };
+    nats.publish(&format!("{}.events.{}", nats.config.subject_prefix, name), None, payload.to_string().as_bytes());
+    if let DomainEvent::UserOnline { user_id, .. } = &*event {
+        deliver_offline(&app_state, *user_id).await;
+    }
+}
+
+/// Drives the server connection until the process exits, reconnecting after failures and
+/// subscribing again on every connection.
+pub async fn run(app_state: Arc<AppState>, mut connection: NatsConnection) {
+    let Some(nats) = &app_state.nats else { return };
+    let server = format!("{}:{}", nats.address.host, nats.address.port);
+    tracing::info!(server = %server, "Starting NATS routing");
+    loop {
+        match TcpStream::connect(&server).await {
+            Ok(stream) => {
+                if let Err(e) = serve(&app_state, stream, &mut connection).await {
+                    tracing::warn!(error = %e, "NATS connection lost; reconnecting");
+                }
+            }
+            Err(e) => tracing::warn!(error = %e, "Cannot connect to the NATS server; retrying"),
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn serve(app_state: &Arc<AppState>, stream: TcpStream, connection: &mut NatsConnection) -> io::Result<()> {
+    let Some(nats) = &app_state.nats else { return Ok(()) };
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    // The server speaks first, with INFO.
+    let mut info = String::new();
+    reader.read_line(&mut info).await?;
+    if !info.starts_with("INFO ") {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a NATS server"));
+    }
+    writer.write_all(&nats.handshake(&mut connection.control)).await?;
+    tracing::info!("Connected to NATS server");
+
+    let mut reading = tokio::spawn(read_messages(app_state.clone(), reader));
+    let state = app_state.clone();
+    tokio::spawn(async move {
+        if let Some(nats) = &state.nats {
+            nats.ensure_offline_stream().await;
+        }
+    });
+    let result = loop {
+        let command = tokio::select! {
+            biased;
+            read = &mut reading => break read.unwrap_or_else(|e| Err(io::Error::other(e))),
+            Some(command) = connection.control.recv() => command,
+            command = connection.outgoing.recv() => match command {
+                Some(command) => command,
+                None => break Ok(()),
+            },
+        };
+        if let Err(e) = writer.write_all(&command).await {
+            break Err(e);
+        }
+    };
+    reading.abort();
+    result
+}
+
+// Reads the server's messages until the connection closes, answering its pings.
+async fn read_messages(app_state: Arc<AppState>, mut reader: impl AsyncBufRead + Unpin) -> io::Result<()> {
+    let Some(nats) = &app_state.nats else { return Ok(()) };
+    loop {
+        match read_incoming(&mut reader).await? {
+            Incoming::Message(message) => nats.dispatch(&app_state, message).await,
+            Incoming::Ping => nats.pong(),
+            Incoming::Error(error) => tracing::warn!(error = %error, "NATS server reported an error"),
+            Incoming::Other => {}
+        }
+    }
+}
+
+// Reads the next line from the server, with the payload following a MSG or HMSG.
+async fn read_incoming(reader: &mut (impl AsyncBufRead + Unpin)) -> io::Result<Incoming> {
+    let mut line = String::new();
+    if reader.read_line(&mut line).await? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let words: Vec<&str> = line.split_whitespace().collect();
+    Ok(match words.as_slice() {
+        // MSG <subject> <sid> [reply-to] <bytes>
+        ["MSG", subject, _, .., size] => {
+            let payload = read_payload(reader, size).await?;
+            Incoming::Message(NatsMessage { subject: subject.to_string(), status: None, payload })
+        }
+        // HMSG <subject> <sid> [reply-to] <header bytes> <total bytes>
+        ["HMSG", subject, _, .., header_size, size] => {
+            let subject = subject.to_string();
+            let header_size: usize = header_size.parse().map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+            let mut payload = read_payload(reader, size).await?;
+            let headers = String::from_utf8_lossy(&payload[..header_size.min(payload.len())]).into_owned();
+            payload.drain(..header_size.min(payload.len()));
+            // "NATS/1.0 503" when the server answers for a request nobody received.
+            let status = headers.lines().next().and_then(|version| version.split_whitespace().nth(1)).and_then(|status| status.parse().ok());
+            Incoming::Message(NatsMessage { subject, status, payload })
+        }
+        ["PING"] => Incoming::Ping,
+        ["-ERR", ..] => Incoming::Error(line.trim_end().to_string()),
+        _ => Incoming::Other,
+    })
+}
+
+async fn read_payload(reader: &mut (impl AsyncRead + Unpin), size: &str) -> io::Result<Vec<u8>> {
+    let size: usize = size.parse().map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+    // The payload is followed by CRLF.
+    let mut payload = vec![0; size + 2];
+    reader.read_exact(&mut payload).await?;
+    payload.truncate(size);
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router() -> (NatsRouter, NatsConnection) {
+        let config = NatsConfig {
+            url: "nats://127.0.0.1:4222".to_string(),
+            subject_prefix: "chat".to_string(),
+            offline_stream: None,
+            offline_max_age_secs: 60,
+        };
+        NatsRouter::new(&config, "chat-1").unwrap()
+    }
+
+    async fn parse(input: &[u8]) -> Vec<Incoming> {
+        let mut reader = input;
+        let mut incoming = Vec::new();
+        while !reader.is_empty() {
+            incoming.push(read_incoming(&mut reader).await.unwrap());
+        }
+        incoming
+    }
+
+    fn message(incoming: &Incoming) -> &NatsMessage {
+        match incoming {
+            Incoming::Message(message) => message,
+            other => panic!("not a message: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_are_read_with_and_without_a_reply_subject() {
+        let incoming = parse(b"MSG chat.users.a 2 5\r\nhello\r\nMSG _INBOX.x.1 1 chat.reply 12\r\nhello\r\nworld\r\nMSG chat.users.b 3 0\r\n\r\n").await;
+        assert_eq!(incoming.len(), 3);
+        assert_eq!(message(&incoming[0]).subject, "chat.users.a");
+        assert_eq!(message(&incoming[0]).payload, b"hello");
+        assert_eq!(message(&incoming[1]).subject, "_INBOX.x.1");
+        assert_eq!(message(&incoming[1]).payload, b"hello\r\nworld");
+        assert_eq!(message(&incoming[1]).status, None);
+        assert!(message(&incoming[2]).payload.is_empty());
+    }
+
+    #[tokio::test]
+    async fn header_messages_carry_their_status_apart_from_the_payload() {
+        let incoming = parse(b"HMSG _INBOX.x.1 1 16 16\r\nNATS/1.0 503\r\n\r\n\r\nHMSG chat.users.a 2 chat.reply 12 17\r\nNATS/1.0\r\n\r\nhello\r\n").await;
+        assert_eq!(message(&incoming[0]).status, Some(503));
+        assert!(message(&incoming[0]).payload.is_empty());
+        assert_eq!(message(&incoming[1]).subject, "chat.users.a");
+        assert_eq!(message(&incoming[1]).status, None);
+        assert_eq!(message(&incoming[1]).payload, b"hello");
+    }
+
+    #[tokio::test]
+    async fn pings_errors_and_other_lines_are_told_apart() {
+        let incoming = parse(b"INFO {\"server_id\":\"x\"}\r\nPING\r\n+OK\r\n-ERR 'Authorization Violation'\r\n").await;
+        assert!(matches!(incoming[0], Incoming::Other));
+        assert!(matches!(incoming[1], Incoming::Ping));
+        assert!(matches!(incoming[2], Incoming::Other));
+        assert!(matches!(&incoming[3], Incoming::Error(error) if error == "-ERR 'Authorization Violation'"));
+    }
+
+    #[tokio::test]
+    async fn malformed_or_truncated_messages_fail_the_connection() {
+        let mut reader: &[u8] = b"MSG chat.users.a 2 many\r\nhello\r\n";
+        assert_eq!(read_incoming(&mut reader).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let mut reader: &[u8] = b"HMSG chat.users.a 2 x 5\r\nhello\r\n";
+        assert_eq!(read_incoming(&mut reader).await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let mut reader: &[u8] = b"MSG chat.users.a 2 10\r\nhello";
+        assert_eq!(read_incoming(&mut reader).await.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        let mut reader: &[u8] = b"";
+        assert_eq!(read_incoming(&mut reader).await.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn pongs_are_sent_while_publications_pile_up() {
+        let (router, mut connection) = router();
+        for _ in 0..=QUEUE_CAPACITY {
+            router.publish("chat.events.user_online", None, b"{}");
+        }
+        let mut reader: &[u8] = b"PING\r\n";
+        assert!(matches!(read_incoming(&mut reader).await.unwrap(), Incoming::Ping));
+        router.pong();
+        assert_eq!(connection.control.try_recv().unwrap(), b"PONG\r\n");
+        assert_eq!(connection.outgoing.len(), QUEUE_CAPACITY);
+    }
+
+    #[tokio::test]
+    async fn nodes_subscribe_to_the_users_connected_to_them() {
+        let (router, mut connection) = router();
+        let (alice, bob) = (Uuid::new_v4(), Uuid::new_v4());
+        router.subscribe_user(alice);
+        router.subscribe_user(alice);
+        router.subscribe_user(bob);
+        let subscribed = String::from_utf8(connection.control.try_recv().unwrap()).unwrap();
+        assert_eq!(subscribed, format!("SUB chat.users.{} {}\r\n", alice, FIRST_USER_SID));
+        assert!(connection.control.try_recv().is_ok());
+        assert!(connection.control.try_recv().is_err());
+
+        router.unsubscribe_user(bob);
+        router.unsubscribe_user(alice);
+        assert_eq!(connection.control.try_recv().unwrap(), format!("UNSUB {}\r\n", FIRST_USER_SID + 1).into_bytes());
+        assert!(connection.control.try_recv().is_err());
+
+        // A new connection subscribes again for the users still connected, and nothing else.
+        router.pong();
+        let handshake = String::from_utf8(router.handshake(&mut connection.control)).unwrap();
+        assert!(handshake.contains(&format!("SUB chat.users.{} {}\r\n", alice, FIRST_USER_SID)), "{}", handshake);
+        assert!(!handshake.contains(&bob.to_string()), "{}", handshake);
+        assert!(connection.control.try_recv().is_err());
+        router.unsubscribe_user(alice);
+        assert_eq!(connection.control.try_recv().unwrap(), format!("UNSUB {}\r\n", FIRST_USER_SID).into_bytes());
+    }
+}
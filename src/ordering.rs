@@ -0,0 +1,86 @@
+// src/ordering.rs
+
+use futures::FutureExt;
+use std::collections::hash_map::DefaultHasher;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::{mpsc, oneshot};
+use tracing::Instrument;
+use uuid::Uuid;
+
+use crate::error::ApiError;
+use crate::stats::{QueueStats, QueueStatsResponse};
+
+type Job = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Runs the fanout of each conversation on one of a fixed set of shard tasks, chosen by the
+/// pair of participants. A shard runs one job at a time, so messages and events of a
+/// conversation reach every session of both users in the order they were submitted, while
+/// other conversations proceed on the other shards.
+#[derive(Debug)]
+pub struct ConversationOrdering {
+    shards: Vec<Shard>,
+}
+
+#[derive(Debug)]
+struct Shard {
+    jobs: mpsc::UnboundedSender<Job>,
+    stats: Arc<QueueStats>,
+}
+
+impl ConversationOrdering {
+    /// Starts `shards` shard tasks (at least one). They end once this is dropped.
+    pub(crate) fn spawn(shards: usize) -> Self {
+        let shards = (0..shards.max(1))
+            .map(|_| {
+                let (jobs, receiver) = mpsc::unbounded_channel();
+                tokio::spawn(run_shard(receiver));
+                Shard { jobs, stats: Arc::new(QueueStats::default()) }
+            })
+            .collect();
+        ConversationOrdering { shards }
+    }
+
+    /// Runs `job` on the shard of the conversation between `a` and `b` once the jobs
+    /// submitted before it are done, and returns its output; `Internal` if it panicked.
+    pub(crate) async fn run<R: Send + 'static>(&self, a: Uuid, b: Uuid, job: impl Future<Output = R> + Send + 'static) -> Result<R, ApiError> {
+        let shard = &self.shards[self.shard_of(a, b)];
+        let (reply, output) = oneshot::channel();
+        let (stats, queued_at) = (shard.stats.clone(), Instant::now());
+        let job = async move {
+            stats.dequeued(queued_at.elapsed());
+            let _ = reply.send(job.await);
+        };
+        shard.stats.enqueued();
+        if shard.jobs.send(Box::pin(job.in_current_span())).is_err() {
+            shard.stats.discarded();
+        }
+        // A job that panicked drops its reply, and the shard has logged the panic.
+        output.await.map_err(|_| ApiError::Internal("Delivery in the conversation failed.".to_string()))
+    }
+
+    /// Jobs waiting for their shard, and how long they waited, over all shards.
+    pub fn queue_stats(&self) -> QueueStatsResponse {
+        self.shards.iter().map(|shard| shard.stats.snapshot()).fold(QueueStatsResponse::default(), QueueStatsResponse::combine)
+    }
+
+    // Both directions of a conversation map to the same shard.
+    fn shard_of(&self, a: Uuid, b: Uuid) -> usize {
+        let mut hasher = DefaultHasher::new();
+        (a.min(b), a.max(b)).hash(&mut hasher);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+}
+
+async fn run_shard(mut jobs: mpsc::UnboundedReceiver<Job>) {
+    while let Some(job) = jobs.recv().await {
+        // One conversation's bug must not stall the others on this shard.
+        if AssertUnwindSafe(job).catch_unwind().await.is_err() {
+            tracing::error!("Conversation job panicked");
+        }
+    }
+}
@@ -0,0 +1,39 @@
+// src/polls.rs
+
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Options a poll may offer.
+pub const MAX_POLL_OPTIONS: usize = 12;
+/// Length limit of the question and of each option, in bytes.
+pub const MAX_POLL_TEXT_LENGTH: usize = 300;
+
+/// A poll posted in a 1:1 conversation. Both participants may vote.
+#[derive(Debug, Clone)]
+pub struct Poll {
+    pub creator_id: Uuid,
+    pub to_user_id: Uuid,
+    pub options: Vec<String>,
+    // Whether a voter may pick several options.
+    pub multi_select: bool,
+    // Current choice of each voter: user id -> option indexes.
+    pub votes: HashMap<Uuid, Vec<usize>>,
+}
+
+impl Poll {
+    pub fn is_participant(&self, user_id: Uuid) -> bool {
+        self.creator_id == user_id || self.to_user_id == user_id
+    }
+
+    /// Votes per option, in option order.
+    pub fn tallies(&self) -> Vec<usize> {
+        let mut tallies = vec![0; self.options.len()];
+        for &option in self.votes.values().flatten() {
+            tallies[option] += 1;
+        }
+        tallies
+    }
+}
+
+/// Polls: poll id -> poll.
+pub type PollRegistry = HashMap<Uuid, Poll>;
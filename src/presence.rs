@@ -0,0 +1,176 @@
+// src/presence.rs
+
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::connections::ConnectionHandle;
+use crate::ws_handlers::{self, AppState, UserSession};
+
+/// The status of one user on the node announcing it.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct NodePresence {
+    pub user_id: Uuid,
+    pub username: String,
+    // "online" or "dnd"; "offline" once the user's last connection to the node closed.
+    pub status: String,
+}
+
+/// What a node tells its peers about the users connected to it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresenceAnnouncement {
+    pub node_id: String,
+    pub users: Vec<NodePresence>,
+    // Whether `users` lists everyone connected to the node; users it leaves out are offline there.
+    pub complete: bool,
+}
+
+/// A user announced as connected by another node.
+#[derive(Debug, Clone)]
+struct RemoteEntry {
+    username: String,
+    status: String,
+    expires_at: Instant,
+}
+
+/// Presence of the users connected to the other nodes of a cluster. Each node announces
+/// its users' status changes to its peers as they happen, and every user connected to it
+/// with each heartbeat; a node's announcement of a user lapses after `ttl` unless renewed,
+/// so the users of a node that went away turn offline elsewhere.
+#[derive(Debug)]
+pub struct ClusterPresence {
+    ttl: Duration,
+    // Users connected to other nodes: user id -> node id -> entry.
+    remote: Mutex<HashMap<Uuid, HashMap<String, RemoteEntry>>>,
+}
+
+impl ClusterPresence {
+    pub fn new(ttl: Duration) -> Self {
+        ClusterPresence { ttl, remote: Mutex::new(HashMap::new()) }
+    }
+
+    /// The status `user_id` shows on other nodes, or `None` when no other node has it connected.
+    pub async fn remote_status(&self, user_id: Uuid) -> Option<String> {
+        let remote = self.remote.lock().await;
+        visible_status(remote.get(&user_id)?.values(), Instant::now())
+    }
+
+    /// Number of users connected to other nodes.
+    pub async fn remote_users(&self) -> usize {
+        let now = Instant::now();
+        self.remote.lock().await.values().filter(|nodes| visible_status(nodes.values(), now).is_some()).count()
+    }
+
+    /// Applies a peer's announcement, returning the users whose status on other nodes
+    /// changed, with their new status ("offline" once no other node has them).
+    pub(crate) async fn apply(&self, announcement: PresenceAnnouncement) -> Vec<NodePresence> {
+        let now = Instant::now();
+        let expires_at = now + self.ttl;
+        let mut remote = self.remote.lock().await;
+        let mut affected: HashMap<Uuid, (Option<String>, String)> = HashMap::new();
+        let announced: HashSet<Uuid> = announcement.users.iter().map(|user| user.user_id).collect();
+
+        if announcement.complete {
+            for (user_id, nodes) in remote.iter_mut() {
+                if !announced.contains(user_id) {
+                    if let Some(entry) = nodes.get(&announcement.node_id) {
+                        affected.insert(*user_id, (visible_status(nodes.values(), now), entry.username.clone()));
+                        nodes.remove(&announcement.node_id);
+                    }
+                }
+            }
+        }
+        for user in announcement.users {
+            let nodes = remote.entry(user.user_id).or_default();
+            affected.entry(user.user_id).or_insert_with(|| (visible_status(nodes.values(), now), user.username.clone()));
+            if user.status == "offline" {
+                nodes.remove(&announcement.node_id);
+            } else {
+                nodes.insert(announcement.node_id.clone(), RemoteEntry { username: user.username, status: user.status, expires_at });
+            }
+        }
+        changes(&mut remote, affected, now)
+    }
+
+    /// Forgets the announcements that were not renewed in time, returning the users whose
+    /// status on other nodes changed as a result.
+    pub(crate) async fn expire(&self) -> Vec<NodePresence> {
+        let now = Instant::now();
+        let mut remote = self.remote.lock().await;
+        let mut affected = HashMap::new();
+        for (user_id, nodes) in remote.iter_mut() {
+            let Some(lapsed) = nodes.values().find(|entry| entry.expires_at <= now) else { continue };
+            // Until now, peers were shown the user with the lapsed entries still counting.
+            affected.insert(*user_id, (status_of(nodes.values()), lapsed.username.clone()));
+            nodes.retain(|_, entry| entry.expires_at > now);
+        }
+        changes(&mut remote, affected, now)
+    }
+}
+
+// The status shown for a user with the unexpired `entries`.
+fn visible_status<'a>(entries: impl Iterator<Item = &'a RemoteEntry>, now: Instant) -> Option<String> {
+    status_of(entries.filter(|entry| entry.expires_at > now))
+}
+
+// The status shown for a user with `entries`: online if it is online on any node, "dnd" if
+// it is connected only with do-not-disturb on.
+fn status_of<'a>(entries: impl Iterator<Item = &'a RemoteEntry>) -> Option<String> {
+    let mut statuses = entries.map(|entry| entry.status.as_str()).peekable();
+    statuses.peek()?;
+    Some(if statuses.any(|status| status == "online") { "online" } else { "dnd" }.to_string())
+}
+
+// The users of `affected` (user id -> status before and username) whose status differs
+// now, dropping the users no other node has any more.
+fn changes(remote: &mut HashMap<Uuid, HashMap<String, RemoteEntry>>, affected: HashMap<Uuid, (Option<String>, String)>, now: Instant) -> Vec<NodePresence> {
+    let mut changed = Vec::new();
+    for (user_id, (before, username)) in affected {
+        let after = remote.get(&user_id).and_then(|nodes| visible_status(nodes.values(), now));
+        if after != before {
+            changed.push(NodePresence { user_id, username, status: after.unwrap_or_else(|| "offline".to_string()) });
+        }
+        if remote.get(&user_id).is_some_and(HashMap::is_empty) {
+            remote.remove(&user_id);
+        }
+    }
+    changed
+}
+
+/// Tells the peers that `session`'s user changed status on this node. A user only goes
+/// offline on this node once its last connection here closed.
+pub async fn announce_status(app_state: &AppState, session: &UserSession, status: &str) {
+    let Some(cluster) = &app_state.cluster else { return };
+    if status == "offline" && app_state.connections.is_online(session.user_id).await {
+        return;
+    }
+    let user = NodePresence { user_id: session.user_id, username: session.username.clone(), status: status.to_string() };
+    let announcement = PresenceAnnouncement { node_id: cluster.node_id().to_string(), users: vec![user], complete: false };
+    cluster.send_to_peers("/cluster/presence", &announcement);
+}
+
+/// Every user with a connection among `connections` (this node's), with its status.
+pub(crate) async fn local_users(app_state: &AppState, connections: Vec<(String, ConnectionHandle)>) -> Vec<NodePresence> {
+    let dnd = app_state.dnd_presence.lock().await.clone();
+    let mut users: HashMap<Uuid, NodePresence> = HashMap::new();
+    for (_, connection) in connections {
+        let status = if dnd.contains(&connection.user_id) { "dnd" } else { "online" };
+        users.insert(connection.user_id, NodePresence { user_id: connection.user_id, username: connection.username, status: status.to_string() });
+    }
+    users.into_values().collect()
+}
+
+/// Shows the local connections the status changes of users connected to other nodes, except
+/// for users also connected here, whose status is announced by this node.
+pub(crate) async fn show_remote_changes(app_state: &Arc<AppState>, changed: Vec<NodePresence>) {
+    for user in changed {
+        if app_state.connections.is_online(user.user_id).await {
+            continue;
+        }
+        tracing::debug!(user_id = %user.user_id, status = %user.status, "Presence changed on another node");
+        ws_handlers::broadcast_remote_status(app_state, user.user_id, user.username, user.status).await;
+    }
+}
@@ -0,0 +1,281 @@
+// src/push.rs
+
+use chrono::{DateTime, NaiveTime, Utc};
+use chrono_tz::Tz;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use crate::events::DomainEvent;
+use crate::ws_handlers::AppState;
+#[cfg(feature = "mobile-push")]
+use crate::mobile_push;
+#[cfg(feature = "web-push")]
+use crate::webpush;
+
+/// Longest notification body, in characters. Push services cap encrypted payloads at ~4 KB.
+const MAX_BODY_CHARS: usize = 200;
+
+/// A browser push subscription, as returned by `PushManager.subscribe()` in the browser.
+#[derive(Debug, Clone)]
+pub struct PushSubscription {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    // Push service URL the notifications are POSTed to.
+    pub endpoint: String,
+    // Browser public key (P-256, base64url) and auth secret used to encrypt payloads.
+    pub p256dh: String,
+    pub auth: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Registered push subscriptions: subscription id -> subscription.
+pub type PushSubscriptionRegistry = HashMap<Uuid, PushSubscription>;
+
+/// Push service a device token was issued by.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DevicePlatform {
+    // Firebase Cloud Messaging (Android, and iOS apps using Firebase).
+    Fcm,
+    // Apple Push Notification service.
+    Apns,
+}
+
+/// A mobile device registered to receive notifications for its user.
+#[derive(Debug, Clone)]
+pub struct DeviceToken {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub platform: DevicePlatform,
+    // Registration token (FCM) or device token (APNs) reported by the app.
+    pub token: String,
+    pub created_at: DateTime<Utc>,
+}
+
+/// Registered devices: device id -> device.
+pub type DeviceTokenRegistry = HashMap<Uuid, DeviceToken>;
+
+/// Which messages of a conversation notify the user.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum NotificationLevel {
+    #[default]
+    All,
+    // Only messages mentioning the user as `@username`.
+    Mentions,
+    Nothing,
+}
+
+/// Recurring do-not-disturb: daily time ranges in the user's timezone. A range whose end is
+/// before its start runs past midnight (e.g. 22:00-07:00).
+#[derive(Debug, Clone)]
+pub struct QuietHours {
+    pub timezone: Tz,
+    pub ranges: Vec<(NaiveTime, NaiveTime)>,
+}
+
+impl QuietHours {
+    /// Whether `now` falls in one of the ranges, in the schedule's timezone.
+    pub fn contains(&self, now: DateTime<Utc>) -> bool {
+        let time = now.with_timezone(&self.timezone).time();
+        self.ranges.iter().any(|&(start, end)| {
+            if start <= end {
+                start <= time && time < end
+            } else {
+                time >= start || time < end
+            }
+        })
+    }
+}
+
+/// A user's controls over notifications, honoured by every push channel; the per-conversation
+/// levels also apply to the user's own webhooks.
+#[derive(Debug, Clone, Default)]
+pub struct NotificationSettings {
+    // Conversation partners whose messages never trigger a notification.
+    pub muted_user_ids: HashSet<Uuid>,
+    // Do not disturb: nothing is pushed before this moment.
+    pub dnd_until: Option<DateTime<Utc>>,
+    // Recurring do not disturb; nothing is pushed while it is on.
+    pub quiet_hours: Option<QuietHours>,
+    // Conversation partner -> level; conversations not listed notify for every message.
+    pub conversation_levels: HashMap<Uuid, NotificationLevel>,
+}
+
+impl NotificationSettings {
+    /// The level of the conversation with `partner_id`; muting overrides it.
+    pub fn level_for(&self, partner_id: Uuid) -> NotificationLevel {
+        if self.muted_user_ids.contains(&partner_id) {
+            return NotificationLevel::Nothing;
+        }
+        self.conversation_levels.get(&partner_id).copied().unwrap_or_default()
+    }
+
+    /// Whether a message from `from_user_id` notifies the user at all.
+    pub fn wants(&self, from_user_id: Uuid, mentioned: bool) -> bool {
+        match self.level_for(from_user_id) {
+            NotificationLevel::All => true,
+            NotificationLevel::Mentions => mentioned,
+            NotificationLevel::Nothing => false,
+        }
+    }
+
+    /// Whether do-not-disturb, one-off or scheduled, is on at `now`.
+    pub fn is_dnd(&self, now: DateTime<Utc>) -> bool {
+        self.dnd_until.is_some_and(|until| now < until) || self.quiet_hours.as_ref().is_some_and(|quiet| quiet.contains(now))
+    }
+
+    /// Whether a message from `from_user_id` may be pushed at `now`.
+    pub fn allows(&self, from_user_id: Uuid, mentioned: bool, now: DateTime<Utc>) -> bool {
+        self.wants(from_user_id, mentioned) && !self.is_dnd(now)
+    }
+}
+
+/// Whether `message` mentions `username` as `@username` (case-insensitive).
+pub fn mentions(message: &str, username: &str) -> bool {
+    let message = message.to_lowercase();
+    let mention = format!("@{}", username.to_lowercase());
+    message.match_indices(&mention).any(|(start, _)| {
+        let end = start + mention.len();
+        message[end..].chars().next().is_none_or(|next| !(next.is_alphanumeric() || next == '_'))
+    })
+}
+
+/// Whether do-not-disturb is on for `user_id` right now.
+pub async fn is_dnd(app_state: &AppState, user_id: Uuid) -> bool {
+    app_state.notification_settings.lock().await.get(&user_id).is_some_and(|settings| settings.is_dnd(Utc::now()))
+}
+
+/// Whether `user_id` wants to hear about a message from `from_user_id` through their
+/// notification channels (pushes and their own webhooks).
+pub async fn wants_notification(app_state: &AppState, user_id: Uuid, from_user_id: Uuid, mentioned: bool) -> bool {
+    app_state
+        .notification_settings
+        .lock()
+        .await
+        .get(&user_id)
+        .is_none_or(|settings| settings.wants(from_user_id, mentioned))
+}
+
+/// The JSON payload a service worker receives in its `push` event.
+#[derive(Serialize, Debug, Clone)]
+pub struct PushNotification {
+    pub title: String,
+    pub body: String,
+    pub message_id: String,
+    pub from_user_id: Uuid,
+    // Notifications sharing a key (one per conversation) replace each other on the device.
+    // Only read by the push senders, which are optional features.
+    #[serde(skip)]
+    #[cfg_attr(not(any(feature = "web-push", feature = "mobile-push")), allow(dead_code))]
+    pub collapse_key: String,
+    // Whether the message mentions the recipient; lets mentions-only conversations notify.
+    #[serde(skip)]
+    pub mentioned: bool,
+    // One-click action of a new-login alert: `POST /api/v1/sessions/revoke` with this token
+    // revokes the new session.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revoke_token: Option<String>,
+}
+
+impl PushNotification {
+    /// A notification for a chat message, with the body shortened to fit a push payload.
+    pub fn chat_message(from_user_id: Uuid, from_username: &str, message_id: &str, message: &str) -> Self {
+        let mut body: String = message.chars().take(MAX_BODY_CHARS).collect();
+        if body.len() < message.len() {
+            body.push('…');
+        }
+        PushNotification {
+            title: from_username.to_string(),
+            body,
+            message_id: message_id.to_string(),
+            from_user_id,
+            collapse_key: from_user_id.simple().to_string(),
+            mentioned: false,
+            revoke_token: None,
+        }
+    }
+
+    /// A notice about the user's own account, such as a lockout. It is not a message, so it
+    /// has no message id and comes "from" the user.
+    pub fn account_notice(user_id: Uuid, title: &str, body: String) -> Self {
+        PushNotification {
+            title: title.to_string(),
+            body,
+            message_id: String::new(),
+            from_user_id: user_id,
+            collapse_key: "account".to_string(),
+            mentioned: false,
+            revoke_token: None,
+        }
+    }
+}
+
+/// Event-bus subscriber: notifies recipients of the messages that reached none of their
+/// connections, and users of changes to their account's lock and of unfamiliar logins.
+pub async fn on_event(app_state: Arc<AppState>, event: Arc<DomainEvent>) {
+    match &*event {
+        DomainEvent::MessageSent { to_user_id, delivered: false, notification, .. } => {
+            notify_offline(&app_state, *to_user_id, notification.clone()).await;
+        }
+        DomainEvent::AccountLocked { user_id, locked_until, .. } => {
+            let body = format!("Too many failed logins. Password logins are refused until {} UTC.", locked_until.format("%Y-%m-%d %H:%M"));
+            notify_account(&app_state, *user_id, PushNotification::account_notice(*user_id, "Account locked", body)).await;
+        }
+        DomainEvent::AccountUnlocked { user_id, .. } => {
+            let body = "Your account accepts password logins again.".to_string();
+            notify_account(&app_state, *user_id, PushNotification::account_notice(*user_id, "Account unlocked", body)).await;
+        }
+        DomainEvent::UnfamiliarLogin { user_id, client_ip, country, device_name, platform, revoke_token, .. } => {
+            let from = match (client_ip, country) {
+                (Some(ip), Some(country)) => format!("{} ({})", ip, country),
+                (Some(ip), None) => ip.to_string(),
+                (None, _) => "an unknown address".to_string(),
+            };
+            let device = match (device_name, platform) {
+                (Some(name), _) => format!("\"{}\"", name),
+                (None, Some(platform)) => format!("a {} device", platform),
+                (None, None) => "a new device".to_string(),
+            };
+            let body = format!("Your account was logged in on {} from {}. Not you? Revoke that session.", device, from);
+            let notification = PushNotification {
+                revoke_token: Some(revoke_token.clone()),
+                ..PushNotification::account_notice(*user_id, "New login", body)
+            };
+            notify_account(&app_state, *user_id, notification).await;
+        }
+        _ => {}
+    }
+}
+
+/// Notifies `user_id` of a change to their account through their browser subscriptions and
+/// mobile devices. Notification levels and do-not-disturb apply to messages only.
+#[cfg_attr(not(any(feature = "web-push", feature = "mobile-push")), allow(unused_variables))]
+async fn notify_account(app_state: &Arc<AppState>, user_id: Uuid, notification: PushNotification) {
+    #[cfg(feature = "web-push")]
+    webpush::notify(app_state, user_id, &notification).await;
+    #[cfg(feature = "mobile-push")]
+    mobile_push::notify(app_state, user_id, &notification).await;
+}
+
+/// Notifies `user_id` of a message that arrived while they had no open connection, through
+/// their browser subscriptions and mobile devices, unless the conversation's notification
+/// level rules it out or do-not-disturb is active.
+async fn notify_offline(app_state: &Arc<AppState>, user_id: Uuid, notification: PushNotification) {
+    let allowed = app_state
+        .notification_settings
+        .lock()
+        .await
+        .get(&user_id)
+        .is_none_or(|settings| settings.allows(notification.from_user_id, notification.mentioned, Utc::now()));
+    if !allowed {
+        tracing::debug!(user_id = %user_id, from_user_id = %notification.from_user_id, "Push suppressed by notification settings");
+    } else {
+        #[cfg(feature = "web-push")]
+        webpush::notify(app_state, user_id, &notification).await;
+        #[cfg(feature = "mobile-push")]
+        mobile_push::notify(app_state, user_id, &notification).await;
+    }
+}
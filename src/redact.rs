@@ -0,0 +1,160 @@
+// src/redact.rs
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+
+/// What a redacted value is logged as.
+const REDACTED: &str = "[redacted]";
+
+/// Whether redacted values are logged as they are (`log.reveal_sensitive`, debug builds only).
+static REVEAL: AtomicBool = AtomicBool::new(false);
+
+/// Key of the pseudonyms logged instead of usernames and IPs; `None` logs them as they are.
+static PSEUDONYM_KEY: RwLock<Option<Vec<u8>>> = RwLock::new(None);
+
+/// Hex digits kept of a pseudonym's hash.
+const PSEUDONYM_LENGTH: usize = 16;
+
+/// Turns the escape hatch on or off. Release builds always redact.
+pub(crate) fn reveal_sensitive(reveal: bool) {
+    REVEAL.store(reveal && cfg!(debug_assertions), Ordering::Relaxed);
+}
+
+/// Logs usernames and IPs as pseudonyms keyed with `key` (`log.pseudonymize`), or as they are.
+pub(crate) fn pseudonymize(key: Option<&str>) {
+    *PSEUDONYM_KEY.write().expect("pseudonym key lock poisoned") = key.map(|key| key.as_bytes().to_vec());
+}
+
+/// A value that must not reach the logs: session keys, passwords and tokens, message bodies,
+/// contact lists, and parse errors quoting any of them. Logged as "[redacted]" whether
+/// formatted with `%` or `?`.
+pub struct Redacted<T>(T);
+
+/// Wraps `value` for a log field, e.g. `session_key = %redacted(&session.session_key)`.
+pub fn redacted<T>(value: T) -> Redacted<T> {
+    Redacted(value)
+}
+
+impl<T: fmt::Display> fmt::Display for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if REVEAL.load(Ordering::Relaxed) {
+            self.0.fmt(f)
+        } else {
+            f.write_str(REDACTED)
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if REVEAL.load(Ordering::Relaxed) {
+            self.0.fmt(f)
+        } else {
+            f.write_str(REDACTED)
+        }
+    }
+}
+
+/// Personal data that the logs may keep only as a pseudonym: usernames (and JIDs) and client
+/// IPs. With `log.pseudonymize` it is logged as the first hex digits of its HMAC-SHA256
+/// under `log.pseudonym_key`: the same value always gets the same pseudonym, so a user's or
+/// address's log lines still go together, but it cannot be read back without the key.
+pub struct Pseudonymous<T>(T);
+
+/// Wraps `value` for a log field, e.g. `username = %pseudonym(&session.username)`.
+pub fn pseudonym<T>(value: T) -> Pseudonymous<T> {
+    Pseudonymous(value)
+}
+
+impl<T: fmt::Display> fmt::Display for Pseudonymous<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let key = PSEUDONYM_KEY.read().expect("pseudonym key lock poisoned");
+        let Some(key) = key.as_deref() else { return self.0.fmt(f) };
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+        mac.update(self.0.to_string().as_bytes());
+        let hash: String = mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect();
+        f.write_str(&hash[..PSEUDONYM_LENGTH])
+    }
+}
+
+// Written like `Display`, so `?client_ip.map(pseudonym)` shows `Some(<pseudonym>)`.
+impl<T: fmt::Display> fmt::Debug for Pseudonymous<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // The settings are global, so tests changing them run one at a time.
+    static SETTINGS: Mutex<()> = Mutex::new(());
+
+    fn settings() -> std::sync::MutexGuard<'static, ()> {
+        let guard = SETTINGS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        reveal_sensitive(false);
+        pseudonymize(None);
+        guard
+    }
+
+    #[test]
+    fn redacted_values_never_show_in_display_or_debug() {
+        let _settings = settings();
+        let session_key = "3b9f0c1e-session-key";
+        let message = "meet me at the usual place";
+        let contacts = vec!["alice".to_string(), "bob".to_string()];
+
+        assert_eq!(format!("{}", redacted(session_key)), REDACTED);
+        assert_eq!(format!("{:?}", redacted(session_key)), REDACTED);
+        assert_eq!(format!("{}", redacted(message)), REDACTED);
+        assert_eq!(format!("{:?}", redacted(&contacts)), REDACTED);
+        assert_eq!(format!("{:?}", Some(redacted(message))), format!("Some({})", REDACTED));
+    }
+
+    // `cargo test --release` (as CI runs it) checks that release builds ignore the setting.
+    #[test]
+    fn reveal_shows_redacted_values_in_debug_builds_only() {
+        let _settings = settings();
+        reveal_sensitive(true);
+        let shown = format!("{} {:?}", redacted("3b9f0c1e-session-key"), redacted(vec!["alice"]));
+        reveal_sensitive(false);
+
+        if cfg!(debug_assertions) {
+            assert_eq!(shown, r#"3b9f0c1e-session-key ["alice"]"#);
+        } else {
+            assert_eq!(shown, format!("{} {}", REDACTED, REDACTED));
+        }
+        assert_eq!(format!("{}", redacted("3b9f0c1e-session-key")), REDACTED);
+    }
+
+    #[test]
+    fn pseudonyms_are_stable_keyed_hashes() {
+        let _settings = settings();
+        pseudonymize(Some("first-pseudonym-key"));
+        let alice = format!("{}", pseudonym("alice"));
+        let ip = format!("{:?}", Some(pseudonym("203.0.113.7".parse::<std::net::IpAddr>().unwrap())));
+        let again = format!("{:?}", pseudonym("alice"));
+        let bob = format!("{}", pseudonym("bob"));
+        pseudonymize(Some("second-pseudonym-key"));
+        let rekeyed = format!("{}", pseudonym("alice"));
+
+        assert_eq!(alice.len(), PSEUDONYM_LENGTH);
+        assert!(alice.chars().all(|c| c.is_ascii_hexdigit()), "{}", alice);
+        assert_eq!(again, alice);
+        assert_ne!(bob, alice);
+        assert_ne!(rekeyed, alice);
+        assert!(ip.starts_with("Some(") && !ip.contains("203.0.113.7"), "{}", ip);
+    }
+
+    #[test]
+    fn pseudonyms_are_the_values_without_a_key() {
+        let _settings = settings();
+        assert_eq!(format!("{}", pseudonym("alice")), "alice");
+        assert_eq!(format!("{:?}", Some(pseudonym("203.0.113.7"))), "Some(203.0.113.7)");
+    }
+}
@@ -0,0 +1,28 @@
+// src/registrations.rs
+
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::ws_handlers::User;
+
+/// A registration waiting for an admin's approval while `registration.require_approval` is
+/// set. The account only exists once approved.
+#[derive(Debug, Clone)]
+pub struct PendingRegistration {
+    pub id: Uuid,
+    // The account as it will be created, password already hashed.
+    pub user: User,
+    // Country the registration came from, when `[geoip]` knows it.
+    pub country: Option<String>,
+    pub requested_at: DateTime<Utc>,
+}
+
+/// Pending registrations by id. Kept in memory only: registrations still pending at a
+/// restart must be made again.
+pub type RegistrationQueue = HashMap<Uuid, PendingRegistration>;
+
+/// The pending registration of `username`, if any.
+pub fn find_by_username<'a>(queue: &'a RegistrationQueue, username: &str) -> Option<&'a PendingRegistration> {
+    queue.values().find(|registration| registration.user.username == username)
+}
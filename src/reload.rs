@@ -0,0 +1,83 @@
+// src/reload.rs
+
+use std::sync::Arc;
+
+use crate::config::{Config, ConfigError, RuntimeConfig};
+use crate::ws_handlers::{self, AppState};
+
+/// Re-reads the configuration file and environment and applies the runtime-tunable
+/// subset (`RuntimeConfig`) and `auth.admin_usernames`. Active WebSocket connections are left untouched; settings
+/// outside the subset are only logged when they differ, since they need a restart.
+pub async fn reload(app_state: &Arc<AppState>) -> Result<RuntimeConfig, ConfigError> {
+    let config = Config::load()?;
+    warn_about_restart_only_changes(&app_state.config, &config);
+
+    let runtime = RuntimeConfig::from(&config);
+    if let Err(e) = app_state.log_level.set_level(&runtime.log_level) {
+        tracing::warn!(error = %e, "Failed to apply reloaded log level");
+    }
+
+    ws_handlers::promote_admins(app_state, &config.auth.admin_usernames).await;
+
+    let previous = std::mem::replace(&mut *app_state.runtime.write().await, runtime.clone());
+    if runtime.banner != previous.banner {
+        if let Some(banner) = &runtime.banner {
+            ws_handlers::broadcast_banner(app_state, banner).await;
+        }
+    }
+
+    tracing::info!(runtime = ?runtime, "Configuration reloaded");
+    Ok(runtime)
+}
+
+/// Reloads the configuration every time the process receives SIGHUP.
+#[cfg(unix)]
+pub fn spawn_sighup_listener(app_state: Arc<AppState>) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(stream) => stream,
+        Err(e) => {
+            tracing::warn!(error = %e, "Cannot listen for SIGHUP; config reload is only available via the admin endpoint");
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            tracing::info!("SIGHUP received, reloading configuration");
+            if let Err(e) = reload(&app_state).await {
+                tracing::error!(error = %e, "Configuration reload failed; keeping previous settings");
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+pub fn spawn_sighup_listener(_app_state: Arc<AppState>) {}
+
+fn warn_about_restart_only_changes(current: &Config, reloaded: &Config) {
+    let changed = [
+        ("bind_address", current.bind_address != reloaded.bind_address),
+        ("static_dir", current.static_dir != reloaded.static_dir),
+        ("log.format", current.log.format != reloaded.log.format),
+        ("limits.max_connections", current.limits.max_connections != reloaded.limits.max_connections),
+        ("storage.dsn", current.storage.dsn != reloaded.storage.dsn),
+        ("storage.snapshot_path", current.storage.snapshot_path != reloaded.storage.snapshot_path),
+        ("storage.snapshot_interval_secs", current.storage.snapshot_interval_secs != reloaded.storage.snapshot_interval_secs),
+        ("tls", current.tls != reloaded.tls),
+        ("grpc", current.grpc != reloaded.grpc),
+        ("proxy.trusted_proxies", current.proxy.trusted_proxies != reloaded.proxy.trusted_proxies),
+        ("webhooks", current.webhooks != reloaded.webhooks),
+        ("web_push", current.web_push != reloaded.web_push),
+        ("fcm", current.fcm != reloaded.fcm),
+        ("apns", current.apns != reloaded.apns),
+        ("matrix", current.matrix != reloaded.matrix),
+        ("xmpp", current.xmpp != reloaded.xmpp),
+        ("mqtt", current.mqtt != reloaded.mqtt),
+        ("geoip", current.geoip != reloaded.geoip),
+        ("registration", current.registration != reloaded.registration),
+    ];
+    for (field, _) in changed.iter().filter(|(_, changed)| *changed) {
+        tracing::warn!(field, "Config change ignored until restart");
+    }
+}
@@ -0,0 +1,734 @@
+// src/routes.rs
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use uuid::Uuid;
+use warp::{
+    ws,
+    Filter, Rejection, Reply,
+};
+use warp::reply::{with_status, json};
+
+use crate::access_log;
+use crate::api_docs;
+use crate::bots::TokenScope;
+use crate::client_ip::with_client_ip;
+use crate::connections::CloseReason;
+use crate::error::{ApiError, ErrorResponse};
+use crate::ip_filter;
+#[cfg(feature = "matrix")]
+use crate::matrix;
+use crate::cluster;
+use crate::middleware::{with_app_state, Auth, Stack};
+use crate::redact::{pseudonym, redacted};
+use crate::handlers;
+use crate::ws_handlers::{self, AppState, Role};
+
+// A filter rejecting WebSocket upgrades from browser pages of origins outside
+// `auth.allowed_origins`, so other sites cannot open connections with their visitors'
+// sessions. Requests without an `Origin` header do not come from a page and pass.
+fn with_allowed_origin(app_state: Arc<AppState>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("origin")
+        .and(with_app_state(app_state))
+        .and_then(|origin: Option<String>, app_state_origin: Arc<AppState>| async move {
+            let allowed = &app_state_origin.config.auth.allowed_origins;
+            match origin {
+                Some(origin)
+                    if !allowed.is_empty()
+                        && !allowed.iter().any(|entry| entry.eq_ignore_ascii_case(&origin)) =>
+                {
+                    tracing::warn!(origin = %origin, "WebSocket upgrade denied: origin not allowed.");
+                    Err(warp::reject::custom(ApiError::OriginNotAllowed))
+                }
+                _ => Ok(()),
+            }
+        })
+        .untuple_one()
+}
+
+// Renders an `ApiError` as its `ErrorResponse` body with the matching status.
+fn error_reply(error: &ApiError) -> warp::reply::WithStatus<warp::reply::Json> {
+    with_status(json(&ErrorResponse::from(error)), error.status())
+}
+
+// Custom rejection handler to convert `ApiError` rejections into `ErrorResponse` bodies.
+async fn handle_rejection(err: Rejection) -> Result<impl Reply, Rejection> {
+    if err.is_not_found() {
+        tracing::debug!(rejection = ?err, "Rejection: Not Found");
+        Ok(error_reply(&ApiError::NotFound))
+    } else if let Some(e) = err.find::<ApiError>() {
+        tracing::warn!(code = e.code(), message = %e, "Rejection: ApiError");
+        Ok(error_reply(e))
+    }
+    // A JSON body that does not match the handler's payload type. The serde error names the
+    // missing or mistyped field and where it failed.
+    else if let Some(e) = err.find::<warp::body::BodyDeserializeError>() {
+        let detail = std::error::Error::source(e).map_or_else(|| e.to_string(), |source| source.to_string());
+        tracing::debug!(detail = %redacted(&detail), "Rejection: Malformed body");
+        Ok(error_reply(&ApiError::MalformedBody(detail)))
+    }
+    // Checked before `MethodNotAllowed`: a route with the right method but no credentials
+    // is a better explanation than a sibling route for another method.
+    else if let Some(e) = err.find::<warp::reject::MissingHeader>() {
+        tracing::debug!(header = e.name(), "Rejection: Missing header");
+        Ok(error_reply(&ApiError::MissingHeader(e.name().to_string())))
+    }
+    // Handle the built-in `warp::reject::MethodNotAllowed` specifically
+    else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
+        tracing::debug!(rejection = ?err, "Rejection: Method Not Allowed");
+        Ok(error_reply(&ApiError::MethodNotAllowed))
+    }
+    // Re-reject other unhandled Rejection types so Warp can handle them
+    // This prevents a blanket 500 and allows Warp to propagate more serious internal errors.
+    else {
+        tracing::warn!(rejection = ?err, "Rejection: Unhandled type of rejection, propagating");
+        Err(err) // Re-reject the error
+    }
+}
+
+/// The complete route tree: the bundled web client, the WebSocket endpoint, the API docs,
+/// the Matrix appservice endpoints and the HTTP API, under `/api/v1` and (deprecated) unversioned.
+pub fn build_routes(app_state: Arc<AppState>) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    // Serve static files from the configured directory ('static' by default).
+    // warp::fs::dir will automatically serve 'index.html' if present at the root path '/'.
+    let static_files = warp::fs::dir(app_state.config.static_dir.clone());
+
+    // Middleware stacks. Every HTTP API route runs `api`'s policies, through `api.public()` or
+    // along with its authentication in `api.authenticated(..)`, and takes its body from `api.json()`.
+    let api = Stack::new(app_state.clone()).rate_limit("api", app_state.config.limits.http_requests_per_minute);
+    let websocket = Stack::new(app_state.clone()).layer(with_allowed_origin(app_state.clone()));
+    // Before any route is tried, the WebSocket upgrade included.
+    let permitted = Stack::new(app_state.clone()).layer(ip_filter::with_permitted_ip(app_state.clone()));
+
+    // WebSocket route
+    let chat_route = warp::path("ws")
+        .and(websocket.public())
+        .and(warp::ws())
+        // NEW: Extract query parameters instead of a header for the WebSocket token
+        .and(warp::query::<HashMap<String, String>>())
+        .and(with_client_ip(app_state.clone()))
+        .and(with_app_state(app_state.clone()))
+        .map(|ws: ws::Ws, query_params: HashMap<String, String>, client_ip: Option<IpAddr>, app_state_filter: Arc<AppState>| {
+            ws.on_upgrade(move |socket| async move {
+                let session_key = query_params.get("token").cloned(); // Get the token from query params
+
+                let (socket, session) = match session_key {
+                    Some(_) if !app_state_filter.config.auth.allow_query_token => {
+                        tracing::warn!(client_ip = ?client_ip.map(pseudonym), "WebSocket connection denied: session keys in the URL are disabled.");
+                        return ws_handlers::refuse_ws(socket, CloseReason::InvalidSession).await;
+                    }
+                    Some(token) => match app_state_filter.session_for_key(&token).await {
+                        Some(session) => (socket, session),
+                        None => {
+                            tracing::warn!(client_ip = ?client_ip.map(pseudonym), "WebSocket connection denied: Invalid session key from query param.");
+                            return ws_handlers::refuse_ws(socket, CloseReason::InvalidSession).await;
+                        }
+                    },
+                    // Without a token in the URL, the client authenticates with its first frame.
+                    None => match ws_handlers::authenticate_ws(socket, client_ip, &app_state_filter).await {
+                        Some(authenticated) => authenticated,
+                        None => return,
+                    },
+                };
+                let open_connections = app_state_filter.connections.count().await;
+                if open_connections >= app_state_filter.config.limits.max_connections {
+                    tracing::warn!(open_connections, client_ip = ?client_ip.map(pseudonym), "WebSocket connection denied: connection limit reached.");
+                    return ws_handlers::refuse_ws(socket, CloseReason::ServerFull).await;
+                }
+                ws_handlers::handle_ws(socket, session, client_ip, app_state_filter).await;
+            })
+        });
+
+    // Registration route
+    let register_route = warp::path("register")
+        .and(warp::post())
+        .and(api.public())
+        .and(api.json())
+        .and(with_client_ip(app_state.clone()))
+        .and(warp::header::optional::<String>("user-agent"))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::auth::register_handler);
+
+    // Guest route: a temporary passwordless account, when `registration.allow_guests` is set
+    let guests_route = warp::path!("guests")
+        .and(warp::post())
+        .and(api.public())
+        .and(with_client_ip(app_state.clone()))
+        .and(warp::header::optional::<String>("user-agent"))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::auth::create_guest_handler);
+
+    // Login route
+    let login_route = warp::path("login")
+        .and(warp::post())
+        .and(api.public())
+        .and(api.json())
+        .and(with_client_ip(app_state.clone()))
+        .and(warp::header::optional::<String>("user-agent"))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::auth::login_handler);
+
+    // One-click session revocation route: the token of a new-login alert stands in for a session
+    let revoke_session_route = warp::path!("sessions" / "revoke")
+        .and(warp::post())
+        .and(api.public())
+        .and(api.json())
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::auth::revoke_session_handler);
+
+    // Self-service unlock route: lifts a lockout from a session opened before it, re-verifying the password
+    let unlock_self_route = warp::path!("me" / "unlock")
+        .and(warp::post())
+        .and(api.json())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::auth::unlock_self_handler);
+
+    // Invite routes: create, list and withdraw the caller's invite codes
+    let invites_post_route = warp::path!("invites")
+        .and(warp::post())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::auth::create_invite_handler);
+    let invites_get_route = warp::path!("invites")
+        .and(warp::get())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::auth::list_invites_handler);
+    let invites_delete_route = warp::path!("invites" / String)
+        .and(warp::delete())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::auth::delete_invite_handler);
+
+    // Password change route: re-verifies the current password and revokes the other sessions
+    let change_password_route = warp::path!("me" / "password")
+        .and(warp::post())
+        .and(api.json())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::auth::change_password_handler);
+
+    // Trusted device routes: list the caller's trusted devices, trust the session's device
+    // (re-verifying the password) and distrust one
+    let trusted_devices_get_route = warp::path!("me" / "trusted-devices")
+        .and(warp::get())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::auth::list_trusted_devices_handler);
+    let trusted_devices_post_route = warp::path!("me" / "trusted-devices")
+        .and(warp::post())
+        .and(api.json())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::auth::trust_device_handler);
+    let trusted_devices_delete_route = warp::path!("me" / "trusted-devices" / Uuid)
+        .and(warp::delete())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::auth::distrust_device_handler);
+
+    // Add contact route
+    let contacts_post_route = warp::path("contacts")
+        .and(warp::post())
+        .and(api.json())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::contacts::add_contact_handler);
+
+    // Get contacts route
+    let contacts_get_route = warp::path("contacts")
+        .and(warp::get())
+        .and(api.authenticated(Auth::SessionOrToken(TokenScope::ReadContacts)))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::contacts::get_contacts_handler);
+
+    // Send message route: injects a chat message into the normal fanout without a WebSocket
+    let messages_post_route = warp::path("messages")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(api.json())
+        .and(api.authenticated(Auth::SessionOrToken(TokenScope::SendMessages)))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::messages::send_message_handler);
+
+    // End-to-end encrypted messages, routed without being read
+    let encrypted_messages_post_route = warp::path!("messages" / "encrypted")
+        .and(warp::post())
+        .and(api.json())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::messages::send_encrypted_message_handler);
+
+    // Key distribution for end-to-end encryption
+    let keys_put_route = warp::path("keys")
+        .and(warp::path::end())
+        .and(warp::put())
+        .and(api.json())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::keys::upload_keys_handler);
+
+    let keys_get_route = warp::path("keys")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::keys::get_keys_handler);
+
+    let prekeys_post_route = warp::path!("keys" / "prekeys")
+        .and(warp::post())
+        .and(api.json())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::keys::add_prekeys_handler);
+
+    let prekey_bundle_route = warp::path!("users" / Uuid / "prekey-bundle")
+        .and(warp::get())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::keys::get_prekey_bundle_handler);
+
+    // Broadcast lists: one message delivered separately to several contacts
+    let broadcast_lists_post_route = warp::path("broadcast-lists")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(api.json())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::broadcasts::create_broadcast_list_handler);
+
+    let broadcast_lists_get_route = warp::path("broadcast-lists")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::broadcasts::list_broadcast_lists_handler);
+
+    let broadcast_lists_delete_route = warp::path!("broadcast-lists" / Uuid)
+        .and(warp::delete())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::broadcasts::delete_broadcast_list_handler);
+
+    let broadcast_messages_post_route = warp::path!("broadcast-lists" / Uuid / "messages")
+        .and(warp::post())
+        .and(api.json())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::broadcasts::send_broadcast_message_handler);
+
+    // Conversation list of the authenticated user, with unread counts
+    let conversations_get_route = warp::path("conversations")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::conversations::list_conversations_handler);
+
+    // Marks a conversation as read up to a message, sending a single read receipt
+    let conversation_read_post_route = warp::path!("conversations" / Uuid / "read")
+        .and(warp::post())
+        .and(api.json())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::conversations::mark_conversation_read_handler);
+
+    // Clears a conversation for the authenticated user only
+    let conversation_messages_delete_route = warp::path!("conversations" / Uuid / "messages")
+        .and(warp::delete())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::conversations::clear_conversation_handler);
+
+    // Per-conversation notification level (all messages, mentions only or nothing)
+    let conversation_notifications_get_route = warp::path!("conversations" / Uuid / "notifications")
+        .and(warp::get())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::conversations::get_conversation_notifications_handler);
+    let conversation_notifications_put_route = warp::path!("conversations" / Uuid / "notifications")
+        .and(warp::put())
+        .and(api.json())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::conversations::set_conversation_notifications_handler);
+
+    // Call log of the authenticated user
+    let calls_get_route = warp::path("calls")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::calls::list_calls_handler);
+
+    // Webhook routes: users manage webhooks for events addressed to them
+    let webhooks_post_route = warp::path("webhooks")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(api.json())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::webhooks::create_webhook_handler);
+
+    let webhooks_get_route = warp::path("webhooks")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::webhooks::list_webhooks_handler);
+
+    let webhooks_delete_route = warp::path!("webhooks" / Uuid)
+        .and(warp::delete())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::webhooks::delete_webhook_handler);
+
+    // Incoming webhook management: secret URLs posting into one of the user's conversations
+    let incoming_webhooks_post_route = warp::path("incoming-webhooks")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(api.json())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::webhooks::create_incoming_webhook_handler);
+
+    let incoming_webhooks_get_route = warp::path("incoming-webhooks")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::webhooks::list_incoming_webhooks_handler);
+
+    let incoming_webhooks_delete_route = warp::path!("incoming-webhooks" / Uuid)
+        .and(warp::delete())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::webhooks::delete_incoming_webhook_handler);
+
+    // Incoming webhook URL: authenticated by the token in the path, not by a session
+    let hooks_post_route = warp::path!("hooks" / String)
+        .and(warp::post())
+        .and(api.public())
+        .and(api.json())
+        .and(with_client_ip(app_state.clone()))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::webhooks::post_incoming_webhook_handler);
+
+    // Bot account routes: users create bots and issue scoped API tokens for them
+    let bots_post_route = warp::path("bots")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(api.json())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::bots::create_bot_handler);
+
+    let bots_get_route = warp::path("bots")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::bots::list_bots_handler);
+
+    let bot_tokens_post_route = warp::path!("bots" / Uuid / "tokens")
+        .and(warp::post())
+        .and(api.json())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::bots::create_api_token_handler);
+
+    let bot_tokens_get_route = warp::path!("bots" / Uuid / "tokens")
+        .and(warp::get())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::bots::list_api_tokens_handler);
+
+    let bot_tokens_delete_route = warp::path!("bots" / Uuid / "tokens" / Uuid)
+        .and(warp::delete())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::bots::revoke_api_token_handler);
+
+    // Web Push routes: browsers subscribe with the server's VAPID key to receive offline messages
+    let vapid_key_route = warp::path!("push" / "vapid-public-key")
+        .and(warp::get())
+        .and(api.public())
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::push::vapid_public_key_handler);
+
+    let push_subscriptions_post_route = warp::path!("push" / "subscriptions")
+        .and(warp::post())
+        .and(api.json())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::push::create_push_subscription_handler);
+
+    let push_subscriptions_get_route = warp::path!("push" / "subscriptions")
+        .and(warp::get())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::push::list_push_subscriptions_handler);
+
+    let push_subscriptions_delete_route = warp::path!("push" / "subscriptions" / Uuid)
+        .and(warp::delete())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::push::delete_push_subscription_handler);
+
+    // Mobile push routes: apps register their FCM/APNs device tokens
+    let devices_post_route = warp::path!("push" / "devices")
+        .and(warp::post())
+        .and(api.json())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::push::register_device_handler);
+
+    let devices_get_route = warp::path!("push" / "devices")
+        .and(warp::get())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::push::list_devices_handler);
+
+    let devices_delete_route = warp::path!("push" / "devices" / Uuid)
+        .and(warp::delete())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::push::delete_device_handler);
+
+    // Mute and do-not-disturb settings shared by every push channel
+    let push_settings_get_route = warp::path!("push" / "settings")
+        .and(warp::get())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::push::get_notification_settings_handler);
+
+    let push_settings_put_route = warp::path!("push" / "settings")
+        .and(warp::put())
+        .and(api.json())
+        .and(api.authenticated(Auth::Session))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::push::set_notification_settings_handler);
+
+    // Announcement route (moderators): broadcasts a notice to every active connection
+    let announcement_route = warp::path!("admin" / "announcements")
+        .and(warp::post())
+        .and(api.json())
+        .and(api.authenticated(Auth::Role(Role::Moderator)))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::admin::announcement_handler);
+
+    // Admin role assignment route
+    let set_role_route = warp::path!("admin" / "users" / String / "role")
+        .and(warp::put())
+        .and(api.json())
+        .and(api.authenticated(Auth::Role(Role::Admin)))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::admin::set_role_handler);
+
+    // Admin unlock route: lifts a user's lockout, re-verifying the admin's password
+    let unlock_user_route = warp::path!("admin" / "users" / String / "unlock")
+        .and(warp::post())
+        .and(api.json())
+        .and(api.authenticated(Auth::Role(Role::Admin)))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::admin::unlock_user_handler);
+
+    let registrations_get_route = warp::path!("admin" / "registrations")
+        .and(warp::get())
+        .and(api.authenticated(Auth::Role(Role::Moderator)))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::admin::list_registrations_handler);
+
+    let registration_approve_route = warp::path!("admin" / "registrations" / Uuid / "approve")
+        .and(warp::post())
+        .and(api.authenticated(Auth::Role(Role::Moderator)))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::admin::approve_registration_handler);
+
+    let registration_reject_route = warp::path!("admin" / "registrations" / Uuid)
+        .and(warp::delete())
+        .and(api.authenticated(Auth::Role(Role::Moderator)))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::admin::reject_registration_handler);
+
+    // Admin statistics route
+    let stats_route = warp::path!("admin" / "stats")
+        .and(warp::get())
+        .and(api.authenticated(Auth::Role(Role::Admin)))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::admin::stats_handler);
+
+    // Admin analytics route: daily or weekly activity aggregates
+    let analytics_route = warp::path!("admin" / "analytics")
+        .and(warp::get())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(api.authenticated(Auth::Role(Role::Admin)))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::admin::analytics_handler);
+
+    // Connection routes (moderators): list live WebSockets and close one
+    let admin_connections_get_route = warp::path!("admin" / "connections")
+        .and(warp::get())
+        .and(api.authenticated(Auth::Role(Role::Moderator)))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::admin::list_connections_handler);
+
+    let admin_connections_delete_route = warp::path!("admin" / "connections" / Uuid)
+        .and(warp::delete())
+        .and(api.authenticated(Auth::Role(Role::Moderator)))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::admin::close_connection_handler);
+
+    // Admin config reload route: applies the runtime-tunable settings without a restart
+    let reload_route = warp::path!("admin" / "config" / "reload")
+        .and(warp::post())
+        .and(api.authenticated(Auth::Role(Role::Admin)))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::admin::reload_config_handler);
+
+    // Admin IP rules routes: client address ranges let in or turned away, replaced at runtime
+    let ip_rules_get_route = warp::path!("admin" / "ip-rules")
+        .and(warp::get())
+        .and(api.authenticated(Auth::Role(Role::Admin)))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::admin::get_ip_rules_handler);
+
+    let ip_rules_put_route = warp::path!("admin" / "ip-rules")
+        .and(warp::put())
+        .and(api.json())
+        .and(with_client_ip(app_state.clone()))
+        .and(api.authenticated(Auth::Role(Role::Admin)))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::admin::set_ip_rules_handler);
+
+    // Admin webhook routes: global webhooks receive every event
+    let admin_webhooks_post_route = warp::path!("admin" / "webhooks")
+        .and(warp::post())
+        .and(api.json())
+        .and(api.authenticated(Auth::Role(Role::Admin)))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::webhooks::create_global_webhook_handler);
+
+    let admin_webhooks_get_route = warp::path!("admin" / "webhooks")
+        .and(warp::get())
+        .and(api.authenticated(Auth::Role(Role::Admin)))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::webhooks::list_global_webhooks_handler);
+
+    let admin_webhooks_delete_route = warp::path!("admin" / "webhooks" / Uuid)
+        .and(warp::delete())
+        .and(api.authenticated(Auth::Role(Role::Admin)))
+        .and(with_app_state(app_state.clone()))
+        .and_then(handlers::webhooks::delete_global_webhook_handler);
+
+    // The endpoints are grouped, and each group boxed, so the future of a request through the
+    // tree is allocated on the heap in parts rather than nested whole on the stack of the task
+    // serving it.
+    // Registration, login and account security.
+    let account_api = register_route
+        .or(guests_route)
+        .or(login_route)
+        .or(revoke_session_route)
+        .or(unlock_self_route)
+        .or(change_password_route)
+        .or(invites_post_route)
+        .or(invites_get_route)
+        .or(invites_delete_route)
+        .or(trusted_devices_get_route)
+        .or(trusted_devices_post_route)
+        .or(trusted_devices_delete_route)
+        .boxed();
+
+    // Contacts, messages, keys and conversations.
+    let messaging_api = contacts_post_route
+        .or(contacts_get_route)
+        .or(messages_post_route)
+        .or(encrypted_messages_post_route)
+        .or(keys_put_route)
+        .or(keys_get_route)
+        .or(prekeys_post_route)
+        .or(prekey_bundle_route)
+        .or(broadcast_lists_post_route)
+        .or(broadcast_lists_get_route)
+        .or(broadcast_lists_delete_route)
+        .or(broadcast_messages_post_route)
+        .or(conversations_get_route)
+        .or(conversation_read_post_route)
+        .or(conversation_messages_delete_route)
+        .or(conversation_notifications_get_route)
+        .or(conversation_notifications_put_route)
+        .or(calls_get_route)
+        .boxed();
+
+    // Webhooks, bots and push notifications.
+    let integrations_api = webhooks_post_route
+        .or(webhooks_get_route)
+        .or(webhooks_delete_route)
+        .or(incoming_webhooks_post_route)
+        .or(incoming_webhooks_get_route)
+        .or(incoming_webhooks_delete_route)
+        .or(hooks_post_route)
+        .or(bots_post_route)
+        .or(bots_get_route)
+        .or(bot_tokens_post_route)
+        .or(bot_tokens_get_route)
+        .or(bot_tokens_delete_route)
+        .or(vapid_key_route)
+        .or(push_subscriptions_post_route)
+        .or(push_subscriptions_get_route)
+        .or(push_subscriptions_delete_route)
+        .or(devices_post_route)
+        .or(devices_get_route)
+        .or(devices_delete_route)
+        .or(push_settings_get_route)
+        .or(push_settings_put_route)
+        .boxed();
+
+    // Administration.
+    let admin_api = announcement_route
+        .or(set_role_route)
+        .or(unlock_user_route)
+        .or(registrations_get_route)
+        .or(registration_approve_route)
+        .or(registration_reject_route)
+        .or(stats_route)
+        .or(analytics_route)
+        .or(admin_connections_get_route)
+        .or(admin_connections_delete_route)
+        .or(reload_route)
+        .or(ip_rules_get_route)
+        .or(ip_rules_put_route)
+        .or(admin_webhooks_post_route)
+        .or(admin_webhooks_get_route)
+        .or(admin_webhooks_delete_route)
+        .boxed();
+
+    // The order of routes matters. Static files should generally be checked first.
+    // All JSON endpoints of version 1 of the HTTP API.
+    let api_v1 = account_api.or(messaging_api).or(integrations_api).or(admin_api).boxed();
+
+    // Versioned mount point. Breaking payload changes ship as a new /api/v2 tree next to it.
+    let versioned_api = warp::path!("api" / "v1" / ..).and(api_v1.clone());
+
+    // Compatibility shim: the original unversioned paths keep serving v1, marked as deprecated.
+    let legacy_api = api_v1.with(warp::reply::with::header("deprecation", "true"));
+
+    let routes = static_files // This will now serve 'static/index.html' for '/'
+        .or(chat_route)
+        .or(api_docs::docs_routes());
+    #[cfg(feature = "matrix")]
+    let routes = routes.or(matrix::appservice_routes(app_state.clone()));
+    let routes = routes
+        .or(cluster::routes(app_state.clone()))
+        .or(versioned_api)
+        .or(legacy_api);
+    permitted
+        .public()
+        .and(routes)
+        .recover(handle_rejection)
+        // Outside `recover`, so requests answered with an error are logged with their status.
+        .with(access_log::request_span(app_state.clone()))
+        .with(access_log::layer(app_state))
+}
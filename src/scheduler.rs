@@ -0,0 +1,142 @@
+// src/scheduler.rs
+
+use chrono::{DateTime, Utc};
+use futures::FutureExt;
+use serde::Serialize;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::ws_handlers::AppState;
+
+/// Runs the server's recurring background jobs (snapshots, session expiry, presence refresh)
+/// as named tasks, keeps statistics on each, and stops them together on shutdown.
+#[derive(Debug)]
+pub struct Scheduler {
+    tasks: Mutex<Vec<Arc<TaskStats>>>,
+    handles: Mutex<Vec<JoinHandle<()>>>,
+    shutdown: watch::Sender<bool>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Scheduler { tasks: Mutex::default(), handles: Mutex::default(), shutdown: watch::channel(false).0 }
+    }
+}
+
+#[derive(Debug)]
+struct TaskStats {
+    name: &'static str,
+    period: Duration,
+    runs: AtomicU64,
+    panics: AtomicU64,
+    total_run_micros: AtomicU64,
+    max_run_micros: AtomicU64,
+    last_run_at: Mutex<Option<DateTime<Utc>>>,
+}
+
+/// Runs of one scheduled task since startup.
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ScheduledTaskResponse {
+    name: &'static str,
+    period_secs: u64,
+    runs: u64,
+    // Runs that panicked; the task carries on with the next one.
+    panics: u64,
+    total_run_micros: u64,
+    max_run_micros: u64,
+    // When the last run started (RFC 3339).
+    last_run_at: Option<String>,
+}
+
+impl Scheduler {
+    /// Runs `job` right away and then every `period`, each wait lengthened by a random delay
+    /// of up to `jitter` so tasks do not fire in lockstep with each other or with other
+    /// servers. The task holds the state weakly and ends once the server is dropped or shut
+    /// down.
+    pub(crate) fn every<F, Fut>(&self, app_state: &Arc<AppState>, name: &'static str, period: Duration, jitter: Duration, job: F)
+    where
+        F: Fn(Arc<AppState>) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send,
+    {
+        let stats = Arc::new(TaskStats {
+            name,
+            period,
+            runs: AtomicU64::new(0),
+            panics: AtomicU64::new(0),
+            total_run_micros: AtomicU64::new(0),
+            max_run_micros: AtomicU64::new(0),
+            last_run_at: Mutex::new(None),
+        });
+        let mut shutdown = self.shutdown.subscribe();
+        let app_state: Weak<AppState> = Arc::downgrade(app_state);
+        let task_stats = stats.clone();
+        let handle = tokio::spawn(async move {
+            while !*shutdown.borrow() {
+                let Some(app_state) = app_state.upgrade() else { break };
+                task_stats.run(job(app_state)).instrument(tracing::info_span!("scheduled_task", task = name)).await;
+                tokio::select! {
+                    _ = tokio::time::sleep(period + random_delay(jitter)) => {}
+                    _ = shutdown.changed() => {}
+                }
+            }
+        });
+        self.tasks.lock().expect("scheduler lock").push(stats);
+        self.handles.lock().expect("scheduler lock").push(handle);
+    }
+
+    /// Stops every task, waiting for runs in progress to finish.
+    pub async fn shutdown(&self) {
+        self.shutdown.send_replace(true);
+        let handles = std::mem::take(&mut *self.handles.lock().expect("scheduler lock"));
+        for handle in handles {
+            let _ = handle.await;
+        }
+    }
+
+    /// Statistics of every scheduled task, in the order they were scheduled.
+    pub fn task_stats(&self) -> Vec<ScheduledTaskResponse> {
+        self.tasks.lock().expect("scheduler lock").iter().map(|task| task.snapshot()).collect()
+    }
+}
+
+impl TaskStats {
+    // A run that panics is counted and logged; it must not end the task.
+    async fn run(&self, job: impl Future<Output = ()>) {
+        let started = Instant::now();
+        *self.last_run_at.lock().expect("task stats lock") = Some(Utc::now());
+        if AssertUnwindSafe(job).catch_unwind().await.is_err() {
+            self.panics.fetch_add(1, Ordering::Relaxed);
+            tracing::error!(task = self.name, "Scheduled task panicked");
+        }
+        let micros = u64::try_from(started.elapsed().as_micros()).unwrap_or(u64::MAX);
+        self.runs.fetch_add(1, Ordering::Relaxed);
+        self.total_run_micros.fetch_add(micros, Ordering::Relaxed);
+        self.max_run_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    fn snapshot(&self) -> ScheduledTaskResponse {
+        ScheduledTaskResponse {
+            name: self.name,
+            period_secs: self.period.as_secs(),
+            runs: self.runs.load(Ordering::Relaxed),
+            panics: self.panics.load(Ordering::Relaxed),
+            total_run_micros: self.total_run_micros.load(Ordering::Relaxed),
+            max_run_micros: self.max_run_micros.load(Ordering::Relaxed),
+            last_run_at: self.last_run_at.lock().expect("task stats lock").map(|at| at.to_rfc3339()),
+        }
+    }
+}
+
+// A uniformly random delay between zero and `jitter`, drawn from a v4 UUID's random bits.
+fn random_delay(jitter: Duration) -> Duration {
+    let range = jitter.as_micros() + 1;
+    Duration::from_micros((Uuid::new_v4().as_u128() % range) as u64)
+}
@@ -0,0 +1,464 @@
+// src/server.rs
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::RwLock;
+use warp::{Filter, Rejection, Reply};
+
+use crate::config::{
+    ClusterConfig, Config, ConfigError, GrpcConfig, KafkaConfig, LimitsConfig, MatrixConfig, MqttConfig, NatsConfig, RuntimeConfig, StorageConfig, TlsConfig,
+    WebPushConfig, XmppConfig,
+};
+#[cfg(feature = "kafka")]
+use crate::kafka::{self, KafkaExporter, KafkaProducer};
+#[cfg(feature = "ldap")]
+use crate::ldap::LdapDirectory;
+#[cfg(feature = "matrix")]
+use crate::matrix::MatrixBridge;
+#[cfg(feature = "mobile-push")]
+use crate::mobile_push::{MobilePushDispatcher, MobilePushError};
+#[cfg(feature = "mqtt")]
+use crate::mqtt::{self, MqttBridge};
+#[cfg(feature = "nats")]
+use crate::nats::{self, NatsConnection, NatsRouter};
+use crate::connections::{CloseReason, ConnectionRegistry, CLOSE_TIMEOUT};
+use crate::fanout::FanoutPool;
+use crate::geoip::GeoIp;
+use crate::ldap::Directory;
+use crate::login_alerts::LoginAlerts;
+use crate::middleware::RateLimits;
+use crate::ordering::ConversationOrdering;
+use crate::cluster::{self, Cluster};
+use crate::routes::build_routes;
+use crate::shards::Sharded;
+use crate::stats::{ServerStats, TimedMutex};
+use crate::telemetry::{self, TelemetryGuard};
+use crate::wal::{self, WriteAheadLog};
+use crate::webhooks::WebhookDispatcher;
+#[cfg(feature = "web-push")]
+use crate::webpush::WebPushSender;
+use crate::ws_handlers::{self, AppState};
+use crate::{events, guests, reload, snapshot};
+
+/// Why a server could not be built.
+#[derive(Debug)]
+pub enum ServerError {
+    Config(ConfigError),
+    #[cfg(feature = "web-push")]
+    WebPush(web_push::WebPushError),
+    #[cfg(feature = "mobile-push")]
+    MobilePush(MobilePushError),
+    #[cfg(feature = "mqtt")]
+    Mqtt(rumqttc::OptionError),
+    #[cfg(feature = "nats")]
+    Nats(String),
+    GeoIp(io::Error),
+    Snapshot(io::Error),
+    WriteAheadLog(io::Error),
+}
+
+impl fmt::Display for ServerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ServerError::Config(e) => write!(f, "configuration error: {}", e),
+            #[cfg(feature = "web-push")]
+            ServerError::WebPush(e) => write!(f, "cannot load the Web Push VAPID key: {}", e),
+            #[cfg(feature = "mobile-push")]
+            ServerError::MobilePush(e) => write!(f, "cannot load the mobile push credentials: {}", e),
+            #[cfg(feature = "mqtt")]
+            ServerError::Mqtt(e) => write!(f, "invalid MQTT broker URL: {}", e),
+            #[cfg(feature = "nats")]
+            ServerError::Nats(e) => write!(f, "invalid NATS server URL: {}", e),
+            ServerError::GeoIp(e) => write!(f, "cannot load the GeoIP database: {}", e),
+            ServerError::Snapshot(e) => write!(f, "cannot load the state snapshot: {}", e),
+            ServerError::WriteAheadLog(e) => write!(f, "cannot replay or open the write-ahead log: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ServerError {}
+
+/// Configures a server programmatically, starting from the defaults or a loaded `Config`.
+///
+/// ```ignore
+/// let server = ChatServerBuilder::new()
+///     .bind_address(([127, 0, 0, 1], 8080).into())
+///     .limits(LimitsConfig { max_connections: 100, ..LimitsConfig::default() })
+///     .build()
+///     .await?;
+/// server.run().await;
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct ChatServerBuilder {
+    config: Config,
+    directory: Option<Arc<dyn Directory>>,
+}
+
+impl ChatServerBuilder {
+    /// A builder with the default configuration (what a missing `config.toml` gives).
+    pub fn new() -> Self {
+        ChatServerBuilder::default()
+    }
+
+    /// A builder starting from `config`, e.g. one returned by `Config::load`.
+    pub fn from_config(config: Config) -> Self {
+        ChatServerBuilder { config, directory: None }
+    }
+
+    pub fn bind_address(mut self, bind_address: SocketAddr) -> Self {
+        self.config.bind_address = bind_address;
+        self
+    }
+
+    /// Directory the bundled web client is served from.
+    pub fn static_dir(mut self, static_dir: impl Into<PathBuf>) -> Self {
+        self.config.static_dir = static_dir.into();
+        self
+    }
+
+    /// Storage backend and snapshot persistence.
+    pub fn storage(mut self, storage: StorageConfig) -> Self {
+        self.config.storage = storage;
+        self
+    }
+
+    /// Serves HTTPS/WSS with the given PEM certificate chain and private key (`tls` feature).
+    pub fn tls(mut self, cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        self.config.tls = Some(TlsConfig { cert_path: cert_path.into(), key_path: key_path.into() });
+        self
+    }
+
+    pub fn limits(mut self, limits: LimitsConfig) -> Self {
+        self.config.limits = limits;
+        self
+    }
+
+    /// Enables the gRPC listener (`grpc` feature).
+    pub fn grpc(mut self, grpc: GrpcConfig) -> Self {
+        self.config.grpc = Some(grpc);
+        self
+    }
+
+    /// Enables Web Push notifications for offline recipients (`web-push` feature).
+    pub fn web_push(mut self, web_push: WebPushConfig) -> Self {
+        self.config.web_push = Some(web_push);
+        self
+    }
+
+    /// Enables the Matrix appservice bridge (`matrix` feature).
+    pub fn matrix(mut self, matrix: MatrixConfig) -> Self {
+        self.config.matrix = Some(matrix);
+        self
+    }
+
+    /// Enables the XMPP listener (`xmpp` feature).
+    pub fn xmpp(mut self, xmpp: XmppConfig) -> Self {
+        self.config.xmpp = Some(xmpp);
+        self
+    }
+
+    /// Enables the MQTT bridge (`mqtt` feature).
+    pub fn mqtt(mut self, mqtt: MqttConfig) -> Self {
+        self.config.mqtt = Some(mqtt);
+        self
+    }
+
+    /// Shares presence with the other nodes of a cluster.
+    pub fn cluster(mut self, cluster: ClusterConfig) -> Self {
+        self.config.cluster = Some(cluster);
+        self
+    }
+
+    /// Routes frames between nodes through a NATS server (`nats` feature).
+    pub fn nats(mut self, nats: NatsConfig) -> Self {
+        self.config.nats = Some(nats);
+        self
+    }
+
+    /// Exports message, presence and audit events to Kafka (`kafka` feature).
+    pub fn kafka(mut self, kafka: KafkaConfig) -> Self {
+        self.config.kafka = Some(kafka);
+        self
+    }
+
+    /// Checks the passwords of directory accounts against `directory` instead of the `[ldap]`
+    /// server, e.g. a fake one in tests. Registration stays closed unless `ldap.allow_registration`.
+    pub fn directory(mut self, directory: Arc<dyn Directory>) -> Self {
+        self.directory = Some(directory);
+        self
+    }
+
+    /// Validates the configuration, installs logging, loads persisted state and sets up every
+    /// enabled subsystem. Nothing listens until `ChatServer::run`.
+    pub async fn build(self) -> Result<ChatServer, ServerError> {
+        let config = self.config;
+        config.validate().map_err(ServerError::Config)?;
+        #[cfg(feature = "ldap")]
+        let directory = self.directory.or_else(|| config.ldap.as_ref().map(|ldap| Arc::new(LdapDirectory::new(ldap)) as Arc<dyn Directory>));
+        #[cfg(not(feature = "ldap"))]
+        let directory = self.directory;
+
+        // Keep the guard alive for the lifetime of the server so buffered spans are flushed on exit.
+        let (telemetry, log_level) = telemetry::init(&config.log);
+
+        // Not fatal: the API and WebSocket still work without the bundled web client.
+        if !config.static_dir.is_dir() {
+            tracing::warn!(static_dir = %config.static_dir.display(), "Static directory not found; the web client will not be served.");
+        }
+
+        #[cfg(feature = "web-push")]
+        let web_push = config.web_push.as_ref().map(WebPushSender::new).transpose().map_err(ServerError::WebPush)?;
+        #[cfg(feature = "mobile-push")]
+        let mobile_push =
+            MobilePushDispatcher::new(config.fcm.as_ref(), config.apns.as_ref()).map_err(ServerError::MobilePush)?;
+        #[cfg(feature = "mqtt")]
+        let (mqtt, mqtt_event_loop) = match config.mqtt.as_ref().map(MqttBridge::new).transpose().map_err(ServerError::Mqtt)? {
+            Some((mqtt, event_loop)) => (Some(mqtt), Some(event_loop)),
+            None => (None, None),
+        };
+        #[cfg(feature = "nats")]
+        let (nats, nats_connection) = {
+            // Named after the cluster node when there is one, for the server's connection list.
+            let node_id = config.cluster.as_ref().map_or_else(|| uuid::Uuid::new_v4().to_string(), |cluster| cluster.node_id.clone());
+            match config.nats.as_ref().map(|nats| NatsRouter::new(nats, &node_id)).transpose().map_err(ServerError::Nats)? {
+                Some((nats, connection)) => (Some(nats), Some(connection)),
+                None => (None, None),
+            }
+        };
+        #[cfg(feature = "kafka")]
+        let (kafka, kafka_producer) = config.kafka.as_ref().map(KafkaExporter::new).unzip();
+
+        let geoip = config.geoip.as_ref().map(GeoIp::load).transpose().map_err(ServerError::GeoIp)?;
+
+        let (mut users, analytics) = config
+            .storage
+            .snapshot_path
+            .as_deref()
+            .map(snapshot::load)
+            .transpose()
+            .map_err(ServerError::Snapshot)?
+            .unwrap_or_default();
+        let mut wal = None;
+        if let Some(path) = &config.storage.snapshot_path {
+            let replayed = wal::replay(path, &mut users).await.map_err(ServerError::WriteAheadLog)?;
+            tracing::info!(path = %path.display(), users = users.len(), replayed, "Loaded state snapshot");
+            wal = Some(WriteAheadLog::open(path).await.map_err(ServerError::WriteAheadLog)?);
+        }
+
+        // Initialize shared application state
+        let app_state = Arc::new(AppState {
+            users: Sharded::from_map("users", config.limits.state_shards, users).with_id_index(|user| user.id),
+            user_sessions: Sharded::new("user_sessions", config.limits.state_shards),
+            connections: ConnectionRegistry::spawn(config.limits.state_shards),
+            stats: ServerStats::default(),
+            runtime: RwLock::new(RuntimeConfig::from(&config)),
+            webhooks: TimedMutex::new("webhooks", HashMap::new()),
+            webhook_dispatcher: WebhookDispatcher::new(&config.webhooks),
+            incoming_webhooks: TimedMutex::new("incoming_webhooks", HashMap::new()),
+            api_tokens: TimedMutex::new("api_tokens", HashMap::new()),
+            push_subscriptions: TimedMutex::new("push_subscriptions", HashMap::new()),
+            #[cfg(feature = "web-push")]
+            web_push,
+            device_tokens: TimedMutex::new("device_tokens", HashMap::new()),
+            #[cfg(feature = "mobile-push")]
+            mobile_push,
+            notification_settings: TimedMutex::new("notification_settings", HashMap::new()),
+            dnd_presence: TimedMutex::new("dnd_presence", HashSet::new()),
+            e2e_keys: TimedMutex::new("e2e_keys", HashMap::new()),
+            conversations: TimedMutex::new("conversations", HashMap::new()),
+            drafts: TimedMutex::new("drafts", HashMap::new()),
+            typing: TimedMutex::new("typing", HashMap::new()),
+            live_locations: TimedMutex::new("live_locations", HashMap::new()),
+            polls: TimedMutex::new("polls", HashMap::new()),
+            broadcast_lists: TimedMutex::new("broadcast_lists", HashMap::new()),
+            calls: TimedMutex::new("calls", HashMap::new()),
+            call_history: TimedMutex::new("call_history", HashMap::new()),
+            analytics: TimedMutex::new("analytics", analytics),
+            rate_limits: TimedMutex::new("rate_limits", RateLimits::default()),
+            ip_rules: TimedMutex::new("ip_rules", config.ip_filter.clone()),
+            geoip,
+            login_failures: TimedMutex::new("login_failures", HashMap::new()),
+            login_alerts: TimedMutex::new("login_alerts", LoginAlerts::default()),
+            trusted_devices: TimedMutex::new("trusted_devices", HashMap::new()),
+            invites: TimedMutex::new("invites", HashMap::new()),
+            registrations: TimedMutex::new("registrations", HashMap::new()),
+            wal,
+            #[cfg(feature = "matrix")]
+            matrix: config.matrix.as_ref().map(MatrixBridge::new),
+            #[cfg(feature = "mqtt")]
+            mqtt,
+            cluster: config.cluster.as_ref().map(Cluster::new),
+            #[cfg(feature = "nats")]
+            nats,
+            #[cfg(feature = "kafka")]
+            kafka,
+            interceptors: Default::default(),
+            directory,
+            events: Default::default(),
+            ordering: ConversationOrdering::spawn(config.limits.conversation_shards),
+            fanout: FanoutPool::spawn(config.limits.fanout_workers),
+            clock: Default::default(),
+            scheduler: Default::default(),
+            #[cfg(feature = "fault-injection")]
+            faults: Default::default(),
+            config,
+            log_level,
+        });
+        ws_handlers::promote_admins(&app_state, &app_state.config.auth.admin_usernames).await;
+
+        Ok(ChatServer {
+            app_state,
+            #[cfg(feature = "mqtt")]
+            mqtt_event_loop,
+            #[cfg(feature = "nats")]
+            nats_connection,
+            #[cfg(feature = "kafka")]
+            kafka_producer,
+            _telemetry: telemetry,
+        })
+    }
+}
+
+/// A fully set up server, ready to `run`.
+pub struct ChatServer {
+    app_state: Arc<AppState>,
+    #[cfg(feature = "mqtt")]
+    mqtt_event_loop: Option<rumqttc::EventLoop>,
+    #[cfg(feature = "nats")]
+    nats_connection: Option<NatsConnection>,
+    #[cfg(feature = "kafka")]
+    kafka_producer: Option<KafkaProducer>,
+    _telemetry: TelemetryGuard,
+}
+
+impl ChatServer {
+    /// The shared state the server runs on.
+    pub fn state(&self) -> Arc<AppState> {
+        self.app_state.clone()
+    }
+
+    /// Starts the background tasks: event subscribers (webhooks, pushes, metrics, audit log),
+    /// the scheduled jobs (snapshots, presence refresh, stale session expiry), the SIGHUP
+    /// reload listener and the enabled bridges and listeners. `run` does this itself; call it
+    /// once when embedding the server with `filter` instead.
+    pub fn start(&mut self) {
+        let app_state = &self.app_state;
+        events::spawn_subscribers(app_state);
+        reload::spawn_sighup_listener(app_state.clone());
+        let scheduler = &app_state.scheduler;
+        scheduler.every(app_state, "dnd_presence", Duration::from_secs(60), Duration::from_secs(5), ws_handlers::refresh_dnd_presence);
+        scheduler.every(app_state, "session_expiry", Duration::from_secs(60), Duration::from_secs(10), ws_handlers::sweep_idle_sessions);
+        if app_state.config.registration.allow_guests {
+            scheduler.every(app_state, "guest_purge", Duration::from_secs(60), Duration::from_secs(10), guests::sweep);
+        }
+        if let Some(path) = app_state.config.storage.snapshot_path.clone() {
+            let interval = Duration::from_secs(app_state.config.storage.snapshot_interval_secs);
+            scheduler.every(app_state, "snapshot", interval, interval / 10, move |app_state| snapshot::run(app_state, path.clone()));
+        }
+        if let Some(cluster) = &app_state.cluster {
+            let interval = cluster.heartbeat_interval();
+            scheduler.every(app_state, "cluster_heartbeat", interval, interval / 10, cluster::heartbeat);
+        }
+
+        #[cfg(feature = "grpc")]
+        if let Some(grpc_config) = &app_state.config.grpc {
+            tokio::spawn(crate::grpc::serve(app_state.clone(), grpc_config.bind_address));
+        }
+        #[cfg(feature = "mqtt")]
+        if let Some(event_loop) = self.mqtt_event_loop.take() {
+            tokio::spawn(mqtt::run(app_state.clone(), event_loop));
+        }
+        #[cfg(feature = "nats")]
+        if let Some(connection) = self.nats_connection.take() {
+            tokio::spawn(nats::run(app_state.clone(), connection));
+        }
+        #[cfg(feature = "kafka")]
+        if let Some(producer) = self.kafka_producer.take() {
+            tokio::spawn(kafka::run(app_state.clone(), producer));
+        }
+        #[cfg(feature = "xmpp")]
+        if let Some(xmpp_config) = &app_state.config.xmpp {
+            tokio::spawn(crate::xmpp::serve(app_state.clone(), xmpp_config.bind_address, xmpp_config.domain.clone()));
+        }
+    }
+
+    /// The complete route tree, for mounting inside a larger warp application instead of
+    /// calling `run`, e.g. under a path prefix:
+    ///
+    /// ```ignore
+    /// let mut server = ChatServerBuilder::new().build().await?;
+    /// server.start();
+    /// let app = warp::path("chat").and(server.filter()).or(my_routes);
+    /// warp::serve(app).run(([0, 0, 0, 0], 8080)).await;
+    /// ```
+    ///
+    /// Keep the `ChatServer` alive while serving: dropping it flushes and stops the telemetry
+    /// exporter. The bundled web client and the Matrix appservice endpoints use absolute paths,
+    /// so they only work when the filter is mounted at the root.
+    pub fn filter(&self) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+        build_routes(self.app_state.clone())
+    }
+
+    /// Closes every WebSocket with `CloseReason::ShuttingDown`, giving them a moment to
+    /// receive it, and stops the scheduled jobs, letting runs in progress (such as a snapshot
+    /// being written) finish. `run` does this on Ctrl-C; call it before exiting when embedding
+    /// the server.
+    pub async fn shutdown(&self) {
+        let connections = &self.app_state.connections;
+        connections.close_all(CloseReason::ShuttingDown).await;
+        let closed = async {
+            while connections.count().await > 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        if tokio::time::timeout(CLOSE_TIMEOUT, closed).await.is_err() {
+            tracing::warn!("Some connections did not close in time");
+        }
+        self.app_state.scheduler.shutdown().await;
+    }
+
+    /// Starts the background tasks and serves HTTP(S) and WebSocket clients until Ctrl-C,
+    /// then shuts down.
+    pub async fn run(mut self) {
+        let bind_address = self.app_state.config.bind_address;
+        let tls = self.app_state.config.tls.clone();
+        tracing::info!(addr = %bind_address, "Starting chat server");
+
+        self.start();
+        let routes = self.filter();
+        match tls {
+            #[cfg(feature = "tls")]
+            Some(tls) => {
+                tracing::info!(cert_path = %tls.cert_path.display(), "Serving HTTPS/WSS");
+                let (_, server) = warp::serve(routes)
+                    .tls()
+                    .cert_path(&tls.cert_path)
+                    .key_path(&tls.key_path)
+                    .bind_with_graceful_shutdown(bind_address, interrupted());
+                server.await;
+            }
+            // Config validation rejects a [tls] section when the feature is disabled.
+            #[cfg(not(feature = "tls"))]
+            Some(_) => unreachable!("TLS configured in a build without the `tls` feature"),
+            None => {
+                let (_, server) = warp::serve(routes).bind_with_graceful_shutdown(bind_address, interrupted());
+                server.await;
+            }
+        }
+        tracing::info!("Shutting down");
+        self.shutdown().await;
+    }
+}
+
+// Completes on Ctrl-C (SIGINT).
+async fn interrupted() {
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        tracing::error!(error = %e, "Cannot listen for Ctrl-C; shut down by killing the process");
+        std::future::pending::<()>().await;
+    }
+}
@@ -0,0 +1,377 @@
+// src/shards.rs
+
+use arc_swap::ArcSwap;
+use std::borrow::Borrow;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Arc;
+use tokio::sync::MutexGuard;
+use uuid::Uuid;
+
+use crate::stats::{LockStatsResponse, TimedMutex};
+
+/// Buckets each shard's entries are split into. A write republishes only the buckets it
+/// changed, so it copies about 1/64 of the shard instead of all of it.
+const BUCKETS: usize = 64;
+
+/// Buckets of an id index, shared by all the shards of the map.
+const ID_BUCKETS: usize = 1024;
+
+/// A map of the shared state split into a fixed number of shards, each behind its own lock
+/// and chosen by the hash of the entry's key. Requests touching different keys (users,
+/// sessions) mostly lock different shards, so they do not wait on each other.
+///
+/// Each shard also keeps immutable copies of its entries, split into buckets, and a write
+/// through its lock replaces the copies of the buckets it changed when it finishes. Lookups
+/// (`get`, `find`, `filter`, ...) read those copies without taking any lock, so the
+/// per-request reads never wait on writers.
+pub struct Sharded<K, V> {
+    name: &'static str,
+    shards: Vec<Shard<K, V>>,
+    ids: Option<Arc<IdIndex<K, V>>>,
+}
+
+/// One shard of a [`Sharded`] map: the locked entries and the copies published for readers.
+pub struct Shard<K, V> {
+    buckets: TimedMutex<Vec<HashMap<K, V>>>,
+    published: Vec<ArcSwap<HashMap<K, V>>>,
+    ids: Option<Arc<IdIndex<K, V>>>,
+}
+
+/// The keys of a [`Sharded`] map by an id of their values, see [`Sharded::with_id_index`].
+/// Kept up to date by the writes through the shard guards.
+struct IdIndex<K, V> {
+    id_of: fn(&V) -> Uuid,
+    buckets: Vec<ArcSwap<HashMap<Uuid, K>>>,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Shard<K, V> {
+    fn new(name: &'static str, buckets: Vec<HashMap<K, V>>) -> Self {
+        Shard {
+            published: buckets.iter().map(|bucket| ArcSwap::from_pointee(bucket.clone())).collect(),
+            buckets: TimedMutex::new(name, buckets),
+            ids: None,
+        }
+    }
+
+    /// Locks the shard. Once the guard is dropped after a change, readers see the new entries.
+    pub async fn lock(&self) -> ShardGuard<'_, K, V> {
+        ShardGuard { buckets: self.buckets.lock().await, shard: self, changed: 0 }
+    }
+
+    // The entries of each bucket as of the last finished write.
+    fn load(&self) -> impl Iterator<Item = Arc<HashMap<K, V>>> + '_ {
+        self.published.iter().map(|bucket| bucket.load_full())
+    }
+}
+
+impl<K: Clone, V> IdIndex<K, V> {
+    fn bucket(&self, id: &Uuid) -> &ArcSwap<HashMap<Uuid, K>> {
+        &self.buckets[(id.as_u128() % self.buckets.len() as u128) as usize]
+    }
+
+    fn get(&self, id: &Uuid) -> Option<K> {
+        self.bucket(id).load().get(id).cloned()
+    }
+
+    // Writers of different shards may change the same bucket, hence the compare-and-swap.
+    fn insert(&self, id: Uuid, key: &K) {
+        self.bucket(&id).rcu(|bucket| {
+            let mut bucket = HashMap::clone(bucket);
+            bucket.insert(id, key.clone());
+            bucket
+        });
+    }
+
+    fn remove(&self, id: &Uuid) {
+        self.bucket(id).rcu(|bucket| {
+            let mut bucket = HashMap::clone(bucket);
+            bucket.remove(id);
+            bucket
+        });
+    }
+}
+
+/// The locked entries of a shard. Changes mark their bucket, and the marked buckets are
+/// published to lock-free readers when the guard is dropped, while the lock is still held.
+pub struct ShardGuard<'a, K: Hash + Eq + Clone, V: Clone> {
+    buckets: MutexGuard<'a, Vec<HashMap<K, V>>>,
+    shard: &'a Shard<K, V>,
+    // One bit per bucket changed through this guard.
+    changed: u64,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> ShardGuard<'_, K, V> {
+    pub fn get<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.buckets[bucket_of(key)].get(key)
+    }
+
+    pub fn contains_key<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.get(key).is_some()
+    }
+
+    /// The value under `key`, to change in place. If the map has an id index, the change
+    /// must keep the value's id.
+    pub fn get_mut<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let bucket = bucket_of(key);
+        let value = self.buckets[bucket].get_mut(key)?;
+        self.changed |= 1 << bucket;
+        Some(value)
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let bucket = bucket_of(&key);
+        self.changed |= 1 << bucket;
+        if let Some(ids) = &self.shard.ids {
+            let id = (ids.id_of)(&value);
+            if let Some(old) = self.buckets[bucket].get(&key).map(ids.id_of).filter(|old| *old != id) {
+                ids.remove(&old);
+            }
+            ids.insert(id, &key);
+        }
+        self.buckets[bucket].insert(key, value)
+    }
+
+    pub fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        let bucket = bucket_of(key);
+        let value = self.buckets[bucket].remove(key)?;
+        self.changed |= 1 << bucket;
+        if let Some(ids) = &self.shard.ids {
+            ids.remove(&(ids.id_of)(&value));
+        }
+        Some(value)
+    }
+
+    /// Keeps only the entries for which `keep` returns true.
+    pub fn retain(&mut self, mut keep: impl FnMut(&K, &V) -> bool) {
+        for (bucket, entries) in self.buckets.iter_mut().enumerate() {
+            let before = entries.len();
+            entries.retain(|key, value| {
+                let kept = keep(key, value);
+                if let (false, Some(ids)) = (kept, &self.shard.ids) {
+                    ids.remove(&(ids.id_of)(value));
+                }
+                kept
+            });
+            if entries.len() != before {
+                self.changed |= 1 << bucket;
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(HashMap::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.iter().all(HashMap::is_empty)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.buckets.iter().flat_map(HashMap::iter)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.buckets.iter().flat_map(HashMap::values)
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Drop for ShardGuard<'_, K, V> {
+    fn drop(&mut self) {
+        for (bucket, entries) in self.buckets.iter().enumerate() {
+            if self.changed & (1 << bucket) != 0 {
+                self.shard.published[bucket].store(Arc::new(entries.clone()));
+            }
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Sharded<K, V> {
+    /// An empty map of `shards` shards (at least one), reported as `name` in the lock statistics.
+    pub fn new(name: &'static str, shards: usize) -> Self {
+        Sharded::from_map(name, shards, HashMap::new())
+    }
+
+    /// A map of `shards` shards holding the entries of `entries`.
+    pub fn from_map(name: &'static str, shards: usize, entries: HashMap<K, V>) -> Self {
+        let mut maps: Vec<Vec<HashMap<K, V>>> = (0..shards.max(1)).map(|_| (0..BUCKETS).map(|_| HashMap::new()).collect()).collect();
+        for (key, value) in entries {
+            let shard = shard_of(&key, maps.len());
+            maps[shard][bucket_of(&key)].insert(key, value);
+        }
+        Sharded { name, shards: maps.into_iter().map(|buckets| Shard::new(name, buckets)).collect(), ids: None }
+    }
+
+    /// The same map, also indexed by the id `id_of` returns for each value, for [`Sharded::get_by_id`].
+    /// Ids must be unique and must not change while the value is in the map.
+    pub fn with_id_index(mut self, id_of: fn(&V) -> Uuid) -> Self {
+        let mut buckets: Vec<HashMap<Uuid, K>> = (0..ID_BUCKETS).map(|_| HashMap::new()).collect();
+        for shard in &self.shards {
+            for entries in shard.load() {
+                for (key, value) in entries.iter() {
+                    let id = id_of(value);
+                    buckets[(id.as_u128() % ID_BUCKETS as u128) as usize].insert(id, key.clone());
+                }
+            }
+        }
+        let ids = Arc::new(IdIndex { id_of, buckets: buckets.into_iter().map(ArcSwap::from_pointee).collect() });
+        for shard in &mut self.shards {
+            shard.ids = Some(ids.clone());
+        }
+        self.ids = Some(ids);
+        self
+    }
+
+    /// Locks the shard holding `key`; entries with other keys may live in other shards.
+    pub async fn lock<Q: Hash + ?Sized>(&self, key: &Q) -> ShardGuard<'_, K, V> {
+        self.shards[shard_of(key, self.shards.len())].lock().await
+    }
+
+    /// The shards, to change every entry one shard at a time. Never hold one shard's lock
+    /// while waiting for another's.
+    pub fn shards(&self) -> impl Iterator<Item = &Shard<K, V>> {
+        self.shards.iter()
+    }
+
+    /// A copy of the value under `key`, without locking.
+    pub fn get<Q>(&self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.shards[shard_of(key, self.shards.len())].published[bucket_of(key)].load().get(key).cloned()
+    }
+
+    /// A copy of the value whose id is `id`, without locking. Always `None` for a map built
+    /// without [`Sharded::with_id_index`].
+    pub fn get_by_id(&self, id: &Uuid) -> Option<V> {
+        let key = self.ids.as_ref()?.get(id)?;
+        self.get(&key)
+    }
+
+    /// Total number of entries, without locking.
+    pub fn len(&self) -> usize {
+        self.shards.iter().flat_map(Shard::load).map(|entries| entries.len()).sum()
+    }
+
+    /// A copy of the first value matching `predicate`, without locking.
+    pub fn find(&self, predicate: impl Fn(&V) -> bool) -> Option<V> {
+        self.shards.iter().flat_map(Shard::load).find_map(|entries| entries.values().find(|value| predicate(value)).cloned())
+    }
+
+    /// A copy of every value, without locking.
+    pub fn values(&self) -> Vec<V> {
+        self.filter(|_| true)
+    }
+
+    /// A copy of every value matching `predicate`, without locking.
+    pub fn filter(&self, predicate: impl Fn(&V) -> bool) -> Vec<V> {
+        self.shards.iter().flat_map(Shard::load).flat_map(|entries| entries.values().filter(|value| predicate(value)).cloned().collect::<Vec<_>>()).collect()
+    }
+
+    /// Wait times of the shard locks, added up.
+    pub fn snapshot(&self) -> LockStatsResponse {
+        self.shards.iter().map(|shard| shard.buckets.snapshot()).reduce(LockStatsResponse::combine).expect("there is at least one shard")
+    }
+}
+
+impl<K, V> std::fmt::Debug for Sharded<K, V> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Sharded").field("name", &self.name).field("shards", &self.shards.len()).finish()
+    }
+}
+
+fn hash_of<Q: Hash + ?Sized>(key: &Q) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// The shard out of `shards` that holds `key`.
+pub(crate) fn shard_of<Q: Hash + ?Sized>(key: &Q, shards: usize) -> usize {
+    (hash_of(key) % shards as u64) as usize
+}
+
+// The bucket of its shard that holds `key`, from the bits `shard_of` does not use.
+fn bucket_of<Q: Hash + ?Sized>(key: &Q) -> usize {
+    ((hash_of(key) >> 32) % BUCKETS as u64) as usize
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn writes_are_published_when_the_guard_drops() {
+        let map: Sharded<String, u32> = Sharded::new("test", 4);
+        let mut guard = map.lock("a").await;
+        guard.insert("a".to_string(), 1);
+        assert_eq!(map.get("a"), None);
+        drop(guard);
+        assert_eq!(map.get("a"), Some(1));
+
+        *map.lock("a").await.get_mut("a").unwrap() = 2;
+        assert_eq!(map.get("a"), Some(2));
+        map.lock("a").await.remove("a");
+        assert_eq!(map.get("a"), None);
+    }
+
+    #[tokio::test]
+    async fn a_write_republishes_only_its_bucket() {
+        let entries: HashMap<String, u32> = (0..1000).map(|n| (n.to_string(), n)).collect();
+        let map = Sharded::from_map("test", 1, entries);
+        let shard = map.shards().next().unwrap();
+        let before: Vec<_> = shard.load().collect();
+        shard.lock().await.insert("new".to_string(), 0);
+        let after: Vec<_> = shard.load().collect();
+        let replaced = before.iter().zip(&after).filter(|(before, after)| !Arc::ptr_eq(before, after)).count();
+        assert_eq!(replaced, 1);
+        assert_eq!(map.len(), 1001);
+    }
+
+    #[tokio::test]
+    async fn retain_publishes_the_buckets_it_emptied() {
+        let entries: HashMap<String, u32> = (0..100).map(|n| (n.to_string(), n)).collect();
+        let map = Sharded::from_map("test", 2, entries);
+        for shard in map.shards() {
+            shard.lock().await.retain(|_, value| value % 2 == 0);
+        }
+        assert_eq!(map.len(), 50);
+        assert!(map.find(|value| value % 2 == 1).is_none());
+    }
+
+    #[tokio::test]
+    async fn the_id_index_follows_inserts_and_removals() {
+        let first = Uuid::new_v4();
+        let second = Uuid::new_v4();
+        let entries = HashMap::from([("a".to_string(), (first, 1))]);
+        let map = Sharded::from_map("test", 4, entries).with_id_index(|(id, _)| *id);
+        assert_eq!(map.get_by_id(&first), Some((first, 1)));
+
+        map.lock("b").await.insert("b".to_string(), (second, 2));
+        assert_eq!(map.get_by_id(&second), Some((second, 2)));
+        map.lock("a").await.remove("a");
+        assert_eq!(map.get_by_id(&first), None);
+        for shard in map.shards() {
+            shard.lock().await.retain(|_, _| false);
+        }
+        assert_eq!(map.get_by_id(&second), None);
+    }
+}
@@ -0,0 +1,137 @@
+// src/snapshot.rs
+
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::analytics::{Analytics, DailyActivity};
+use crate::wal;
+use crate::ws_handlers::{AppState, Role, User};
+
+/// Format version written to every snapshot; files of another version are refused.
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// What survives a restart: registered users and their contacts, and the daily activity
+/// behind the analytics.
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    version: u32,
+    users: Vec<UserRecord>,
+    // Missing from snapshots written before analytics were collected.
+    #[serde(default)]
+    analytics: BTreeMap<String, DailyActivity>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct UserRecord {
+    id: Uuid,
+    username: String,
+    password_hash: String,
+    role: Role,
+    // contact user id -> contact username
+    contacts: HashMap<Uuid, String>,
+    bot_owner: Option<Uuid>,
+    // Missing from snapshots written before LDAP accounts existed.
+    #[serde(default)]
+    ldap: bool,
+}
+
+impl UserRecord {
+    // Contacts in `guest_ids` are left out, like the guests themselves.
+    async fn from_user(user: &User, guest_ids: &HashSet<Uuid>) -> Self {
+        let contacts = user.contacts.lock().await.iter().filter(|(id, _)| !guest_ids.contains(id)).map(|(id, name)| (*id, name.clone())).collect();
+        UserRecord {
+            id: user.id,
+            username: user.username.clone(),
+            password_hash: user.password_hash.clone(),
+            role: user.role,
+            contacts,
+            bot_owner: user.bot_owner,
+            ldap: user.ldap,
+        }
+    }
+
+    fn into_user(self) -> User {
+        User {
+            id: self.id,
+            username: self.username,
+            password_hash: self.password_hash,
+            role: self.role,
+            contacts: Arc::new(Mutex::new(self.contacts)),
+            bot_owner: self.bot_owner,
+            guest_until: None,
+            ldap: self.ldap,
+        }
+    }
+}
+
+/// Reads the users (username -> user) and analytics saved at `path`. A missing file means a
+/// fresh start.
+pub fn load(path: &Path) -> io::Result<(HashMap<String, User>, Analytics)> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Default::default()),
+        Err(e) => return Err(e),
+    };
+    let snapshot: Snapshot = serde_json::from_slice(&bytes)?;
+    if snapshot.version != SNAPSHOT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported snapshot version {} (expected {})", snapshot.version, SNAPSHOT_VERSION),
+        ));
+    }
+    let users = snapshot.users.into_iter().map(|record| (record.username.clone(), record.into_user())).collect();
+    Ok((users, Analytics::from_days(snapshot.analytics)))
+}
+
+/// Writes the current users and analytics to `path`. The file is replaced atomically, so a crash while
+/// saving leaves the previous snapshot intact. Guest accounts are not saved.
+pub async fn save(app_state: &AppState, path: &Path) -> io::Result<()> {
+    let (users, guests): (Vec<User>, Vec<User>) = app_state.users.values().into_iter().partition(|user| user.guest_until.is_none());
+    let guest_ids: HashSet<Uuid> = guests.iter().map(|guest| guest.id).collect();
+    let mut records = Vec::with_capacity(users.len());
+    for user in &users {
+        records.push(UserRecord::from_user(user, &guest_ids).await);
+    }
+    let analytics = app_state.analytics.lock().await.days();
+    let bytes = serde_json::to_vec(&Snapshot { version: SNAPSHOT_VERSION, users: records, analytics })?;
+
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+    // Flushed to disk before it replaces the old snapshot, and the rename before returning: the
+    // caller truncates the write-ahead log next.
+    let mut temp_file = tokio::fs::File::create(&temp_path).await?;
+    temp_file.write_all(&bytes).await?;
+    temp_file.sync_all().await?;
+    drop(temp_file);
+    tokio::fs::rename(&temp_path, path).await?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    tokio::fs::File::open(dir).await?.sync_all().await
+}
+
+/// Saves a snapshot and empties the write-ahead log it now covers. Appends wait meanwhile,
+/// so no entry is dropped that the snapshot missed.
+async fn checkpoint(app_state: &AppState, path: &Path) -> io::Result<()> {
+    let Some(wal) = &app_state.wal else { return save(app_state, path).await };
+    let mut log = wal.lock().await;
+    save(app_state, path).await?;
+    wal::truncate(&mut log).await
+}
+
+/// Saves a snapshot. Scheduled every `storage.snapshot_interval_secs`; the first run, at
+/// startup, folds the replayed log into a fresh snapshot.
+pub async fn run(app_state: Arc<AppState>, path: PathBuf) {
+    match checkpoint(&app_state, &path).await {
+        Ok(()) => tracing::debug!(path = %path.display(), "State snapshot saved"),
+        Err(e) => tracing::error!(path = %path.display(), error = %e, "Cannot save state snapshot"),
+    }
+}
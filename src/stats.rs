@@ -0,0 +1,232 @@
+// src/stats.rs
+
+use serde::Serialize;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use utoipa::ToSchema;
+
+/// Number of one-second buckets kept by a `RateCounter` (one minute of history).
+const WINDOW_SECS: usize = 60;
+
+/// Counts events over a sliding one-minute window using per-second buckets,
+/// so memory stays constant no matter how many events are recorded.
+#[derive(Debug)]
+pub struct RateCounter {
+    // Each bucket holds (unix second it belongs to, events recorded in that second).
+    buckets: Mutex<[(u64, u64); WINDOW_SECS]>,
+}
+
+impl Default for RateCounter {
+    fn default() -> Self {
+        RateCounter { buckets: Mutex::new([(0, 0); WINDOW_SECS]) }
+    }
+}
+
+impl RateCounter {
+    /// Records a single event at the current time.
+    pub fn record(&self) {
+        let now = unix_secs();
+        let mut buckets = self.buckets.lock().unwrap();
+        let bucket = &mut buckets[(now % WINDOW_SECS as u64) as usize];
+        if bucket.0 != now {
+            // The bucket still holds an older second; start it over.
+            *bucket = (now, 0);
+        }
+        bucket.1 += 1;
+    }
+
+    /// Returns how many events were recorded during the last minute.
+    pub fn per_minute(&self) -> u64 {
+        let now = unix_secs();
+        let buckets = self.buckets.lock().unwrap();
+        buckets
+            .iter()
+            .filter(|(second, _)| now.saturating_sub(*second) < WINDOW_SECS as u64)
+            .map(|(_, count)| count)
+            .sum()
+    }
+}
+
+/// Counters maintained alongside `AppState` for the admin statistics endpoint.
+#[derive(Debug, Default)]
+pub struct ServerStats {
+    // Total chat messages routed since startup.
+    pub messages_routed_total: AtomicU64,
+    // Chat messages routed during the last minute.
+    pub messages_routed: RateCounter,
+    // Connections closed since startup for not reading their frames fast enough.
+    pub slow_consumers_disconnected: AtomicU64,
+    // Sessions removed since startup for being expired or idle.
+    pub sessions_expired: AtomicU64,
+}
+
+impl ServerStats {
+    /// Records that one chat message was routed through the fanout.
+    pub fn record_message_routed(&self) {
+        self.messages_routed_total.fetch_add(1, Ordering::Relaxed);
+        self.messages_routed.record();
+    }
+}
+
+/// How long something waited for a lock or in a queue, accumulated since startup.
+#[derive(Debug, Default)]
+pub struct WaitStats {
+    count: AtomicU64,
+    // Waits that could not proceed immediately.
+    delayed: AtomicU64,
+    total_micros: AtomicU64,
+    max_micros: AtomicU64,
+}
+
+/// A point-in-time copy of a `WaitStats`, as reported by the admin statistics endpoint.
+#[derive(Serialize, ToSchema, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WaitStatsResponse {
+    // Lock acquisitions, or items taken off the queue.
+    count: u64,
+    // How many of them had to wait (lock contended, or queue not empty).
+    delayed: u64,
+    total_wait_micros: u64,
+    max_wait_micros: u64,
+}
+
+impl WaitStats {
+    /// Records one wait; `delayed` tells whether it could not proceed immediately.
+    pub fn record(&self, waited: Duration, delayed: bool) {
+        let micros = u64::try_from(waited.as_micros()).unwrap_or(u64::MAX);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        if delayed {
+            self.delayed.fetch_add(1, Ordering::Relaxed);
+        }
+        self.total_micros.fetch_add(micros, Ordering::Relaxed);
+        self.max_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> WaitStatsResponse {
+        WaitStatsResponse {
+            count: self.count.load(Ordering::Relaxed),
+            delayed: self.delayed.load(Ordering::Relaxed),
+            total_wait_micros: self.total_micros.load(Ordering::Relaxed),
+            max_wait_micros: self.max_micros.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl WaitStatsResponse {
+    /// Adds up the waits of `self` and `other`.
+    pub fn combine(self, other: WaitStatsResponse) -> WaitStatsResponse {
+        WaitStatsResponse {
+            count: self.count + other.count,
+            delayed: self.delayed + other.delayed,
+            total_wait_micros: self.total_wait_micros.saturating_add(other.total_wait_micros),
+            max_wait_micros: self.max_wait_micros.max(other.max_wait_micros),
+        }
+    }
+}
+
+/// Depth of a queue and how long items wait in it before they are handled.
+#[derive(Debug, Default)]
+pub struct QueueStats {
+    depth: AtomicUsize,
+    pub wait: WaitStats,
+}
+
+/// A point-in-time copy of a `QueueStats`.
+#[derive(Serialize, ToSchema, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct QueueStatsResponse {
+    // Items queued but not handled yet.
+    depth: usize,
+    wait: WaitStatsResponse,
+}
+
+impl QueueStatsResponse {
+    /// Adds up two queues, e.g. the shards of a sharded queue.
+    pub fn combine(self, other: QueueStatsResponse) -> QueueStatsResponse {
+        QueueStatsResponse { depth: self.depth + other.depth, wait: self.wait.combine(other.wait) }
+    }
+}
+
+impl QueueStats {
+    /// Counts an item entering the queue.
+    pub fn enqueued(&self) {
+        self.depth.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts an item leaving the queue after `waited` in it.
+    pub fn dequeued(&self, waited: Duration) {
+        // Counted as delayed when other items were still queued with it.
+        let depth_before = self.depth.fetch_sub(1, Ordering::Relaxed);
+        self.wait.record(waited, depth_before > 1);
+    }
+
+    /// Counts an item that never entered the queue after all (e.g. its receiver is gone).
+    pub fn discarded(&self) {
+        self.depth.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    pub fn snapshot(&self) -> QueueStatsResponse {
+        QueueStatsResponse { depth: self.depth(), wait: self.wait.snapshot() }
+    }
+}
+
+/// A tokio mutex that records how long `lock` waits for it, so the admin statistics show
+/// which parts of the shared state are contended.
+pub struct TimedMutex<T> {
+    name: &'static str,
+    inner: tokio::sync::Mutex<T>,
+    stats: WaitStats,
+}
+
+impl<T> TimedMutex<T> {
+    pub fn new(name: &'static str, value: T) -> Self {
+        TimedMutex { name, inner: tokio::sync::Mutex::new(value), stats: WaitStats::default() }
+    }
+
+    /// Locks the mutex, waiting for it if it is held.
+    pub async fn lock(&self) -> tokio::sync::MutexGuard<'_, T> {
+        if let Ok(guard) = self.inner.try_lock() {
+            self.stats.record(Duration::ZERO, false);
+            return guard;
+        }
+        let started = std::time::Instant::now();
+        let guard = self.inner.lock().await;
+        self.stats.record(started.elapsed(), true);
+        guard
+    }
+
+    pub fn snapshot(&self) -> LockStatsResponse {
+        LockStatsResponse { name: self.name, wait: self.stats.snapshot() }
+    }
+}
+
+/// Wait times of one of the locks on the shared state.
+#[derive(Serialize, ToSchema, Debug, Clone, PartialEq, Eq)]
+pub struct LockStatsResponse {
+    name: &'static str,
+    wait: WaitStatsResponse,
+}
+
+impl LockStatsResponse {
+    /// Adds up two locks of the same name, e.g. the shards of a sharded map.
+    pub fn combine(self, other: LockStatsResponse) -> LockStatsResponse {
+        LockStatsResponse { name: self.name, wait: self.wait.combine(other.wait) }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for TimedMutex<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TimedMutex").field("name", &self.name).field("inner", &self.inner).finish()
+    }
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
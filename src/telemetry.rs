@@ -0,0 +1,133 @@
+// src/telemetry.rs
+
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+use crate::config::{LogConfig, LogFormat};
+use crate::redact;
+
+/// Quiets warp's own per-request events: the access log already records each request, with
+/// its route instead of the raw path, and warp's debug and trace events quote paths and headers.
+const WARP_REQUEST_EVENTS: &str = "warp::filters=info,warp::filters::trace=off";
+
+/// Keeps the tracing pipeline alive; dropping it flushes any spans still buffered
+/// for export.
+pub struct TelemetryGuard {
+    #[cfg(feature = "otel")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Drop for TelemetryGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otel")]
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                tracing::warn!(error = %e, "Failed to flush OpenTelemetry spans");
+            }
+        }
+    }
+}
+
+/// Lets the active log filter be swapped at runtime without restarting the server.
+#[derive(Debug, Clone)]
+pub struct LogLevelHandle {
+    handle: reload::Handle<EnvFilter, Registry>,
+}
+
+impl LogLevelHandle {
+    /// Replaces the active filter with `level`. A `RUST_LOG` set at startup keeps precedence.
+    pub fn set_level(&self, level: &str) -> Result<(), String> {
+        if std::env::var("RUST_LOG").is_ok() {
+            return Ok(());
+        }
+        let filter = env_filter(level).map_err(|e| e.to_string())?;
+        self.handle.reload(filter).map_err(|e| e.to_string())
+    }
+}
+
+/// Installs the global tracing subscriber, unless the embedding application already did.
+///
+/// Events and spans are filtered with `RUST_LOG`, falling back to the configured `log.level`.
+/// `log.format = "json"` switches the output to one JSON object per line for log aggregation.
+/// Values wrapped with `redact::redacted` are logged as "[redacted]" unless `log.reveal_sensitive`,
+/// and those wrapped with `redact::pseudonym` as keyed hashes with `log.pseudonymize`.
+/// When built with the `otel` feature and `OTEL_EXPORTER_OTLP_ENDPOINT` is set, spans are also
+/// exported via OTLP/HTTP.
+pub fn init(log: &LogConfig) -> (TelemetryGuard, LogLevelHandle) {
+    redact::reveal_sensitive(log.reveal_sensitive);
+    redact::pseudonymize(log.pseudonym_key.as_deref().filter(|_| log.pseudonymize));
+    // The configured level was validated at startup, so it always parses here.
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| env_filter(&log.level).expect("log.level is validated"));
+    let (filter, handle) = reload::Layer::new(filter);
+    let log_level = LogLevelHandle { handle };
+    let json = log.format == LogFormat::Json;
+    let registry = tracing_subscriber::registry()
+        .with(filter)
+        .with(json.then(|| tracing_subscriber::fmt::layer().json()))
+        .with((!json).then(tracing_subscriber::fmt::layer));
+
+    #[cfg(feature = "otel")]
+    {
+        let provider = otel::provider();
+        let layer = provider.as_ref().ok().and_then(Option::as_ref).map(otel::layer);
+        let _ = registry.with(layer).try_init();
+        // Told once the subscriber is installed, so the notice goes through it.
+        let provider = match provider {
+            Ok(Some(provider)) => Some(provider),
+            Ok(None) => {
+                tracing::info!("OTEL_EXPORTER_OTLP_ENDPOINT not set; OpenTelemetry export disabled");
+                None
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "Failed to build the OTLP span exporter; OpenTelemetry export disabled");
+                None
+            }
+        };
+        (TelemetryGuard { provider }, log_level)
+    }
+
+    #[cfg(not(feature = "otel"))]
+    {
+        let _ = registry.try_init();
+        (TelemetryGuard {}, log_level)
+    }
+}
+
+// The filter of the configured `level`, with warp's request events off.
+pub(crate) fn env_filter(level: &str) -> Result<EnvFilter, tracing_subscriber::filter::ParseError> {
+    EnvFilter::try_new(format!("{},{}", level, WARP_REQUEST_EVENTS))
+}
+
+#[cfg(feature = "otel")]
+mod otel {
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use opentelemetry_sdk::Resource;
+    use tracing_subscriber::registry::LookupSpan;
+
+    /// Builds the OTLP tracer provider, or `None` when no collector endpoint is configured.
+    /// Called before the subscriber is installed, so errors are returned for `init` to log.
+    pub fn provider() -> Result<Option<SdkTracerProvider>, opentelemetry_otlp::ExporterBuildError> {
+        if std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").is_err() {
+            return Ok(None);
+        }
+
+        // The exporter reads the endpoint and headers from the standard OTEL_* variables.
+        let exporter = opentelemetry_otlp::SpanExporter::builder().with_http().build()?;
+
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(Resource::builder().with_service_name("rust_chat").build())
+            .build();
+        opentelemetry::global::set_tracer_provider(provider.clone());
+        Ok(Some(provider))
+    }
+
+    pub fn layer<S>(provider: &SdkTracerProvider) -> impl tracing_subscriber::Layer<S>
+    where
+        S: tracing::Subscriber + for<'span> LookupSpan<'span>,
+    {
+        tracing_opentelemetry::layer().with_tracer(provider.tracer("rust_chat"))
+    }
+}
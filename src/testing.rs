@@ -0,0 +1,420 @@
+// src/testing.rs
+
+//! In-process test harness: a server driven through `warp::test` (HTTP requests in memory,
+//! WebSockets over an ephemeral loopback port), helpers to register and log in users, a
+//! WebSocket test client, and socket-less simulated connections for benchmarks.
+//!
+//! Test crates using it need `#![recursion_limit = "256"]`, like this crate, for the futures
+//! of the route tree.
+//!
+//! ```ignore
+//! let server = TestServer::new().await;
+//! let alice = server.register("alice", "secret").await;
+//! let mut ws = server.connect(&alice).await;
+//! ws.send_json(&serde_json::json!({ "type": "typingIndicator", "to_user_id": alice.user_id, "is_typing": true })).await;
+//! ```
+
+use serde_json::Value;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::subscriber::DefaultGuard;
+use uuid::Uuid;
+use warp::http::StatusCode;
+use warp::test::WsClient;
+
+use crate::config::{AuthConfig, Config, LogConfig};
+use crate::server::{ChatServer, ChatServerBuilder};
+use crate::telemetry;
+use crate::connections::{self, ConnectionReceiver};
+use crate::device::Device;
+use crate::ldap::Directory;
+use crate::ws_handlers::{self, AppState, LastActive, Role, User, UserSession};
+use crate::webhooks;
+
+/// How long `WsTestClient::recv_json` waits for a frame before failing the test.
+const RECV_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A fully built and started server whose routes are driven by `warp::test`.
+pub struct TestServer {
+    server: ChatServer,
+}
+
+/// A registered user and the session key of its latest login.
+#[derive(Debug, Clone)]
+pub struct TestUser {
+    pub user_id: Uuid,
+    pub username: String,
+    pub session_key: String,
+}
+
+/// The default configuration, except for a minimal bcrypt cost so registrations stay fast,
+/// and quiet logging. A base for tests that need other settings:
+/// `TestServer::with_config(Config { limits, ..test_config() })`.
+pub fn test_config() -> Config {
+    Config {
+        log: LogConfig { level: "warn".to_string(), ..LogConfig::default() },
+        auth: AuthConfig { bcrypt_cost: 4, ..AuthConfig::default() },
+        ..Config::default()
+    }
+}
+
+impl TestServer {
+    /// A server with `test_config()`.
+    pub async fn new() -> Self {
+        TestServer::with_config(test_config()).await
+    }
+
+    /// A started server with `config`; panics if the configuration is rejected.
+    pub async fn with_config(config: Config) -> Self {
+        TestServer::from_builder(ChatServerBuilder::from_config(config)).await
+    }
+
+    /// A started server with `config` checking the passwords of directory accounts against
+    /// `directory` instead of an LDAP server.
+    pub async fn with_directory(config: Config, directory: Arc<dyn Directory>) -> Self {
+        TestServer::from_builder(ChatServerBuilder::from_config(config).directory(directory)).await
+    }
+
+    async fn from_builder(builder: ChatServerBuilder) -> Self {
+        let mut server = builder.build().await.expect("test server config is valid");
+        server.start();
+        TestServer { server }
+    }
+
+    /// Stops the scheduled jobs, as the server does on Ctrl-C.
+    pub async fn shutdown(&self) {
+        self.server.shutdown().await;
+    }
+
+    /// The shared state, for assertions on connections, users and the like.
+    pub fn state(&self) -> Arc<AppState> {
+        self.server.state()
+    }
+
+    /// Sends a request to `path` (e.g. `/api/v1/contacts`), authenticated with `session_key`
+    /// if given, and returns the status with the JSON body (`Value::Null` when empty).
+    pub async fn request(&self, method: &str, path: &str, session_key: Option<&str>, body: Option<&Value>) -> (StatusCode, Value) {
+        let mut request = warp::test::request().method(method).path(path);
+        if let Some(session_key) = session_key {
+            request = request.header("x-session-key", session_key);
+        }
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+        let response = request.reply(&self.server.filter()).await;
+        let body = serde_json::from_slice(response.body()).unwrap_or(Value::Null);
+        (response.status(), body)
+    }
+
+    /// Sends `body` to `path` (e.g. `/cluster/heartbeat`) as another node of the cluster
+    /// would, signed with `secret`, and returns the response status.
+    pub async fn receive_from_node(&self, path: &str, secret: &str, body: &Value) -> StatusCode {
+        let body = body.to_string();
+        let timestamp = chrono::Utc::now().timestamp().to_string();
+        let response = warp::test::request()
+            .method("POST")
+            .path(path)
+            .header("x-chat-timestamp", &timestamp)
+            .header("x-chat-signature", format!("sha256={}", webhooks::sign(secret, &timestamp, &body)))
+            .body(body)
+            .reply(&self.server.filter())
+            .await;
+        response.status()
+    }
+
+    /// Registers `username`, which also logs it in; panics unless registration succeeds.
+    pub async fn register(&self, username: &str, password: &str) -> TestUser {
+        let payload = serde_json::json!({ "username": username, "password": password });
+        let (status, body) = self.request("POST", "/api/v1/register", None, Some(&payload)).await;
+        assert_eq!(status, StatusCode::OK, "registering {}: {}", username, body);
+        TestUser::from_auth_response(&body)
+    }
+
+    /// Creates `username` as a previous run would have left it, applies `auth.admin_usernames`
+    /// as a restart does, and logs it in; panics unless the configuration lists `username`.
+    pub async fn register_admin(&self, username: &str, password: &str) -> TestUser {
+        let state = self.state();
+        let user = User {
+            id: Uuid::new_v4(),
+            username: username.to_string(),
+            password_hash: bcrypt::hash(password, state.config.auth.bcrypt_cost).expect("bcrypt hashes"),
+            role: Role::User,
+            contacts: Default::default(),
+            bot_owner: None,
+            guest_until: None,
+            ldap: false,
+        };
+        state.users.lock(username).await.insert(username.to_string(), user);
+        ws_handlers::promote_admins(&state, &state.config.auth.admin_usernames).await;
+        assert_eq!(state.users.get(username).map(|user| user.role), Some(Role::Admin), "auth.admin_usernames does not list {}", username);
+        self.login(username, password).await
+    }
+
+    /// Opens a guest account; panics unless guests are allowed.
+    pub async fn join_as_guest(&self) -> TestUser {
+        let (status, body) = self.request("POST", "/api/v1/guests", None, None).await;
+        assert_eq!(status, StatusCode::OK, "joining as a guest: {}", body);
+        TestUser::from_auth_response(&body)
+    }
+
+    /// Logs `username` in, revoking its previous session; panics unless login succeeds.
+    pub async fn login(&self, username: &str, password: &str) -> TestUser {
+        let payload = serde_json::json!({ "username": username, "password": password });
+        let (status, body) = self.request("POST", "/api/v1/login", None, Some(&payload)).await;
+        assert_eq!(status, StatusCode::OK, "logging in {}: {}", username, body);
+        TestUser::from_auth_response(&body)
+    }
+
+    /// Opens another session of `user` next to its latest one, as the XMPP and gRPC gateways
+    /// do for their streams, and returns it.
+    pub async fn open_another_session(&self, user: &TestUser) -> TestUser {
+        let state = self.state();
+        let mut session = state.user_sessions.get(&user.session_key).expect("the user's latest session is open");
+        session.session_key = Uuid::new_v4().to_string();
+        state.user_sessions.lock(&session.session_key).await.insert(session.session_key.clone(), session.clone());
+        TestUser { session_key: session.session_key, ..user.clone() }
+    }
+
+    /// Sends a request like `request`, from a client at `ip` sending `headers` (e.g. its
+    /// `user-agent`).
+    pub async fn request_from(&self, method: &str, path: &str, ip: IpAddr, headers: &[(&str, &str)], body: Option<&Value>) -> (StatusCode, Value) {
+        let mut request = warp::test::request().method(method).path(path).remote_addr(SocketAddr::new(ip, 40000));
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        if let Some(body) = body {
+            request = request.json(body);
+        }
+        let response = request.reply(&self.server.filter()).await;
+        let body = serde_json::from_slice(response.body()).unwrap_or(Value::Null);
+        (response.status(), body)
+    }
+
+    /// Logs `username` in like `login`, from a client at `ip`.
+    pub async fn login_from(&self, username: &str, password: &str, ip: IpAddr) -> TestUser {
+        let payload = serde_json::json!({ "username": username, "password": password });
+        let (status, body) = self.request_from("POST", "/api/v1/login", ip, &[], Some(&payload)).await;
+        assert_eq!(status, StatusCode::OK, "logging in {} from {}: {}", username, ip, body);
+        TestUser::from_auth_response(&body)
+    }
+
+    /// Makes `user` and `contact` contacts of each other; panics unless it succeeds.
+    pub async fn add_contact(&self, user: &TestUser, contact: &TestUser) {
+        let payload = serde_json::json!({ "contact_username": contact.username });
+        let (status, body) = self.request("POST", "/api/v1/contacts", Some(&user.session_key), Some(&payload)).await;
+        assert_eq!(status, StatusCode::OK, "adding contact {}: {}", contact.username, body);
+    }
+
+    /// Opens a WebSocket for `user`'s session and waits until the server registered it, so
+    /// messages routed afterwards reach it. Frames queued on connect (unread counts, drafts,
+    /// presence of others) are left for the caller to read or skip.
+    pub async fn connect(&self, user: &TestUser) -> WsTestClient {
+        let client = warp::test::ws()
+            .path(&format!("/ws?token={}", user.session_key))
+            .handshake(self.server.filter())
+            .await
+            .expect("WebSocket handshake succeeds");
+        let state = self.state();
+        let connected = async {
+            while !state.connections.contains(&user.session_key).await {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(RECV_TIMEOUT, connected).await.expect("connection is registered after the handshake");
+        WsTestClient { client }
+    }
+
+    /// Opens a WebSocket without a session key in its URL; the server expects an `auth` frame
+    /// before anything else.
+    pub async fn connect_unauthenticated(&self) -> WsTestClient {
+        let client = warp::test::ws().path("/ws").handshake(self.server.filter()).await.expect("WebSocket handshake succeeds");
+        WsTestClient { client }
+    }
+
+    /// Whether a WebSocket upgrade a browser page of `origin` sends for `user` is accepted.
+    pub async fn upgrade_from_origin(&self, user: &TestUser, origin: &str) -> bool {
+        warp::test::ws()
+            .path(&format!("/ws?token={}", user.session_key))
+            .header("origin", origin)
+            .handshake(self.server.filter())
+            .await
+            .is_ok()
+    }
+
+    /// Waits until `session_key` has no open connection left, i.e. the server finished the
+    /// disconnect cleanup of its WebSocket; panics after `RECV_TIMEOUT`.
+    pub async fn wait_disconnected(&self, session_key: &str) {
+        let state = self.state();
+        let disconnected = async {
+            while state.connections.contains(session_key).await {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(RECV_TIMEOUT, disconnected).await.expect("connection is cleaned up after disconnect");
+    }
+
+    /// Registers `count` connections of fresh users that have a session but no socket behind
+    /// them: frames routed to them stay queued until drained. Far cheaper than `connect`, for
+    /// benchmarks and load simulations.
+    pub async fn simulate_connections(&self, count: usize) -> Vec<SimulatedConnection> {
+        let state = self.state();
+        let mut simulated = Vec::with_capacity(count);
+        for i in 0..count {
+            let session = UserSession {
+                user_id: Uuid::new_v4(),
+                username: format!("simulated-{}", i),
+                session_key: Uuid::new_v4().to_string(),
+                created_at: Instant::now(),
+                client_ip: None,
+                country: None,
+                device: Device::default(),
+                trusted_device: Arc::default(),
+                signing_key: None,
+                last_active: LastActive::now(),
+            };
+            let (handle, receiver) = connections::connection_channel(&session, None);
+            state.user_sessions.lock(&session.session_key).await.insert(session.session_key.clone(), session.clone());
+            state.connections.register(session.session_key.clone(), handle);
+            simulated.push(SimulatedConnection { session, receiver });
+        }
+        simulated
+    }
+
+    /// Routes a chat message from `from` to `to_user_id` the way a WebSocket `chatMessage`
+    /// is, returning its id; panics if the message is rejected.
+    pub async fn send_message(&self, from: &SimulatedConnection, to_user_id: Uuid, message: &str) -> String {
+        ws_handlers::route_chat_message(&self.state(), &from.session, to_user_id, message.to_string())
+            .await
+            .expect("message is routed")
+    }
+
+    /// Announces `connection`'s user as `status` ("online", "offline") to every other
+    /// connection, and waits until the fanout workers queued it on all of them.
+    pub async fn broadcast_status(&self, connection: &SimulatedConnection, status: &str) {
+        let state = self.state();
+        ws_handlers::broadcast_status(&state, &connection.session, status).await;
+        state.fanout.flush().await;
+    }
+}
+
+/// A connection registered by `TestServer::simulate_connections`.
+pub struct SimulatedConnection {
+    pub(crate) session: UserSession,
+    receiver: ConnectionReceiver,
+}
+
+impl SimulatedConnection {
+    pub fn user_id(&self) -> Uuid {
+        self.session.user_id
+    }
+
+    /// Discards every queued frame and returns how many there were.
+    pub fn drain(&mut self) -> usize {
+        std::iter::from_fn(|| self.receiver.discard().then_some(())).count()
+    }
+
+    /// Takes every queued frame, parsed as JSON.
+    pub fn received(&mut self) -> Vec<Value> {
+        std::iter::from_fn(|| self.receiver.try_recv())
+            .map(|message| serde_json::from_str(message.to_str().expect("server frames are text")).expect("server frames are JSON"))
+            .collect()
+    }
+}
+
+impl TestUser {
+    fn from_auth_response(body: &Value) -> Self {
+        TestUser {
+            user_id: body["user_id"].as_str().and_then(|id| id.parse().ok()).expect("auth response has a user_id"),
+            username: body["username"].as_str().expect("auth response has a username").to_string(),
+            session_key: body["session_key"].as_str().expect("auth response has a session_key").to_string(),
+        }
+    }
+}
+
+/// WebSocket client connected to a `TestServer`, exchanging JSON frames. Dropping it closes
+/// the connection.
+pub struct WsTestClient {
+    client: WsClient,
+}
+
+impl WsTestClient {
+    /// Sends `message` as a text frame.
+    pub async fn send_json(&mut self, message: &Value) {
+        self.client.send_text(message.to_string()).await;
+    }
+
+    /// The next frame, parsed as JSON; panics if none arrives within `RECV_TIMEOUT`.
+    pub async fn recv_json(&mut self) -> Value {
+        let message = tokio::time::timeout(RECV_TIMEOUT, self.client.recv())
+            .await
+            .expect("a frame arrives in time")
+            .expect("the connection is open");
+        let text = message.to_str().expect("server frames are text");
+        serde_json::from_str(text).expect("server frames are JSON")
+    }
+
+    /// Skips frames until one of `message_type` (e.g. `"chatMessage"`) arrives and returns it.
+    pub async fn recv_type(&mut self, message_type: &str) -> Value {
+        loop {
+            let message = self.recv_json().await;
+            if message["type"] == message_type {
+                return message;
+            }
+        }
+    }
+
+    /// Skips frames until the server ends the connection. The test client consumes close
+    /// frames itself, so their code and reason are not visible here.
+    pub async fn recv_closed(&mut self) {
+        tokio::time::timeout(RECV_TIMEOUT, async { while self.client.recv().await.is_ok() {} })
+            .await
+            .expect("the connection closes in time");
+    }
+
+    /// Closes the connection.
+    pub fn close(self) {
+        drop(self);
+    }
+}
+
+/// The log lines written on the current thread while it is alive, formatted as the server
+/// writes them and filtered as it would be at `level` (e.g. `"debug"`). Under `#[tokio::test]`
+/// the runtime is single-threaded, so this includes the server's own tasks.
+pub struct CapturedLogs {
+    buffer: LogBuffer,
+    _guard: DefaultGuard,
+}
+
+#[derive(Clone, Default)]
+struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for LogBuffer {
+    fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().expect("log buffer lock poisoned").extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl CapturedLogs {
+    pub fn start(level: &str) -> Self {
+        let buffer = LogBuffer::default();
+        let writer = buffer.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_env_filter(telemetry::env_filter(level).expect("a valid log level"))
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        CapturedLogs { buffer, _guard: tracing::subscriber::set_default(subscriber) }
+    }
+
+    /// Everything logged so far.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.buffer.0.lock().expect("log buffer lock poisoned")).into_owned()
+    }
+}
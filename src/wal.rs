@@ -0,0 +1,169 @@
+// src/wal.rs
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{Mutex, MutexGuard};
+use uuid::Uuid;
+
+use crate::ws_handlers::{AppState, Role, User};
+
+/// A change to the persisted state, as recorded in the log. Replaying an entry that the
+/// snapshot already contains leaves the state unchanged.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Mutation {
+    // A user, bot or Matrix ghost account was created.
+    UserCreated {
+        id: Uuid,
+        username: String,
+        password_hash: String,
+        role: Role,
+        bot_owner: Option<Uuid>,
+        // Missing from entries logged before LDAP accounts existed.
+        #[serde(default)]
+        ldap: bool,
+    },
+    // Two users became mutual contacts.
+    ContactAdded { user_id: Uuid, contact_id: Uuid },
+    RoleChanged { user_id: Uuid, role: Role },
+    PasswordChanged { user_id: Uuid, password_hash: String },
+}
+
+impl Mutation {
+    pub fn user_created(user: &User) -> Self {
+        Mutation::UserCreated {
+            id: user.id,
+            username: user.username.clone(),
+            password_hash: user.password_hash.clone(),
+            role: user.role,
+            bot_owner: user.bot_owner,
+            ldap: user.ldap,
+        }
+    }
+
+    async fn apply(self, users: &mut HashMap<String, User>) {
+        match self {
+            Mutation::UserCreated { id, username, password_hash, role, bot_owner, ldap } => {
+                users.entry(username.clone()).or_insert_with(|| User {
+                    id,
+                    username,
+                    password_hash,
+                    role,
+                    contacts: Arc::new(Mutex::new(HashMap::new())),
+                    bot_owner,
+                    guest_until: None,
+                    ldap,
+                });
+            }
+            Mutation::ContactAdded { user_id, contact_id } => {
+                let user = users.values().find(|user| user.id == user_id);
+                let contact = users.values().find(|user| user.id == contact_id);
+                if let (Some(user), Some(contact)) = (user, contact) {
+                    user.contacts.lock().await.insert(contact.id, contact.username.clone());
+                    contact.contacts.lock().await.insert(user.id, user.username.clone());
+                }
+            }
+            Mutation::RoleChanged { user_id, role } => {
+                if let Some(user) = users.values_mut().find(|user| user.id == user_id) {
+                    user.role = role;
+                }
+            }
+            Mutation::PasswordChanged { user_id, password_hash } => {
+                if let Some(user) = users.values_mut().find(|user| user.id == user_id) {
+                    user.password_hash = password_hash;
+                }
+            }
+        }
+    }
+}
+
+/// Append-only log of mutations made since the last snapshot, so a crash between snapshots
+/// loses nothing. Every entry is a JSON line, synced to disk before `append` returns.
+#[derive(Debug)]
+pub struct WriteAheadLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl WriteAheadLog {
+    /// Opens (or creates) the log that goes with the snapshot at `snapshot_path`. A torn last
+    /// line is terminated so new entries start on a line of their own.
+    pub async fn open(snapshot_path: &Path) -> io::Result<Self> {
+        let path = log_path(snapshot_path);
+        let torn = match tokio::fs::read(&path).await {
+            Ok(contents) => contents.last().is_some_and(|&last| last != b'\n'),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e),
+        };
+        let mut file = OpenOptions::new().create(true).append(true).open(&path).await?;
+        if torn {
+            file.write_all(b"\n").await?;
+        }
+        Ok(WriteAheadLog { path, file: Mutex::new(file) })
+    }
+
+    pub async fn append(&self, mutation: &Mutation) -> io::Result<()> {
+        let mut line = serde_json::to_vec(mutation)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().await;
+        file.write_all(&line).await?;
+        file.sync_data().await
+    }
+
+    /// Holds off appends until the guard is dropped, e.g. while a snapshot is written.
+    pub async fn lock(&self) -> MutexGuard<'_, File> {
+        self.file.lock().await
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+/// Empties the log once a snapshot containing all of its entries has been saved.
+pub async fn truncate(file: &mut File) -> io::Result<()> {
+    file.set_len(0).await?;
+    file.sync_data().await
+}
+
+fn log_path(snapshot_path: &Path) -> PathBuf {
+    let mut path = snapshot_path.as_os_str().to_owned();
+    path.push(".wal");
+    PathBuf::from(path)
+}
+
+/// Applies the log kept next to the snapshot at `snapshot_path` to `users`, returning the
+/// number of entries replayed. Torn lines, left by a crash mid-write, are skipped.
+pub async fn replay(snapshot_path: &Path, users: &mut HashMap<String, User>) -> io::Result<usize> {
+    let path = log_path(snapshot_path);
+    let contents = match tokio::fs::read_to_string(&path).await {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let mut replayed = 0;
+    for line in contents.lines().filter(|line| !line.is_empty()) {
+        match serde_json::from_str::<Mutation>(line) {
+            Ok(mutation) => {
+                mutation.apply(users).await;
+                replayed += 1;
+            }
+            Err(e) => tracing::warn!(path = %path.display(), error = %e, "Skipping incomplete write-ahead log entry"),
+        }
+    }
+    Ok(replayed)
+}
+
+/// Records `mutation` in the write-ahead log, if persistence is enabled. Callers must not
+/// hold the `users` lock or a contacts lock, which snapshots take while holding the log.
+pub async fn record(app_state: &AppState, mutation: Mutation) {
+    let Some(wal) = &app_state.wal else { return };
+    if let Err(e) = wal.append(&mutation).await {
+        tracing::error!(path = %wal.path().display(), error = %e, "Cannot write to the write-ahead log");
+    }
+}
@@ -0,0 +1,380 @@
+// src/webhooks.rs
+
+use chrono::Utc;
+use hmac::{Hmac, Mac};
+use ipnet::IpNet;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use reqwest::redirect::Policy;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+use utoipa::ToSchema;
+use uuid::Uuid;
+
+use crate::config::WebhookConfig;
+use crate::events::DomainEvent;
+use crate::push;
+use crate::ws_handlers::{AppState, Role};
+
+/// Longest pause between two delivery attempts.
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Kinds of events a webhook can subscribe to.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEventKind {
+    MessageReceived,
+    ContactAdded,
+    UserOnline,
+    RegistrationPending,
+}
+
+/// An event delivered to webhooks, serialized as `{ "event": ..., "data": { ... } }`.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "event", content = "data", rename_all = "snake_case")]
+pub enum WebhookEvent {
+    MessageReceived {
+        message_id: String,
+        from_user_id: Uuid,
+        from_username: String,
+        to_user_id: Uuid,
+        message: String,
+    },
+    ContactAdded {
+        user_id: Uuid,
+        contact_user_id: Uuid,
+        contact_username: String,
+    },
+    UserOnline {
+        user_id: Uuid,
+        username: String,
+    },
+    // A registration is waiting for approval; sent to global webhooks and moderators' own.
+    RegistrationPending {
+        registration_id: Uuid,
+        username: String,
+    },
+}
+
+impl WebhookEvent {
+    pub fn kind(&self) -> WebhookEventKind {
+        match self {
+            WebhookEvent::MessageReceived { .. } => WebhookEventKind::MessageReceived,
+            WebhookEvent::ContactAdded { .. } => WebhookEventKind::ContactAdded,
+            WebhookEvent::UserOnline { .. } => WebhookEventKind::UserOnline,
+            WebhookEvent::RegistrationPending { .. } => WebhookEventKind::RegistrationPending,
+        }
+    }
+}
+
+/// Who a webhook belongs to: global webhooks (registered by admins) receive every event,
+/// user webhooks only the events addressed to that user.
+#[derive(Serialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookOwner {
+    Global,
+    User(Uuid),
+}
+
+/// A registered webhook endpoint.
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    pub id: Uuid,
+    pub owner: WebhookOwner,
+    pub url: String,
+    // Shared secret used to sign every delivery (HMAC-SHA256).
+    pub secret: String,
+    pub events: Vec<WebhookEventKind>,
+}
+
+/// The JSON body POSTed to a webhook.
+#[derive(Serialize)]
+struct Delivery<'a> {
+    id: Uuid,
+    timestamp: String,
+    #[serde(flatten)]
+    event: &'a WebhookEvent,
+}
+
+/// Sends webhook deliveries in the background, retrying failures with exponential backoff.
+#[derive(Debug)]
+pub struct WebhookDispatcher {
+    client: reqwest::Client,
+    max_attempts: u32,
+}
+
+impl WebhookDispatcher {
+    /// Deliveries follow no redirects, and host names are resolved again on every attempt
+    /// and refused if they now point to an address `check_destination` would refuse.
+    pub fn new(config: &WebhookConfig) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_secs))
+            .user_agent(concat!("rust_chat-webhooks/", env!("CARGO_PKG_VERSION")))
+            .redirect(Policy::none())
+            .dns_resolver(Arc::new(DestinationResolver { allow_networks: config.allow_networks.clone() }))
+            .build()
+            .expect("failed to build webhook HTTP client");
+        WebhookDispatcher { client, max_attempts: config.max_attempts }
+    }
+}
+
+/// Checks that `url` is an http(s) URL whose host only resolves to public addresses, or to
+/// addresses in `allow_networks`, so that webhooks cannot reach the server's own network:
+/// loopback, private and link-local ranges (cloud metadata services among them) are refused.
+pub async fn check_destination(url: &str, allow_networks: &[IpNet]) -> Result<(), &'static str> {
+    let url = Url::parse(url).map_err(|_| "Webhook URL is not a valid URL.")?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err("Webhook URL must start with http:// or https://.");
+    }
+    let host = url.host_str().ok_or("Webhook URL has no host.")?;
+    let port = url.port_or_known_default().unwrap_or(80);
+    let addresses: Vec<IpAddr> = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => vec![ip],
+        Err(_) => match tokio::net::lookup_host((host, port)).await {
+            Ok(addresses) => addresses.map(|address| address.ip()).collect(),
+            Err(_) => return Err("Webhook URL host cannot be resolved."),
+        },
+    };
+    if addresses.is_empty() || !addresses.iter().all(|ip| allowed_destination(*ip, allow_networks)) {
+        return Err("Webhook URL must not point to a loopback, private or link-local address.");
+    }
+    Ok(())
+}
+
+// Whether deliveries may be sent to `ip`.
+fn allowed_destination(ip: IpAddr, allow_networks: &[IpNet]) -> bool {
+    is_public(ip) || allow_networks.iter().any(|network| network.contains(&ip))
+}
+
+// Whether `ip` is a public unicast address. Addresses embedding an IPv4 address (mapped and
+// NAT64) are judged by it.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public_v4(ip);
+            }
+            let segments = ip.segments();
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                return is_public_v4(Ipv4Addr::from(((segments[6] as u32) << 16) | segments[7] as u32));
+            }
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+                || (segments[0] == 0x2001 && segments[1] == 0x0db8))
+        }
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        || a == 0
+        // Shared address space (carrier-grade NAT), also used by some metadata services.
+        || (a == 100 && (64..128).contains(&b))
+        || (a == 192 && b == 0 && ip.octets()[2] == 0)
+        || (a == 198 && (b == 18 || b == 19))
+        || a >= 240)
+}
+
+// Resolves delivery hosts, failing when any of their addresses is not allowed, so that a
+// name checked at registration cannot later be pointed at an internal address.
+struct DestinationResolver {
+    allow_networks: Vec<IpNet>,
+}
+
+impl Resolve for DestinationResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let allow_networks = self.allow_networks.clone();
+        Box::pin(async move {
+            let addresses: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0)).await?.collect();
+            if addresses.iter().any(|address| !allowed_destination(address.ip(), &allow_networks)) {
+                return Err("webhook host resolves to a loopback, private or link-local address".into());
+            }
+            Ok(Box::new(addresses.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Queues `event` for every webhook subscribed to it. `recipients` are the users the event
+/// concerns; their own webhooks receive it alongside the global ones.
+pub async fn emit(app_state: &Arc<AppState>, event: WebhookEvent, recipients: &[Uuid]) {
+    let kind = event.kind();
+    let targets: Vec<Webhook> = app_state
+        .webhooks
+        .lock()
+        .await
+        .values()
+        .filter(|webhook| webhook.events.contains(&kind))
+        .filter(|webhook| match webhook.owner {
+            WebhookOwner::Global => true,
+            WebhookOwner::User(owner) => recipients.contains(&owner),
+        })
+        .cloned()
+        .collect();
+    if targets.is_empty() {
+        return;
+    }
+
+    let delivery = Delivery { id: Uuid::new_v4(), timestamp: Utc::now().to_rfc3339(), event: &event };
+    let body = match serde_json::to_string(&delivery) {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::error!(error = %e, "Error serializing webhook event");
+            return;
+        }
+    };
+
+    for webhook in targets {
+        let app_state = app_state.clone();
+        let body = body.clone();
+        let delivery_id = delivery.id;
+        tokio::spawn(async move { deliver(&app_state.webhook_dispatcher, &webhook, delivery_id, &body).await });
+    }
+}
+
+/// Event-bus subscriber: turns chat messages, new contacts, users coming online and
+/// registrations awaiting approval into webhook events.
+pub async fn on_event(app_state: Arc<AppState>, event: Arc<DomainEvent>) {
+    match &*event {
+        DomainEvent::MessageSent { message_id, from_user_id, from_username, to_user_id, text: Some(text), notification, .. } => {
+            // The recipient's own webhooks follow their notification level for the conversation.
+            let recipients: &[Uuid] =
+                if push::wants_notification(&app_state, *to_user_id, *from_user_id, notification.mentioned).await {
+                    &[*to_user_id]
+                } else {
+                    &[]
+                };
+            let event = WebhookEvent::MessageReceived {
+                message_id: message_id.clone(),
+                from_user_id: *from_user_id,
+                from_username: from_username.clone(),
+                to_user_id: *to_user_id,
+                message: text.clone(),
+            };
+            emit(&app_state, event, recipients).await;
+        }
+        DomainEvent::ContactAdded { user_id, contact_user_id, contact_username } => {
+            let event = WebhookEvent::ContactAdded {
+                user_id: *user_id,
+                contact_user_id: *contact_user_id,
+                contact_username: contact_username.clone(),
+            };
+            emit(&app_state, event, &[*user_id, *contact_user_id]).await;
+        }
+        // User webhooks receive it when the user is one of their owner's contacts.
+        DomainEvent::UserOnline { user_id, username } => {
+            let user = app_state.users.get(username);
+            let contact_ids: Vec<Uuid> = match user {
+                Some(user) => user.contacts.lock().await.keys().copied().collect(),
+                None => Vec::new(),
+            };
+            let event = WebhookEvent::UserOnline { user_id: *user_id, username: username.clone() };
+            emit(&app_state, event, &contact_ids).await;
+        }
+        DomainEvent::RegistrationRequested { registration_id, username, .. } => {
+            let moderator_ids: Vec<Uuid> = app_state.users.filter(|user| user.role >= Role::Moderator).iter().map(|user| user.id).collect();
+            let event = WebhookEvent::RegistrationPending { registration_id: *registration_id, username: username.clone() };
+            emit(&app_state, event, &moderator_ids).await;
+        }
+        DomainEvent::MessageSent { .. }
+        | DomainEvent::UserRegistered { .. }
+        | DomainEvent::AccountLocked { .. }
+        | DomainEvent::AccountUnlocked { .. }
+        | DomainEvent::UnfamiliarLogin { .. } => {}
+    }
+}
+
+async fn deliver(dispatcher: &WebhookDispatcher, webhook: &Webhook, delivery_id: Uuid, body: &str) {
+    let mut backoff = Duration::from_secs(1);
+    for attempt in 1..=dispatcher.max_attempts {
+        // Each attempt is signed with a fresh timestamp so receivers can reject replays.
+        let timestamp = Utc::now().timestamp().to_string();
+        let result = dispatcher
+            .client
+            .post(&webhook.url)
+            .header("content-type", "application/json")
+            .header("x-chat-webhook-id", webhook.id.to_string())
+            .header("x-chat-delivery-id", delivery_id.to_string())
+            .header("x-chat-timestamp", &timestamp)
+            .header("x-chat-signature", format!("sha256={}", sign(&webhook.secret, &timestamp, body)))
+            .body(body.to_string())
+            .send()
+            .await;
+
+        match result {
+            Ok(response) if response.status().is_success() => {
+                tracing::debug!(webhook_id = %webhook.id, %delivery_id, attempt, "Webhook delivered");
+                return;
+            }
+            Ok(response) => {
+                tracing::warn!(webhook_id = %webhook.id, %delivery_id, attempt, status = %response.status(), "Webhook delivery rejected");
+            }
+            Err(e) => {
+                tracing::warn!(webhook_id = %webhook.id, %delivery_id, attempt, error = %e, "Webhook delivery failed");
+            }
+        }
+
+        if attempt < dispatcher.max_attempts {
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+    tracing::error!(webhook_id = %webhook.id, %delivery_id, attempts = dispatcher.max_attempts, "Giving up on webhook delivery");
+}
+
+/// Hex-encoded HMAC-SHA256 of `"{timestamp}.{body}"` keyed with the webhook secret.
+pub(crate) fn sign(secret: &str, timestamp: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body.as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Generates a random signing secret for a new webhook.
+pub fn generate_secret() -> String {
+    format!("whsec_{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// Registered webhooks: webhook id -> webhook.
+pub type WebhookRegistry = HashMap<Uuid, Webhook>;
+
+/// An incoming webhook: a secret URL through which external systems (CI, monitoring, ...)
+/// post messages into one conversation of its owner without a user account of their own.
+#[derive(Debug, Clone)]
+pub struct IncomingWebhook {
+    pub id: Uuid,
+    // Secret path segment of the webhook URL; whoever knows it can post.
+    pub token: String,
+    pub owner_user_id: Uuid,
+    // The other side of the conversation (the owner themselves for a notes-to-self feed).
+    pub to_user_id: Uuid,
+    // Shown as the sender name of the posted messages.
+    pub name: String,
+}
+
+/// Generates the URL token of a new incoming webhook.
+pub fn generate_incoming_token() -> String {
+    format!("whin_{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+/// Registered incoming webhooks: token -> webhook.
+pub type IncomingWebhookRegistry = HashMap<String, IncomingWebhook>;
@@ -0,0 +1,148 @@
+// src/webpush.rs
+
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use std::fmt;
+use std::fs::File;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+use web_push::{
+    ContentEncoding, PartialVapidSignatureBuilder, SubscriptionInfo, Urgency, VapidSignatureBuilder, WebPushError,
+    WebPushMessageBuilder,
+};
+
+use crate::config::WebPushConfig;
+use crate::push::{PushNotification, PushSubscription};
+use crate::ws_handlers::AppState;
+
+/// Sends VAPID-signed, encrypted Web Push notifications to browser push services.
+pub struct WebPushSender {
+    client: reqwest::Client,
+    vapid: PartialVapidSignatureBuilder,
+    subject: String,
+    ttl_secs: u32,
+}
+
+impl fmt::Debug for WebPushSender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebPushSender")
+            .field("subject", &self.subject)
+            .field("ttl_secs", &self.ttl_secs)
+            .finish_non_exhaustive()
+    }
+}
+
+impl WebPushSender {
+    /// Loads the VAPID private key named in the configuration.
+    pub fn new(config: &WebPushConfig) -> Result<Self, WebPushError> {
+        let key_file = File::open(&config.vapid_private_key_path).map_err(|_| WebPushError::IoError)?;
+        let vapid = VapidSignatureBuilder::from_pem_no_sub(key_file)?;
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .user_agent(concat!("rust_chat-push/", env!("CARGO_PKG_VERSION")))
+            .build()
+            .expect("failed to build push HTTP client");
+        Ok(WebPushSender { client, vapid, subject: config.subject.clone(), ttl_secs: config.ttl_secs })
+    }
+
+    /// The VAPID public key (uncompressed P-256 point, base64url) browsers pass to
+    /// `PushManager.subscribe()` as `applicationServerKey`.
+    pub fn public_key(&self) -> String {
+        URL_SAFE_NO_PAD.encode(self.vapid.get_public_key())
+    }
+
+    async fn send(&self, subscription: &PushSubscription, payload: &[u8], topic: &str) -> Result<(), WebPushError> {
+        let info = SubscriptionInfo::new(
+            subscription.endpoint.as_str(),
+            subscription.p256dh.as_str(),
+            subscription.auth.as_str(),
+        );
+        let mut signature = self.vapid.clone().add_sub_info(&info);
+        signature.add_claim("sub", self.subject.as_str());
+
+        let mut builder = WebPushMessageBuilder::new(&info);
+        builder.set_ttl(self.ttl_secs);
+        builder.set_urgency(Urgency::High);
+        builder.set_topic(topic.to_string());
+        builder.set_payload(ContentEncoding::Aes128Gcm, payload);
+        builder.set_vapid_signature(signature.build()?);
+        let message = builder.build()?;
+
+        let mut request = self
+            .client
+            .post(message.endpoint.to_string())
+            .header("ttl", message.ttl.to_string());
+        if let Some(urgency) = message.urgency {
+            request = request.header("urgency", urgency.to_string());
+        }
+        if let Some(topic) = message.topic {
+            request = request.header("topic", topic);
+        }
+        if let Some(payload) = message.payload {
+            request = request
+                .header("content-encoding", payload.content_encoding.to_str())
+                .header("content-type", "application/octet-stream");
+            for (name, value) in payload.crypto_headers {
+                request = request.header(name, value);
+            }
+            request = request.body(payload.content);
+        }
+
+        let response = request.send().await.map_err(|e| WebPushError::Other(e.to_string()))?;
+        match response.status().as_u16() {
+            200..=299 => Ok(()),
+            404 => Err(WebPushError::EndpointNotFound),
+            410 => Err(WebPushError::EndpointNotValid),
+            status => Err(WebPushError::Other(format!("push service responded with status {}", status))),
+        }
+    }
+}
+
+/// Sends `notification` to every browser subscription of `user_id`. Subscriptions the push
+/// service reports as expired are removed. A no-op when Web Push is not configured.
+pub async fn notify(app_state: &Arc<AppState>, user_id: Uuid, notification: &PushNotification) {
+    if app_state.web_push.is_none() {
+        return;
+    }
+    let subscriptions: Vec<PushSubscription> = app_state
+        .push_subscriptions
+        .lock()
+        .await
+        .values()
+        .filter(|subscription| subscription.user_id == user_id)
+        .cloned()
+        .collect();
+    if subscriptions.is_empty() {
+        return;
+    }
+
+    let payload = match serde_json::to_vec(notification) {
+        Ok(payload) => payload,
+        Err(e) => {
+            tracing::error!(error = %e, "Error serializing push notification");
+            return;
+        }
+    };
+
+    for subscription in subscriptions {
+        let app_state = app_state.clone();
+        let payload = payload.clone();
+        let topic = notification.collapse_key.clone();
+        tokio::spawn(async move {
+            let Some(sender) = &app_state.web_push else { return };
+            match sender.send(&subscription, &payload, &topic).await {
+                Ok(()) => {
+                    tracing::debug!(user_id = %subscription.user_id, subscription_id = %subscription.id, "Web Push notification sent");
+                }
+                Err(WebPushError::EndpointNotFound | WebPushError::EndpointNotValid) => {
+                    app_state.push_subscriptions.lock().await.remove(&subscription.id);
+                    tracing::info!(user_id = %subscription.user_id, subscription_id = %subscription.id, "Removed expired push subscription");
+                }
+                Err(e) => {
+                    tracing::warn!(user_id = %subscription.user_id, subscription_id = %subscription.id, error = %e, "Web Push notification failed");
+                }
+            }
+        });
+    }
+}
@@ -1,480 +1,2061 @@
-// src/ws_handlers.rs
-
-use chrono::Utc;
-use futures::{SinkExt, StreamExt};
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::sync::Arc;
-use tokio::sync::{mpsc, Mutex};
-use uuid::Uuid;
-use warp::{
-    http::StatusCode,
-    ws::{Message, WebSocket},
-    Rejection, Reply,
-};
-use bcrypt;
-use warp::reject::Reject; // Import the Reject trait
-
-/// Global application state, shared across all handlers.
-#[derive(Debug)]
-pub struct AppState {
-    // Stores registered users: username -> User struct
-    pub users: Mutex<HashMap<String, User>>,
-    // Stores active user sessions: session_key (UUID string) -> UserSession struct
-    // The key here is the unique session_key itself.
-    pub user_sessions: Mutex<HashMap<String, UserSession>>,
-    // Stores active WebSocket connections: session_key (String) -> mpsc sender channel
-    // Now keyed by the unique session_key, allowing multiple connections per user.
-    pub active_connections: Mutex<HashMap<String, mpsc::UnboundedSender<Message>>>,
-}
-
-/// Represents a registered user in the system.
-#[derive(Debug, Clone)]
-pub struct User {
-    pub id: Uuid,
-    pub username: String,
-    pub password_hash: String,
-    // Stores contacts: contact_user_id (UUID) -> contact_username (String)
-    pub contacts: Arc<Mutex<HashMap<Uuid, String>>>,
-}
-
-/// Represents an active user session, holding basic user information
-/// that's validated with a session key.
-#[derive(Clone, Debug)]
-pub struct UserSession {
-    pub user_id: Uuid,
-    pub username: String,
-    pub session_key: String, // Added session_key to UserSession
-}
-
-/// Custom error response struct for consistent API error messages.
-#[derive(Debug, Serialize)]
-pub struct ErrorResponse {
-    pub message: String,
-}
-
-// REQUIRED: Implement the `warp::reject::Reject` trait for your custom error.
-// This allows `warp::reject::custom` to accept `ErrorResponse`.
-impl Reject for ErrorResponse {}
-
-
-// --- WebSocket Message Structures ---
-
-/// Messages sent FROM the client TO the server.
-#[derive(Deserialize, Debug)]
-#[serde(tag = "type", rename_all = "camelCase")]
-enum ClientMessage {
-    ChatMessage {
-        to_user_id: Uuid,
-        message: String,
-    },
-    TypingIndicator {
-        to_user_id: Uuid,
-        is_typing: bool,
-    },
-    // The client sends this when it has displayed a message.
-    ReadReceipt {
-        // This is the *original sender* of the message that was just read.
-        to_user_id: Uuid,
-        message_id: String,
-    },
-}
-
-/// Messages sent FROM the server TO the clients.
-#[derive(Serialize, Debug, Clone)]
-#[serde(tag = "type", rename_all = "camelCase")]
-enum ServerMessage {
-    ChatMessage {
-        from_user_id: Uuid,
-        from_username: String,
-        to_user_id: Uuid,
-        message_id: String,
-        timestamp: String,
-        // Emojis are supported natively by Rust's UTF-8 String type.
-        message: String,
-    },
-    StatusMessage {
-        user_id: Uuid,
-        username: String,
-        status: String, // "online" or "offline"
-    },
-    // The server forwards this receipt to the original message sender.
-    ReadReceipt {
-        from_user_id: Uuid, // The user who just read the message.
-        message_id: String,
-    },
-    TypingIndicator {
-        from_user_id: Uuid,
-        is_typing: bool,
-    },
-}
-
-/// Main handler for an active WebSocket connection.
-pub async fn handle_ws(ws: WebSocket, session: UserSession, app_state: Arc<AppState>) {
-    // The `.split()` method is now available because `StreamExt` is in scope.
-    let (mut ws_sender, mut ws_receiver) = ws.split();
-    let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
-
-    // Add this user's sending channel to the global map of active connections,
-    // using the unique session_key as the identifier for this specific connection.
-    app_state
-        .active_connections
-        .lock()
-        .await
-        .insert(session.session_key.clone(), tx);
-    
-    // Announce to everyone that this user is now online.
-    // This will broadcast the status based on the user_id,
-    // which should update all instances of that user in others' contact lists.
-    broadcast_status(&app_state, &session, "online").await;
-
-    // This task forwards messages from the channel to the client's WebSocket sender.
-    tokio::spawn(async move {
-        while let Some(message_to_send) = rx.recv().await {
-            if ws_sender.send(message_to_send).await.is_err() {
-                // Client disconnected.
-                break;
-            }
-        }
-    });
-
-    // This loop handles incoming messages from the client.
-    while let Some(Ok(msg)) = ws_receiver.next().await {
-        if let Ok(text) = msg.to_str() {
-            match serde_json::from_str::<ClientMessage>(text) { // Changed ClientWebSocketMessage to ClientMessage
-                Ok(client_msg) => {
-                    handle_client_message(client_msg, &session, &app_state).await;
-                }
-                Err(e) => {
-                    eprintln!("Error deserializing client message: {}", e);
-                }
-            }
-        }
-    }
-
-    // -- Cleanup on Disconnect --
-    println!("User '{}' (session: {}) disconnected.", session.username, session.session_key);
-    // Remove the connection using its unique session key.
-    app_state
-        .active_connections
-        .lock()
-        .await
-        .remove(&session.session_key);
-    
-    // Announce to everyone that this user is now offline.
-    // This will broadcast the status based on the user_id.
-    // Note: A user is only truly "offline" if ALL their sessions are disconnected.
-    // For simplicity here, we broadcast if *this* session disconnects.
-    // A more robust solution would track active session count per user.
-    broadcast_status(&app_state, &session, "offline").await;
-}
-
-/// Processes a deserialized message from a client and forwards it appropriately.
-async fn handle_client_message(
-    msg: ClientMessage,
-    sender_session: &UserSession,
-    app_state: &Arc<AppState>,
-) {
-    let connections_lock = app_state.active_connections.lock().await;
-    let user_sessions_lock = app_state.user_sessions.lock().await;
-
-
-    match msg {
-        ClientMessage::ChatMessage { to_user_id, message } => {
-            let server_msg = ServerMessage::ChatMessage {
-                from_user_id: sender_session.user_id,
-                from_username: sender_session.username.clone(),
-                to_user_id,
-                message_id: Uuid::new_v4().to_string(),
-                timestamp: Utc::now().to_rfc3339(),
-                message,
-            };
-
-            if let Ok(json) = serde_json::to_string(&server_msg) {
-                // Send to ALL active sessions belonging to the recipient user
-                for (session_key, tx) in connections_lock.iter() {
-                    if let Some(target_session) = user_sessions_lock.get(session_key) {
-                        if target_session.user_id == to_user_id {
-                             let _ = tx.send(Message::text(json.clone()));
-                        }
-                    }
-                }
-                // Also send back to all sessions of the sender for UI sync
-                for (session_key, tx) in connections_lock.iter() {
-                    if let Some(target_session) = user_sessions_lock.get(session_key) {
-                        if target_session.user_id == sender_session.user_id {
-                             let _ = tx.send(Message::text(json.clone()));
-                        }
-                    }
-                }
-            }
-        }
-        ClientMessage::TypingIndicator { to_user_id, is_typing } => {
-            let server_msg = ServerMessage::TypingIndicator {
-                from_user_id: sender_session.user_id,
-                is_typing,
-            };
-            if let Ok(json) = serde_json::to_string(&server_msg) {
-                for (session_key, tx) in connections_lock.iter() {
-                    if let Some(target_session) = user_sessions_lock.get(session_key) {
-                        // Typing indicators only go to sessions of the recipient user
-                        if target_session.user_id == to_user_id {
-                             let _ = tx.send(Message::text(json.clone()));
-                        }
-                    }
-                }
-            }
-        }
-        ClientMessage::ReadReceipt { to_user_id, message_id } => {
-            let server_msg = ServerMessage::ReadReceipt {
-                from_user_id: sender_session.user_id, // The user who just read the message.
-                message_id,
-            };
-            if let Ok(json) = serde_json::to_string(&server_msg) {
-                for (session_key, tx) in connections_lock.iter() {
-                    if let Some(target_session) = user_sessions_lock.get(session_key) {
-                        // Read receipts only go to sessions of the original message sender (to_user_id here refers to the original sender's ID)
-                        if target_session.user_id == to_user_id {
-                             let _ = tx.send(Message::text(json.clone()));
-                        }
-                    }
-                }
-            }
-        }
-    }
-}
-
-
-/// Broadcasts a user's status to all other connected clients.
-async fn broadcast_status(app_state: &Arc<AppState>, session: &UserSession, status: &str) {
-    let status_msg = ServerMessage::StatusMessage {
-        user_id: session.user_id,
-        username: session.username.clone(),
-        status: status.to_string(),
-    };
-    if let Ok(text) = serde_json::to_string(&status_msg) {
-        let msg = Message::text(text);
-        
-        let connections = app_state.active_connections.lock().await;
-        // The `user_sessions` lock is not explicitly needed here unless filtering by user type.
-        // It's not harmful to hold it, but dropping it early might be an option if performance is critical
-        // and no user_session specific lookup is being done in this loop.
-        // let user_sessions = app_state.user_sessions.lock().await; 
-
-        for (other_session_key, tx) in connections.iter() {
-            // Send to all *other* sessions of *other* users, or other sessions of the same user.
-            // A status update (online/offline) should typically be seen by everyone.
-            // The logic here is to send to all connections EXCEPT the one that triggered the broadcast.
-            if *other_session_key != session.session_key {
-                let _ = tx.send(msg.clone());
-            }
-        }
-    }
-}
-
-
-// --- HTTP Handlers ---
-
-// Structs for strongly-typed request bodies.
-#[derive(Deserialize)]
-pub struct AuthPayload {
-    username: String,
-    password: String,
-}
-
-#[derive(Deserialize)]
-pub struct AddContactPayload {
-    contact_username: String,
-}
-
-// Struct for a consistent successful authentication response.
-#[derive(Serialize)]
-pub struct AuthResponse {
-    message: String,
-    session_key: String,
-    user_id: Uuid,
-    username: String,
-}
-
-
-pub async fn register_handler(
-    payload: AuthPayload,
-    app_state: Arc<AppState>,
-) -> Result<impl Reply, Rejection> {
-    if payload.username.is_empty() || payload.password.is_empty() {
-        return Err(warp::reject::custom(ErrorResponse {
-            message: "Username and password are required.".into(),
-        }));
-    }
-
-    let mut users = app_state.users.lock().await;
-    if users.contains_key(&payload.username) {
-        return Err(warp::reject::custom(ErrorResponse {
-            message: "Username already exists.".into(),
-        }));
-    }
-
-    // Securely hash the password before storing.
-    let password_hash = match bcrypt::hash(&payload.password, bcrypt::DEFAULT_COST) {
-        Ok(hash) => hash,
-        Err(_) => return Err(warp::reject::custom(ErrorResponse {
-            message: "Failed to hash password.".into()
-        })),
-    };
-
-    let user = User {
-        id: Uuid::new_v4(),
-        username: payload.username.clone(),
-        password_hash,
-        contacts: Arc::new(Mutex::new(HashMap::new())),
-    };
-
-    let response = create_session(&user, app_state.clone()).await;
-    users.insert(payload.username.to_string(), user);
-    println!("Registered user: {} ({})", payload.username, response.user_id); // Added log
-    Ok(warp::reply::json(&response))
-}
-
-
-pub async fn login_handler(
-    payload: AuthPayload,
-    app_state: Arc<AppState>,
-) -> Result<impl Reply, Rejection> {
-     if payload.username.is_empty() || payload.password.is_empty() {
-        return Err(warp::reject::custom(ErrorResponse { message: "Username and password are required.".into() }));
-    }
-
-    let users = app_state.users.lock().await;
-    match users.get(&payload.username) {
-        Some(user) => {
-            // Securely verify the password against the stored hash.
-            let is_valid = bcrypt::verify(&payload.password, &user.password_hash).unwrap_or(false);
-
-            if is_valid {
-                let response = create_session(user, app_state.clone()).await;
-                println!("Logged in user: {} ({})", payload.username, response.user_id); // Added log
-                Ok(warp::reply::json(&response))
-            } else {
-                Err(warp::reject::custom(ErrorResponse { message: "Invalid username or password.".into() }))
-            }
-        }
-        None => Err(warp::reject::custom(ErrorResponse { message: "Invalid username or password.".into() })),
-    }
-}
-
-/// Helper function to create a new session for a user.
-async fn create_session(user: &User, app_state: Arc<AppState>) -> AuthResponse {
-    let new_session_key = Uuid::new_v4().to_string();
-    
-    // --- Invalidate all old sessions and their WebSocket connections for this user_id ---
-    let mut user_sessions_guard = app_state.user_sessions.lock().await;
-    let mut active_connections_guard = app_state.active_connections.lock().await;
-
-    // Collect session keys to remove
-    let session_keys_to_remove: Vec<String> = user_sessions_guard
-        .iter()
-        .filter(|(_, session)| session.user_id == user.id)
-        .map(|(session_key, _)| session_key.clone())
-        .collect();
-
-    for old_session_key in session_keys_to_remove {
-        user_sessions_guard.remove(&old_session_key);
-        if active_connections_guard.remove(&old_session_key).is_some() {
-            println!("Closed old WebSocket connection for user {} (session: {})", user.username, old_session_key);
-            // Optionally, send a message to the old client to explicitly tell it to re-login
-            // (requires a way to get the old tx, which we just removed. A `send_close_message` fn might be needed)
-        }
-    }
-    // --- End Invalidation ---
-
-    let new_session = UserSession {
-        user_id: user.id,
-        username: user.username.clone(),
-        session_key: new_session_key.clone(),
-    };
-    user_sessions_guard.insert(new_session_key.clone(), new_session);
-
-    AuthResponse {
-        message: "Authentication successful".to_string(),
-        session_key: new_session_key,
-        user_id: user.id,
-        username: user.username.clone(),
-    }
-}
-
-pub async fn add_contact_handler(
-    payload: AddContactPayload,
-    session: UserSession,
-    app_state: Arc<AppState>,
-) -> Result<impl Reply, Rejection> {
-    let contact_username = payload.contact_username;
-
-    if contact_username.is_empty() {
-        eprintln!("Add contact failed: contact_username is empty for user {}", session.username);
-        return Err(warp::reject::custom(ErrorResponse { message: "contact_username cannot be empty".to_string() }));
-    }
-    
-    if contact_username == session.username {
-        eprintln!("Add contact failed: user {} tried to add themselves as a contact", session.username);
-        return Err(warp::reject::custom(ErrorResponse { message: "You cannot add yourself as a contact.".to_string() }));
-    }
-
-    let users_guard = app_state.users.lock().await; // Acquire read lock once
-    
-    let current_user_opt = users_guard.get(&session.username).cloned();
-    let contact_to_add_opt = users_guard.get(&contact_username).cloned();
-
-    // Explicitly drop the guard to release the read lock on the main `users` HashMap.
-    // This allows us to acquire independent locks on the inner `contacts` HashMaps later.
-    drop(users_guard);
-
-    let current_user = match current_user_opt {
-        Some(u) => u,
-        None => {
-            eprintln!("Add contact failed: current user '{}' not found in users map (session might be invalid)", session.username);
-            return Err(warp::reject::custom(ErrorResponse { message: "User session invalid or user data missing.".to_string() }));
-        }
-    };
-
-    let contact_to_add = match contact_to_add_opt {
-        Some(c) => c,
-        None => {
-            eprintln!("Add contact failed: contact user '{}' not found for user {}", contact_username, session.username);
-            return Err(warp::reject::custom(ErrorResponse { message: "User not found".to_string() }));
-        }
-    };
-
-    // Now, acquire mutable locks on the individual `contacts` HashMaps.
-    let mut current_user_contacts = current_user.contacts.lock().await;
-    let mut contact_to_add_contacts = contact_to_add.contacts.lock().await;
-
-    // Add each user to the other's contact list for a mutual connection.
-    current_user_contacts.insert(contact_to_add.id, contact_to_add.username.clone());
-    contact_to_add_contacts.insert(current_user.id, current_user.username.clone());
-
-    println!("User '{}' (ID: {}) successfully added '{}' (ID: {}) as a contact.", 
-             session.username, session.user_id, contact_username, contact_to_add.id);
-    
-    // Debugging: Print current user's contacts after adding
-    println!("{}'s contacts after adding {}: {:?}", session.username, contact_username, current_user_contacts.keys().collect::<Vec<_>>());
-
-    Ok(StatusCode::OK)
-}
-
-pub async fn get_contacts_handler(
-    session: UserSession,
-    app_state: Arc<AppState>,
-) -> Result<impl Reply, Rejection> {
-    let users = app_state.users.lock().await;
-    if let Some(user) = users.get(&session.username) {
-        let contacts_map = user.contacts.lock().await;
-        let contacts_list: Vec<_> = contacts_map.iter().map(|(id, username)| {
-            serde_json::json!({ "id": id, "username": username })
-        }).collect();
-        println!("Retrieving contacts for user {}: {:?}", session.username, contacts_list); // Added log
-        Ok(warp::reply::json(&contacts_list))
-    } else {
-        eprintln!("Get contacts failed: User '{}' not found in users map during contacts retrieval.", session.username);
-        Err(warp::reject::custom(ErrorResponse { message: "User session invalid or user data missing.".to_string() }))
-    }
-}
\ No newline at end of file
+// src/ws_handlers.rs
+
+use chrono::{DateTime, Utc};
+use ed25519_dalek::VerifyingKey;
+use futures::stream::SplitSink;
+use futures::{FutureExt, SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use tokio::sync::{Mutex, RwLock};
+use tracing::Instrument;
+use utoipa::ToSchema;
+use uuid::Uuid;
+use warp::ws::{Message, WebSocket};
+
+use crate::analytics::Analytics;
+use crate::bots::ApiTokenRegistry;
+use crate::broadcasts::BroadcastListRegistry;
+use crate::calls::{self, Call, CallEndReason, CallHistory, CallRecord, CallRegistry, CallState};
+use crate::clock::HybridClock;
+use crate::cluster::Cluster;
+use crate::config::{BannerConfig, Config, IpFilterConfig, RuntimeConfig};
+use crate::connections::{
+    connection_channel, CloseReason, ConnectionHandle, ConnectionReceiver, ConnectionRegistry, FrameRateLimit, SharedFrame,
+    CLOSE_TIMEOUT,
+};
+use crate::conversations::{self, ConversationRegistry, MAX_READ_RECEIPT_BATCH};
+use crate::error::ApiError;
+#[cfg(feature = "fault-injection")]
+use crate::faults::FaultInjector;
+use crate::e2e::{self, EncryptedMessage, KeyRegistry};
+use crate::events::{DomainEvent, EventBus, MessageKind};
+use crate::fanout::FanoutPool;
+use crate::geoip::GeoIp;
+use crate::guests;
+use crate::handlers::broadcasts::BroadcastReceiptResponse;
+use crate::handlers::conversations::ConversationResponse;
+use crate::interceptors::{InterceptedMessage, Interceptors};
+use crate::location::{self, LiveLocation, LiveLocationRegistry};
+use crate::device::{Device, TrustedDeviceRegistry};
+use crate::invites::InviteRegistry;
+use crate::ldap::Directory;
+use crate::lockout::LoginFailureRegistry;
+use crate::login_alerts::LoginAlerts;
+use crate::markdown;
+use crate::registrations::RegistrationQueue;
+use crate::middleware::RateLimits;
+use crate::ordering::ConversationOrdering;
+#[cfg(feature = "kafka")]
+use crate::kafka::{self, KafkaExporter};
+#[cfg(feature = "matrix")]
+use crate::matrix::{self, MatrixBridge};
+#[cfg(feature = "mqtt")]
+use crate::mqtt::{self, MqttBridge};
+#[cfg(feature = "nats")]
+use crate::nats::{self, NatsRouter};
+#[cfg(feature = "mobile-push")]
+use crate::mobile_push::MobilePushDispatcher;
+use crate::polls::{Poll, PollRegistry, MAX_POLL_OPTIONS, MAX_POLL_TEXT_LENGTH};
+use crate::presence;
+use crate::push::{self, DeviceTokenRegistry, NotificationSettings, PushNotification, PushSubscriptionRegistry};
+use crate::redact::{pseudonym, redacted};
+use crate::scheduler::Scheduler;
+use crate::shards::Sharded;
+use crate::stats::{LockStatsResponse, ServerStats, TimedMutex};
+use crate::telemetry::LogLevelHandle;
+use crate::wal::{self, Mutation, WriteAheadLog};
+#[cfg(feature = "web-push")]
+use crate::webpush::WebPushSender;
+use crate::webhooks::{IncomingWebhookRegistry, WebhookDispatcher, WebhookRegistry};
+
+/// Global application state, shared across all handlers.
+#[derive(Debug)]
+pub struct AppState {
+    // Stores registered users: username -> User struct, sharded by username and indexed by user id
+    pub users: Sharded<String, User>,
+    // Stores active user sessions: session_key (UUID string) -> UserSession struct
+    // The key here is the unique session_key itself, which also picks the shard.
+    pub user_sessions: Sharded<String, UserSession>,
+    // Active connections, owned by the registry tasks (one per shard of users) that route frames to them.
+    // WebSockets are keyed by their unique session_key, allowing multiple connections per user;
+    // other event consumers (e.g. gRPC streams) use their own unique keys.
+    pub connections: ConnectionRegistry,
+    // Counters reported by the admin statistics endpoint.
+    pub stats: ServerStats,
+    // Validated configuration the server was started with.
+    pub config: Config,
+    // Settings that can be changed at runtime via SIGHUP or POST /admin/config/reload.
+    pub runtime: RwLock<RuntimeConfig>,
+    // Swaps the active log filter when the log level is reloaded.
+    pub log_level: LogLevelHandle,
+    // Registered outgoing webhooks: webhook id -> webhook.
+    pub webhooks: TimedMutex<WebhookRegistry>,
+    // Sends webhook deliveries in the background.
+    pub webhook_dispatcher: WebhookDispatcher,
+    // Incoming webhooks that post into conversations: token -> webhook.
+    pub incoming_webhooks: TimedMutex<IncomingWebhookRegistry>,
+    // API tokens of bot accounts: token secret -> token.
+    pub api_tokens: TimedMutex<ApiTokenRegistry>,
+    // Browser push subscriptions: subscription id -> subscription.
+    pub push_subscriptions: TimedMutex<PushSubscriptionRegistry>,
+    // Sends Web Push notifications; `None` when `[web_push]` is not configured.
+    #[cfg(feature = "web-push")]
+    pub web_push: Option<WebPushSender>,
+    // Mobile devices registered for FCM/APNs pushes: device id -> device.
+    pub device_tokens: TimedMutex<DeviceTokenRegistry>,
+    // Sends FCM/APNs pushes for the configured platforms.
+    #[cfg(feature = "mobile-push")]
+    pub mobile_push: MobilePushDispatcher,
+    // Mute and do-not-disturb settings for offline notifications: user id -> settings.
+    pub notification_settings: TimedMutex<HashMap<Uuid, NotificationSettings>>,
+    // Online users currently announced as "dnd".
+    pub dnd_presence: TimedMutex<HashSet<Uuid>>,
+    // Identity keys and prekeys published for end-to-end encryption: user id -> keys.
+    pub e2e_keys: TimedMutex<KeyRegistry>,
+    // Unread counts and last activity: (user, conversation partner) -> conversation.
+    pub conversations: TimedMutex<ConversationRegistry>,
+    // Unsent messages: (user, conversation partner) -> draft.
+    pub drafts: TimedMutex<HashMap<(Uuid, Uuid), Draft>>,
+    // Users currently typing: (typist, recipient) -> when the indicator expires.
+    pub typing: TimedMutex<HashMap<(Uuid, Uuid), Instant>>,
+    // Live locations being shared: location id -> share.
+    pub live_locations: TimedMutex<LiveLocationRegistry>,
+    // Polls posted in conversations: poll id -> poll.
+    pub polls: TimedMutex<PollRegistry>,
+    // Broadcast lists for one-to-many messaging: list id -> list.
+    pub broadcast_lists: TimedMutex<BroadcastListRegistry>,
+    // Calls that are ringing or in progress: call id -> call.
+    pub calls: TimedMutex<CallRegistry>,
+    // Ended calls, for the call log: user id -> the user's calls.
+    pub call_history: TimedMutex<CallHistory>,
+    // Daily activity of the last 90 days, saved with the snapshot.
+    pub analytics: TimedMutex<Analytics>,
+    // HTTP API requests per client IP in the current minute, for the middleware rate limits.
+    pub rate_limits: TimedMutex<RateLimits>,
+    // Client addresses let in or turned away; starts as `ip_filter` in the config.
+    pub ip_rules: TimedMutex<IpFilterConfig>,
+    // Countries of client addresses; `None` when `[geoip]` is not configured.
+    pub geoip: Option<GeoIp>,
+    // Consecutive failed logins per user, and the accounts they locked.
+    pub login_failures: TimedMutex<LoginFailureRegistry>,
+    // Addresses users logged in from, and tokens revoking sessions of unfamiliar logins.
+    pub login_alerts: TimedMutex<LoginAlerts>,
+    // Devices each user marked as trusted.
+    pub trusted_devices: TimedMutex<TrustedDeviceRegistry>,
+    // Invite codes created by users, used and pending.
+    pub invites: TimedMutex<InviteRegistry>,
+    // Registrations waiting for an admin's approval, with `registration.require_approval`.
+    pub registrations: TimedMutex<RegistrationQueue>,
+    // Log of user and contact changes since the last snapshot; `None` without `storage.snapshot_path`.
+    pub wal: Option<WriteAheadLog>,
+    // Matrix appservice bridge; `None` when `[matrix]` is not configured.
+    #[cfg(feature = "matrix")]
+    pub matrix: Option<MatrixBridge>,
+    // Publishes events to an MQTT broker; `None` when `[mqtt]` is not configured.
+    #[cfg(feature = "mqtt")]
+    pub mqtt: Option<MqttBridge>,
+    // The other nodes serving the same users and the presence of their users; `None` when
+    // `[cluster]` is not configured.
+    pub cluster: Option<Cluster>,
+    // Routes frames between nodes through a NATS server; `None` when `[nats]` is not configured.
+    #[cfg(feature = "nats")]
+    pub nats: Option<NatsRouter>,
+    // Exports events to Kafka; `None` when `[kafka]` is not configured.
+    #[cfg(feature = "kafka")]
+    pub kafka: Option<KafkaExporter>,
+    // Hooks run on every routed chat message (content filters, metrics, archiving, ...).
+    pub interceptors: Interceptors,
+    // Checks the passwords of directory accounts; `None` without `[ldap]` or `ChatServerBuilder::directory`.
+    pub directory: Option<Arc<dyn Directory>>,
+    // Domain events (messages sent, users online, ...) for webhooks, pushes, metrics and auditing.
+    pub events: EventBus,
+    // Shards that deliver each conversation's messages one at a time, in order.
+    pub ordering: ConversationOrdering,
+    // Workers queuing broadcasts on many connections, off the tasks handling clients.
+    pub fanout: FanoutPool,
+    // Stamps delivered messages (`timestamp` and `hlc`) so they never go back in time.
+    pub clock: HybridClock,
+    // Recurring background jobs: snapshots, session expiry, presence refresh.
+    pub scheduler: Scheduler,
+    // Latency, registry contention and dropped sends injected into the fanout by resilience tests.
+    #[cfg(feature = "fault-injection")]
+    pub faults: FaultInjector,
+}
+
+impl AppState {
+    /// How long `session` stays valid after login: the full session TTL on a device its user
+    /// trusts, the shorter untrusted one elsewhere.
+    pub fn session_ttl(&self, session: &UserSession) -> Duration {
+        let auth = &self.config.auth;
+        if session.trusted_device.load(Ordering::Relaxed) {
+            Duration::from_secs(auth.session_ttl_secs)
+        } else {
+            Duration::from_secs(auth.untrusted_session_ttl_secs.min(auth.session_ttl_secs))
+        }
+    }
+
+    /// How long a session without an open connection may go unused before it is expired.
+    pub fn session_idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.config.auth.session_idle_timeout_secs)
+    }
+
+    /// Looks up a live (not expired) session by its key, marking it as used.
+    /// Reads the published copy of the sessions, so it takes no lock.
+    pub async fn session_for_key(&self, session_key: &str) -> Option<UserSession> {
+        let session = self.user_sessions.get(session_key).filter(|session| !session.is_expired(self.session_ttl(session)))?;
+        session.last_active.touch();
+        Some(session)
+    }
+
+    /// Marks the session as used now, if it still exists.
+    pub async fn touch_session(&self, session_key: &str) {
+        if let Some(session) = self.user_sessions.get(session_key) {
+            session.last_active.touch();
+        }
+    }
+
+    /// Removes the sessions past their TTL, and those without an open connection that went
+    /// unused for longer than the idle timeout. Returns how many were removed.
+    pub async fn expire_idle_sessions(&self) -> usize {
+        let connected: HashSet<String> =
+            self.connections.snapshot().await.into_iter().map(|(_, connection)| connection.session_key).collect();
+        let idle_timeout = self.session_idle_timeout();
+        let mut expired = 0;
+        for shard in self.user_sessions.shards() {
+            let mut sessions = shard.lock().await;
+            let before = sessions.len();
+            sessions.retain(|session_key, session| {
+                !session.is_expired(self.session_ttl(session)) && (connected.contains(session_key) || session.last_active.elapsed() <= idle_timeout)
+            });
+            expired += before - sessions.len();
+        }
+        self.stats.sessions_expired.fetch_add(expired as u64, Ordering::Relaxed);
+        expired
+    }
+
+    /// Deletes the guest accounts past `registration.guest_ttl_secs` along with their
+    /// sessions and data. Returns how many were deleted.
+    pub async fn purge_expired_guests(&self) -> usize {
+        guests::purge_expired(self).await
+    }
+
+    /// Wait times of every lock on the shared state.
+    pub fn lock_stats(&self) -> Vec<LockStatsResponse> {
+        vec![
+            self.users.snapshot(),
+            self.user_sessions.snapshot(),
+            self.webhooks.snapshot(),
+            self.incoming_webhooks.snapshot(),
+            self.api_tokens.snapshot(),
+            self.push_subscriptions.snapshot(),
+            self.device_tokens.snapshot(),
+            self.notification_settings.snapshot(),
+            self.dnd_presence.snapshot(),
+            self.e2e_keys.snapshot(),
+            self.conversations.snapshot(),
+            self.drafts.snapshot(),
+            self.typing.snapshot(),
+            self.live_locations.snapshot(),
+            self.polls.snapshot(),
+            self.broadcast_lists.snapshot(),
+            self.calls.snapshot(),
+            self.call_history.snapshot(),
+            self.analytics.snapshot(),
+            self.rate_limits.snapshot(),
+            self.ip_rules.snapshot(),
+            self.login_failures.snapshot(),
+            self.login_alerts.snapshot(),
+            self.trusted_devices.snapshot(),
+            self.invites.snapshot(),
+            self.registrations.snapshot(),
+        ]
+    }
+
+    /// Applies injected latency and registry contention before a fanout; a no-op without the
+    /// `fault-injection` feature.
+    async fn inject_fanout_faults(&self) {
+        #[cfg(feature = "fault-injection")]
+        self.faults.before_fanout(&self.connections).await;
+    }
+
+    /// Whether fault injection drops the send about to happen; always false without the
+    /// `fault-injection` feature.
+    fn drop_injected_send(&self) -> bool {
+        #[cfg(feature = "fault-injection")]
+        return self.faults.drop_send();
+        #[cfg(not(feature = "fault-injection"))]
+        false
+    }
+}
+
+/// Represents a registered user in the system.
+#[derive(Debug, Clone)]
+pub struct User {
+    pub id: Uuid,
+    pub username: String,
+    pub password_hash: String,
+    pub role: Role,
+    // Stores contacts: contact_user_id (UUID) -> contact_username (String)
+    pub contacts: Arc<Mutex<HashMap<Uuid, String>>>,
+    // Set for bot accounts: the user who created the bot. Bots cannot log in and
+    // authenticate with API tokens instead.
+    pub bot_owner: Option<Uuid>,
+    // Set for guest accounts: when the account is deleted along with its data. Guests have
+    // no password and are neither logged nor snapshotted, so they never outlive a restart.
+    pub guest_until: Option<DateTime<Utc>>,
+    // Set for accounts provisioned from `[ldap]`: their password is checked against the
+    // directory and never stored.
+    pub ldap: bool,
+}
+
+/// Access level of a user. Variants are ordered so that a higher role
+/// satisfies any check for a lower one (an admin can do everything a moderator can).
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    User,
+    Moderator,
+    Admin,
+}
+
+/// Gives the admin role to the existing accounts named in `usernames` (`auth.admin_usernames`),
+/// at startup and on every configuration reload. Registering a listed name does not make an
+/// admin: otherwise whoever registered it first would get the role.
+pub(crate) async fn promote_admins(app_state: &AppState, usernames: &[String]) {
+    for username in usernames {
+        let mut users = app_state.users.lock(username).await;
+        let Some(user) = users.get_mut(username) else {
+            tracing::warn!(username = %pseudonym(username), "Account of auth.admin_usernames not registered yet; reload the configuration once it is");
+            continue;
+        };
+        if user.role == Role::Admin || user.bot_owner.is_some() || user.guest_until.is_some() || is_matrix_id(username) {
+            continue;
+        }
+        user.role = Role::Admin;
+        let mutation = Mutation::RoleChanged { user_id: user.id, role: Role::Admin };
+        drop(users);
+        wal::record(app_state, mutation).await;
+        tracing::info!(username = %pseudonym(username), "Gave the admin role to an account of auth.admin_usernames");
+    }
+}
+
+/// A message being written, saved so the user can continue it on another device.
+#[derive(Debug, Clone)]
+pub struct Draft {
+    pub text: String,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Represents an active user session, holding basic user information
+/// that's validated with a session key.
+#[derive(Clone, Debug)]
+pub struct UserSession {
+    pub user_id: Uuid,
+    pub username: String,
+    pub session_key: String, // Added session_key to UserSession
+    pub created_at: Instant,
+    // Real client address at login, resolved through trusted proxies.
+    pub client_ip: Option<IpAddr>,
+    // Country the login address is located in, when `[geoip]` knows it.
+    pub country: Option<String>,
+    // Device the session logged in from, as far as its client told.
+    pub device: Device,
+    // Whether its user trusts that device, giving the session the full TTL. Shared by every
+    // copy of the session, so trusting the device takes effect on the session in use.
+    pub trusted_device: Arc<AtomicBool>,
+    // Ed25519 key registered at login; chat messages signed with it are marked verified.
+    pub signing_key: Option<VerifyingKey>,
+    // Last time the session was used: a request made with it, or its WebSocket connecting
+    // or disconnecting. Shared by every copy of the session.
+    pub last_active: LastActive,
+}
+
+impl UserSession {
+    /// Whether the session has outlived `ttl` (see `AppState::session_ttl`).
+    pub fn is_expired(&self, ttl: Duration) -> bool {
+        self.created_at.elapsed() > ttl
+    }
+}
+
+/// When a session was last used. Copies of a session share it, so marking the session used
+/// takes no lock and does not replace the published copy of the sessions map.
+#[derive(Clone, Debug)]
+pub struct LastActive(Arc<AtomicU64>);
+
+impl LastActive {
+    /// Used just now.
+    pub fn now() -> Self {
+        LastActive(Arc::new(AtomicU64::new(Self::since_start())))
+    }
+
+    /// Marks the session as used now.
+    pub fn touch(&self) {
+        self.0.fetch_max(Self::since_start(), Ordering::Relaxed);
+    }
+
+    /// Time since the session was last used.
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_millis(Self::since_start().saturating_sub(self.0.load(Ordering::Relaxed)))
+    }
+
+    // Milliseconds since the first reading in this process.
+    fn since_start() -> u64 {
+        static START: OnceLock<Instant> = OnceLock::new();
+        START.get_or_init(Instant::now).elapsed().as_millis() as u64
+    }
+}
+
+
+// --- WebSocket Message Structures ---
+
+/// Messages sent FROM the client TO the server.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum ClientMessage {
+    ChatMessage {
+        to_user_id: Uuid,
+        message: String,
+        // Base64 Ed25519 signature of `message` made with the session's signing key.
+        #[serde(default)]
+        signature: Option<String>,
+    },
+    TypingIndicator {
+        to_user_id: Uuid,
+        is_typing: bool,
+    },
+    // The client sends this when it has displayed a message.
+    ReadReceipt {
+        // This is the *original sender* of the message that was just read.
+        to_user_id: Uuid,
+        message_id: String,
+    },
+    // Read receipts for several messages of the conversation with `to_user_id` at once, e.g.
+    // on opening a conversation with many unread messages.
+    ReadReceipts {
+        to_user_id: Uuid,
+        message_ids: Vec<String>,
+    },
+    // End-to-end encrypted chat message: `{ to_user_id, ciphertext, header }`. The server
+    // routes it without reading or logging the payload.
+    Encrypted {
+        to_user_id: Uuid,
+        #[serde(flatten)]
+        message: EncryptedMessage,
+    },
+    // WebRTC signaling for 1:1 calls. The server relays these and tracks each call's state;
+    // `call_id` is chosen by the caller and ties all messages of one call together. Either
+    // party may send a new offer (and get an answer) during the call to renegotiate its
+    // media, e.g. to add a screen-share track.
+    CallOffer {
+        to_user_id: Uuid,
+        call_id: Uuid,
+        sdp: String,
+    },
+    CallAnswer {
+        to_user_id: Uuid,
+        call_id: Uuid,
+        sdp: String,
+    },
+    IceCandidate {
+        to_user_id: Uuid,
+        call_id: Uuid,
+        // The browser's RTCIceCandidateInit object, passed through as is.
+        candidate: serde_json::Value,
+    },
+    // Posts a poll in the conversation with `to_user_id`.
+    Poll {
+        to_user_id: Uuid,
+        question: String,
+        options: Vec<String>,
+        #[serde(default)]
+        multi_select: bool,
+    },
+    // Replaces the sender's choice in a poll (indexes into its options); empty retracts it.
+    Vote {
+        poll_id: Uuid,
+        options: Vec<usize>,
+    },
+    // Saves the unsent text of the conversation with `to_user_id`; empty text discards it.
+    SaveDraft {
+        to_user_id: Uuid,
+        text: String,
+    },
+    // Shares a position (degrees, accuracy in meters). With `live_until` (RFC 3339), the
+    // location is live and can be moved with `LocationUpdate` until then.
+    Location {
+        to_user_id: Uuid,
+        lat: f64,
+        lon: f64,
+        #[serde(default)]
+        accuracy: Option<f64>,
+        #[serde(default)]
+        live_until: Option<String>,
+    },
+    LocationUpdate {
+        location_id: Uuid,
+        lat: f64,
+        lon: f64,
+        #[serde(default)]
+        accuracy: Option<f64>,
+    },
+    // Stops sharing a live location before it expires.
+    StopLiveLocation {
+        location_id: Uuid,
+    },
+    // One message to every recipient of one of the sender's broadcast lists.
+    BroadcastMessage {
+        list_id: Uuid,
+        message: String,
+    },
+    // The sender started or stopped sending audio, video or its screen.
+    MediaChanged {
+        call_id: Uuid,
+        audio: bool,
+        video: bool,
+        screen_share: bool,
+    },
+    // The callee rejects a ringing call.
+    CallDecline {
+        call_id: Uuid,
+    },
+    // Either party ends the call; before an answer, the caller cancels it.
+    CallHangup {
+        call_id: Uuid,
+    },
+    // Asks for the server's clock. `client_time` is anything the client wants echoed back,
+    // typically its own clock when sending, to measure the round trip and its clock's skew.
+    TimeSync {
+        #[serde(default)]
+        client_time: serde_json::Value,
+    },
+}
+
+/// Messages sent FROM the server TO the clients.
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub(crate) enum ServerMessage {
+    ChatMessage {
+        from_user_id: Uuid,
+        from_username: String,
+        to_user_id: Uuid,
+        message_id: String,
+        // When the server handled the message, in RFC 3339 (millisecond precision).
+        timestamp: String,
+        // The same instant on the server's hybrid logical clock (`<millis>-<counter>`).
+        // Unlike `timestamp` it is unique and never goes back, even across clock
+        // adjustments: sorting by it gives the order the server handled messages in.
+        hlc: String,
+        // Emojis are supported natively by Rust's UTF-8 String type.
+        message: String,
+        // Whether the server checked the message's signature against the sender's signing key.
+        verified: bool,
+        // Sanitized HTML rendering of the message's markdown, if enabled on the server.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        html: Option<String>,
+    },
+    StatusMessage {
+        user_id: Uuid,
+        username: String,
+        status: String, // "online", "dnd" (online during do-not-disturb) or "offline"
+    },
+    // The server forwards this receipt to the original message sender.
+    ReadReceipt {
+        from_user_id: Uuid, // The user who just read the message.
+        message_id: String,
+    },
+    // A batch of read receipts, forwarded as one frame.
+    ReadReceipts {
+        from_user_id: Uuid,
+        message_ids: Vec<String>,
+    },
+    // Sent to the reader's other sessions when one of them reads the conversation with
+    // `to_user_id` up to `message_id`.
+    ReadStateSync {
+        to_user_id: Uuid,
+        message_id: String,
+    },
+    // Sent to the sender of an encrypted or broadcast message with the id read receipts
+    // will refer to; `delivered` tells whether a recipient connection received it.
+    DeliveryReceipt {
+        to_user_id: Uuid,
+        message_id: String,
+        delivered: bool,
+    },
+    TypingIndicator {
+        from_user_id: Uuid,
+        is_typing: bool,
+    },
+    // End-to-end encrypted message, relayed as received.
+    EncryptedMessage {
+        from_user_id: Uuid,
+        from_username: String,
+        to_user_id: Uuid,
+        message_id: String,
+        timestamp: String,
+        hlc: String,
+        ciphertext: String,
+        header: String,
+    },
+    // A new poll, sent to both participants of the conversation.
+    Poll {
+        poll_id: Uuid,
+        from_user_id: Uuid,
+        from_username: String,
+        to_user_id: Uuid,
+        timestamp: String,
+        hlc: String,
+        question: String,
+        options: Vec<String>,
+        multi_select: bool,
+    },
+    // Live tallies of a poll after a vote, sent to both participants.
+    PollUpdate {
+        poll_id: Uuid,
+        // Votes per option, in option order.
+        tallies: Vec<usize>,
+        // Participants who currently have a vote in.
+        voters: usize,
+    },
+    // Sent on connect: the user's conversations that have unread messages.
+    UnreadCounts {
+        conversations: Vec<ConversationResponse>,
+    },
+    // The user's draft for a conversation changed on another session (or on connect, for
+    // every saved draft). Empty text means the draft was discarded or sent.
+    DraftUpdated {
+        to_user_id: Uuid,
+        text: String,
+        updated_at: String,
+    },
+    // A shared location, sent to both participants of the conversation.
+    Location {
+        location_id: Uuid,
+        from_user_id: Uuid,
+        from_username: String,
+        to_user_id: Uuid,
+        timestamp: String,
+        hlc: String,
+        lat: f64,
+        lon: f64,
+        accuracy: Option<f64>,
+        live_until: Option<String>,
+    },
+    // New position of a live location.
+    LocationUpdate {
+        location_id: Uuid,
+        from_user_id: Uuid,
+        timestamp: String,
+        hlc: String,
+        lat: f64,
+        lon: f64,
+        accuracy: Option<f64>,
+    },
+    // The sender stopped sharing a live location before it expired.
+    LiveLocationStopped {
+        location_id: Uuid,
+        from_user_id: Uuid,
+    },
+    // WebRTC signaling relayed from the other party of a call.
+    CallOffer {
+        from_user_id: Uuid,
+        from_username: String,
+        call_id: Uuid,
+        sdp: String,
+        // Whether this renegotiates the media of a call already in progress.
+        renegotiation: bool,
+    },
+    CallAnswer {
+        from_user_id: Uuid,
+        call_id: Uuid,
+        sdp: String,
+    },
+    IceCandidate {
+        from_user_id: Uuid,
+        call_id: Uuid,
+        candidate: serde_json::Value,
+    },
+    MediaChanged {
+        from_user_id: Uuid,
+        call_id: Uuid,
+        audio: bool,
+        video: bool,
+        screen_share: bool,
+    },
+    // Sent to every session of both parties when a call ends, so no device keeps ringing.
+    CallEnded {
+        call_id: Uuid,
+        reason: CallEndReason,
+    },
+    // Server-wide notice sent by an administrator to every active connection.
+    Announcement {
+        title: String,
+        body: String,
+        severity: AnnouncementSeverity,
+    },
+    // Answers the sender's `timeSync`, with the server's clock (RFC 3339) when it was handled.
+    TimeSync {
+        client_time: serde_json::Value,
+        server_time: String,
+    },
+    // Sent to the user's sessions when repeated wrong passwords lock their account: password
+    // logins are refused until `locked_until` (RFC 3339), unless it is unlocked sooner.
+    AccountLocked {
+        locked_until: String,
+    },
+    // Sent to the user's sessions when their locked account is unlocked, by themselves or an admin.
+    AccountUnlocked {
+        by_admin: bool,
+    },
+    // Sent to admins when a registration starts waiting for their approval.
+    RegistrationPending {
+        registration_id: Uuid,
+        username: String,
+        requested_at: String,
+    },
+    // Sent to the user's existing sessions just before a login from an unfamiliar device
+    // replaces them. `POST /api/v1/sessions/revoke` with `revoke_token` revokes the new
+    // session, without needing one of its own.
+    SecurityAlert {
+        kind: &'static str,
+        client_ip: Option<IpAddr>,
+        country: Option<String>,
+        device_name: Option<String>,
+        platform: Option<&'static str>,
+        logged_in_at: String,
+        revoke_token: String,
+    },
+    // Accepts the `auth` frame of a WebSocket opened without a session key in its URL.
+    Authenticated {
+        user_id: Uuid,
+        username: String,
+    },
+    // Tells the client about a problem with its connection, e.g. that it is about to be
+    // closed for reading too slowly (`SLOW_CONSUMER`), or with a frame it sent
+    // (`VALIDATION_FAILED`, listing the offending `fields`).
+    Error {
+        code: String,
+        message: String,
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        fields: Vec<FieldError>,
+    },
+}
+
+/// A field of a client frame that breaks the protocol's rules, and why.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub(crate) struct FieldError {
+    field: String,
+    reason: String,
+}
+
+impl FieldError {
+    fn new(field: impl Into<String>, reason: impl Into<String>) -> Self {
+        FieldError { field: field.into(), reason: reason.into() }
+    }
+}
+
+impl ClientMessage {
+    // The fields a frame of `message_type` may carry besides `type`; strict parsing rejects
+    // any other. Keep in sync with the variants above.
+    fn known_fields(message_type: &str) -> &'static [&'static str] {
+        match message_type {
+            "chatMessage" => &["to_user_id", "message", "signature"],
+            "typingIndicator" => &["to_user_id", "is_typing"],
+            "readReceipt" => &["to_user_id", "message_id"],
+            "readReceipts" => &["to_user_id", "message_ids"],
+            "encrypted" => &["to_user_id", "ciphertext", "header"],
+            "callOffer" | "callAnswer" => &["to_user_id", "call_id", "sdp"],
+            "iceCandidate" => &["to_user_id", "call_id", "candidate"],
+            "poll" => &["to_user_id", "question", "options", "multi_select"],
+            "vote" => &["poll_id", "options"],
+            "saveDraft" => &["to_user_id", "text"],
+            "location" => &["to_user_id", "lat", "lon", "accuracy", "live_until"],
+            "locationUpdate" => &["location_id", "lat", "lon", "accuracy"],
+            "stopLiveLocation" => &["location_id"],
+            "broadcastMessage" => &["list_id", "message"],
+            "mediaChanged" => &["call_id", "audio", "video", "screen_share"],
+            "callDecline" | "callHangup" => &["call_id"],
+            "timeSync" => &["client_time"],
+            _ => &[],
+        }
+    }
+
+    // The user the frame is addressed to, for the frames that name one.
+    fn to_user_id(&self) -> Option<Uuid> {
+        match self {
+            ClientMessage::ChatMessage { to_user_id, .. }
+            | ClientMessage::TypingIndicator { to_user_id, .. }
+            | ClientMessage::ReadReceipt { to_user_id, .. }
+            | ClientMessage::ReadReceipts { to_user_id, .. }
+            | ClientMessage::Encrypted { to_user_id, .. }
+            | ClientMessage::CallOffer { to_user_id, .. }
+            | ClientMessage::CallAnswer { to_user_id, .. }
+            | ClientMessage::IceCandidate { to_user_id, .. }
+            | ClientMessage::Poll { to_user_id, .. }
+            | ClientMessage::SaveDraft { to_user_id, .. }
+            | ClientMessage::Location { to_user_id, .. } => Some(*to_user_id),
+            _ => None,
+        }
+    }
+
+    // The fields that are empty or too long. Rules that need the server's state (known
+    // recipients, polls, calls) are checked elsewhere.
+    fn field_errors(&self, max_message_length: usize) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        match self {
+            ClientMessage::ChatMessage { message, .. } | ClientMessage::BroadcastMessage { message, .. } => {
+                check_text(&mut errors, "message", message, max_message_length, true);
+            }
+            ClientMessage::SaveDraft { text, .. } => check_text(&mut errors, "text", text, max_message_length, false),
+            ClientMessage::ReadReceipt { message_id, .. } => check_text(&mut errors, "message_id", message_id, usize::MAX, true),
+            ClientMessage::ReadReceipts { message_ids, .. } => {
+                if !(1..=MAX_READ_RECEIPT_BATCH).contains(&message_ids.len()) {
+                    errors.push(FieldError::new("message_ids", format!("must have between 1 and {} ids", MAX_READ_RECEIPT_BATCH)));
+                }
+                for (index, message_id) in message_ids.iter().enumerate() {
+                    check_text(&mut errors, &format!("message_ids[{}]", index), message_id, usize::MAX, true);
+                }
+            }
+            ClientMessage::CallOffer { sdp, .. } | ClientMessage::CallAnswer { sdp, .. } => {
+                check_text(&mut errors, "sdp", sdp, usize::MAX, true);
+            }
+            ClientMessage::Poll { question, options, .. } => {
+                check_text(&mut errors, "question", question, MAX_POLL_TEXT_LENGTH, true);
+                for (index, option) in options.iter().enumerate() {
+                    check_text(&mut errors, &format!("options[{}]", index), option, MAX_POLL_TEXT_LENGTH, true);
+                }
+                if !(2..=MAX_POLL_OPTIONS).contains(&options.len()) {
+                    errors.push(FieldError::new("options", format!("must have between 2 and {} options", MAX_POLL_OPTIONS)));
+                }
+            }
+            _ => {}
+        }
+        errors
+    }
+}
+
+fn check_text(errors: &mut Vec<FieldError>, field: &str, value: &str, max: usize, required: bool) {
+    if required && value.trim().is_empty() {
+        errors.push(FieldError::new(field, "must not be empty"));
+    } else if value.len() > max {
+        errors.push(FieldError::new(field, format!("must be at most {} bytes", max)));
+    }
+}
+
+/// How prominently clients should surface an announcement.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AnnouncementSeverity {
+    Info,
+    Warning,
+    Critical,
+}
+
+/// Main handler for an active WebSocket connection.
+#[tracing::instrument(name = "ws_connection", skip_all, fields(user_id = %session.user_id, client_ip = ?client_ip.map(pseudonym)))]
+pub async fn handle_ws(ws: WebSocket, session: UserSession, client_ip: Option<IpAddr>, app_state: Arc<AppState>) {
+    // The `.split()` method is now available because `StreamExt` is in scope.
+    let (ws_sender, mut ws_receiver) = ws.split();
+    let (connection, rx) = connection_channel(&session, client_ip);
+
+    tracing::info!(
+        user_id = %session.user_id,
+        client_ip = ?client_ip.map(pseudonym),
+        login_ip = ?session.client_ip.map(pseudonym),
+        "User connected"
+    );
+
+    // Register this connection's sending channel with the connection registry,
+    // using the unique session_key as the identifier for this specific connection.
+    register_connection(&app_state, session.session_key.clone(), connection.clone());
+
+    send_unread_counts(&*app_state.conversations.lock().await, &session, &connection);
+    send_saved_drafts(&*app_state.drafts.lock().await, &session, &connection);
+
+    // Show the configured banner, if any, to the newly connected client.
+    if let Some(banner) = app_state.runtime.read().await.banner.clone() {
+        if let Ok(json) = serde_json::to_string(&banner_message(&banner)) {
+            let _ = connection.send(Message::text(json));
+        }
+    }
+
+    // Announce to everyone that this user is now online.
+    // This will broadcast the status based on the user_id,
+    // which should update all instances of that user in others' contact lists.
+    broadcast_status(&app_state, &session, "online").await;
+    emit_user_online(&app_state, &session);
+
+    // This task forwards messages from the channel to the client's WebSocket sender.
+    let mut writer = tokio::spawn(
+        write_frames(app_state.clone(), session.user_id, rx, ws_sender).instrument(tracing::info_span!("ws_writer")),
+    );
+
+    // Per-connection rate limiting over fixed one-minute windows.
+    let mut rate_limit = FrameRateLimit::new();
+
+    // This loop handles incoming messages from the client, until it goes away or the writer
+    // gives up on it.
+    loop {
+        let msg = tokio::select! {
+            frame = ws_receiver.next() => match frame {
+                Some(Ok(msg)) => msg,
+                _ => break,
+            },
+            _ = &mut writer => break,
+        };
+        connection.record_activity();
+        if let Ok(text) = msg.to_str() {
+            let max_per_minute = app_state.runtime.read().await.max_messages_per_minute;
+            if !rate_limit.allow(max_per_minute) {
+                if rate_limit.flooding(max_per_minute) {
+                    tracing::warn!(user_id = %session.user_id, client_ip = ?client_ip.map(pseudonym), "Closing connection: client kept exceeding the rate limit");
+                    // The writer sends what is queued, then the close frame.
+                    let _ = connection.send(CloseReason::RateLimited.frame());
+                    break;
+                }
+                tracing::warn!(user_id = %session.user_id, client_ip = ?client_ip.map(pseudonym), "Dropping client frame: rate limit exceeded");
+                continue;
+            }
+
+            // A bug in a handler must not take the connection down with it: the task would end
+            // without the disconnect cleanup below and leave a dead entry in the connection registry.
+            match AssertUnwindSafe(handle_client_frame(text, &session, &app_state)).catch_unwind().await {
+                Ok(Some(reply)) => {
+                    let _ = connection.send(reply);
+                }
+                Ok(None) => {}
+                Err(_) => tracing::error!(user_id = %session.user_id, "Client frame handler panicked; frame dropped"),
+            }
+        }
+    }
+
+    // -- Cleanup on Disconnect --
+    tracing::info!(user_id = %session.user_id, username = %pseudonym(&session.username), session_key = %redacted(&session.session_key), "User disconnected");
+    // Remove the connection using its unique session key.
+    remove_connection(&app_state, &session.session_key, session.user_id);
+    let last_connection = !app_state.connections.is_online(session.user_id).await;
+
+    // Nobody is left to take part in the user's call, if any.
+    if last_connection {
+        let ongoing = app_state.calls.lock().await.values().find(|call| call.involves(session.user_id)).map(|call| call.call_id);
+        if let Some(call_id) = ongoing {
+            let user_id = session.user_id;
+            end_call(&app_state, call_id, CallEndReason::Disconnected, |call| call.involves(user_id)).await;
+        }
+    }
+    
+    // Announce to everyone that this user is now offline, unless another connection of theirs
+    // is still open here.
+    if last_connection {
+        broadcast_status(&app_state, &session, "offline").await;
+    }
+    // The session idles from now on, until it is used or connects again.
+    app_state.touch_session(&session.session_key).await;
+}
+
+/// Adds a connection to the registry and, with `[nats]`, subscribes this node to the frames
+/// for its user.
+pub(crate) fn register_connection(app_state: &AppState, connection_key: String, connection: ConnectionHandle) {
+    #[cfg(feature = "nats")]
+    if let Some(nats) = &app_state.nats {
+        nats.subscribe_user(connection.user_id);
+    }
+    app_state.connections.register(connection_key, connection);
+}
+
+/// Removes a connection of `user_id` registered with `register_connection`.
+#[cfg_attr(not(feature = "nats"), allow(unused_variables))]
+pub(crate) fn remove_connection(app_state: &AppState, connection_key: &str, user_id: Uuid) {
+    app_state.connections.remove(connection_key);
+    #[cfg(feature = "nats")]
+    if let Some(nats) = &app_state.nats {
+        nats.unsubscribe_user(user_id);
+    }
+}
+
+/// The first frame of a WebSocket opened without a session key in its URL.
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum AuthFrame {
+    Auth { session_key: String },
+}
+
+/// Authenticates a WebSocket opened without a session key in its URL: its first text frame
+/// must be `{ "type": "auth", "session_key" }`, sent within `auth.auth_frame_timeout_secs`.
+/// The client is told it is in with an `authenticated` frame; otherwise the connection is
+/// closed and `None` returned.
+pub async fn authenticate_ws(mut ws: WebSocket, client_ip: Option<IpAddr>, app_state: &AppState) -> Option<(WebSocket, UserSession)> {
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(app_state.config.auth.auth_frame_timeout_secs);
+    // Pings and pongs may come first; any other frame is the one that must authenticate.
+    let frame = loop {
+        match tokio::time::timeout_at(deadline, ws.next()).await {
+            Ok(Some(Ok(frame))) if frame.is_ping() || frame.is_pong() => continue,
+            Ok(Some(Ok(frame))) => break Some(frame),
+            // The client went away.
+            Ok(_) => return None,
+            Err(_) => break None,
+        }
+    };
+    let Some(AuthFrame::Auth { session_key }) =
+        frame.as_ref().and_then(|frame| frame.to_str().ok()).and_then(|text| serde_json::from_str(text).ok())
+    else {
+        tracing::warn!(client_ip = ?client_ip.map(pseudonym), "WebSocket connection denied: no auth frame");
+        refuse_ws(ws, CloseReason::AuthRequired).await;
+        return None;
+    };
+    let Some(session) = app_state.session_for_key(&session_key).await else {
+        tracing::warn!(client_ip = ?client_ip.map(pseudonym), "WebSocket connection denied: Invalid session key in auth frame.");
+        refuse_ws(ws, CloseReason::InvalidSession).await;
+        return None;
+    };
+    let authenticated = ServerMessage::Authenticated { user_id: session.user_id, username: session.username.clone() };
+    let json = serde_json::to_string(&authenticated).ok()?;
+    ws.send(Message::text(json)).await.ok()?;
+    Some((ws, session))
+}
+
+/// Closes a WebSocket that is not let in, telling the client why.
+pub async fn refuse_ws(mut ws: WebSocket, reason: CloseReason) {
+    let _ = tokio::time::timeout(CLOSE_TIMEOUT, ws.send(reason.frame())).await;
+}
+
+/// Writes the frames queued for a connection to its WebSocket until either side goes away,
+/// or up to a queued close frame.
+/// A connection whose queue stays at `limits.slow_consumer_queue_depth` frames or more for
+/// `limits.slow_consumer_grace_secs`, or whose socket takes longer than
+/// `limits.send_timeout_secs` to accept a frame, is closed as a slow consumer.
+async fn write_frames(app_state: Arc<AppState>, user_id: Uuid, mut rx: ConnectionReceiver, mut ws_sender: SplitSink<WebSocket, Message>) {
+    let limits = &app_state.config.limits;
+    let send_timeout = Duration::from_secs(limits.send_timeout_secs);
+    let grace = Duration::from_secs(limits.slow_consumer_grace_secs);
+    let mut saturated_since: Option<Instant> = None;
+    while let Some(message_to_send) = rx.recv().await {
+        let closing = message_to_send.is_close();
+        match tokio::time::timeout(send_timeout, ws_sender.send(message_to_send)).await {
+            // The server closed the connection; nothing more is written.
+            Ok(Ok(())) if closing => return,
+            Ok(Ok(())) => {}
+            // Client disconnected.
+            Ok(Err(_)) => return,
+            Err(_) => return close_slow_consumer(&app_state, user_id, ws_sender, "writing a frame stalled").await,
+        }
+        let saturated = limits.slow_consumer_queue_depth > 0 && rx.queue_depth() >= limits.slow_consumer_queue_depth;
+        saturated_since = if saturated { Some(saturated_since.unwrap_or_else(Instant::now)) } else { None };
+        if saturated_since.is_some_and(|since| since.elapsed() >= grace) {
+            return close_slow_consumer(&app_state, user_id, ws_sender, "too many frames queued").await;
+        }
+    }
+}
+
+/// Warns a slow consumer with an `error` frame and closes its WebSocket with
+/// `CloseReason::SlowConsumer`, without waiting on a socket that does not drain.
+async fn close_slow_consumer(app_state: &AppState, user_id: Uuid, mut ws_sender: SplitSink<WebSocket, Message>, reason: &str) {
+    tracing::warn!(user_id = %user_id, reason, "Closing slow consumer");
+    app_state.stats.slow_consumers_disconnected.fetch_add(1, Ordering::Relaxed);
+    let warning = ServerMessage::Error {
+        code: "SLOW_CONSUMER".to_string(),
+        message: "The connection is not reading fast enough and is being closed; reconnect to continue.".to_string(),
+        fields: Vec::new(),
+    };
+    let close = async {
+        if let Ok(json) = serde_json::to_string(&warning) {
+            ws_sender.send(Message::text(json)).await?;
+        }
+        ws_sender.send(CloseReason::SlowConsumer.frame()).await
+    };
+    let _ = tokio::time::timeout(CLOSE_TIMEOUT, close).await;
+}
+
+/// Parses one text frame from a client and handles it. Returns the frame to answer the
+/// sender's connection with: a `VALIDATION_FAILED` error when the message breaks the
+/// protocol's rules. Frames that are not a valid `ClientMessage` are logged and dropped, and
+/// unknown fields ignored, unless `messages.strict_parsing` is on: then they are answered
+/// with a `MALFORMED_MESSAGE` error, or listed as `VALIDATION_FAILED` fields.
+pub(crate) async fn handle_client_frame(text: &str, session: &UserSession, app_state: &Arc<AppState>) -> Option<Message> {
+    let strict = app_state.runtime.read().await.strict_parsing;
+    let client_msg = match serde_json::from_str::<ClientMessage>(text) {
+        Ok(client_msg) => client_msg,
+        Err(e) => {
+            // Serde quotes the offending input, which may be the message itself.
+            tracing::warn!(user_id = %session.user_id, error = %redacted(&e), "Error deserializing client message");
+            if !strict {
+                return None;
+            }
+            return error_frame("MALFORMED_MESSAGE", format!("The message could not be parsed: {}", e), Vec::new());
+        }
+    };
+    let mut fields = if strict { unknown_fields(text) } else { Vec::new() };
+    fields.extend(validate_client_message(&client_msg, app_state).await);
+    if fields.is_empty() {
+        // Answered on the sender's connection alone; nothing is routed.
+        if let ClientMessage::TimeSync { client_time } = client_msg {
+            let reply = ServerMessage::TimeSync { client_time, server_time: Utc::now().to_rfc3339() };
+            return serde_json::to_string(&reply).ok().map(Message::text);
+        }
+        handle_client_message(client_msg, session, app_state).await;
+        return None;
+    }
+    tracing::warn!(user_id = %session.user_id, fields = ?fields, "Rejecting invalid client message");
+    error_frame("VALIDATION_FAILED", "The message has invalid fields and was not processed.".to_string(), fields)
+}
+
+fn error_frame(code: &str, message: String, fields: Vec<FieldError>) -> Option<Message> {
+    let error = ServerMessage::Error { code: code.to_string(), message, fields };
+    serde_json::to_string(&error).ok().map(Message::text)
+}
+
+/// The fields of a frame that parsed as a `ClientMessage` but that its type does not have.
+fn unknown_fields(text: &str) -> Vec<FieldError> {
+    let Ok(serde_json::Value::Object(frame)) = serde_json::from_str(text) else { return Vec::new() };
+    let known = ClientMessage::known_fields(frame.get("type").and_then(|t| t.as_str()).unwrap_or_default());
+    frame
+        .keys()
+        .filter(|field| *field != "type" && !known.contains(&field.as_str()))
+        .map(|field| FieldError::new(field.as_str(), "unknown field"))
+        .collect()
+}
+
+/// The fields of `msg` that break the protocol's rules; empty if it may be processed.
+async fn validate_client_message(msg: &ClientMessage, app_state: &Arc<AppState>) -> Vec<FieldError> {
+    let max_message_length = app_state.runtime.read().await.max_message_length;
+    let mut errors = msg.field_errors(max_message_length);
+    if let Some(to_user_id) = msg.to_user_id() {
+        if app_state.users.get_by_id(&to_user_id).is_none() {
+            errors.push(FieldError::new("to_user_id", "unknown user"));
+        }
+    }
+    errors
+}
+
+/// Processes a deserialized message from a client and forwards it appropriately.
+#[tracing::instrument(
+    name = "client_message",
+    skip_all,
+    fields(from_user_id = %sender_session.user_id, message_id = tracing::field::Empty)
+)]
+async fn handle_client_message(
+    msg: ClientMessage,
+    sender_session: &UserSession,
+    app_state: &Arc<AppState>,
+) {
+    match msg {
+        ClientMessage::ChatMessage { to_user_id, message, signature } => {
+            if let Err(e) = route_signed_chat_message(app_state, sender_session, to_user_id, message, signature).await {
+                tracing::warn!(user_id = %sender_session.user_id, reason = %e, "Dropping chat message");
+            }
+        }
+        ClientMessage::Encrypted { to_user_id, message } => {
+            if let Err(e) = route_encrypted_message(app_state, sender_session, to_user_id, message).await {
+                tracing::warn!(user_id = %sender_session.user_id, reason = %e, "Dropping encrypted message");
+            }
+        }
+        ClientMessage::Poll { to_user_id, question, options, multi_select } => {
+            if let Err(e) = create_poll(app_state, sender_session, to_user_id, question, options, multi_select).await {
+                tracing::warn!(user_id = %sender_session.user_id, reason = %e, "Dropping poll");
+            }
+        }
+        ClientMessage::Vote { poll_id, options } => {
+            if let Err(e) = vote_in_poll(app_state, sender_session, poll_id, options).await {
+                tracing::warn!(user_id = %sender_session.user_id, poll_id = %poll_id, reason = %e, "Dropping vote");
+            }
+        }
+        ClientMessage::SaveDraft { to_user_id, text } => {
+            if let Err(e) = save_draft(app_state, sender_session, to_user_id, text).await {
+                tracing::warn!(user_id = %sender_session.user_id, reason = %e, "Dropping draft");
+            }
+        }
+        ClientMessage::Location { to_user_id, lat, lon, accuracy, live_until } => {
+            if let Err(e) = share_location(app_state, sender_session, to_user_id, lat, lon, accuracy, live_until).await {
+                tracing::warn!(user_id = %sender_session.user_id, reason = %e, "Dropping location");
+            }
+        }
+        ClientMessage::LocationUpdate { location_id, lat, lon, accuracy } => {
+            if let Err(e) = update_live_location(app_state, sender_session, location_id, lat, lon, accuracy).await {
+                tracing::warn!(user_id = %sender_session.user_id, location_id = %location_id, reason = %e, "Dropping location update");
+            }
+        }
+        ClientMessage::StopLiveLocation { location_id } => {
+            let mut live_locations = app_state.live_locations.lock().await;
+            match live_locations.get(&location_id) {
+                Some(live) if live.sender_id == sender_session.user_id => {
+                    let to_user_id = live.to_user_id;
+                    live_locations.remove(&location_id);
+                    drop(live_locations);
+                    let stopped = ServerMessage::LiveLocationStopped { location_id, from_user_id: sender_session.user_id };
+                    if let Err(e) = send_to_conversation(app_state, sender_session.user_id, to_user_id, &stopped).await {
+                        tracing::warn!(user_id = %sender_session.user_id, location_id = %location_id, reason = %e, "Dropping live location stop");
+                    }
+                }
+                _ => tracing::warn!(user_id = %sender_session.user_id, location_id = %location_id, "Dropping stop for an unknown live location"),
+            }
+        }
+        ClientMessage::BroadcastMessage { list_id, message } => {
+            if let Err(e) = send_broadcast(app_state, sender_session, list_id, message).await {
+                tracing::warn!(user_id = %sender_session.user_id, reason = %e, "Dropping broadcast message");
+            }
+        }
+        ClientMessage::TypingIndicator { to_user_id, is_typing } => {
+            send_typing_indicator(app_state, sender_session, to_user_id, is_typing).await;
+        }
+        ClientMessage::CallOffer { to_user_id, call_id, sdp } => {
+            offer_call(app_state, sender_session, to_user_id, call_id, sdp).await;
+        }
+        ClientMessage::CallAnswer { to_user_id, call_id, sdp } => {
+            answer_call(app_state, sender_session, to_user_id, call_id, sdp).await;
+        }
+        ClientMessage::IceCandidate { to_user_id, call_id, candidate } => {
+            let in_call = app_state.calls.lock().await.get(&call_id).and_then(|call| call.peer_of(sender_session.user_id)) == Some(to_user_id);
+            if !in_call {
+                tracing::warn!(user_id = %sender_session.user_id, call_id = %call_id, "Dropping ICE candidate for an unknown call");
+                return;
+            }
+            let signal = ServerMessage::IceCandidate { from_user_id: sender_session.user_id, call_id, candidate };
+            relay_call_signal(app_state, sender_session, to_user_id, &signal).await;
+        }
+        ClientMessage::MediaChanged { call_id, audio, video, screen_share } => {
+            let Some(peer_id) = app_state.calls.lock().await.get(&call_id).and_then(|call| call.peer_of(sender_session.user_id)) else {
+                tracing::warn!(user_id = %sender_session.user_id, call_id = %call_id, "Dropping media change for an unknown call");
+                return;
+            };
+            let signal = ServerMessage::MediaChanged { from_user_id: sender_session.user_id, call_id, audio, video, screen_share };
+            relay_call_signal(app_state, sender_session, peer_id, &signal).await;
+        }
+        ClientMessage::CallDecline { call_id } => {
+            let user_id = sender_session.user_id;
+            end_call(app_state, call_id, CallEndReason::Declined, |call| call.state == CallState::Ringing && call.callee_id == user_id).await;
+        }
+        ClientMessage::CallHangup { call_id } => {
+            let user_id = sender_session.user_id;
+            end_call(app_state, call_id, CallEndReason::HangUp, |call| call.involves(user_id)).await;
+        }
+        ClientMessage::ReadReceipt { to_user_id, message_id } => {
+            tracing::Span::current().record("message_id", message_id.as_str());
+            if let Err(e) = send_read_receipt(app_state, sender_session, to_user_id, vec![message_id]).await {
+                tracing::warn!(user_id = %sender_session.user_id, reason = %e, "Dropping read receipt");
+            }
+        }
+        ClientMessage::ReadReceipts { to_user_id, message_ids } => {
+            if let Err(e) = send_read_receipt(app_state, sender_session, to_user_id, message_ids).await {
+                tracing::warn!(user_id = %sender_session.user_id, reason = %e, "Dropping read receipts");
+            }
+        }
+        // Answered by `handle_client_frame`, on the sender's connection.
+        ClientMessage::TimeSync { .. } => {}
+    }
+}
+
+/// Forwards read receipts for `message_ids` (at least one, oldest first) to the original
+/// sender of the messages, in a single frame, and tells the reader's other sessions, so they
+/// clear their unread state for the conversation too.
+pub(crate) async fn send_read_receipt(app_state: &Arc<AppState>, reader_session: &UserSession, to_user_id: Uuid, message_ids: Vec<String>) -> Result<(), ApiError> {
+    let from_user_id = reader_session.user_id; // The user who just read the messages.
+    let last_read = message_ids.last().cloned().unwrap_or_default();
+    // A single receipt keeps the frame older clients know.
+    let receipt = match <[String; 1]>::try_from(message_ids) {
+        Ok([message_id]) => ServerMessage::ReadReceipt { from_user_id, message_id },
+        Err(message_ids) => ServerMessage::ReadReceipts { from_user_id, message_ids },
+    };
+    let sync = ServerMessage::ReadStateSync { to_user_id, message_id: last_read };
+    conversations::mark_read(&mut *app_state.conversations.lock().await, reader_session.user_id, to_user_id);
+    // Ordered with the conversation's messages, so a receipt never overtakes the message it acknowledges.
+    let (state, reader) = (app_state.clone(), reader_session.clone());
+    let fanout = async move {
+        if let Ok(json) = serde_json::to_string(&receipt) {
+            // Read receipts only go to sessions of the original message sender (to_user_id here refers to the original sender's ID)
+            state.connections.send_to_user(to_user_id, &json).await;
+        }
+        if let Ok(json) = serde_json::to_string(&sync) {
+            state.connections.send_to_other_sessions(&reader, &json);
+        }
+    };
+    app_state.ordering.run(reader_session.user_id, to_user_id, fanout).await
+}
+
+/// Routes a chat message from `sender_session` to every connection of the recipient, and
+/// echoes it to the sender's own connections for UI sync. Shared by the WebSocket protocol
+/// and the server-to-server entry points. Returns the id assigned to the message.
+pub async fn route_chat_message(
+    app_state: &Arc<AppState>,
+    sender_session: &UserSession,
+    to_user_id: Uuid,
+    message: String,
+) -> Result<String, ApiError> {
+    route_signed_chat_message(app_state, sender_session, to_user_id, message, None)
+        .await
+        .map(|routed| routed.message_id)
+}
+
+/// Outcome of routing a chat message.
+pub struct RoutedMessage {
+    pub message_id: String,
+    // Whether at least one connection of the recipient received it.
+    pub delivered: bool,
+}
+
+/// Like `route_chat_message`, for a message that may carry the sender's signature. A signed
+/// message is only routed if the signature matches the signing key registered at login;
+/// recipients then see it as verified.
+pub async fn route_signed_chat_message(
+    app_state: &Arc<AppState>,
+    sender_session: &UserSession,
+    to_user_id: Uuid,
+    message: String,
+    signature: Option<String>,
+) -> Result<RoutedMessage, ApiError> {
+    let (max_message_length, render_markdown) = {
+        let runtime = app_state.runtime.read().await;
+        (runtime.max_message_length, runtime.render_markdown)
+    };
+    if message.len() > max_message_length {
+        return Err(ApiError::MessageTooLong { max: max_message_length });
+    }
+
+    let verified = match (&signature, &sender_session.signing_key) {
+        (None, _) => false,
+        (Some(_), None) => {
+            return Err(ApiError::SigningKeyRequired);
+        }
+        (Some(signature), Some(key)) => {
+            if !e2e::verify_signature(key, &message, signature) {
+                return Err(ApiError::InvalidSignature);
+            }
+            true
+        }
+    };
+
+    let message_id = Uuid::new_v4().to_string();
+    tracing::Span::current().record("message_id", message_id.as_str());
+    let mut intercepted = InterceptedMessage {
+        message_id: message_id.clone(),
+        from_user_id: sender_session.user_id,
+        from_username: sender_session.username.clone(),
+        to_user_id,
+        text: message.clone(),
+    };
+    app_state.interceptors.pre_send(&mut intercepted).map_err(ApiError::MessageRejected)?;
+    // The signature covers the text the sender wrote, not an interceptor's rewrite.
+    let verified = verified && intercepted.text == message;
+    let message = intercepted.text.clone();
+
+    // Sending the message ends the sender's typing state; clients hide the indicator when it arrives.
+    app_state.typing.lock().await.remove(&(sender_session.user_id, to_user_id));
+    let draft_sent = app_state.drafts.lock().await.remove(&(sender_session.user_id, to_user_id)).is_some();
+    let mentioned = match app_state.users.get_by_id(&to_user_id) {
+        Some(recipient) => push::mentions(&message, &recipient.username),
+        None => false,
+    };
+    let mut notification = PushNotification::chat_message(sender_session.user_id, &sender_session.username, &message_id, &message);
+    notification.mentioned = mentioned;
+    #[cfg(feature = "matrix")]
+    matrix::relay_outbound(app_state, sender_session, to_user_id, &message_id, &message).await;
+
+    // Timestamped and delivered in conversation order, so every session of both users sees
+    // the conversation's messages in the same order.
+    let (state, sender, id) = (app_state.clone(), sender_session.clone(), message_id.clone());
+    let fanout = async move {
+        let stamp = state.clock.now();
+        let timestamp = stamp.rfc3339();
+        #[cfg(feature = "mqtt")]
+        mqtt::publish_message(&state, &sender, to_user_id, &id, &message, &timestamp);
+        let server_msg = ServerMessage::ChatMessage {
+            from_user_id: sender.user_id,
+            from_username: sender.username.clone(),
+            to_user_id,
+            message_id: id,
+            timestamp,
+            hlc: stamp.to_string(),
+            html: render_markdown.then(|| markdown::render(&message)),
+            message,
+            verified,
+        };
+
+        let json = serde_json::to_string(&server_msg).map_err(|e| ApiError::Internal(format!("Failed to serialize message: {}", e)))?;
+        let frame = SharedFrame::new(json);
+        conversations::record_message(&mut *state.conversations.lock().await, sender.user_id, to_user_id);
+
+        state.inject_fanout_faults().await;
+        // Send to ALL active sessions belonging to the recipient user
+        let recipient_connections =
+            if state.drop_injected_send() { 0 } else { send_frame_to_user(&state, to_user_id, &frame).await };
+        // Also send back to all sessions of the sender for UI sync
+        if sender.user_id != to_user_id {
+            send_frame_to_user(&state, sender.user_id, &frame).await;
+        }
+        // The sender's other sessions drop the draft that was just sent.
+        if draft_sent {
+            let cleared = ServerMessage::DraftUpdated { to_user_id, text: String::new(), updated_at: Utc::now().to_rfc3339() };
+            if let Ok(json) = serde_json::to_string(&cleared) {
+                state.connections.send_to_other_sessions(&sender, &json);
+            }
+        }
+        Ok(recipient_connections)
+    };
+    let recipient_connections = app_state.ordering.run(sender_session.user_id, to_user_id, fanout).await??;
+
+    let delivered = recipient_connections > 0;
+    app_state.events.publish(DomainEvent::MessageSent {
+        kind: MessageKind::Chat,
+        message_id: message_id.clone(),
+        from_user_id: sender_session.user_id,
+        from_username: sender_session.username.clone(),
+        to_user_id,
+        text: Some(intercepted.text.clone()),
+        delivered,
+        notification,
+    });
+    app_state.interceptors.post_receive(&intercepted, delivered);
+    Ok(RoutedMessage { message_id, delivered })
+}
+
+/// Posts a poll in the conversation between the sender and `to_user_id`, delivered to both
+/// like a chat message.
+async fn create_poll(
+    app_state: &Arc<AppState>,
+    sender_session: &UserSession,
+    to_user_id: Uuid,
+    question: String,
+    options: Vec<String>,
+    multi_select: bool,
+) -> Result<(), ApiError> {
+    let question = question.trim().to_string();
+    let options: Vec<String> = options.iter().map(|option| option.trim().to_string()).collect();
+    if question.is_empty() || options.iter().any(String::is_empty) {
+        return Err(ApiError::invalid("The question and options cannot be empty."));
+    }
+    if !(2..=MAX_POLL_OPTIONS).contains(&options.len()) {
+        return Err(ApiError::invalid(format!("A poll needs between 2 and {} options.", MAX_POLL_OPTIONS)));
+    }
+    if question.len() > MAX_POLL_TEXT_LENGTH || options.iter().any(|option| option.len() > MAX_POLL_TEXT_LENGTH) {
+        return Err(ApiError::invalid(format!("The question and options are limited to {} bytes each.", MAX_POLL_TEXT_LENGTH)));
+    }
+
+    let poll_id = Uuid::new_v4();
+    tracing::Span::current().record("message_id", tracing::field::display(poll_id));
+    let notification =
+        PushNotification::chat_message(sender_session.user_id, &sender_session.username, &poll_id.to_string(), &format!("📊 {}", question));
+    let stamp = app_state.clock.now();
+    let server_msg = ServerMessage::Poll {
+        poll_id,
+        from_user_id: sender_session.user_id,
+        from_username: sender_session.username.clone(),
+        to_user_id,
+        timestamp: stamp.rfc3339(),
+        hlc: stamp.to_string(),
+        question,
+        options: options.clone(),
+        multi_select,
+    };
+    app_state.polls.lock().await.insert(
+        poll_id,
+        Poll { creator_id: sender_session.user_id, to_user_id, options, multi_select, votes: HashMap::new() },
+    );
+    tracing::info!(user_id = %sender_session.user_id, to_user_id = %to_user_id, poll_id = %poll_id, "Poll created");
+
+    let delivered = send_to_conversation(app_state, sender_session.user_id, to_user_id, &server_msg).await? > 0;
+    app_state.events.publish(DomainEvent::MessageSent {
+        kind: MessageKind::Poll,
+        message_id: poll_id.to_string(),
+        from_user_id: sender_session.user_id,
+        from_username: sender_session.username.clone(),
+        to_user_id,
+        text: None,
+        delivered,
+        notification,
+    });
+    Ok(())
+}
+
+/// Records a participant's vote and sends the new tallies to both participants.
+async fn vote_in_poll(app_state: &Arc<AppState>, sender_session: &UserSession, poll_id: Uuid, mut options: Vec<usize>) -> Result<(), ApiError> {
+    let mut polls = app_state.polls.lock().await;
+    let poll = match polls.get_mut(&poll_id) {
+        Some(poll) if poll.is_participant(sender_session.user_id) => poll,
+        _ => return Err(ApiError::PollNotFound),
+    };
+    options.sort_unstable();
+    options.dedup();
+    if options.iter().any(|&option| option >= poll.options.len()) {
+        return Err(ApiError::invalid("Unknown poll option."));
+    }
+    if options.len() > 1 && !poll.multi_select {
+        return Err(ApiError::invalid("This poll allows a single option."));
+    }
+    if options.is_empty() {
+        poll.votes.remove(&sender_session.user_id);
+    } else {
+        poll.votes.insert(sender_session.user_id, options);
+    }
+    let update = ServerMessage::PollUpdate { poll_id, tallies: poll.tallies(), voters: poll.votes.len() };
+    let (creator_id, to_user_id) = (poll.creator_id, poll.to_user_id);
+    drop(polls);
+
+    send_to_conversation(app_state, creator_id, to_user_id, &update).await?;
+    Ok(())
+}
+
+/// Stores (or, for empty text, discards) the user's draft for a conversation and shows it on
+/// the user's other sessions.
+async fn save_draft(app_state: &Arc<AppState>, session: &UserSession, to_user_id: Uuid, text: String) -> Result<(), ApiError> {
+    let max_message_length = app_state.runtime.read().await.max_message_length;
+    if text.len() > max_message_length {
+        return Err(ApiError::MessageTooLong { max: max_message_length });
+    }
+    let updated_at = Utc::now();
+    let mut drafts = app_state.drafts.lock().await;
+    if text.is_empty() {
+        if drafts.remove(&(session.user_id, to_user_id)).is_none() {
+            return Ok(());
+        }
+    } else {
+        drafts.insert((session.user_id, to_user_id), Draft { text: text.clone(), updated_at });
+    }
+    drop(drafts);
+
+    let update = ServerMessage::DraftUpdated { to_user_id, text, updated_at: updated_at.to_rfc3339() };
+    if let Ok(json) = serde_json::to_string(&update) {
+        app_state.connections.send_to_other_sessions(session, &json);
+    }
+    Ok(())
+}
+
+/// Tells a newly opened connection which of the user's conversations have unread messages.
+fn send_unread_counts(conversations: &ConversationRegistry, session: &UserSession, connection: &ConnectionHandle) {
+    let conversations = conversations
+        .iter()
+        .filter(|((user_id, _), conversation)| *user_id == session.user_id && conversation.unread_count > 0)
+        .map(|((_, partner_id), conversation)| ConversationResponse::new(*partner_id, conversation))
+        .collect();
+    if let Ok(json) = serde_json::to_string(&ServerMessage::UnreadCounts { conversations }) {
+        let _ = connection.send(Message::text(json));
+    }
+}
+
+/// Sends every saved draft of the user to a newly opened connection.
+fn send_saved_drafts(drafts: &HashMap<(Uuid, Uuid), Draft>, session: &UserSession, connection: &ConnectionHandle) {
+    for ((user_id, to_user_id), draft) in drafts {
+        if *user_id != session.user_id {
+            continue;
+        }
+        let update = ServerMessage::DraftUpdated { to_user_id: *to_user_id, text: draft.text.clone(), updated_at: draft.updated_at.to_rfc3339() };
+        if let Ok(json) = serde_json::to_string(&update) {
+            let _ = connection.send(Message::text(json));
+        }
+    }
+}
+
+/// Shares a location in the conversation between the sender and `to_user_id`, delivered to
+/// both like a chat message. A location with `live_until` accepts updates until then.
+async fn share_location(
+    app_state: &Arc<AppState>,
+    sender_session: &UserSession,
+    to_user_id: Uuid,
+    lat: f64,
+    lon: f64,
+    accuracy: Option<f64>,
+    live_until: Option<String>,
+) -> Result<(), ApiError> {
+    if !location::is_valid_position(lat, lon, accuracy) {
+        return Err(ApiError::invalid("Invalid coordinates or accuracy."));
+    }
+    let now = Utc::now();
+    let live_until = match live_until {
+        Some(live_until) => {
+            let live_until = DateTime::parse_from_rfc3339(&live_until)
+                .map_err(|_| ApiError::invalid("live_until must be an RFC 3339 timestamp."))?
+                .with_timezone(&Utc);
+            if live_until <= now || live_until > now + location::MAX_LIVE_DURATION {
+                return Err(ApiError::invalid("live_until must be in the next 8 hours."));
+            }
+            Some(live_until)
+        }
+        None => None,
+    };
+
+    let location_id = Uuid::new_v4();
+    tracing::Span::current().record("message_id", tracing::field::display(location_id));
+    if let Some(live_until) = live_until {
+        let mut live_locations = app_state.live_locations.lock().await;
+        live_locations.retain(|_, live| live.live_until > now);
+        live_locations.insert(location_id, LiveLocation { sender_id: sender_session.user_id, to_user_id, live_until });
+    }
+    let stamp = app_state.clock.now();
+    let server_msg = ServerMessage::Location {
+        location_id,
+        from_user_id: sender_session.user_id,
+        from_username: sender_session.username.clone(),
+        to_user_id,
+        timestamp: stamp.rfc3339(),
+        hlc: stamp.to_string(),
+        lat,
+        lon,
+        accuracy,
+        live_until: live_until.map(|live_until| live_until.to_rfc3339()),
+    };
+    tracing::info!(user_id = %sender_session.user_id, to_user_id = %to_user_id, location_id = %location_id, live = live_until.is_some(), "Location shared");
+
+    let delivered = send_to_conversation(app_state, sender_session.user_id, to_user_id, &server_msg).await? > 0;
+    app_state.events.publish(DomainEvent::MessageSent {
+        kind: MessageKind::Location,
+        message_id: location_id.to_string(),
+        from_user_id: sender_session.user_id,
+        from_username: sender_session.username.clone(),
+        to_user_id,
+        text: None,
+        delivered,
+        notification: PushNotification::chat_message(
+            sender_session.user_id,
+            &sender_session.username,
+            &location_id.to_string(),
+            "📍 Shared a location",
+        ),
+    });
+    Ok(())
+}
+
+/// Moves a live location that the sender is still sharing.
+async fn update_live_location(
+    app_state: &Arc<AppState>,
+    sender_session: &UserSession,
+    location_id: Uuid,
+    lat: f64,
+    lon: f64,
+    accuracy: Option<f64>,
+) -> Result<(), ApiError> {
+    if !location::is_valid_position(lat, lon, accuracy) {
+        return Err(ApiError::invalid("Invalid coordinates or accuracy."));
+    }
+    let now = Utc::now();
+    let to_user_id = match app_state.live_locations.lock().await.get(&location_id) {
+        Some(live) if live.sender_id == sender_session.user_id && live.live_until > now => live.to_user_id,
+        _ => return Err(ApiError::LiveLocationNotFound),
+    };
+    let stamp = app_state.clock.now();
+    let update = ServerMessage::LocationUpdate {
+        location_id,
+        from_user_id: sender_session.user_id,
+        timestamp: stamp.rfc3339(),
+        hlc: stamp.to_string(),
+        lat,
+        lon,
+        accuracy,
+    };
+    send_to_conversation(app_state, sender_session.user_id, to_user_id, &update).await?;
+    Ok(())
+}
+
+/// Sends an event to every connection of both participants of a conversation, returning
+/// how many connections of `to_user_id` it was queued on.
+async fn send_to_conversation(app_state: &Arc<AppState>, from_user_id: Uuid, to_user_id: Uuid, server_msg: &ServerMessage) -> Result<usize, ApiError> {
+    let Ok(json) = serde_json::to_string(server_msg) else { return Ok(0) };
+    let frame = SharedFrame::new(json);
+    let state = app_state.clone();
+    let fanout = async move {
+        state.inject_fanout_faults().await;
+        let recipient_connections =
+            if state.drop_injected_send() { 0 } else { send_frame_to_user(&state, to_user_id, &frame).await };
+        if from_user_id != to_user_id {
+            send_frame_to_user(&state, from_user_id, &frame).await;
+        }
+        recipient_connections
+    };
+    app_state.ordering.run(from_user_id, to_user_id, fanout).await
+}
+
+/// Queues a frame on every connection of `user_id`, on this node and, with `[nats]`, on the
+/// others, returning how many connections it was queued on here; a user connected to
+/// another node counts as one more.
+async fn send_frame_to_user(app_state: &AppState, user_id: Uuid, frame: &Arc<SharedFrame>) -> usize {
+    let local_connections = app_state.connections.send_frame_to_user(user_id, frame).await;
+    #[cfg(feature = "nats")]
+    if nats::route_frame(app_state, user_id, frame, local_connections).await {
+        return local_connections + 1;
+    }
+    local_connections
+}
+
+/// Sends a message to each recipient of one of the sender's broadcast lists, as a separate
+/// chat message in each 1:1 conversation. The sender's sessions get a delivery receipt per
+/// recipient. Recipients the message could not be routed to are skipped.
+pub async fn send_broadcast(
+    app_state: &Arc<AppState>,
+    sender_session: &UserSession,
+    list_id: Uuid,
+    message: String,
+) -> Result<Vec<BroadcastReceiptResponse>, ApiError> {
+    if message.trim().is_empty() {
+        return Err(ApiError::invalid("message cannot be empty"));
+    }
+    let recipient_ids = match app_state.broadcast_lists.lock().await.get(&list_id) {
+        Some(list) if list.owner_user_id == sender_session.user_id => list.recipient_ids.clone(),
+        _ => return Err(ApiError::BroadcastListNotFound),
+    };
+
+    let mut receipts = Vec::with_capacity(recipient_ids.len());
+    for to_user_id in recipient_ids {
+        let routed = match route_signed_chat_message(app_state, sender_session, to_user_id, message.clone(), None).await {
+            Ok(routed) => routed,
+            Err(e) => {
+                tracing::warn!(user_id = %sender_session.user_id, to_user_id = %to_user_id, reason = %e, "Broadcast to recipient failed");
+                continue;
+            }
+        };
+        let receipt = ServerMessage::DeliveryReceipt { to_user_id, message_id: routed.message_id.clone(), delivered: routed.delivered };
+        if let Ok(json) = serde_json::to_string(&receipt) {
+            app_state.connections.send_to_user(sender_session.user_id, &json).await;
+        }
+        receipts.push(BroadcastReceiptResponse { to_user_id, message_id: routed.message_id, delivered: routed.delivered });
+    }
+    tracing::info!(user_id = %sender_session.user_id, list_id = %list_id, recipients = receipts.len(), "Broadcast sent");
+    Ok(receipts)
+}
+
+/// Routes an end-to-end encrypted message to every connection of the recipient. The server
+/// cannot read it, so it is neither echoed to the sender's sessions (which could not decrypt
+/// it either) nor passed on to webhooks and bridges; offline pushes only say that a message
+/// arrived. The sender's sessions get a delivery receipt instead. Returns the id assigned to
+/// the message.
+pub async fn route_encrypted_message(
+    app_state: &Arc<AppState>,
+    sender_session: &UserSession,
+    to_user_id: Uuid,
+    encrypted: EncryptedMessage,
+) -> Result<String, ApiError> {
+    // Base64 and the encryption protocol's own framing inflate the body, so the limit on
+    // plaintext messages is applied with headroom.
+    let max_length = app_state.runtime.read().await.max_message_length * 2;
+    if encrypted.ciphertext.len() + encrypted.header.len() > max_length {
+        return Err(ApiError::MessageTooLong { max: max_length });
+    }
+
+    // Sending the message ends the sender's typing state; clients hide the indicator when it arrives.
+    app_state.typing.lock().await.remove(&(sender_session.user_id, to_user_id));
+    let message_id = Uuid::new_v4().to_string();
+    tracing::Span::current().record("message_id", message_id.as_str());
+    let notification =
+        PushNotification::chat_message(sender_session.user_id, &sender_session.username, &message_id, "New encrypted message");
+    // Timestamped and delivered in conversation order, like plaintext messages.
+    let (state, sender, id) = (app_state.clone(), sender_session.clone(), message_id.clone());
+    let fanout = async move {
+        let stamp = state.clock.now();
+        let server_msg = ServerMessage::EncryptedMessage {
+            from_user_id: sender.user_id,
+            from_username: sender.username.clone(),
+            to_user_id,
+            message_id: id.clone(),
+            timestamp: stamp.rfc3339(),
+            hlc: stamp.to_string(),
+            ciphertext: encrypted.ciphertext,
+            header: encrypted.header,
+        };
+
+        let json = serde_json::to_string(&server_msg).map_err(|e| ApiError::Internal(format!("Failed to serialize message: {}", e)))?;
+
+        let recipient_connections = state.connections.send_to_user(to_user_id, &json).await;
+        let receipt = ServerMessage::DeliveryReceipt { to_user_id, message_id: id, delivered: recipient_connections > 0 };
+        if let Ok(json) = serde_json::to_string(&receipt) {
+            state.connections.send_to_user(sender.user_id, &json).await;
+        }
+        Ok(recipient_connections)
+    };
+    let recipient_connections = app_state.ordering.run(sender_session.user_id, to_user_id, fanout).await??;
+
+    app_state.events.publish(DomainEvent::MessageSent {
+        kind: MessageKind::Encrypted,
+        message_id: message_id.clone(),
+        from_user_id: sender_session.user_id,
+        from_username: sender_session.username.clone(),
+        to_user_id,
+        text: None,
+        delivered: recipient_connections > 0,
+        notification,
+    });
+    Ok(message_id)
+}
+
+/// Tells every connection of `to_user_id` whether the sender is typing to them.
+pub async fn send_typing_indicator(app_state: &Arc<AppState>, sender_session: &UserSession, to_user_id: Uuid, is_typing: bool) {
+    // Only changes are forwarded: repeated "typing" events (e.g. one per keystroke) just
+    // push back the expiry.
+    let timeout = Duration::from_secs(app_state.runtime.read().await.typing_timeout_secs);
+    let key = (sender_session.user_id, to_user_id);
+    let mut typing = app_state.typing.lock().await;
+    let changed = if is_typing {
+        typing.insert(key, Instant::now() + timeout).is_none()
+    } else {
+        typing.remove(&key).is_some()
+    };
+    drop(typing);
+    if !changed {
+        return;
+    }
+
+    forward_typing_indicator(app_state, sender_session.user_id, to_user_id, is_typing).await;
+    if is_typing {
+        tokio::spawn(expire_typing_indicator(app_state.clone(), key));
+    }
+}
+
+/// Reports the typist as no longer typing once the indicator expires, so a client that
+/// crashed mid-sentence doesn't leave the recipient's UI showing "typing…" forever.
+async fn expire_typing_indicator(app_state: Arc<AppState>, key: (Uuid, Uuid)) {
+    let mut deadline = Instant::now();
+    loop {
+        tokio::time::sleep_until(deadline.into()).await;
+        let mut typing = app_state.typing.lock().await;
+        match typing.get(&key) {
+            // Stopped explicitly, or by sending the message.
+            None => return,
+            Some(expires_at) if *expires_at > Instant::now() => deadline = *expires_at,
+            Some(_) => {
+                typing.remove(&key);
+                drop(typing);
+                forward_typing_indicator(&app_state, key.0, key.1, false).await;
+                return;
+            }
+        }
+    }
+}
+
+async fn forward_typing_indicator(app_state: &AppState, from_user_id: Uuid, to_user_id: Uuid, is_typing: bool) {
+    let server_msg = ServerMessage::TypingIndicator { from_user_id, is_typing };
+    if let Ok(json) = serde_json::to_string(&server_msg) {
+        // Typing indicators only go to sessions of the recipient user
+        app_state.connections.send_to_user(to_user_id, &json).await;
+    }
+}
+
+/// Starts ringing `to_user_id` for a new call, or tells the caller the call cannot start
+/// because either party is already in one. Calls still ringing after `RING_TIMEOUT` end.
+/// An offer for a call in progress renegotiates its media and is only relayed.
+async fn offer_call(app_state: &Arc<AppState>, sender_session: &UserSession, to_user_id: Uuid, call_id: Uuid, sdp: String) {
+    let mut calls = app_state.calls.lock().await;
+    if let Some(call) = calls.get(&call_id) {
+        let renegotiation = call.state == CallState::Active && call.peer_of(sender_session.user_id) == Some(to_user_id);
+        drop(calls);
+        if renegotiation {
+            let offer = ServerMessage::CallOffer {
+                from_user_id: sender_session.user_id,
+                from_username: sender_session.username.clone(),
+                call_id,
+                sdp,
+                renegotiation: true,
+            };
+            relay_call_signal(app_state, sender_session, to_user_id, &offer).await;
+        } else {
+            tracing::warn!(user_id = %sender_session.user_id, call_id = %call_id, "Dropping offer for a call not in progress");
+        }
+        return;
+    }
+    if to_user_id == sender_session.user_id {
+        tracing::warn!(user_id = %sender_session.user_id, call_id = %call_id, "Dropping invalid call offer");
+        return;
+    }
+    if calls::is_busy(&calls, sender_session.user_id) || calls::is_busy(&calls, to_user_id) {
+        drop(calls);
+        let busy = ServerMessage::CallEnded { call_id, reason: CallEndReason::Busy };
+        if let Ok(json) = serde_json::to_string(&busy) {
+            app_state.connections.send_to_user(sender_session.user_id, &json).await;
+        }
+        return;
+    }
+    calls.insert(call_id, Call::ringing(call_id, sender_session.user_id, to_user_id));
+    drop(calls);
+    tracing::info!(user_id = %sender_session.user_id, to_user_id = %to_user_id, call_id = %call_id, "Call ringing");
+
+    let offer = ServerMessage::CallOffer {
+        from_user_id: sender_session.user_id,
+        from_username: sender_session.username.clone(),
+        call_id,
+        sdp,
+        renegotiation: false,
+    };
+    relay_call_signal(app_state, sender_session, to_user_id, &offer).await;
+
+    let app_state = app_state.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(calls::RING_TIMEOUT).await;
+        end_call(&app_state, call_id, CallEndReason::Timeout, |call| call.state == CallState::Ringing).await;
+    });
+}
+
+/// Connects a ringing call answered by its callee and stops it ringing on the callee's
+/// other sessions. Answers to renegotiation offers of a call in progress are only relayed.
+async fn answer_call(app_state: &Arc<AppState>, sender_session: &UserSession, to_user_id: Uuid, call_id: Uuid, sdp: String) {
+    let mut calls = app_state.calls.lock().await;
+    match calls.get_mut(&call_id) {
+        Some(call) if call.state == CallState::Ringing && call.callee_id == sender_session.user_id && call.caller_id == to_user_id => {
+            call.state = CallState::Active;
+            call.answered_at = Some(Utc::now());
+        }
+        Some(call) if call.state == CallState::Active && call.peer_of(sender_session.user_id) == Some(to_user_id) => {
+            drop(calls);
+            let answer = ServerMessage::CallAnswer { from_user_id: sender_session.user_id, call_id, sdp };
+            relay_call_signal(app_state, sender_session, to_user_id, &answer).await;
+            return;
+        }
+        _ => {
+            tracing::warn!(user_id = %sender_session.user_id, call_id = %call_id, "Dropping answer for a call that is not ringing");
+            return;
+        }
+    }
+    drop(calls);
+    tracing::info!(user_id = %sender_session.user_id, call_id = %call_id, "Call answered");
+
+    let answer = ServerMessage::CallAnswer { from_user_id: sender_session.user_id, call_id, sdp };
+    relay_call_signal(app_state, sender_session, to_user_id, &answer).await;
+    let answered = ServerMessage::CallEnded { call_id, reason: CallEndReason::AnsweredElsewhere };
+    if let Ok(json) = serde_json::to_string(&answered) {
+        app_state.connections.send_to_other_sessions(sender_session, &json);
+    }
+}
+
+/// Ends a call if `condition` holds for it, notifying every session of both parties.
+async fn end_call(app_state: &Arc<AppState>, call_id: Uuid, reason: CallEndReason, condition: impl FnOnce(&Call) -> bool) {
+    let mut calls = app_state.calls.lock().await;
+    let Some(call) = calls.get(&call_id).filter(|call| condition(call)).cloned() else { return };
+    calls.remove(&call_id);
+    drop(calls);
+    tracing::info!(call_id = %call_id, reason = ?reason, "Call ended");
+    calls::record(&mut *app_state.call_history.lock().await, CallRecord::ended(&call, reason));
+
+    let ended = ServerMessage::CallEnded { call_id, reason };
+    if let Ok(json) = serde_json::to_string(&ended) {
+        app_state.connections.send_to_user(call.caller_id, &json).await;
+        app_state.connections.send_to_user(call.callee_id, &json).await;
+    }
+}
+
+/// Relays a WebRTC signaling message to every session of the other party of a call.
+async fn relay_call_signal(app_state: &Arc<AppState>, sender_session: &UserSession, to_user_id: Uuid, signal: &ServerMessage) {
+    let Ok(json) = serde_json::to_string(signal) else { return };
+    if app_state.connections.send_to_user(to_user_id, &json).await == 0 {
+        tracing::debug!(user_id = %sender_session.user_id, to_user_id = %to_user_id, "Call signal for an offline user dropped");
+    }
+}
+
+/// Broadcasts a user's status to all other connected clients. An online user whose
+/// do-not-disturb is on is announced as "dnd".
+pub async fn broadcast_status(app_state: &Arc<AppState>, session: &UserSession, status: &str) {
+    let status = match status {
+        "online" if push::is_dnd(app_state, session.user_id).await => {
+            app_state.dnd_presence.lock().await.insert(session.user_id);
+            "dnd"
+        }
+        _ => {
+            app_state.dnd_presence.lock().await.remove(&session.user_id);
+            status
+        }
+    };
+    #[cfg(feature = "mqtt")]
+    mqtt::publish_presence(app_state, session, status);
+    #[cfg(feature = "kafka")]
+    kafka::publish_presence(app_state, session, status);
+    presence::announce_status(app_state, session, status).await;
+    if status == "offline" {
+        if let Some(cluster) = &app_state.cluster {
+            if cluster.presence.remote_status(session.user_id).await.is_some() {
+                // Still connected to another node, which keeps it shown there.
+                return;
+            }
+        }
+    }
+    let status_msg = ServerMessage::StatusMessage {
+        user_id: session.user_id,
+        username: session.username.clone(),
+        status: status.to_string(),
+    };
+    if let Ok(text) = serde_json::to_string(&status_msg) {
+        app_state.inject_fanout_faults().await;
+        // Send to all *other* sessions of *other* users, or other sessions of the same user.
+        // A status update (online/offline) should typically be seen by everyone.
+        // The logic here is to send to all connections EXCEPT the one that triggered the broadcast.
+        let recipients = app_state
+            .connections
+            .snapshot()
+            .await
+            .into_iter()
+            .filter(|(other_session_key, _)| *other_session_key != session.session_key && !app_state.drop_injected_send())
+            .map(|(_, connection)| connection)
+            .collect();
+        // The workers queue it on every connection; this task moves on.
+        app_state.fanout.deliver(recipients, SharedFrame::new(text)).await;
+    }
+}
+
+/// Shows every connection that a user connected to another node changed to `status`.
+pub(crate) async fn broadcast_remote_status(app_state: &Arc<AppState>, user_id: Uuid, username: String, status: String) {
+    let status_msg = ServerMessage::StatusMessage { user_id, username, status };
+    if let Ok(text) = serde_json::to_string(&status_msg) {
+        let recipients = app_state.connections.snapshot().await.into_iter().map(|(_, connection)| connection).collect();
+        app_state.fanout.deliver(recipients, SharedFrame::new(text)).await;
+    }
+}
+
+/// Re-announces online users whose do-not-disturb turned on or off. Scheduled once a minute
+/// so quiet hours show in their presence as they start and end.
+pub async fn refresh_dnd_presence(app_state: Arc<AppState>) {
+    let mut online: HashMap<Uuid, String> = HashMap::new();
+    for (_, connection) in app_state.connections.snapshot().await {
+        online.entry(connection.user_id).or_insert_with(|| connection.session_key.clone());
+    }
+    for session_key in online.into_values() {
+        let Some(session) = app_state.session_for_key(&session_key).await else { continue };
+        let announced = app_state.dnd_presence.lock().await.contains(&session.user_id);
+        if push::is_dnd(&app_state, session.user_id).await != announced {
+            broadcast_status(&app_state, &session, "online").await;
+        }
+    }
+}
+
+/// Expires stale sessions: sessions past their TTL, and sessions whose WebSocket never
+/// connected or disconnected longer than the idle timeout ago. Scheduled once a minute.
+pub async fn sweep_idle_sessions(app_state: Arc<AppState>) {
+    let expired = app_state.expire_idle_sessions().await;
+    if expired > 0 {
+        tracing::info!(expired, "Expired stale sessions");
+    }
+}
+
+/// Publishes that a user came online.
+pub fn emit_user_online(app_state: &AppState, session: &UserSession) {
+    app_state.events.publish(DomainEvent::UserOnline { user_id: session.user_id, username: session.username.clone() });
+}
+
+/// Sends an announcement to every active connection, returning how many received it.
+pub(crate) async fn broadcast_announcement(app_state: &Arc<AppState>, announcement: &ServerMessage) -> usize {
+    let text = match serde_json::to_string(announcement) {
+        Ok(text) => text,
+        Err(e) => {
+            tracing::error!(error = %e, "Error serializing announcement");
+            return 0;
+        }
+    };
+    app_state.connections.broadcast(text).await
+}
+
+fn banner_message(banner: &BannerConfig) -> ServerMessage {
+    ServerMessage::Announcement {
+        title: banner.title.clone(),
+        body: banner.body.clone(),
+        severity: banner.severity,
+    }
+}
+
+/// Broadcasts a configured banner to every active connection (used when it changes on reload).
+pub async fn broadcast_banner(app_state: &Arc<AppState>, banner: &BannerConfig) {
+    let delivered = broadcast_announcement(app_state, &banner_message(banner)).await;
+    tracing::info!(delivered, "Updated banner broadcast");
+}
+
+/// Whether `username` has the shape of a Matrix user ID (`@localpart:server`). Such names are
+/// reserved for ghost users of the Matrix bridge, whether or not it is enabled.
+pub(crate) fn is_matrix_id(username: &str) -> bool {
+    username
+        .strip_prefix('@')
+        .and_then(|rest| rest.split_once(':'))
+        .is_some_and(|(localpart, server)| !localpart.is_empty() && !server.is_empty())
+}
@@ -0,0 +1,746 @@
+// src/xmpp.rs
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufRead, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+use uuid::Uuid;
+
+use crate::connections::{self, ConnectionReceiver};
+use crate::ip_filter;
+use crate::redact::pseudonym;
+use crate::handlers;
+use crate::ws_handlers::{self, AppState, UserSession};
+
+const NS_STREAMS: &str = "urn:ietf:params:xml:ns:xmpp-streams";
+const NS_STANZAS: &str = "urn:ietf:params:xml:ns:xmpp-stanzas";
+const NS_SASL: &str = "urn:ietf:params:xml:ns:xmpp-sasl";
+const NS_BIND: &str = "urn:ietf:params:xml:ns:xmpp-bind";
+const NS_SESSION: &str = "urn:ietf:params:xml:ns:xmpp-session";
+const NS_ROSTER: &str = "jabber:iq:roster";
+const NS_PING: &str = "urn:xmpp:ping";
+const NS_DISCO_INFO: &str = "http://jabber.org/protocol/disco#info";
+const NS_CHAT_STATES: &str = "http://jabber.org/protocol/chatstates";
+
+/// Largest stanza accepted from a client, in bytes.
+const MAX_STANZA_BYTES: u64 = 256 * 1024;
+/// Deepest element nesting accepted inside a stanza. Real stanzas stay far below it; the
+/// limit keeps a flood of opening tags from building a tree too deep to drop safely.
+const MAX_STANZA_DEPTH: usize = 32;
+/// Time a client gets to authenticate and bind a resource.
+const NEGOTIATION_TIMEOUT: Duration = Duration::from_secs(30);
+/// Failed SASL attempts before the stream is closed.
+const MAX_AUTH_ATTEMPTS: u32 = 3;
+
+/// Why a client stream ended abnormally.
+#[derive(Debug)]
+enum StreamError {
+    Io(std::io::Error),
+    Xml(quick_xml::Error),
+    // The client broke the protocol; sent back as a stream error with this condition.
+    Protocol(&'static str),
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamError::Io(e) => write!(f, "I/O error: {}", e),
+            StreamError::Xml(e) => write!(f, "malformed XML: {}", e),
+            StreamError::Protocol(condition) => write!(f, "protocol violation: {}", condition),
+        }
+    }
+}
+
+impl From<std::io::Error> for StreamError {
+    fn from(e: std::io::Error) -> Self {
+        StreamError::Io(e)
+    }
+}
+
+impl From<quick_xml::Error> for StreamError {
+    fn from(e: quick_xml::Error) -> Self {
+        StreamError::Xml(e)
+    }
+}
+
+/// A parsed stanza (or any other top-level element of the stream). Namespace prefixes are
+/// dropped; `xmlns` is kept as a plain attribute.
+#[derive(Debug, Default)]
+struct Element {
+    name: String,
+    attrs: Vec<(String, String)>,
+    children: Vec<Element>,
+    text: String,
+}
+
+impl Element {
+    fn from_start(start: &BytesStart) -> Result<Self, StreamError> {
+        let mut attrs = Vec::new();
+        for attr in start.attributes() {
+            let attr = attr.map_err(quick_xml::Error::from)?;
+            let key = String::from_utf8_lossy(attr.key.local_name().as_ref()).into_owned();
+            attrs.push((key, attr.unescape_value()?.into_owned()));
+        }
+        let name = String::from_utf8_lossy(start.local_name().as_ref()).into_owned();
+        Ok(Element { name, attrs, ..Default::default() })
+    }
+
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.name == name)
+    }
+
+    fn child_ns(&self, name: &str, xmlns: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.name == name && child.attr("xmlns") == Some(xmlns))
+    }
+}
+
+/// What the client sent at the top level of its stream.
+enum StreamEvent {
+    // A stream header (sent at the start and again after authentication).
+    Open(Element),
+    Stanza(Element),
+    Close,
+}
+
+/// Incremental parser turning the client's XML stream into stream events.
+struct XmlStream<R> {
+    reader: Reader<R>,
+    buf: Vec<u8>,
+    // Elements of the stanza being parsed, outermost first.
+    stack: Vec<Element>,
+    stanza_start: u64,
+}
+
+impl<R: AsyncBufRead + Unpin> XmlStream<R> {
+    fn new(input: R) -> Self {
+        let mut reader = Reader::from_reader(input);
+        // Every restart opens another <stream:stream> that is never closed.
+        reader.config_mut().check_end_names = false;
+        reader.config_mut().trim_text(true);
+        XmlStream { reader, buf: Vec::new(), stack: Vec::new(), stanza_start: 0 }
+    }
+
+    /// Returns the next top-level event, or `None` when the client closed the connection.
+    async fn next(&mut self) -> Result<Option<StreamEvent>, StreamError> {
+        loop {
+            if !self.stack.is_empty() && self.reader.buffer_position() - self.stanza_start > MAX_STANZA_BYTES {
+                return Err(StreamError::Protocol("policy-violation"));
+            }
+            self.buf.clear();
+            match self.reader.read_event_into_async(&mut self.buf).await? {
+                Event::Start(start) if start.local_name().as_ref() == b"stream" && self.stack.is_empty() => {
+                    return Ok(Some(StreamEvent::Open(Element::from_start(&start)?)));
+                }
+                Event::Start(start) => {
+                    if self.stack.is_empty() {
+                        self.stanza_start = self.reader.buffer_position();
+                    } else if self.stack.len() >= MAX_STANZA_DEPTH {
+                        return Err(StreamError::Protocol("policy-violation"));
+                    }
+                    let element = Element::from_start(&start)?;
+                    self.stack.push(element);
+                }
+                Event::Empty(start) => {
+                    let element = Element::from_start(&start)?;
+                    match self.stack.last_mut() {
+                        Some(parent) => parent.children.push(element),
+                        None => return Ok(Some(StreamEvent::Stanza(element))),
+                    }
+                }
+                Event::Text(text) => {
+                    if let Some(element) = self.stack.last_mut() {
+                        element.text.push_str(&text.unescape()?);
+                    }
+                }
+                Event::CData(data) => {
+                    if let Some(element) = self.stack.last_mut() {
+                        element.text.push_str(&String::from_utf8_lossy(&data));
+                    }
+                }
+                Event::End(_) => match self.stack.pop() {
+                    None => return Ok(Some(StreamEvent::Close)),
+                    Some(element) => match self.stack.last_mut() {
+                        Some(parent) => parent.children.push(element),
+                        None => return Ok(Some(StreamEvent::Stanza(element))),
+                    },
+                },
+                Event::Eof => return Ok(None),
+                // XML declaration, comments, processing instructions.
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Parses `input` as a client stream until it ends or fails. Used by the fuzz targets.
+pub(crate) async fn parse_stream(input: &[u8]) {
+    let mut stream = XmlStream::new(input);
+    while let Ok(Some(_)) = stream.next().await {}
+}
+
+/// Events parsed from the client stream, read by a task of their own: a parse in progress
+/// cannot be cancelled without losing input, so it must not race against outgoing events.
+type EventReceiver = mpsc::Receiver<Result<StreamEvent, StreamError>>;
+
+fn spawn_reader(mut stream: XmlStream<BufReader<OwnedReadHalf>>) -> (EventReceiver, JoinHandle<()>) {
+    let (tx, rx) = mpsc::channel(16);
+    let reader = tokio::spawn(async move {
+        loop {
+            let event = stream.next().await;
+            let done = !matches!(event, Ok(Some(_)));
+            if let Some(event) = event.transpose() {
+                if tx.send(event).await.is_err() {
+                    break;
+                }
+            }
+            if done {
+                break;
+            }
+        }
+    });
+    (rx, reader)
+}
+
+/// Escapes a username into a JID localpart (XEP-0106), so names with spaces, `@` or `/` still
+/// form valid JIDs.
+fn escape_node(username: &str) -> String {
+    let mut node = String::with_capacity(username.len());
+    for c in username.chars() {
+        match c {
+            ' ' => node.push_str("\\20"),
+            '"' => node.push_str("\\22"),
+            '&' => node.push_str("\\26"),
+            '\'' => node.push_str("\\27"),
+            '/' => node.push_str("\\2f"),
+            ':' => node.push_str("\\3a"),
+            '<' => node.push_str("\\3c"),
+            '>' => node.push_str("\\3e"),
+            '@' => node.push_str("\\40"),
+            '\\' => node.push_str("\\5c"),
+            _ => node.push(c),
+        }
+    }
+    node
+}
+
+/// Reverses `escape_node`. Backslashes not followed by a known escape are kept as they are.
+fn unescape_node(node: &str) -> String {
+    let mut username = String::with_capacity(node.len());
+    let mut rest = node;
+    while let Some(pos) = rest.find('\\') {
+        username.push_str(&rest[..pos]);
+        let escaped = rest.get(pos + 1..pos + 3).and_then(|hex| match hex.to_ascii_lowercase().as_str() {
+            "20" => Some(' '),
+            "22" => Some('"'),
+            "26" => Some('&'),
+            "27" => Some('\''),
+            "2f" => Some('/'),
+            "3a" => Some(':'),
+            "3c" => Some('<'),
+            "3e" => Some('>'),
+            "40" => Some('@'),
+            "5c" => Some('\\'),
+            _ => None,
+        });
+        match escaped {
+            Some(c) => {
+                username.push(c);
+                rest = &rest[pos + 3..];
+            }
+            None => {
+                username.push('\\');
+                rest = &rest[pos + 1..];
+            }
+        }
+    }
+    username.push_str(rest);
+    username
+}
+
+/// The localpart of `jid` if it is a user JID on `domain` (the resource is ignored).
+fn local_node<'a>(jid: &'a str, domain: &str) -> Option<&'a str> {
+    let bare = jid.split('/').next().unwrap_or(jid);
+    let (node, jid_domain) = bare.split_once('@')?;
+    (jid_domain.eq_ignore_ascii_case(domain) && !node.is_empty()).then_some(node)
+}
+
+fn stream_header(domain: &str, stream_id: &str) -> String {
+    format!(
+        "<?xml version='1.0'?><stream:stream xmlns='jabber:client' xmlns:stream='http://etherx.jabber.org/streams' \
+         id='{}' from='{}' version='1.0' xml:lang='en'>",
+        stream_id,
+        escape(domain)
+    )
+}
+
+fn stream_error(condition: &str) -> String {
+    format!("<stream:error><{} xmlns='{}'/></stream:error></stream:stream>", condition, NS_STREAMS)
+}
+
+fn stanza_error(kind: &str, id: Option<&str>, to: &str, from: Option<&str>, error_type: &str, condition: &str) -> String {
+    let id = id.map(|id| format!(" id='{}'", escape(id))).unwrap_or_default();
+    let from = from.map(|from| format!(" from='{}'", escape(from))).unwrap_or_default();
+    format!(
+        "<{kind} type='error'{id} to='{to}'{from}><error type='{error_type}'><{condition} xmlns='{NS_STANZAS}'/></error></{kind}>",
+        to = escape(to),
+    )
+}
+
+/// One authenticated client stream.
+struct XmppSession {
+    app_state: Arc<AppState>,
+    session: UserSession,
+    domain: String,
+    // Full JID bound to this stream.
+    jid: String,
+    writer: OwnedWriteHalf,
+    // Set once the client sent its initial presence; status updates are only pushed after it.
+    available: bool,
+    // Usernames of users this stream has talked about, by id.
+    usernames: HashMap<Uuid, String>,
+}
+
+impl XmppSession {
+    fn user_jid(&self, username: &str) -> String {
+        format!("{}@{}", escape_node(username), self.domain)
+    }
+
+    async fn write(&mut self, xml: &str) -> Result<(), StreamError> {
+        self.writer.write_all(xml.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Resolves a user id to a username through the user's contacts.
+    async fn username_for(&mut self, user_id: Uuid) -> Option<String> {
+        if !self.usernames.contains_key(&user_id) {
+            let contacts = handlers::contacts::list_contacts(&self.app_state, &self.session).await.ok()?;
+            self.usernames.extend(contacts.into_iter().map(|contact| (contact.id, contact.username)));
+        }
+        self.usernames.get(&user_id).cloned()
+    }
+
+    /// Resolves the local user addressed by a JID.
+    async fn user_id_for(&self, jid: &str) -> Option<Uuid> {
+        let username = unescape_node(local_node(jid, &self.domain)?);
+        let users = &self.app_state.users;
+        users.get(&username).or_else(|| users.find(|user| user.username.eq_ignore_ascii_case(&username))).map(|user| user.id)
+    }
+
+    /// Translates an event queued for this connection (a frame of the WebSocket protocol)
+    /// into a stanza.
+    async fn deliver(&mut self, event: &Value) -> Result<(), StreamError> {
+        let user_id = |field: &str| event[field].as_str().and_then(|id| Uuid::parse_str(id).ok());
+        match event["type"].as_str() {
+            Some("chatMessage") => {
+                let (Some(from_user_id), Some(to_user_id)) = (user_id("from_user_id"), user_id("to_user_id")) else {
+                    return Ok(());
+                };
+                // The echo of a message this user sent to someone else.
+                if from_user_id == self.session.user_id && to_user_id != self.session.user_id {
+                    return Ok(());
+                }
+                let from_username = event["from_username"].as_str().unwrap_or_default().to_string();
+                self.usernames.insert(from_user_id, from_username.clone());
+                let stanza = format!(
+                    "<message type='chat' id='{}' from='{}' to='{}'><body>{}</body><active xmlns='{}'/></message>",
+                    escape(event["message_id"].as_str().unwrap_or_default()),
+                    escape(self.user_jid(&from_username)),
+                    escape(&self.jid),
+                    escape(event["message"].as_str().unwrap_or_default()),
+                    NS_CHAT_STATES,
+                );
+                self.write(&stanza).await
+            }
+            Some("typingIndicator") => {
+                let Some(from_user_id) = user_id("from_user_id") else { return Ok(()) };
+                let Some(from_username) = self.username_for(from_user_id).await else { return Ok(()) };
+                let state = if event["is_typing"].as_bool() == Some(true) { "composing" } else { "paused" };
+                let stanza = format!(
+                    "<message type='chat' from='{}' to='{}'><{} xmlns='{}'/></message>",
+                    escape(self.user_jid(&from_username)),
+                    escape(&self.jid),
+                    state,
+                    NS_CHAT_STATES,
+                );
+                self.write(&stanza).await
+            }
+            Some("statusMessage") if self.available => {
+                let Some(status_user_id) = user_id("user_id") else { return Ok(()) };
+                if status_user_id == self.session.user_id || self.username_for(status_user_id).await.is_none() {
+                    return Ok(());
+                }
+                let username = event["username"].as_str().unwrap_or_default();
+                let kind = if event["status"] == "offline" { " type='unavailable'" } else { "" };
+                let stanza = format!("<presence{} from='{}' to='{}'/>", kind, escape(self.user_jid(username)), escape(&self.jid));
+                self.write(&stanza).await
+            }
+            Some("announcement") => {
+                let stanza = format!(
+                    "<message type='headline' from='{}' to='{}'><subject>{}</subject><body>{}</body></message>",
+                    escape(&self.domain),
+                    escape(&self.jid),
+                    escape(event["title"].as_str().unwrap_or_default()),
+                    escape(event["body"].as_str().unwrap_or_default()),
+                );
+                self.write(&stanza).await
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn handle_stanza(&mut self, stanza: Element) -> Result<(), StreamError> {
+        match stanza.name.as_str() {
+            "message" => self.handle_message(stanza).await,
+            "presence" => self.handle_presence(stanza).await,
+            "iq" => self.handle_iq(stanza).await,
+            _ => Err(StreamError::Protocol("unsupported-stanza-type")),
+        }
+    }
+
+    async fn handle_message(&mut self, stanza: Element) -> Result<(), StreamError> {
+        if matches!(stanza.attr("type"), Some("error" | "groupchat" | "headline")) {
+            return Ok(());
+        }
+        let to = stanza.attr("to").unwrap_or_default().to_string();
+        let Some(to_user_id) = self.user_id_for(&to).await else {
+            let error = stanza_error("message", stanza.attr("id"), &self.jid, Some(&to), "cancel", "item-not-found");
+            return self.write(&error).await;
+        };
+
+        if let Some(body) = stanza.child("body").filter(|body| !body.text.is_empty()) {
+            if let Err(e) = ws_handlers::route_chat_message(&self.app_state, &self.session, to_user_id, body.text.clone()).await {
+                tracing::warn!(user_id = %self.session.user_id, reason = %e, "Dropping XMPP message");
+                let error = stanza_error("message", stanza.attr("id"), &self.jid, Some(&to), "modify", "not-acceptable");
+                return self.write(&error).await;
+            }
+        } else if stanza.child_ns("composing", NS_CHAT_STATES).is_some() {
+            ws_handlers::send_typing_indicator(&self.app_state, &self.session, to_user_id, true).await;
+        } else if stanza.children.iter().any(|child| child.attr("xmlns") == Some(NS_CHAT_STATES)) {
+            ws_handlers::send_typing_indicator(&self.app_state, &self.session, to_user_id, false).await;
+        }
+        Ok(())
+    }
+
+    async fn handle_presence(&mut self, stanza: Element) -> Result<(), StreamError> {
+        // Subscriptions are managed through the contacts API; directed presence is not relayed.
+        if stanza.attr("to").is_some() {
+            return Ok(());
+        }
+        match stanza.attr("type") {
+            None if !self.available => {
+                self.available = true;
+                self.send_contact_presence().await?;
+                ws_handlers::broadcast_status(&self.app_state, &self.session, "online").await;
+                ws_handlers::emit_user_online(&self.app_state, &self.session);
+            }
+            Some("unavailable") if self.available => {
+                self.available = false;
+                ws_handlers::broadcast_status(&self.app_state, &self.session, "offline").await;
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Sends the presence of every contact that currently has a connection.
+    async fn send_contact_presence(&mut self) -> Result<(), StreamError> {
+        let contacts = handlers::contacts::list_contacts(&self.app_state, &self.session).await.unwrap_or_default();
+        let connections = self.app_state.connections.snapshot().await;
+        let online: Vec<String> = contacts
+            .into_iter()
+            .filter(|contact| connections.iter().any(|(_, connection)| connection.user_id == contact.id))
+            .map(|contact| contact.username)
+            .collect();
+        for username in online {
+            let stanza = format!("<presence from='{}' to='{}'/>", escape(self.user_jid(&username)), escape(&self.jid));
+            self.write(&stanza).await?;
+        }
+        Ok(())
+    }
+
+    async fn handle_iq(&mut self, stanza: Element) -> Result<(), StreamError> {
+        let id = stanza.attr("id").unwrap_or_default();
+        let kind = stanza.attr("type").unwrap_or_default();
+        // Results and errors answer requests this server never sends.
+        if !matches!(kind, "get" | "set") {
+            return Ok(());
+        }
+        let result = |payload: &str| {
+            format!("<iq type='result' id='{}' to='{}'>{}</iq>", escape(id), escape(&self.jid), payload)
+        };
+
+        let response = if let Some(query) = stanza.child_ns("query", NS_ROSTER) {
+            if kind == "get" {
+                let contacts = handlers::contacts::list_contacts(&self.app_state, &self.session).await.unwrap_or_default();
+                let items: String = contacts
+                    .iter()
+                    .map(|contact| {
+                        format!(
+                            "<item jid='{}' name='{}' subscription='both'/>",
+                            escape(self.user_jid(&contact.username)),
+                            escape(&contact.username)
+                        )
+                    })
+                    .collect();
+                self.usernames.extend(contacts.into_iter().map(|contact| (contact.id, contact.username)));
+                result(&format!("<query xmlns='{}'>{}</query>", NS_ROSTER, items))
+            } else {
+                // Contacts are added through the HTTP API.
+                tracing::debug!(user_id = %self.session.user_id, items = query.children.len(), "Rejecting XMPP roster change");
+                stanza_error("iq", Some(id), &self.jid, None, "cancel", "not-allowed")
+            }
+        } else if stanza.child_ns("ping", NS_PING).is_some() || stanza.child_ns("session", NS_SESSION).is_some() {
+            result("")
+        } else if kind == "get" && stanza.child_ns("query", NS_DISCO_INFO).is_some() {
+            let features: String = [NS_DISCO_INFO, NS_PING, NS_ROSTER, NS_CHAT_STATES]
+                .iter()
+                .map(|feature| format!("<feature var='{}'/>", feature))
+                .collect();
+            result(&format!(
+                "<query xmlns='{}'><identity category='server' type='im' name='rust_chat'/>{}</query>",
+                NS_DISCO_INFO, features
+            ))
+        } else {
+            stanza_error("iq", Some(id), &self.jid, None, "cancel", "service-unavailable")
+        };
+        self.write(&response).await
+    }
+}
+
+/// Decodes a SASL PLAIN response (`authzid \0 authcid \0 password`) into username and password.
+pub(crate) fn decode_plain(response: &str, domain: &str) -> Option<(String, String)> {
+    let decoded = String::from_utf8(STANDARD.decode(response.trim()).ok()?).ok()?;
+    let mut parts = decoded.split('\0');
+    let (authzid, authcid, password) = (parts.next()?, parts.next()?, parts.next()?);
+    let username = unescape_node(authcid);
+    // An authorization identity is only accepted when it names the authenticating user.
+    if !authzid.is_empty() && local_node(authzid, domain).map(unescape_node).as_deref() != Some(username.as_str()) {
+        return None;
+    }
+    Some((username, password.to_string()))
+}
+
+/// Runs stream negotiation: SASL PLAIN authentication, the stream restart and resource
+/// binding. Returns the logged-in session and the bound full JID.
+async fn negotiate(
+    app_state: &Arc<AppState>,
+    domain: &str,
+    peer: SocketAddr,
+    events: &mut EventReceiver,
+    writer: &mut OwnedWriteHalf,
+) -> Result<(UserSession, String), StreamError> {
+    let mut session = None;
+    let mut auth_attempts = 0;
+    loop {
+        let Some(event) = events.recv().await.transpose()? else { return Err(StreamError::Protocol("connection closed")) };
+        match event {
+            StreamEvent::Open(header) => {
+                writer.write_all(stream_header(domain, &Uuid::new_v4().simple().to_string()).as_bytes()).await?;
+                if !header.attr("to").is_some_and(|to| to.eq_ignore_ascii_case(domain)) {
+                    return Err(StreamError::Protocol("host-unknown"));
+                }
+                let features = if session.is_none() {
+                    format!("<mechanisms xmlns='{}'><mechanism>PLAIN</mechanism></mechanisms>", NS_SASL)
+                } else {
+                    format!("<bind xmlns='{}'/><session xmlns='{}'><optional/></session>", NS_BIND, NS_SESSION)
+                };
+                writer.write_all(format!("<stream:features>{}</stream:features>", features).as_bytes()).await?;
+            }
+            StreamEvent::Stanza(auth) if auth.name == "auth" && session.is_none() => {
+                let credentials = match auth.attr("mechanism") {
+                    Some("PLAIN") => decode_plain(&auth.text, domain),
+                    _ => None,
+                };
+                let logged_in = match credentials {
+                    Some((username, password)) => {
+                        handlers::auth::password_login(app_state, &username, &password, Some(peer.ip())).await
+                    }
+                    None => None,
+                };
+                match logged_in {
+                    Some(logged_in) => {
+                        tracing::info!(user_id = %logged_in.user_id, username = %pseudonym(&logged_in.username), client_ip = %pseudonym(peer.ip()), "Logged in user over XMPP");
+                        writer.write_all(format!("<success xmlns='{}'/>", NS_SASL).as_bytes()).await?;
+                        session = Some(logged_in);
+                    }
+                    None => {
+                        auth_attempts += 1;
+                        tracing::warn!(client_ip = %pseudonym(peer.ip()), "Failed XMPP login");
+                        writer.write_all(format!("<failure xmlns='{}'><not-authorized/></failure>", NS_SASL).as_bytes()).await?;
+                        if auth_attempts >= MAX_AUTH_ATTEMPTS {
+                            return Err(StreamError::Protocol("policy-violation"));
+                        }
+                    }
+                }
+            }
+            StreamEvent::Stanza(iq) if iq.name == "iq" => {
+                let (Some(session), Some(bind)) = (session, iq.child_ns("bind", NS_BIND)) else {
+                    return Err(StreamError::Protocol("not-authorized"));
+                };
+                let resource = bind
+                    .child("resource")
+                    .map(|resource| resource.text.clone())
+                    .filter(|resource| !resource.is_empty())
+                    .unwrap_or_else(|| Uuid::new_v4().simple().to_string());
+                let jid = format!("{}@{}/{}", escape_node(&session.username), domain, resource);
+                let result = format!(
+                    "<iq type='result' id='{}'><bind xmlns='{}'><jid>{}</jid></bind></iq>",
+                    escape(iq.attr("id").unwrap_or_default()),
+                    NS_BIND,
+                    escape(&jid)
+                );
+                writer.write_all(result.as_bytes()).await?;
+                return Ok((session, jid));
+            }
+            StreamEvent::Stanza(_) => return Err(StreamError::Protocol("not-authorized")),
+            StreamEvent::Close => return Err(StreamError::Protocol("connection closed")),
+        }
+    }
+}
+
+/// Handles an authenticated stream until either side closes it.
+async fn run_session(
+    xmpp: &mut XmppSession,
+    events: &mut EventReceiver,
+    receiver: &mut ConnectionReceiver,
+) -> Result<(), StreamError> {
+    let banner = xmpp.app_state.runtime.read().await.banner.clone();
+    if let Some(banner) = banner {
+        let announcement = serde_json::json!({ "type": "announcement", "title": banner.title, "body": banner.body });
+        xmpp.deliver(&announcement).await?;
+    }
+
+    // Per-connection rate limiting over fixed one-minute windows, as for WebSockets.
+    let mut window_started = Instant::now();
+    let mut stanzas_in_window: u32 = 0;
+    loop {
+        tokio::select! {
+            event = events.recv() => match event.transpose()? {
+                Some(StreamEvent::Stanza(stanza)) => {
+                    let max_per_minute = xmpp.app_state.runtime.read().await.max_messages_per_minute;
+                    if window_started.elapsed() >= Duration::from_secs(60) {
+                        window_started = Instant::now();
+                        stanzas_in_window = 0;
+                    }
+                    stanzas_in_window += 1;
+                    if max_per_minute > 0 && stanzas_in_window > max_per_minute {
+                        tracing::warn!(user_id = %xmpp.session.user_id, "Dropping XMPP stanza: rate limit exceeded");
+                        continue;
+                    }
+                    xmpp.handle_stanza(stanza).await?;
+                }
+                Some(StreamEvent::Open(_)) => return Err(StreamError::Protocol("invalid-xml")),
+                Some(StreamEvent::Close) | None => {
+                    let _ = xmpp.write("</stream:stream>").await;
+                    return Ok(());
+                }
+            },
+            frame = receiver.recv_text() => match frame {
+                // Parsed once for all the XMPP clients it is fanned out to.
+                Some(frame) => {
+                    if let Some(event) = frame.event() {
+                        xmpp.deliver(event).await?;
+                    }
+                }
+                // The session was replaced by a newer login.
+                None => return Err(StreamError::Protocol("conflict")),
+            },
+        }
+    }
+}
+
+async fn handle_connection(app_state: Arc<AppState>, domain: String, socket: TcpStream, peer: SocketAddr) {
+    let (read_half, mut writer) = socket.into_split();
+    let (mut events, reader) = spawn_reader(XmlStream::new(BufReader::new(read_half)));
+    let negotiated = tokio::time::timeout(NEGOTIATION_TIMEOUT, negotiate(&app_state, &domain, peer, &mut events, &mut writer)).await;
+    let (session, jid) = match negotiated {
+        Ok(Ok(negotiated)) => negotiated,
+        Ok(Err(e)) => {
+            tracing::debug!(client_ip = %pseudonym(peer.ip()), error = %e, "XMPP negotiation failed");
+            if let StreamError::Protocol(condition) = e {
+                let _ = writer.write_all(stream_error(condition).as_bytes()).await;
+            }
+            reader.abort();
+            return;
+        }
+        Err(_) => {
+            let _ = writer.write_all(stream_error("connection-timeout").as_bytes()).await;
+            reader.abort();
+            return;
+        }
+    };
+
+    // Register the stream like any other connection, under its own key.
+    let connection_key = format!("xmpp:{}", Uuid::new_v4());
+    let (connection, mut receiver) = connections::connection_channel(&session, Some(peer.ip()));
+    ws_handlers::register_connection(&app_state, connection_key.clone(), connection);
+    tracing::info!(user_id = %session.user_id, jid = %pseudonym(&jid), client_ip = %pseudonym(peer.ip()), "XMPP client connected");
+
+    let mut xmpp = XmppSession {
+        app_state: app_state.clone(),
+        session,
+        domain,
+        jid,
+        writer,
+        available: false,
+        usernames: HashMap::new(),
+    };
+    if let Err(e) = run_session(&mut xmpp, &mut events, &mut receiver).await {
+        tracing::debug!(user_id = %xmpp.session.user_id, error = %e, "XMPP stream closed with an error");
+        if let StreamError::Protocol(condition) = e {
+            let _ = xmpp.write(&stream_error(condition)).await;
+        }
+    }
+
+    // -- Cleanup on Disconnect --
+    reader.abort();
+    ws_handlers::remove_connection(&app_state, &connection_key, xmpp.session.user_id);
+    // The session was created for this stream alone.
+    app_state.user_sessions.lock(&xmpp.session.session_key).await.remove(&xmpp.session.session_key);
+    if xmpp.available && !app_state.connections.is_online(xmpp.session.user_id).await {
+        ws_handlers::broadcast_status(&app_state, &xmpp.session, "offline").await;
+    }
+    tracing::info!(user_id = %xmpp.session.user_id, jid = %pseudonym(&xmpp.jid), "XMPP client disconnected");
+}
+
+/// Accepts XMPP client connections on `addr` until the process exits.
+pub async fn serve(app_state: Arc<AppState>, addr: SocketAddr, domain: String) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            tracing::error!(addr = %addr, error = %e, "Cannot bind the XMPP listener");
+            return;
+        }
+    };
+    tracing::info!(addr = %addr, domain = %domain, "Starting XMPP gateway");
+    loop {
+        match listener.accept().await {
+            Ok((socket, peer)) => {
+                if !ip_filter::permits(&*app_state.ip_rules.lock().await, Some(peer.ip())) {
+                    tracing::debug!(client_ip = %pseudonym(peer.ip()), "XMPP connection refused: IP not allowed");
+                    continue;
+                }
+                let connection = handle_connection(app_state.clone(), domain.clone(), socket, peer);
+                tokio::spawn(connection.instrument(tracing::info_span!("xmpp_connection", client_ip = %pseudonym(peer.ip()))));
+            }
+            Err(e) => tracing::warn!(error = %e, "Error accepting XMPP connection"),
+        }
+    }
+}
@@ -0,0 +1,1321 @@
+// tests/chat.rs
+
+// Driving the combined warp route filter nests deeper than the default limit allows.
+#![recursion_limit = "256"]
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ed25519_dalek::{Signer, SigningKey};
+use futures::future::BoxFuture;
+use rust_chat::events::{DomainEvent, MessageKind};
+use rust_chat::interceptors::{InterceptedMessage, MessageInterceptor};
+use rust_chat::config::{AuthConfig, ClusterConfig, CountryRules, GeoIpConfig, IpFilterConfig, LimitsConfig, MessagesConfig, RegistrationConfig, StorageConfig, WebhookConfig};
+use rust_chat::testing::{self, SimulatedConnection, TestServer};
+use rust_chat::{Config, Directory};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use warp::http::StatusCode;
+use warp::ws::Message;
+
+#[tokio::test]
+async fn chat_message_reaches_recipient_and_echoes_to_sender() {
+    let server = TestServer::new().await;
+    let alice = server.register("alice", "secret").await;
+    let bob = server.register("bob", "secret").await;
+    server.add_contact(&alice, &bob).await;
+    let mut alice_ws = server.connect(&alice).await;
+    let mut bob_ws = server.connect(&bob).await;
+
+    alice_ws.send_json(&json!({ "type": "chatMessage", "to_user_id": bob.user_id, "message": "hi bob" })).await;
+
+    let received = bob_ws.recv_type("chatMessage").await;
+    assert_eq!(received["from_user_id"], alice.user_id.to_string());
+    assert_eq!(received["from_username"], "alice");
+    assert_eq!(received["message"], "hi bob");
+    assert_eq!(received["verified"], false);
+    let echoed = alice_ws.recv_type("chatMessage").await;
+    assert_eq!(echoed["message_id"], received["message_id"]);
+}
+
+#[tokio::test]
+async fn read_receipt_reaches_the_original_sender() {
+    let server = TestServer::new().await;
+    let alice = server.register("alice", "secret").await;
+    let bob = server.register("bob", "secret").await;
+    let mut alice_ws = server.connect(&alice).await;
+    let mut bob_ws = server.connect(&bob).await;
+
+    alice_ws.send_json(&json!({ "type": "chatMessage", "to_user_id": bob.user_id, "message": "read me" })).await;
+    let message_id = bob_ws.recv_type("chatMessage").await["message_id"].clone();
+    bob_ws.send_json(&json!({ "type": "readReceipt", "to_user_id": alice.user_id, "message_id": message_id })).await;
+
+    let receipt = alice_ws.recv_type("readReceipt").await;
+    assert_eq!(receipt["from_user_id"], bob.user_id.to_string());
+    assert_eq!(receipt["message_id"], message_id);
+}
+
+#[tokio::test]
+async fn batched_read_receipts_reach_the_sender_in_one_frame() {
+    let server = TestServer::new().await;
+    let alice = server.register("alice", "secret").await;
+    let bob = server.register("bob", "secret").await;
+    let mut alice_ws = server.connect(&alice).await;
+    let mut bob_ws = server.connect(&bob).await;
+
+    let mut message_ids = Vec::new();
+    for n in 0..3 {
+        alice_ws.send_json(&json!({ "type": "chatMessage", "to_user_id": bob.user_id, "message": n.to_string() })).await;
+        message_ids.push(bob_ws.recv_type("chatMessage").await["message_id"].clone());
+    }
+    bob_ws.send_json(&json!({ "type": "readReceipts", "to_user_id": alice.user_id, "message_ids": message_ids })).await;
+
+    let receipts = alice_ws.recv_type("readReceipts").await;
+    assert_eq!(receipts["from_user_id"], bob.user_id.to_string());
+    assert_eq!(receipts["message_ids"], json!(message_ids));
+}
+
+#[tokio::test]
+async fn disconnect_removes_the_connection_and_announces_offline() {
+    let server = TestServer::new().await;
+    let alice = server.register("alice", "secret").await;
+    let bob = server.register("bob", "secret").await;
+    let mut alice_ws = server.connect(&alice).await;
+    let bob_ws = server.connect(&bob).await;
+    let online = alice_ws.recv_type("statusMessage").await;
+    assert_eq!(online["user_id"], bob.user_id.to_string());
+    assert_eq!(online["status"], "online");
+
+    bob_ws.close();
+    server.wait_disconnected(&bob.session_key).await;
+
+    let offline = alice_ws.recv_type("statusMessage").await;
+    assert_eq!(offline["user_id"], bob.user_id.to_string());
+    assert_eq!(offline["status"], "offline");
+    assert!(server.state().connections.contains(&alice.session_key).await);
+}
+
+#[tokio::test]
+async fn closing_one_of_two_connections_keeps_the_user_online() {
+    let server = TestServer::new().await;
+    let alice = server.register("alice", "secret").await;
+    let bob = server.register("bob", "secret").await;
+    let bob_gateway = server.open_another_session(&bob).await;
+    let mut alice_ws = server.connect(&alice).await;
+    let bob_ws = server.connect(&bob).await;
+    let mut bob_gateway_ws = server.connect(&bob_gateway).await;
+    assert_eq!(alice_ws.recv_type("statusMessage").await["status"], "online");
+    assert_eq!(alice_ws.recv_type("statusMessage").await["status"], "online");
+
+    bob_ws.close();
+    server.wait_disconnected(&bob.session_key).await;
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    bob_gateway_ws.send_json(&json!({ "type": "chatMessage", "to_user_id": alice.user_id, "message": "still here" })).await;
+    let next = loop {
+        let frame = alice_ws.recv_json().await;
+        if frame["type"] == "statusMessage" || frame["type"] == "chatMessage" {
+            break frame;
+        }
+    };
+    assert_eq!((next["type"].as_str(), next["message"].as_str()), (Some("chatMessage"), Some("still here")));
+
+    bob_gateway_ws.close();
+    server.wait_disconnected(&bob_gateway.session_key).await;
+    let offline = alice_ws.recv_type("statusMessage").await;
+    assert_eq!((offline["user_id"].as_str(), offline["status"].as_str()), (Some(bob.user_id.to_string().as_str()), Some("offline")));
+}
+
+#[tokio::test]
+async fn presence_reflects_users_connected_to_other_nodes() {
+    let cluster = ClusterConfig { node_id: "chat-1".to_string(), peers: Vec::new(), secret: "s3cret".to_string(), presence_ttl_secs: 30 };
+    let server = TestServer::with_config(Config { cluster: Some(cluster), ..testing::test_config() }).await;
+    let alice = server.register("alice", "secret").await;
+    let bob = server.register("bob", "secret").await;
+    let carol = server.register("carol", "secret").await;
+    let mut alice_ws = server.connect(&alice).await;
+
+    let bob_online = json!({ "node_id": "chat-2", "complete": false, "users": [
+        { "user_id": bob.user_id, "username": "bob", "status": "online" },
+    ] });
+    assert_eq!(server.receive_from_node("/cluster/presence", "wrong", &bob_online).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(server.receive_from_node("/cluster/presence", "s3cret", &bob_online).await, StatusCode::NO_CONTENT);
+    let status = alice_ws.recv_type("statusMessage").await;
+    assert_eq!((status["user_id"].as_str(), status["status"].as_str()), (Some(bob.user_id.to_string().as_str()), Some("online")));
+
+    // Carol is connected here and to chat-2: closing her connection here keeps her online.
+    let carol_ws = server.connect(&carol).await;
+    assert_eq!(alice_ws.recv_type("statusMessage").await["user_id"], carol.user_id.to_string());
+    let both_online = json!({ "node_id": "chat-2", "complete": false, "users": [
+        { "user_id": carol.user_id, "username": "carol", "status": "online" },
+    ] });
+    assert_eq!(server.receive_from_node("/cluster/presence", "s3cret", &both_online).await, StatusCode::NO_CONTENT);
+    carol_ws.close();
+    server.wait_disconnected(&carol.session_key).await;
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    let bob_dnd = json!({ "node_id": "chat-2", "complete": false, "users": [
+        { "user_id": bob.user_id, "username": "bob", "status": "dnd" },
+    ] });
+    assert_eq!(server.receive_from_node("/cluster/presence", "s3cret", &bob_dnd).await, StatusCode::NO_CONTENT);
+    let status = alice_ws.recv_type("statusMessage").await;
+    assert_eq!((status["user_id"].as_str(), status["status"].as_str()), (Some(bob.user_id.to_string().as_str()), Some("dnd")));
+
+    // A complete announcement leaving carol out means she left chat-2 too.
+    let only_bob = json!({ "node_id": "chat-2", "complete": true, "users": [
+        { "user_id": bob.user_id, "username": "bob", "status": "dnd" },
+    ] });
+    assert_eq!(server.receive_from_node("/cluster/presence", "s3cret", &only_bob).await, StatusCode::NO_CONTENT);
+    let status = alice_ws.recv_type("statusMessage").await;
+    assert_eq!((status["user_id"].as_str(), status["status"].as_str()), (Some(carol.user_id.to_string().as_str()), Some("offline")));
+}
+
+#[tokio::test]
+async fn cluster_nodes_join_with_heartbeats_and_show_in_admin_stats() {
+    let config = testing::test_config();
+    let auth = AuthConfig { admin_usernames: vec!["root".to_string()], ..config.auth.clone() };
+    let cluster = ClusterConfig { node_id: "chat-1".to_string(), peers: Vec::new(), secret: "s3cret".to_string(), presence_ttl_secs: 30 };
+    let server = TestServer::with_config(Config { auth, cluster: Some(cluster), ..config }).await;
+    let root = server.register_admin("root", "secret").await;
+    let bob = server.register("bob", "secret").await;
+    let mut root_ws = server.connect(&root).await;
+
+    let heartbeat = json!({ "node_id": "chat-2", "started_at": "2026-01-01T00:00:00Z", "connections": 5, "users": [
+        { "user_id": bob.user_id, "username": "bob", "status": "online" },
+    ] });
+    assert_eq!(server.receive_from_node("/cluster/heartbeat", "wrong", &heartbeat).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(server.receive_from_node("/cluster/heartbeat", "s3cret", &heartbeat).await, StatusCode::NO_CONTENT);
+    let status = root_ws.recv_type("statusMessage").await;
+    assert_eq!((status["user_id"].as_str(), status["status"].as_str()), (Some(bob.user_id.to_string().as_str()), Some("online")));
+
+    let (status, stats) = server.request("GET", "/api/v1/admin/stats", Some(&root.session_key), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let nodes = stats["cluster_nodes"].as_array().expect("cluster nodes are listed");
+    assert_eq!(nodes.len(), 2);
+    assert_eq!((nodes[0]["node_id"].as_str(), nodes[0]["local"].as_bool(), nodes[0]["connections"].as_u64()), (Some("chat-1"), Some(true), Some(1)));
+    assert_eq!((nodes[1]["node_id"].as_str(), nodes[1]["alive"].as_bool(), nodes[1]["connections"].as_u64()), (Some("chat-2"), Some(true), Some(5)));
+    assert_eq!(nodes[1]["online_users"], 1);
+}
+
+#[tokio::test]
+async fn admin_analytics_aggregate_todays_activity() {
+    let config = testing::test_config();
+    let auth = AuthConfig { admin_usernames: vec!["root".to_string()], ..config.auth.clone() };
+    let server = TestServer::with_config(Config { auth, ..config }).await;
+    let root = server.register_admin("root", "secret").await;
+    let alice = server.register("alice", "secret").await;
+    let bob = server.register("bob", "secret").await;
+    let mut alice_ws = server.connect(&alice).await;
+    let _bob_ws = server.connect(&bob).await;
+    for text in ["one", "two", "three"] {
+        alice_ws.send_json(&json!({ "type": "chatMessage", "to_user_id": bob.user_id, "message": text })).await;
+        alice_ws.recv_type("chatMessage").await;
+    }
+
+    // The analytics subscriber counts the events in the background.
+    let mut today = json!(null);
+    for _ in 0..20 {
+        let (status, report) = server.request("GET", "/api/v1/admin/analytics?count=2", Some(&root.session_key), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(report["buckets"].as_array().map(Vec::len), Some(2));
+        today = report["buckets"][1].clone();
+        if today["messages"] == 3 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+    }
+    assert_eq!((today["active_users"].as_u64(), today["messages"].as_u64()), (Some(2), Some(3)));
+    assert_eq!(today["messages_per_user"], 3.0);
+    assert_eq!(today["peak_concurrent_connections"], 2);
+
+    let (status, weekly) = server.request("GET", "/api/v1/admin/analytics?period=weekly", Some(&root.session_key), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(weekly["buckets"].as_array().and_then(|weeks| weeks.last()).map(|week| week["messages"].clone()), Some(json!(3)));
+    let (status, _) = server.request("GET", "/api/v1/admin/analytics?period=hourly", Some(&root.session_key), None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = server.request("GET", "/api/v1/admin/analytics", Some(&alice.session_key), None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn api_errors_carry_their_code_and_status() {
+    let server = TestServer::new().await;
+    let alice = server.register("alice", "secret").await;
+
+    let taken = json!({ "username": "alice", "password": "other" });
+    let (status, body) = server.request("POST", "/api/v1/register", None, Some(&taken)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["code"], "USERNAME_TAKEN");
+
+    let (status, body) = server.request("GET", "/api/v1/calls", Some("not-a-session"), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["code"], "INVALID_SESSION");
+
+    let malformed = json!({ "username": "bob" });
+    let (status, body) = server.request("POST", "/api/v1/login", None, Some(&malformed)).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["code"], "MALFORMED_BODY");
+
+    let unknown = json!({ "contact_username": "nobody" });
+    let (status, body) = server.request("POST", "/api/v1/contacts", Some(&alice.session_key), Some(&unknown)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["code"], "USER_NOT_FOUND");
+}
+
+#[tokio::test]
+async fn api_stack_limits_body_size_and_request_rate() {
+    let limits = LimitsConfig { max_body_bytes: 4096, http_requests_per_minute: 4, ..LimitsConfig::default() };
+    let server = TestServer::with_config(Config { limits, ..testing::test_config() }).await;
+    let alice = server.register("alice", "secret").await;
+
+    // Too large a body is refused before it is parsed, and before it counts as a request.
+    let oversized = json!({ "to_user_id": alice.user_id, "message": "x".repeat(5000) });
+    let (status, body) = server.request("POST", "/api/v1/messages", Some(&alice.session_key), Some(&oversized)).await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(body["code"], "BODY_TOO_LARGE");
+
+    // Registering was the first of the 4 requests allowed this minute.
+    for _ in 0..3 {
+        let (status, _) = server.request("GET", "/api/v1/contacts", Some(&alice.session_key), None).await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    let (status, body) = server.request("GET", "/api/v1/contacts", Some(&alice.session_key), None).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(body["code"], "RATE_LIMITED");
+}
+
+#[tokio::test]
+async fn admins_replace_the_ip_rules_at_runtime() {
+    let config = testing::test_config();
+    let auth = AuthConfig { admin_usernames: vec!["root".to_string()], ..config.auth.clone() };
+    let server = TestServer::with_config(Config { auth, ..config }).await;
+    let root = server.register_admin("root", "secret").await;
+    let alice = server.register("alice", "secret").await;
+
+    let rules = json!({ "allow": [], "deny": ["203.0.113.0/24"] });
+    let (status, body) = server.request("PUT", "/api/v1/admin/ip-rules", Some(&root.session_key), Some(&rules)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, rules);
+    let (_, body) = server.request("GET", "/api/v1/admin/ip-rules", Some(&root.session_key), None).await;
+    assert_eq!(body, rules);
+    let (status, _) = server.request("PUT", "/api/v1/admin/ip-rules", Some(&alice.session_key), Some(&rules)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // Test requests have no known address, which an allow list turns away.
+    let lockout = json!({ "allow": ["10.0.0.0/8"], "deny": [] });
+    let (status, _) = server.request("PUT", "/api/v1/admin/ip-rules", Some(&root.session_key), Some(&lockout)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let ip_filter = IpFilterConfig { allow: vec!["10.0.0.0/8".parse().unwrap()], deny: Vec::new() };
+    let restricted = TestServer::with_config(Config { ip_filter, ..testing::test_config() }).await;
+    let payload = json!({ "username": "bob", "password": "secret" });
+    let (status, body) = restricted.request("POST", "/api/v1/register", None, Some(&payload)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "IP_NOT_ALLOWED");
+}
+
+#[tokio::test]
+async fn geoip_rules_restrict_registration_and_login_by_country() {
+    let dir = std::env::temp_dir().join(format!("rust_chat_geoip_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let blocks_path = dir.join("GeoLite2-Country-Blocks-IPv4.csv");
+    let locations_path = dir.join("GeoLite2-Country-Locations-en.csv");
+    std::fs::write(&blocks_path, "network,geoname_id,registered_country_geoname_id,represented_country_geoname_id,is_anonymous_proxy,is_satellite_provider\n203.0.113.0/24,2921044,2921044,,0,0\n").unwrap();
+    std::fs::write(&locations_path, "geoname_id,locale_code,continent_code,continent_name,country_iso_code,country_name,is_in_european_union\n2921044,en,EU,Europe,DE,Germany,1\n").unwrap();
+    let geoip = GeoIpConfig {
+        blocks_paths: vec![blocks_path],
+        locations_path,
+        registration: CountryRules { allow: Vec::new(), deny: vec!["FR".to_string()] },
+        login: CountryRules { allow: vec!["DE".to_string()], deny: Vec::new() },
+    };
+    let server = TestServer::with_config(Config { geoip: Some(geoip), ..testing::test_config() }).await;
+
+    // Test requests have no known address: only a deny list lets an unknown country in.
+    server.register("alice", "secret").await;
+    let payload = json!({ "username": "alice", "password": "secret" });
+    let (status, body) = server.request("POST", "/api/v1/login", None, Some(&payload)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "COUNTRY_NOT_ALLOWED");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn login_revokes_the_previous_session() {
+    let server = TestServer::new().await;
+    let first = server.register("alice", "secret").await;
+    let second = server.login("alice", "secret").await;
+
+    let (status, _) = server.request("GET", "/api/v1/contacts", Some(&first.session_key), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, body) = server.request("GET", "/api/v1/contacts", Some(&second.session_key), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body, json!([]));
+}
+
+#[tokio::test]
+async fn failed_logins_lock_the_account_until_its_owner_unlocks_it() {
+    let config = testing::test_config();
+    let auth = AuthConfig { max_failed_logins: 3, ..config.auth.clone() };
+    let server = TestServer::with_config(Config { auth, ..config }).await;
+    let alice = server.register("alice", "secret").await;
+    let mut alice_ws = server.connect(&alice).await;
+
+    let wrong = json!({ "username": "alice", "password": "guess" });
+    for _ in 0..3 {
+        let (status, _) = server.request("POST", "/api/v1/login", None, Some(&wrong)).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+    assert!(alice_ws.recv_type("accountLocked").await["locked_until"].is_string());
+    let right = json!({ "username": "alice", "password": "secret" });
+    let (status, body) = server.request("POST", "/api/v1/login", None, Some(&right)).await;
+    assert_eq!(status, StatusCode::LOCKED);
+    assert_eq!(body["code"], "ACCOUNT_LOCKED");
+
+    // The session opened before the lock unlocks it by re-entering the password.
+    let (status, _) = server.request("POST", "/api/v1/me/unlock", Some(&alice.session_key), Some(&json!({ "password": "guess" }))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, body) = server.request("POST", "/api/v1/me/unlock", Some(&alice.session_key), Some(&json!({ "password": "secret" }))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["was_locked"], true);
+    assert_eq!(alice_ws.recv_type("accountUnlocked").await["by_admin"], false);
+    let (status, _) = server.request("POST", "/api/v1/login", None, Some(&right)).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn wrong_passwords_re_entered_in_a_session_lock_the_account_too() {
+    let config = testing::test_config();
+    let auth = AuthConfig { max_failed_logins: 3, ..config.auth.clone() };
+    let server = TestServer::with_config(Config { auth, ..config }).await;
+    let alice = server.register("alice", "secret").await;
+    let mut alice_ws = server.connect(&alice).await;
+
+    let change = json!({ "current_password": "guess", "new_password": "other" });
+    server.request("POST", "/api/v1/me/password", Some(&alice.session_key), Some(&change)).await;
+    server.request("POST", "/api/v1/me/trusted-devices", Some(&alice.session_key), Some(&json!({ "password": "guess" }))).await;
+    let (status, _) = server.request("POST", "/api/v1/me/unlock", Some(&alice.session_key), Some(&json!({ "password": "guess" }))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    assert!(alice_ws.recv_type("accountLocked").await["locked_until"].is_string());
+    let (status, _) = server.request("POST", "/api/v1/login", None, Some(&json!({ "username": "alice", "password": "secret" }))).await;
+    assert_eq!(status, StatusCode::LOCKED);
+}
+
+#[tokio::test]
+async fn logins_from_unfamiliar_addresses_alert_the_replaced_session_with_a_revoke_token() {
+    let server = TestServer::new().await;
+    server.register("alice", "secret").await;
+    let home = "203.0.113.7".parse().unwrap();
+    let alice = server.login_from("alice", "secret", home).await;
+    let mut alice_ws = server.connect(&alice).await;
+
+    // A login from the usual address replaces the session quietly.
+    let alice = server.login_from("alice", "secret", home).await;
+    alice_ws.recv_closed().await;
+    let mut alice_ws = server.connect(&alice).await;
+
+    let intruder = server.login_from("alice", "secret", "198.51.100.9".parse().unwrap()).await;
+    let alert = alice_ws.recv_type("securityAlert").await;
+    assert_eq!(alert["kind"], "new_login");
+    assert_eq!(alert["client_ip"], "198.51.100.9");
+    let revoke = json!({ "token": alert["revoke_token"] });
+    let (status, _) = server.request("POST", "/api/v1/sessions/revoke", None, Some(&revoke)).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = server.request("GET", "/api/v1/contacts", Some(&intruder.session_key), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, body) = server.request("POST", "/api/v1/sessions/revoke", None, Some(&revoke)).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["code"], "TOKEN_NOT_FOUND");
+}
+
+#[tokio::test]
+async fn re_logins_from_a_known_device_do_not_alert_but_new_devices_do() {
+    let server = TestServer::new().await;
+    let mut events = server.state().events.subscribe();
+    server.register("alice", "secret").await;
+    let laptop = [("user-agent", "Mozilla/5.0 (Windows NT 10.0; Win64; x64) Firefox/131.0")];
+    let login = json!({ "username": "alice", "password": "secret", "device_name": "Work laptop" });
+    let (status, _) = server.request_from("POST", "/api/v1/login", "203.0.113.7".parse().unwrap(), &laptop, Some(&login)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // The same device from another network is a re-login, not a new device.
+    let (status, body) = server.request_from("POST", "/api/v1/login", "198.51.100.9".parse().unwrap(), &laptop, Some(&login)).await;
+    assert_eq!(status, StatusCode::OK);
+    let session = server.state().session_for_key(body["session_key"].as_str().unwrap()).await.unwrap();
+    assert_eq!(session.device.name.as_deref(), Some("Work laptop"));
+    assert_eq!(session.device.platform, Some("Windows"));
+    let alice = server.login_from("alice", "secret", "198.51.100.9".parse().unwrap()).await;
+    assert!(!std::iter::from_fn(|| events.try_recv().ok()).any(|event| matches!(&*event, DomainEvent::UnfamiliarLogin { .. })));
+
+    let mut alice_ws = server.connect(&alice).await;
+    let phone = [("user-agent", "Mozilla/5.0 (iPhone; CPU iPhone OS 18_0 like Mac OS X) Mobile/15E148")];
+    let login = json!({ "username": "alice", "password": "secret", "device_name": "Phone" });
+    let (status, _) = server.request_from("POST", "/api/v1/login", "198.51.100.9".parse().unwrap(), &phone, Some(&login)).await;
+    assert_eq!(status, StatusCode::OK);
+    let alert = alice_ws.recv_type("securityAlert").await;
+    assert_eq!(alert["device_name"], "Phone");
+    assert_eq!(alert["platform"], "iOS");
+
+    let long_name = json!({ "username": "alice", "password": "secret", "device_name": "x".repeat(65) });
+    let (status, _) = server.request("POST", "/api/v1/login", None, Some(&long_name)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn sessions_on_untrusted_devices_expire_sooner() {
+    let config = testing::test_config();
+    let auth = AuthConfig { untrusted_session_ttl_secs: 1, ..config.auth.clone() };
+    let server = TestServer::with_config(Config { auth, ..config }).await;
+    let laptop = [("user-agent", "Mozilla/5.0 (X11; Linux x86_64) Firefox/131.0")];
+    let payload = json!({ "username": "alice", "password": "secret", "device_name": "Home" });
+    let (status, body) = server.request_from("POST", "/api/v1/register", "203.0.113.7".parse().unwrap(), &laptop, Some(&payload)).await;
+    assert_eq!(status, StatusCode::OK);
+    let session_key = body["session_key"].as_str().unwrap();
+    let bob = server.register("bob", "secret").await;
+
+    let (status, _) = server.request("POST", "/api/v1/me/trusted-devices", Some(session_key), Some(&json!({ "password": "guess" }))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, device) = server.request("POST", "/api/v1/me/trusted-devices", Some(session_key), Some(&json!({ "password": "secret" }))).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(device["platform"], "Linux");
+    let (status, body) = server.request("POST", "/api/v1/me/trusted-devices", Some(&bob.session_key), Some(&json!({ "password": "secret" }))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    let (_, devices) = server.request("GET", "/api/v1/me/trusted-devices", Some(session_key), None).await;
+    assert_eq!(devices, json!([device]));
+    assert_eq!(devices[0]["current"], true);
+
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    let (status, _) = server.request("GET", "/api/v1/contacts", Some(session_key), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = server.request("GET", "/api/v1/contacts", Some(&bob.session_key), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let path = format!("/api/v1/me/trusted-devices/{}", device["id"].as_str().unwrap());
+    let (status, _) = server.request("DELETE", &path, Some(session_key), None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = server.request("GET", "/api/v1/contacts", Some(session_key), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn users_change_their_password_by_confirming_the_current_one() {
+    let server = TestServer::new().await;
+    let alice = server.register("alice", "secret").await;
+
+    let wrong = json!({ "current_password": "guess", "new_password": "hunter2" });
+    let (status, _) = server.request("POST", "/api/v1/me/password", Some(&alice.session_key), Some(&wrong)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let change = json!({ "current_password": "secret", "new_password": "hunter2" });
+    let (status, _) = server.request("POST", "/api/v1/me/password", Some(&alice.session_key), Some(&change)).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = server.request("GET", "/api/v1/contacts", Some(&alice.session_key), None).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _) = server.request("POST", "/api/v1/login", None, Some(&json!({ "username": "alice", "password": "secret" }))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    server.login("alice", "hunter2").await;
+}
+
+#[tokio::test]
+async fn invite_only_registration_takes_one_valid_invite_per_account() {
+    let config = testing::test_config();
+    let auth = AuthConfig { admin_usernames: vec!["root".to_string()], ..config.auth.clone() };
+    let registration = RegistrationConfig { invite_only: true, invites_per_user: 1, ..config.registration.clone() };
+    let server = TestServer::with_config(Config { auth, registration, ..config }).await;
+    let root = server.register_admin("root", "secret").await;
+    let register = |username: &str, code: &serde_json::Value| json!({ "username": username, "password": "secret", "invite_code": code });
+
+    let (status, body) = server.request("POST", "/api/v1/register", None, Some(&register("bob", &json!(null)))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "INVITE_REQUIRED");
+    let (_, first) = server.request("POST", "/api/v1/invites", Some(&root.session_key), None).await;
+    let (status, second) = server.request("POST", "/api/v1/invites", Some(&root.session_key), None).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, body) = server.request("POST", "/api/v1/register", None, Some(&register("bob", &first["code"]))).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = server.request("POST", "/api/v1/register", None, Some(&register("carol", &first["code"]))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let bob_key = body["session_key"].as_str().unwrap();
+    let (status, _) = server.request("POST", "/api/v1/invites", Some(bob_key), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, body) = server.request("POST", "/api/v1/invites", Some(bob_key), None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "INVITE_QUOTA_EXCEEDED");
+
+    let (_, invites) = server.request("GET", "/api/v1/invites", Some(&root.session_key), None).await;
+    assert_eq!(invites.as_array().unwrap().len(), 2);
+    assert_eq!(invites[0]["used_by"], "bob");
+    let path = format!("/api/v1/invites/{}", second["code"].as_str().unwrap());
+    let (status, _) = server.request("DELETE", &path, Some(bob_key), None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = server.request("DELETE", &path, Some(&root.session_key), None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = server.request("POST", "/api/v1/register", None, Some(&register("carol", &second["code"]))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn webhooks_are_refused_for_internal_addresses_and_capped_per_user() {
+    let config = testing::test_config();
+    let webhooks = WebhookConfig { max_per_user: 2, allow_networks: vec!["10.20.0.0/16".parse().unwrap()], ..config.webhooks.clone() };
+    let server = TestServer::with_config(Config { webhooks, ..config }).await;
+    let alice = server.register("alice", "secret").await;
+    let webhook = |url: &str| json!({ "url": url, "events": ["message_received"] });
+
+    for url in [
+        "http://127.0.0.1:8080/hook",
+        "http://localhost/hook",
+        "http://169.254.169.254/latest/meta-data/",
+        "http://192.168.1.10/hook",
+        "http://[::1]/hook",
+        "http://[::ffff:10.0.0.1]/hook",
+        "ftp://8.8.8.8/hook",
+    ] {
+        let (status, body) = server.request("POST", "/api/v1/webhooks", Some(&alice.session_key), Some(&webhook(url))).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", url);
+        assert_eq!(body["code"], "VALIDATION_FAILED");
+    }
+
+    let (status, _) = server.request("POST", "/api/v1/webhooks", Some(&alice.session_key), Some(&webhook("https://8.8.8.8/hook"))).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = server.request("POST", "/api/v1/webhooks", Some(&alice.session_key), Some(&webhook("http://10.20.3.4/hook"))).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, body) = server.request("POST", "/api/v1/webhooks", Some(&alice.session_key), Some(&webhook("https://1.1.1.1/hook"))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "WEBHOOK_QUOTA_EXCEEDED");
+}
+
+#[tokio::test]
+async fn registrations_needing_approval_log_in_once_an_admin_approves_them() {
+    let config = testing::test_config();
+    let auth = AuthConfig { admin_usernames: vec!["root".to_string()], ..config.auth.clone() };
+    let registration = RegistrationConfig { require_approval: true, ..config.registration.clone() };
+    let server = TestServer::with_config(Config { auth, registration, ..config }).await;
+    let root = server.register_admin("root", "secret").await;
+    let mut root_ws = server.connect(&root).await;
+    let payload = json!({ "username": "bob", "password": "secret" });
+
+    let (status, pending) = server.request("POST", "/api/v1/register", None, Some(&payload)).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert_eq!(pending["status"], "pending");
+    assert_eq!(root_ws.recv_type("registrationPending").await["username"], "bob");
+    let (status, _) = server.request("POST", "/api/v1/register", None, Some(&payload)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let (status, body) = server.request("POST", "/api/v1/login", None, Some(&payload)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "REGISTRATION_PENDING");
+    let wrong_password = json!({ "username": "bob", "password": "guess" });
+    let (status, _) = server.request("POST", "/api/v1/login", None, Some(&wrong_password)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let path = format!("/api/v1/admin/registrations/{}/approve", pending["registration_id"].as_str().unwrap());
+    let (_, queue) = server.request("GET", "/api/v1/admin/registrations", Some(&root.session_key), None).await;
+    assert_eq!(queue[0]["username"], "bob");
+    let (status, approved) = server.request("POST", &path, Some(&root.session_key), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(approved["username"], "bob");
+    let (status, _) = server.request("POST", &path, Some(&root.session_key), None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    server.login("bob", "secret").await;
+}
+
+#[tokio::test]
+async fn admin_usernames_promote_existing_accounts_at_startup_not_at_registration() {
+    let dir = std::env::temp_dir().join(format!("rust_chat_admins_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let config = testing::test_config();
+    let auth = AuthConfig { admin_usernames: vec!["root".to_string()], ..config.auth.clone() };
+    let storage = StorageConfig { snapshot_path: Some(dir.join("state.json")), ..config.storage.clone() };
+    let root = json!({ "username": "root", "password": "secret" });
+
+    // A listed name is neither exempt from invites nor an admin when registered.
+    let registration = RegistrationConfig { invite_only: true, ..config.registration.clone() };
+    let invite_only = TestServer::with_config(Config { auth: auth.clone(), registration, ..config.clone() }).await;
+    let (status, body) = invite_only.request("POST", "/api/v1/register", None, Some(&root)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "INVITE_REQUIRED");
+    let server = TestServer::with_config(Config { auth: auth.clone(), storage: storage.clone(), ..config.clone() }).await;
+    let registered = server.register("root", "secret").await;
+    let (status, _) = server.request("GET", "/api/v1/admin/stats", Some(&registered.session_key), None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    server.shutdown().await;
+
+    // The next start gives the existing account the role.
+    let restarted = TestServer::with_config(Config { auth, storage, ..config }).await;
+    let root = restarted.login("root", "secret").await;
+    let (status, _) = restarted.request("GET", "/api/v1/admin/stats", Some(&root.session_key), None).await;
+    assert_eq!(status, StatusCode::OK);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn moderators_handle_registrations_and_connections_but_not_admin_settings() {
+    let config = testing::test_config();
+    let auth = AuthConfig { admin_usernames: vec!["root".to_string()], ..config.auth.clone() };
+    let registration = RegistrationConfig { require_approval: true, ..config.registration.clone() };
+    let server = TestServer::with_config(Config { auth, registration, ..config }).await;
+    let root = server.register_admin("root", "secret").await;
+    let (status, _) = server.request("POST", "/api/v1/register", None, Some(&json!({ "username": "mod", "password": "secret" }))).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let (_, pending) = server.request("GET", "/api/v1/admin/registrations", Some(&root.session_key), None).await;
+    let path = format!("/api/v1/admin/registrations/{}/approve", pending[0]["id"].as_str().unwrap());
+    let (status, _) = server.request("POST", &path, Some(&root.session_key), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = server.request("PUT", "/api/v1/admin/users/mod/role", Some(&root.session_key), Some(&json!({ "role": "moderator" }))).await;
+    assert_eq!(status, StatusCode::OK);
+    let moderator = server.login("mod", "secret").await;
+
+    let (status, _) = server.request("POST", "/api/v1/register", None, Some(&json!({ "username": "carol", "password": "secret" }))).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let (status, pending) = server.request("GET", "/api/v1/admin/registrations", Some(&moderator.session_key), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let path = format!("/api/v1/admin/registrations/{}/approve", pending[0]["id"].as_str().unwrap());
+    let (status, _) = server.request("POST", &path, Some(&moderator.session_key), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = server.request("GET", "/api/v1/admin/connections", Some(&moderator.session_key), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = server.request("POST", "/api/v1/admin/announcements", Some(&moderator.session_key), Some(&json!({ "title": "Maintenance", "body": "At noon" }))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    for (method, path) in [("GET", "/api/v1/admin/stats"), ("GET", "/api/v1/admin/ip-rules"), ("POST", "/api/v1/admin/config/reload")] {
+        let (status, body) = server.request(method, path, Some(&moderator.session_key), None).await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "{}", path);
+        assert_eq!(body["code"], "INSUFFICIENT_ROLE");
+    }
+    let (status, _) = server.request("PUT", "/api/v1/admin/users/carol/role", Some(&moderator.session_key), Some(&json!({ "role": "moderator" }))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn bot_tokens_reach_only_the_routes_of_their_scopes_until_revoked() {
+    let server = TestServer::new().await;
+    let alice = server.register("alice", "secret").await;
+    let (status, bot) = server.request("POST", "/api/v1/bots", Some(&alice.session_key), Some(&json!({ "username": "helper" }))).await;
+    assert_eq!(status, StatusCode::OK);
+    let tokens_path = format!("/api/v1/bots/{}/tokens", bot["id"].as_str().unwrap());
+    let (status, token) = server.request("POST", &tokens_path, Some(&alice.session_key), Some(&json!({ "scopes": ["read_contacts"] }))).await;
+    assert_eq!(status, StatusCode::OK);
+    let authorization = format!("Bearer {}", token["token"].as_str().unwrap());
+    let ip = "127.0.0.1".parse().unwrap();
+    let headers = [("authorization", authorization.as_str())];
+
+    let (status, contacts) = server.request_from("GET", "/api/v1/contacts", ip, &headers, None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(contacts[0]["username"], "alice");
+    let message = json!({ "to_user_id": alice.user_id, "message": "hello" });
+    let (status, body) = server.request_from("POST", "/api/v1/messages", ip, &headers, Some(&message)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "INSUFFICIENT_SCOPE");
+
+    // Routes taking only sessions ask for one, whatever the token's scopes.
+    for (method, path) in [("POST", "/api/v1/contacts"), ("GET", "/api/v1/bots")] {
+        let (status, body) = server.request_from(method, path, ip, &headers, Some(&json!({ "contact_username": "alice" }))).await;
+        assert_eq!((status, body["code"].as_str()), (StatusCode::BAD_REQUEST, Some("MISSING_HEADER")), "{} {}", method, path);
+    }
+
+    let revoke_path = format!("{}/{}", tokens_path, token["id"].as_str().unwrap());
+    let (status, _) = server.request("DELETE", &revoke_path, Some(&alice.session_key), None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, body) = server.request_from("GET", "/api/v1/contacts", ip, &headers, None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["code"], "INVALID_API_TOKEN");
+}
+
+#[tokio::test]
+async fn guests_chat_until_they_expire_and_are_deleted_with_their_data() {
+    let (status, _) = TestServer::new().await.request("POST", "/api/v1/guests", None, None).await;
+    assert_eq!(status, StatusCode::NOT_IMPLEMENTED);
+
+    let config = testing::test_config();
+    let registration = RegistrationConfig { allow_guests: true, guest_ttl_secs: 1, ..config.registration.clone() };
+    let server = TestServer::with_config(Config { registration, ..config }).await;
+    let agent = server.register("agent", "secret").await;
+    let guest = server.join_as_guest().await;
+    assert!(guest.username.starts_with("guest-"));
+    server.add_contact(&guest, &agent).await;
+    let mut guest_ws = server.connect(&guest).await;
+    let mut agent_ws = server.connect(&agent).await;
+
+    guest_ws.send_json(&json!({ "type": "chatMessage", "to_user_id": agent.user_id, "message": "hello?" })).await;
+    assert_eq!(agent_ws.recv_type("chatMessage").await["from_username"], guest.username.as_str());
+    let (status, body) = server.request("POST", "/api/v1/invites", Some(&guest.session_key), None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "GUEST_NOT_ALLOWED");
+    let payload = json!({ "username": "guest-12345678", "password": "secret" });
+    let (status, _) = server.request("POST", "/api/v1/register", None, Some(&payload)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(server.state().purge_expired_guests().await, 0);
+
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    assert_eq!(server.state().purge_expired_guests().await, 1);
+    guest_ws.recv_closed().await;
+    let (status, _) = server.request("GET", "/api/v1/contacts", Some(&guest.session_key), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (_, contacts) = server.request("GET", "/api/v1/contacts", Some(&agent.session_key), None).await;
+    assert_eq!(contacts, json!([]));
+    let (_, conversations) = server.request("GET", "/api/v1/conversations", Some(&agent.session_key), None).await;
+    assert_eq!(conversations, json!([]));
+}
+
+#[tokio::test]
+async fn idle_sessions_without_a_connection_expire() {
+    let config = testing::test_config();
+    let auth = AuthConfig { session_idle_timeout_secs: 1, ..config.auth.clone() };
+    let server = TestServer::with_config(Config { auth, ..config }).await;
+    let alice = server.register("alice", "secret").await;
+    let bob = server.register("bob", "secret").await;
+    let _bob_ws = server.connect(&bob).await;
+
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    assert_eq!(server.state().expire_idle_sessions().await, 1);
+
+    let (status, _) = server.request("GET", "/api/v1/contacts", Some(&alice.session_key), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = server.request("GET", "/api/v1/contacts", Some(&bob.session_key), None).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn requests_keep_a_session_from_expiring_idle() {
+    let config = testing::test_config();
+    let auth = AuthConfig { session_idle_timeout_secs: 1, ..config.auth.clone() };
+    let server = TestServer::with_config(Config { auth, ..config }).await;
+    let alice = server.register("alice", "secret").await;
+
+    // Marking the session used goes through the lock-free copy of the sessions map, and is
+    // still seen by the expiry, which works on the locked map.
+    for _ in 0..3 {
+        tokio::time::sleep(std::time::Duration::from_millis(600)).await;
+        let (status, _) = server.request("GET", "/api/v1/contacts", Some(&alice.session_key), None).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(server.state().expire_idle_sessions().await, 0);
+    }
+}
+
+#[tokio::test]
+async fn malformed_frames_do_not_close_the_connection() {
+    let server = TestServer::new().await;
+    let alice = server.register("alice", "secret").await;
+    let bob = server.register("bob", "secret").await;
+    let mut alice_ws = server.connect(&alice).await;
+    let mut bob_ws = server.connect(&bob).await;
+
+    alice_ws.send_json(&json!("not an object")).await;
+    alice_ws.send_json(&json!({ "type": "noSuchMessage" })).await;
+    alice_ws.send_json(&json!({ "type": "vote", "poll_id": bob.user_id, "options": [usize::MAX] })).await;
+    alice_ws.send_json(&json!({ "type": "location", "to_user_id": bob.user_id, "lat": 1e308, "lon": -1e308 })).await;
+    alice_ws.send_json(&json!({ "type": "chatMessage", "to_user_id": bob.user_id, "message": "still here" })).await;
+
+    assert_eq!(bob_ws.recv_type("chatMessage").await["message"], "still here");
+}
+
+// Rejects messages mentioning spam, shouts everything else and counts deliveries.
+#[derive(Default)]
+struct ShoutingFilter {
+    delivered: AtomicUsize,
+}
+
+impl MessageInterceptor for ShoutingFilter {
+    fn pre_send(&self, message: &mut InterceptedMessage) -> Result<(), String> {
+        if message.text.contains("spam") {
+            return Err("no spam".to_string());
+        }
+        message.text = message.text.to_uppercase();
+        Ok(())
+    }
+
+    fn post_receive(&self, _message: &InterceptedMessage, delivered: bool) {
+        if delivered {
+            self.delivered.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[tokio::test]
+async fn interceptors_rewrite_reject_and_observe_messages() {
+    let server = TestServer::new().await;
+    let filter = Arc::new(ShoutingFilter::default());
+    server.state().interceptors.register(filter.clone());
+    let alice = server.register("alice", "secret").await;
+    let bob = server.register("bob", "secret").await;
+    let mut bob_ws = server.connect(&bob).await;
+
+    let spam = json!({ "to_user_id": bob.user_id, "message": "buy spam" });
+    let (status, body) = server.request("POST", "/api/v1/messages", Some(&alice.session_key), Some(&spam)).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "MESSAGE_REJECTED");
+
+    let hello = json!({ "to_user_id": bob.user_id, "message": "hello" });
+    let (status, _) = server.request("POST", "/api/v1/messages", Some(&alice.session_key), Some(&hello)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(bob_ws.recv_type("chatMessage").await["message"], "HELLO");
+    assert_eq!(filter.delivered.load(Ordering::Relaxed), 1);
+}
+
+#[tokio::test]
+async fn handlers_publish_domain_events() {
+    let server = TestServer::new().await;
+    let mut events = server.state().events.subscribe();
+    let alice = server.register("alice", "secret").await;
+    let bob = server.register("bob", "secret").await;
+    server.add_contact(&alice, &bob).await;
+    let message = json!({ "to_user_id": bob.user_id, "message": "are you there?" });
+    let (status, _) = server.request("POST", "/api/v1/messages", Some(&alice.session_key), Some(&message)).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let mut next = || events.try_recv().expect("event published");
+    assert!(matches!(&*next(), DomainEvent::UserRegistered { username, .. } if username == "alice"));
+    assert!(matches!(&*next(), DomainEvent::UserRegistered { username, .. } if username == "bob"));
+    assert!(matches!(&*next(), DomainEvent::ContactAdded { user_id, contact_user_id, .. }
+        if *user_id == alice.user_id && *contact_user_id == bob.user_id));
+    match &*next() {
+        DomainEvent::MessageSent { kind, to_user_id, text, delivered, .. } => {
+            assert_eq!(*kind, MessageKind::Chat);
+            assert_eq!(*to_user_id, bob.user_id);
+            assert_eq!(text.as_deref(), Some("are you there?"));
+            assert!(!delivered);
+        }
+        other => panic!("expected MessageSent, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn both_participants_see_a_conversation_in_the_same_order() {
+    let server = TestServer::new().await;
+    let mut connections = server.simulate_connections(2).await;
+    let (alice, bob) = (connections[0].user_id(), connections[1].user_id());
+
+    // Messages in both directions at once: each fanout queues on both users' connections.
+    let sends = (0..40).map(|i| match i % 2 {
+        0 => server.send_message(&connections[0], bob, "ping"),
+        _ => server.send_message(&connections[1], alice, "pong"),
+    });
+    futures::future::join_all(sends).await;
+
+    let received = |connection: &mut SimulatedConnection| {
+        let frames = connection.received();
+        let ids: Vec<String> = frames.iter().map(|frame| frame["message_id"].as_str().unwrap().to_string()).collect();
+        let timestamps: Vec<String> = frames.iter().map(|frame| frame["timestamp"].as_str().unwrap().to_string()).collect();
+        (ids, timestamps)
+    };
+    let (alice_ids, alice_timestamps) = received(&mut connections[0]);
+    let (bob_ids, _) = received(&mut connections[1]);
+    assert_eq!(alice_ids.len(), 40);
+    assert_eq!(alice_ids, bob_ids);
+    assert!(alice_timestamps.windows(2).all(|pair| pair[0] <= pair[1]));
+}
+
+#[tokio::test]
+async fn admin_stats_report_lock_waits_and_queue_depths() {
+    let config = testing::test_config();
+    let auth = AuthConfig { admin_usernames: vec!["root".to_string()], ..config.auth.clone() };
+    let server = TestServer::with_config(Config { auth, ..config }).await;
+    let root = server.register_admin("root", "secret").await;
+    let mut connections = server.simulate_connections(2).await;
+    server.send_message(&connections[0], connections[1].user_id(), "hello").await;
+
+    let (status, stats) = server.request("GET", "/api/v1/admin/stats", Some(&root.session_key), None).await;
+    assert_eq!(status, StatusCode::OK);
+    // The message is still queued on both simulated connections.
+    assert_eq!(stats["queued_frames_total"], 2);
+    assert_eq!(stats["conversation_shards"]["wait"]["count"], 1);
+    assert_eq!(stats["conversation_shards"]["depth"], 0);
+    assert!(stats["connection_registry"]["wait"]["count"].as_u64().unwrap() > 0);
+    let users_lock = stats["locks"].as_array().unwrap().iter().find(|lock| lock["name"] == "users").expect("users lock is reported");
+    assert!(users_lock["wait"]["count"].as_u64().unwrap() > 0);
+
+    connections[1].drain();
+    let (_, stats) = server.request("GET", "/api/v1/admin/stats", Some(&root.session_key), None).await;
+    assert_eq!(stats["queued_frames_total"], 1);
+    assert_eq!(stats["connection_queue_wait"]["count"], 1);
+
+    // Presence goes out through the fanout workers.
+    server.broadcast_status(&connections[0], "online").await;
+    let (_, stats) = server.request("GET", "/api/v1/admin/stats", Some(&root.session_key), None).await;
+    assert_eq!(stats["fanout_workers"]["wait"]["count"], 1);
+    assert_eq!(stats["fanout_workers"]["depth"], 0);
+    assert_eq!(connections[1].drain(), 1);
+}
+
+#[tokio::test]
+async fn sharded_users_and_sessions_are_counted_and_revoked_across_shards() {
+    let config = testing::test_config();
+    let auth = AuthConfig { admin_usernames: vec!["root".to_string()], ..config.auth.clone() };
+    let limits = LimitsConfig { state_shards: 4, ..config.limits.clone() };
+    let server = TestServer::with_config(Config { auth, limits, ..config }).await;
+    let root = server.register_admin("root", "secret").await;
+    let mut registered = Vec::new();
+    for i in 0..8 {
+        registered.push(server.register(&format!("user{}", i), "secret").await);
+    }
+    // Logging in again revokes the registration's session, whichever shard it landed on.
+    for user in &registered {
+        server.login(&user.username, "secret").await;
+        let (status, _) = server.request("GET", "/api/v1/contacts", Some(&user.session_key), None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+    let mut connections = server.simulate_connections(3).await;
+    server.send_message(&connections[0], connections[2].user_id(), "hello").await;
+    assert_eq!(connections[2].received().len(), 1);
+
+    let (status, stats) = server.request("GET", "/api/v1/admin/stats", Some(&root.session_key), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(stats["registered_users"], 9);
+    assert_eq!(stats["active_sessions"], 9 + 3);
+    assert_eq!(stats["open_connections"], 3);
+}
+
+#[tokio::test]
+async fn scheduled_tasks_report_their_runs_and_stop_on_shutdown() {
+    let config = testing::test_config();
+    let auth = AuthConfig { admin_usernames: vec!["root".to_string()], ..config.auth.clone() };
+    let server = TestServer::with_config(Config { auth, ..config }).await;
+    let root = server.register_admin("root", "secret").await;
+
+    // Every task runs once right after the server starts.
+    let tasks = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        loop {
+            let (status, stats) = server.request("GET", "/api/v1/admin/stats", Some(&root.session_key), None).await;
+            assert_eq!(status, StatusCode::OK);
+            let tasks = stats["scheduled_tasks"].as_array().unwrap().clone();
+            if tasks.iter().all(|task| task["runs"] == 1) {
+                return tasks;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("every task runs at startup");
+    let names: Vec<&str> = tasks.iter().map(|task| task["name"].as_str().unwrap()).collect();
+    assert_eq!(names, ["dnd_presence", "session_expiry"]);
+    assert_eq!(tasks[0]["period_secs"], 60);
+    assert!(tasks.iter().all(|task| task["panics"] == 0 && task["last_run_at"].is_string()));
+
+    tokio::time::timeout(std::time::Duration::from_secs(5), server.shutdown()).await.expect("scheduled tasks stop");
+}
+
+#[tokio::test]
+async fn admins_list_and_close_websocket_connections() {
+    let config = testing::test_config();
+    let auth = AuthConfig { admin_usernames: vec!["root".to_string()], ..config.auth.clone() };
+    let server = TestServer::with_config(Config { auth, ..config }).await;
+    let root = server.register_admin("root", "secret").await;
+    let alice = server.register("alice", "secret").await;
+    let mut alice_ws = server.connect(&alice).await;
+
+    let (status, connections) = server.request("GET", "/api/v1/admin/connections", Some(&root.session_key), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let connections = connections.as_array().unwrap();
+    assert_eq!(connections.len(), 1);
+    let connection = &connections[0];
+    assert_eq!(connection["username"], "alice");
+    assert_eq!(connection["user_id"], json!(alice.user_id));
+    assert_eq!(connection["queue_depth"], 0);
+    assert!(connection["connected_at"].is_string() && connection["last_activity_at"].is_string());
+    let session_key = connection["session_key"].as_str().unwrap();
+    assert!(alice.session_key.starts_with(session_key.trim_end_matches('…')));
+    assert_ne!(session_key, alice.session_key);
+
+    let (status, _) = server.request("GET", "/api/v1/admin/connections", Some(&alice.session_key), None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let path = format!("/api/v1/admin/connections/{}", connection["id"].as_str().unwrap());
+    let (status, _) = server.request("DELETE", &path, Some(&root.session_key), None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    alice_ws.recv_closed().await;
+    let (status, body) = server.request("DELETE", &path, Some(&root.session_key), None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["code"], "CONNECTION_NOT_FOUND");
+}
+
+#[tokio::test]
+async fn server_closes_revoked_flooding_and_remaining_connections() {
+    let limits = LimitsConfig { max_messages_per_minute: 2, ..LimitsConfig::default() };
+    let server = TestServer::with_config(Config { limits, ..testing::test_config() }).await;
+    let alice = server.register("alice", "secret").await;
+    let bob = server.register("bob", "secret").await;
+    let carol = server.register("carol", "secret").await;
+    let mut alice_ws = server.connect(&alice).await;
+    let mut bob_ws = server.connect(&bob).await;
+    let mut carol_ws = server.connect(&carol).await;
+
+    // Logging in again revokes the session of the open WebSocket.
+    server.login("alice", "secret").await;
+    alice_ws.recv_closed().await;
+
+    // Frames over the limit are dropped; twice the limit closes the connection.
+    for _ in 0..5 {
+        bob_ws.send_json(&json!({ "type": "typingIndicator", "to_user_id": carol.user_id, "is_typing": false })).await;
+    }
+    bob_ws.recv_closed().await;
+    server.wait_disconnected(&bob.session_key).await;
+
+    server.shutdown().await;
+    carol_ws.recv_closed().await;
+    assert_eq!(server.state().connections.count().await, 0);
+}
+
+#[tokio::test]
+async fn websockets_authenticate_with_their_first_frame() {
+    let config = testing::test_config();
+    let auth = AuthConfig { allow_query_token: false, auth_frame_timeout_secs: 1, ..config.auth.clone() };
+    let server = TestServer::with_config(Config { auth, ..config }).await;
+    let alice = server.register("alice", "secret").await;
+
+    let mut alice_ws = server.connect_unauthenticated().await;
+    alice_ws.send_json(&json!({ "type": "auth", "session_key": alice.session_key })).await;
+    let authenticated = alice_ws.recv_type("authenticated").await;
+    assert_eq!(authenticated["username"], "alice");
+    assert_eq!(authenticated["user_id"], json!(alice.user_id));
+
+    // Any other first frame, an unknown key, or none in time closes the connection.
+    let mut eager = server.connect_unauthenticated().await;
+    eager.send_json(&json!({ "type": "typingIndicator", "to_user_id": alice.user_id, "is_typing": true })).await;
+    eager.recv_closed().await;
+    let mut forged = server.connect_unauthenticated().await;
+    forged.send_json(&json!({ "type": "auth", "session_key": "forged" })).await;
+    forged.recv_closed().await;
+    let mut silent = server.connect_unauthenticated().await;
+    silent.recv_closed().await;
+    assert_eq!(server.state().connections.count().await, 1);
+}
+
+#[tokio::test]
+async fn websocket_upgrades_from_other_origins_are_refused() {
+    let config = testing::test_config();
+    let auth = AuthConfig { allowed_origins: vec!["https://chat.example.com".to_string()], ..config.auth.clone() };
+    let server = TestServer::with_config(Config { auth, ..config }).await;
+    let alice = server.register("alice", "secret").await;
+
+    assert!(server.upgrade_from_origin(&alice, "https://chat.example.com").await);
+    assert!(server.upgrade_from_origin(&alice, "HTTPS://Chat.Example.com").await);
+    assert!(!server.upgrade_from_origin(&alice, "https://evil.example").await);
+    // Clients that are not browsers send no origin.
+    server.connect(&alice).await;
+}
+
+#[tokio::test]
+async fn invalid_client_messages_are_answered_with_the_offending_fields() {
+    let server = TestServer::new().await;
+    let alice = server.register("alice", "secret").await;
+    let bob = server.register("bob", "secret").await;
+    let mut alice_ws = server.connect(&alice).await;
+
+    alice_ws.send_json(&json!({ "type": "chatMessage", "to_user_id": uuid::Uuid::new_v4(), "message": " " })).await;
+    let error = alice_ws.recv_type("error").await;
+    assert_eq!(error["code"], "VALIDATION_FAILED");
+    assert_eq!(
+        error["fields"],
+        json!([{ "field": "message", "reason": "must not be empty" }, { "field": "to_user_id", "reason": "unknown user" }])
+    );
+
+    let poll = json!({ "type": "poll", "to_user_id": bob.user_id, "question": "x".repeat(1000), "options": ["yes"] });
+    alice_ws.send_json(&poll).await;
+    let error = alice_ws.recv_type("error").await;
+    let fields: Vec<_> = error["fields"].as_array().unwrap().iter().map(|field| field["field"].as_str().unwrap()).collect();
+    assert_eq!(fields, ["question", "options"]);
+}
+
+#[tokio::test]
+async fn strict_parsing_rejects_unknown_fields_and_types() {
+    let messages = MessagesConfig { strict_parsing: true, ..MessagesConfig::default() };
+    let server = TestServer::with_config(Config { messages, ..testing::test_config() }).await;
+    let alice = server.register("alice", "secret").await;
+    let bob = server.register("bob", "secret").await;
+    let mut alice_ws = server.connect(&alice).await;
+
+    alice_ws.send_json(&json!({ "type": "typingIndicator", "to_user_id": bob.user_id, "is_typing": true, "colour": "red" })).await;
+    let error = alice_ws.recv_type("error").await;
+    assert_eq!(error["code"], "VALIDATION_FAILED");
+    assert_eq!(error["fields"], json!([{ "field": "colour", "reason": "unknown field" }]));
+
+    alice_ws.send_json(&json!({ "type": "wave", "to_user_id": bob.user_id })).await;
+    let error = alice_ws.recv_type("error").await;
+    assert_eq!(error["code"], "MALFORMED_MESSAGE");
+
+    // Lenient parsing, the default, ignores what it does not know.
+    let server = TestServer::new().await;
+    let alice = server.register("alice", "secret").await;
+    let bob = server.register("bob", "secret").await;
+    let mut alice_ws = server.connect(&alice).await;
+    let mut bob_ws = server.connect(&bob).await;
+    alice_ws.send_json(&json!({ "type": "wave", "to_user_id": bob.user_id })).await;
+    alice_ws.send_json(&json!({ "type": "chatMessage", "to_user_id": bob.user_id, "message": "hi", "colour": "red" })).await;
+    assert_eq!(bob_ws.recv_type("chatMessage").await["message"], "hi");
+}
+
+#[tokio::test]
+async fn time_sync_echoes_the_client_time_with_the_server_clock() {
+    let server = TestServer::new().await;
+    let alice = server.register("alice", "secret").await;
+    let mut alice_ws = server.connect(&alice).await;
+
+    let before = chrono::Utc::now();
+    alice_ws.send_json(&json!({ "type": "timeSync", "client_time": 1234 })).await;
+    let reply = alice_ws.recv_type("timeSync").await;
+    assert_eq!(reply["client_time"], 1234);
+    let server_time = chrono::DateTime::parse_from_rfc3339(reply["server_time"].as_str().unwrap()).unwrap();
+    assert!(server_time >= before && server_time <= chrono::Utc::now());
+}
+
+#[tokio::test]
+async fn only_messages_signed_with_the_session_key_are_verified() {
+    let server = TestServer::new().await;
+    server.register("alice", "secret").await;
+    let bob = server.register("bob", "secret").await;
+    let signing_key = SigningKey::from_bytes(&[7; 32]);
+    let login = json!({ "username": "alice", "password": "secret", "signing_key": STANDARD.encode(signing_key.verifying_key().as_bytes()) });
+    let (status, body) = server.request("POST", "/api/v1/login", None, Some(&login)).await;
+    assert_eq!(status, StatusCode::OK);
+    let alice_session = body["session_key"].as_str().unwrap();
+    let mut bob_ws = server.connect(&bob).await;
+    let sign = |message: &str| STANDARD.encode(signing_key.sign(message.as_bytes()).to_bytes());
+
+    let signed = json!({ "to_user_id": bob.user_id, "message": "hello", "signature": sign("hello") });
+    let (status, _) = server.request("POST", "/api/v1/messages", Some(alice_session), Some(&signed)).await;
+    assert_eq!(status, StatusCode::OK);
+    let received = bob_ws.recv_type("chatMessage").await;
+    assert_eq!((received["message"].as_str(), received["verified"].as_bool()), (Some("hello"), Some(true)));
+
+    // Signed for another text: refused rather than delivered unverified.
+    let tampered = json!({ "to_user_id": bob.user_id, "message": "hello!", "signature": sign("hello") });
+    let (status, body) = server.request("POST", "/api/v1/messages", Some(alice_session), Some(&tampered)).await;
+    assert_eq!((status, body["code"].as_str()), (StatusCode::BAD_REQUEST, Some("INVALID_SIGNATURE")));
+    let unsigned = json!({ "to_user_id": bob.user_id, "message": "plain" });
+    let (status, _) = server.request("POST", "/api/v1/messages", Some(alice_session), Some(&unsigned)).await;
+    assert_eq!(status, StatusCode::OK);
+    let received = bob_ws.recv_type("chatMessage").await;
+    assert_eq!((received["message"].as_str(), received["verified"].as_bool()), (Some("plain"), Some(false)));
+}
+
+#[tokio::test]
+async fn messages_carry_hybrid_clock_readings_in_delivery_order() {
+    let server = TestServer::new().await;
+    let alice = server.register("alice", "secret").await;
+    let bob = server.register("bob", "secret").await;
+    let mut alice_ws = server.connect(&alice).await;
+    let mut bob_ws = server.connect(&bob).await;
+
+    for n in 0..10 {
+        alice_ws.send_json(&json!({ "type": "chatMessage", "to_user_id": bob.user_id, "message": n.to_string() })).await;
+    }
+    let mut received = Vec::new();
+    for _ in 0..10 {
+        received.push(bob_ws.recv_type("chatMessage").await);
+    }
+    let hlcs: Vec<&str> = received.iter().map(|message| message["hlc"].as_str().unwrap()).collect();
+    assert!(hlcs.windows(2).all(|pair| pair[0] < pair[1]), "{:?}", hlcs);
+    let timestamps: Vec<_> =
+        received.iter().map(|message| chrono::DateTime::parse_from_rfc3339(message["timestamp"].as_str().unwrap()).unwrap()).collect();
+    assert!(timestamps.windows(2).all(|pair| pair[0] <= pair[1]));
+}
+
+#[tokio::test]
+async fn slow_consumers_are_warned_and_disconnected() {
+    let limits = LimitsConfig { slow_consumer_queue_depth: 8, slow_consumer_grace_secs: 0, ..LimitsConfig::default() };
+    let server = TestServer::with_config(Config { limits, ..testing::test_config() }).await;
+    let alice = server.register("alice", "secret").await;
+    let mut alice_ws = server.connect(&alice).await;
+
+    // Queue far more frames at once than the connection may have pending.
+    let connections = server.state().connections.snapshot().await;
+    let (_, connection) = connections.iter().find(|(key, _)| *key == alice.session_key).expect("alice is connected");
+    for _ in 0..64 {
+        connection.send(Message::text("{}")).unwrap();
+    }
+
+    let warning = alice_ws.recv_type("error").await;
+    assert_eq!(warning["code"], "SLOW_CONSUMER");
+    alice_ws.recv_closed().await;
+    server.wait_disconnected(&alice.session_key).await;
+}
+
+/// A directory accepting the passwords in `passwords`, or failing while `unavailable`.
+#[derive(Debug, Default)]
+struct FakeDirectory {
+    passwords: Mutex<HashMap<String, String>>,
+    unavailable: AtomicBool,
+}
+
+impl FakeDirectory {
+    fn with_user(username: &str, password: &str) -> Arc<Self> {
+        let directory = FakeDirectory::default();
+        directory.passwords.lock().unwrap().insert(username.to_string(), password.to_string());
+        Arc::new(directory)
+    }
+}
+
+impl Directory for FakeDirectory {
+    fn authenticate<'a>(&'a self, username: &'a str, password: &'a str) -> BoxFuture<'a, Result<bool, String>> {
+        let answer = if self.unavailable.load(Ordering::SeqCst) {
+            Err("directory down".to_string())
+        } else {
+            Ok(self.passwords.lock().unwrap().get(username).is_some_and(|known| known == password))
+        };
+        Box::pin(async move { answer })
+    }
+}
+
+#[tokio::test]
+async fn directory_users_get_an_account_on_their_first_login() {
+    let directory = FakeDirectory::with_user("alice", "secret");
+    let server = TestServer::with_directory(testing::test_config(), directory.clone()).await;
+    let login = |username: &str, password: &str| json!({ "username": username, "password": password });
+
+    let (status, body) = server.request("POST", "/api/v1/register", None, Some(&login("bob", "secret"))).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["code"], "REGISTRATION_DISABLED");
+    let (status, _) = server.request("POST", "/api/v1/login", None, Some(&login("bob", "secret"))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, _) = server.request("POST", "/api/v1/login", None, Some(&login("alice", "guess"))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert!(server.state().users.get("alice").is_none());
+
+    let alice = server.login("alice", "secret").await;
+    let account = server.state().users.get("alice").expect("provisioned on first login");
+    assert!(account.ldap);
+    assert_eq!(account.id, alice.user_id);
+
+    // Later logins ask the directory again rather than a local password.
+    directory.passwords.lock().unwrap().insert("alice".to_string(), "rotated".to_string());
+    let (status, _) = server.request("POST", "/api/v1/login", None, Some(&login("alice", "secret"))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(server.login("alice", "rotated").await.user_id, alice.user_id);
+}
+
+#[tokio::test]
+async fn an_unavailable_directory_refuses_logins_without_counting_them_as_failed() {
+    let directory = FakeDirectory::with_user("alice", "secret");
+    let server = TestServer::with_directory(testing::test_config(), directory.clone()).await;
+    server.login("alice", "secret").await;
+    let max_failed_logins = server.state().config.auth.max_failed_logins;
+
+    directory.unavailable.store(true, Ordering::SeqCst);
+    for username in ["alice", "carol"] {
+        let (status, body) = server.request("POST", "/api/v1/login", None, Some(&json!({ "username": username, "password": "secret" }))).await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["code"], "DIRECTORY_UNAVAILABLE");
+    }
+    for _ in 0..max_failed_logins {
+        server.request("POST", "/api/v1/login", None, Some(&json!({ "username": "alice", "password": "secret" }))).await;
+    }
+    assert!(server.state().users.get("carol").is_none());
+
+    directory.unavailable.store(false, Ordering::SeqCst);
+    server.login("alice", "secret").await;
+}
+
+#[tokio::test]
+async fn directory_accounts_change_their_password_in_the_directory() {
+    let server = TestServer::with_directory(testing::test_config(), FakeDirectory::with_user("alice", "secret")).await;
+    let alice = server.login("alice", "secret").await;
+
+    let change = json!({ "current_password": "secret", "new_password": "hunter2" });
+    let (status, body) = server.request("POST", "/api/v1/me/password", Some(&alice.session_key), Some(&change)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["code"], "VALIDATION_FAILED");
+    let (status, _) = server.request("POST", "/api/v1/login", None, Some(&json!({ "username": "alice", "password": "hunter2" }))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    server.login("alice", "secret").await;
+}
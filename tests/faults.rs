@@ -0,0 +1,67 @@
+// tests/faults.rs
+
+// Driving the combined warp route filter nests deeper than the default limit allows.
+#![recursion_limit = "256"]
+
+use rust_chat::testing::TestServer;
+use std::time::{Duration, Instant};
+
+#[tokio::test]
+async fn every_nth_send_is_dropped() {
+    let server = TestServer::new().await;
+    let mut connections = server.simulate_connections(2).await;
+    let to_user_id = connections[1].user_id();
+    server.state().faults.drop_every_nth_send(3);
+
+    for _ in 0..6 {
+        server.send_message(&connections[0], to_user_id, "hello").await;
+    }
+
+    // The 3rd and 6th messages never reach the recipient; the sender's echo is not dropped.
+    assert_eq!(connections[1].drain(), 4);
+    assert_eq!(connections[0].drain(), 6);
+}
+
+#[tokio::test]
+async fn presence_updates_are_dropped_per_connection() {
+    let server = TestServer::new().await;
+    let mut connections = server.simulate_connections(5).await;
+    server.state().faults.drop_every_nth_send(2);
+
+    server.broadcast_status(&connections[0], "online").await;
+
+    let received: usize = connections[1..].iter_mut().map(|connection| connection.drain()).sum();
+    assert_eq!(received, 2);
+}
+
+#[tokio::test]
+async fn latency_delays_the_fanout() {
+    let server = TestServer::new().await;
+    let connections = server.simulate_connections(2).await;
+    server.state().faults.set_latency(Duration::from_millis(50));
+
+    let started = Instant::now();
+    server.send_message(&connections[0], connections[1].user_id(), "slow").await;
+    assert!(started.elapsed() >= Duration::from_millis(50));
+
+    server.state().faults.reset();
+    let started = Instant::now();
+    server.send_message(&connections[0], connections[1].user_id(), "fast").await;
+    assert!(started.elapsed() < Duration::from_millis(50));
+}
+
+#[tokio::test]
+async fn registry_contention_serializes_concurrent_fanouts() {
+    let server = TestServer::new().await;
+    let connections = server.simulate_connections(4).await;
+    server.state().faults.set_lock_contention(Duration::from_millis(40));
+
+    let started = Instant::now();
+    tokio::join!(
+        server.send_message(&connections[0], connections[1].user_id(), "first"),
+        server.send_message(&connections[2], connections[3].user_id(), "second"),
+    );
+
+    // Each fanout stalls the connection registry for 40ms in turn.
+    assert!(started.elapsed() >= Duration::from_millis(80));
+}